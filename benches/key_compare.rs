@@ -0,0 +1,86 @@
+//! Compares `FixedKey` with plain byte arrays on the paths that compare keys the
+//! most: sorting, flushing a memtable, and looking keys up on disk. Run with
+//! `cargo bench --bench key_compare`.
+
+extern crate btree;
+extern crate rand;
+
+use btree::{BTree, FixedKey, Options, SimDisk};
+use rand::{thread_rng, Rng};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const KEYS: usize = 200_000;
+
+fn time<T>(f: impl FnOnce() -> T) -> Duration {
+    let start = Instant::now();
+    std::hint::black_box(f());
+    start.elapsed()
+}
+
+/// The keys share a long prefix, like hashes bucketed by a common header
+fn random_keys() -> Vec<[u8; 32]> {
+    let mut rng = thread_rng();
+
+    (0..KEYS)
+        .map(|_| {
+            let mut key = [0xab; 32];
+            rng.fill(&mut key[16..]);
+            key
+        })
+        .collect()
+}
+
+fn sort<K: Ord + Clone>(keys: &[K]) -> Duration {
+    let mut keys = keys.to_vec();
+    time(move || keys.sort_unstable())
+}
+
+fn tree<K: btree::KeyType>(keys: &[K]) -> (Duration, Duration) {
+    let options = Options {
+        storage: Arc::new(SimDisk::new(0)),
+        flush_threshold: KEYS / 4,
+        ..Options::default()
+    };
+    let mut btree = BTree::<K, u32>::with_options("bench", 32, 4, options).unwrap();
+
+    let insert = time(|| {
+        for key in keys {
+            btree.insert(key.clone(), 0).unwrap();
+        }
+        btree.flush().unwrap();
+    });
+
+    let get = time(|| {
+        for key in keys.iter().step_by(10) {
+            btree.get(key).unwrap();
+        }
+    });
+
+    (insert, get)
+}
+
+fn main() {
+    let arrays = random_keys();
+    let fixed: Vec<FixedKey<32>> = arrays.iter().map(|key| FixedKey(*key)).collect();
+
+    println!(
+        "sort {} keys:     [u8; 32] {:?}, FixedKey {:?}",
+        KEYS,
+        sort(&arrays),
+        sort(&fixed)
+    );
+
+    let (array_insert, array_get) = tree(&arrays);
+    let (fixed_insert, fixed_get) = tree(&fixed);
+
+    println!(
+        "insert and flush: [u8; 32] {:?}, FixedKey {:?}",
+        array_insert, fixed_insert
+    );
+    println!(
+        "get:              [u8; 32] {:?}, FixedKey {:?}",
+        array_get, fixed_get
+    );
+}
@@ -0,0 +1,11 @@
+//! Generates the messages, server and client of `proto/btree.proto`, with the
+//! protoc that comes with `protoc-bin-vendored` rather than one on the path
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/btree.proto");
+
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_prost_build::compile_protos("proto/btree.proto")?;
+
+    Ok(())
+}
@@ -0,0 +1,300 @@
+//! A gRPC service for sharing a `BTree` of byte string keys and values, as
+//! `proto/btree.proto` defines it, for clients in any language protoc generates
+//! code for. It lives in a crate of its own because the code tonic generates needs
+//! a newer edition than the tree's.
+
+use btree::BTree;
+
+use std::error::Error;
+use std::net::TcpListener;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::sync::{Arc, Mutex};
+
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("btree");
+}
+
+pub use proto::b_tree_client::BTreeClient;
+pub use proto::b_tree_server::BTreeServer;
+
+use proto::{
+    CompactRequest, CompactResponse, DeleteRequest, DeleteResponse, Entry, GetRequest, GetResponse,
+    PutRequest, PutResponse, ScanRequest, StatsRequest, StatsResponse,
+};
+
+/// The tree the service shares
+pub type GrpcTree = BTree<Vec<u8>, Vec<u8>>;
+
+// how many keys a scan reads with the tree locked, before letting other calls in
+const SCAN_BATCH: usize = 1000;
+
+// how many entries a scan reads ahead of a client slow to take them
+const SCAN_BUFFER: usize = 64;
+
+/// Answers the calls of `proto/btree.proto` against a shared tree. Every call runs
+/// on the blocking pool, as the tree does its I/O in line, and locks the tree for
+/// its own length: a scan for each batch of `SCAN_BATCH` keys only.
+#[derive(Clone)]
+pub struct BTreeService {
+    tree: Arc<Mutex<GrpcTree>>,
+}
+
+impl BTreeService {
+    pub fn new(tree: Arc<Mutex<GrpcTree>>) -> BTreeService {
+        BTreeService { tree }
+    }
+
+    pub fn into_server(self) -> BTreeServer<BTreeService> {
+        BTreeServer::new(self)
+    }
+
+    /// Runs `op` against the tree on the blocking pool, an error it returns
+    /// becoming an internal one
+    async fn with_tree<T, F>(&self, op: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut GrpcTree) -> Result<T, Box<dyn Error>> + Send + 'static,
+    {
+        let tree = self.tree.clone();
+
+        tokio::task::spawn_blocking(move || {
+            op(&mut tree.lock().unwrap()).map_err(|e| Status::internal(e.to_string()))
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+    }
+}
+
+#[tonic::async_trait]
+impl proto::b_tree_server::BTree for BTreeService {
+    type ScanStream = ReceiverStream<Result<Entry, Status>>;
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest { key, value } = request.into_inner();
+
+        self.with_tree(move |tree| tree.insert(key, value)).await?;
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let DeleteRequest { key, value } = request.into_inner();
+
+        self.with_tree(move |tree| tree.delete(key, value)).await?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let GetRequest { key } = request.into_inner();
+        let values = self.with_tree(move |tree| tree.get(&key)).await?;
+
+        Ok(Response::new(GetResponse {
+            values: values.unwrap_or_default(),
+        }))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> Result<Response<Self::ScanStream>, Status> {
+        let ScanRequest { start, end } = request.into_inner();
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        let tree = self.tree.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let end = end.map_or(Unbounded, Excluded);
+            let mut start = start.map_or(Unbounded, Included);
+
+            loop {
+                let batch = read_batch(&tree, start, end.clone());
+                let more = matches!(&batch, Ok(entries) if entries.len() == SCAN_BATCH);
+
+                let entries = match batch {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        let _ = sender.blocking_send(Err(Status::internal(e)));
+                        return;
+                    }
+                };
+
+                start = match entries.last() {
+                    Some((key, _)) => Excluded(key.clone()),
+                    None => return,
+                };
+
+                for (key, values) in entries {
+                    // the client is gone
+                    if sender.blocking_send(Ok(Entry { key, values })).is_err() {
+                        return;
+                    }
+                }
+
+                if !more {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn compact(
+        &self,
+        _request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let compacted = self.with_tree(|tree| tree.compact_range(..)).await?;
+
+        Ok(Response::new(CompactResponse { compacted }))
+    }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> Result<Response<StatsResponse>, Status> {
+        let stats = self.with_tree(|tree| Ok(tree.stats())).await?;
+
+        Ok(Response::new(StatsResponse {
+            writes: stats.writes,
+            flushes: stats.flushes,
+            compactions: stats.compactions,
+            pending_bytes: stats.pending_bytes as u64,
+            cache_hits: stats.cache_hits,
+            cache_misses: stats.cache_misses,
+        }))
+    }
+}
+
+/// The keys and values of a batch of a scan, at most `SCAN_BATCH` of them
+type Batch = Vec<(Vec<u8>, Vec<Vec<u8>>)>;
+
+/// Reads the next batch of a scan with the tree locked, failing on a record that
+/// can't be read rather than leaving it out
+fn read_batch(
+    tree: &Mutex<GrpcTree>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+) -> Result<Batch, String> {
+    let tree = tree.lock().unwrap();
+    let entries = tree.try_range((start, end)).map_err(|e| e.to_string())?;
+
+    entries
+        .take(SCAN_BATCH)
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())
+}
+
+/// Answers gRPC calls made to `listener` until it fails, on a runtime of its own
+pub fn serve(tree: Arc<Mutex<GrpcTree>>, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+    listener.set_nonblocking(true)?;
+
+    Runtime::new()?.block_on(async move {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+
+        Server::builder()
+            .add_service(BTreeService::new(tree).into_server())
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await?;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, BTreeClient, GrpcTree};
+    use crate::proto::{
+        CompactRequest, DeleteRequest, GetRequest, PutRequest, ScanRequest, StatsRequest,
+    };
+    use btree::{Options, SimDisk};
+
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use tokio::runtime::Runtime;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn clients_share_a_tree_over_grpc() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = Arc::new(Mutex::new(
+            GrpcTree::with_options("db", 8, 16, options).unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || serve(tree, listener).map_err(|e| e.to_string()));
+
+        Runtime::new().unwrap().block_on(async move {
+            let mut client = BTreeClient::connect(address).await.unwrap();
+
+            // more keys than a scan reads at a time
+            for i in 0..2500u32 {
+                let request = PutRequest {
+                    key: i.to_be_bytes().to_vec(),
+                    value: b"value".to_vec(),
+                };
+                client.put(request).await.unwrap();
+            }
+            let request = DeleteRequest {
+                key: 3u32.to_be_bytes().to_vec(),
+                value: b"value".to_vec(),
+            };
+            client.delete(request).await.unwrap();
+
+            let get = |i: u32| GetRequest {
+                key: i.to_be_bytes().to_vec(),
+            };
+            let values = client.get(get(1)).await.unwrap().into_inner().values;
+            assert_eq!(values, vec![b"value".to_vec()]);
+            assert!(client
+                .get(get(3))
+                .await
+                .unwrap()
+                .into_inner()
+                .values
+                .is_empty());
+
+            let request = ScanRequest {
+                start: Some(2u32.to_be_bytes().to_vec()),
+                end: None,
+            };
+            let keys: Vec<Vec<u8>> = client
+                .scan(request)
+                .await
+                .unwrap()
+                .into_inner()
+                .map(|entry| entry.unwrap().key)
+                .collect()
+                .await;
+            let expected: Vec<Vec<u8>> = (2..2500u32)
+                .filter(|i| *i != 3)
+                .map(|i| i.to_be_bytes().to_vec())
+                .collect();
+            assert_eq!(keys, expected);
+
+            let compact = client
+                .compact(CompactRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(compact.compacted);
+
+            let stats = client.stats(StatsRequest {}).await.unwrap().into_inner();
+            assert_eq!(stats.writes, 2501);
+            assert!(stats.compactions >= 1);
+            assert_eq!(stats.pending_bytes, 0);
+        });
+    }
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+
+// the accented letters of Latin-1 and Latin Extended-A, by accent, with the
+// letters they're accents on
+const ACCENTS: [(&str, &str); 14] = [
+    ("àèìòùÀÈÌÒÙ", "aeiouAEIOU"),                             // grave
+    ("áéíóúýÁÉÍÓÚÝćĆńŃśŚźŹĺĹŕŔ", "aeiouyAEIOUYcCnNsSzZlLrR"), // acute
+    ("âêîôûÂÊÎÔÛĉĈĝĜĥĤĵĴŝŜŵŴŷŶ", "aeiouAEIOUcCgGhHjJsSwWyY"), // circumflex
+    ("ãñõÃÑÕĩĨũŨ", "anoANOiIuU"),                             // tilde
+    ("äëïöüÿÄËÏÖÜŸ", "aeiouyAEIOUY"),                         // diaeresis
+    ("åÅůŮ", "aAuU"),                                         // ring
+    ("çÇşŞţŢķĶļĻņŅŗŖģĢ", "cCsStTkKlLnNrRgG"),                 // cedilla
+    ("čČďĎěĚňŇřŘšŠťŤžŽ", "cCdDeEnNrRsStTzZ"),                 // caron
+    ("āĀēĒīĪōŌūŪ", "aAeEiIoOuU"),                             // macron
+    ("ăĂĕĔğĞĭĬŏŎŭŬ", "aAeEgGiIoOuU"),                         // breve
+    ("ąĄęĘįĮųŲ", "aAeEiIuU"),                                 // ogonek
+    ("ċĊėĖġĠżŻİ", "cCeEgGzZI"),                               // dot above
+    ("őŐűŰ", "oOuU"),                                         // double acute
+    ("øØłŁđĐħĦ", "oOlLdDhH"),                                 // stroke
+];
+
+/// A string key ordered by Latin accent folding rather than by its bytes: first by
+/// its letters, with their accents and case folded away, then by its accents, then
+/// by its case, lowercase first, so "Äpfel" < "apple" < "Apple" < "Ápple" <
+/// "banana". Only the accented Latin letters of Latin-1 and Latin Extended-A are
+/// folded; anything else sorts by code point. This isn't the Unicode collation
+/// algorithm, nor any language's collation.
+///
+/// The sort key is stored along with the text, taking five bytes a character and
+/// three more on top of the text, and 16 for the two lengths.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AccentFoldedString {
+    sort_key: Vec<u8>,
+    text: String,
+}
+
+impl AccentFoldedString {
+    pub fn new(text: &str) -> AccentFoldedString {
+        let mut primary = Vec::new();
+        let mut secondary = Vec::new();
+        let mut tertiary = Vec::new();
+
+        for c in text.chars() {
+            let (base, accent) = split_accent(c);
+            let lower = base.to_lowercase().next().unwrap_or(base);
+
+            // a character's letter goes in three bytes, one more than its code
+            // point, so it sorts after the zeros ending the letters
+            primary.extend_from_slice(&(lower as u32 + 1).to_be_bytes()[1..]);
+            secondary.push(accent);
+            tertiary.push(u8::from(lower != base));
+        }
+
+        primary.extend_from_slice(&[0, 0, 0]);
+        primary.append(&mut secondary);
+        primary.append(&mut tertiary);
+
+        AccentFoldedString {
+            sort_key: primary,
+            text: text.to_owned(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// The bytes the string is ordered by
+    pub fn sort_key(&self) -> &[u8] {
+        &self.sort_key
+    }
+}
+
+// strings that fold alike, as different normalisations can, still fall back to
+// their bytes so that only equal strings are equal
+impl Ord for AccentFoldedString {
+    fn cmp(&self, other: &AccentFoldedString) -> Ordering {
+        self.sort_key
+            .cmp(&other.sort_key)
+            .then_with(|| self.text.cmp(&other.text))
+    }
+}
+
+impl PartialOrd for AccentFoldedString {
+    fn partial_cmp(&self, other: &AccentFoldedString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<&str> for AccentFoldedString {
+    fn from(text: &str) -> AccentFoldedString {
+        AccentFoldedString::new(text)
+    }
+}
+
+/// The letter `c` is an accent on, and which accent, counting from 1; 0 for none
+fn split_accent(c: char) -> (char, u8) {
+    for (accent, (accented, bases)) in ACCENTS.iter().enumerate() {
+        if let Some(i) = accented.chars().position(|a| a == c) {
+            return (bases.chars().nth(i).unwrap_or(c), accent as u8 + 1);
+        }
+    }
+
+    (c, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use accent_folding::AccentFoldedString;
+    use {BTree, Options, SimDisk};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn strings_sort_by_letter_then_accent_then_case() {
+        let mut words: Vec<AccentFoldedString> = [
+            "banana", "Äpfel", "apple", "Apple", "äpfel", "zoo", "Ápple", "app",
+        ]
+        .iter()
+        .map(|word| AccentFoldedString::new(word))
+        .collect();
+        words.sort();
+
+        let words: Vec<&str> = words.iter().map(|word| word.as_str()).collect();
+        assert_eq!(
+            words,
+            ["äpfel", "Äpfel", "app", "apple", "Apple", "Ápple", "banana", "zoo"]
+        );
+    }
+
+    #[test]
+    fn ranges_follow_the_folding() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree =
+            BTree::<AccentFoldedString, u32>::with_options("db", 96, 4, options).unwrap();
+
+        for word in ["Zebra", "église", "eagle", "fig", "Egg"] {
+            btree.insert(AccentFoldedString::new(word), 0).unwrap();
+        }
+
+        let words: Vec<String> = btree
+            .range(AccentFoldedString::new("e")..AccentFoldedString::new("f"))
+            .unwrap()
+            .map(|(word, _)| word.as_str().to_owned())
+            .collect();
+        assert_eq!(words, ["eagle", "Egg", "église"]);
+    }
+}
@@ -0,0 +1,27 @@
+/// What `BTree::aggregate` computes over the values in a range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Agg {
+    Count, // how many values there are, across every key
+    Min,
+    Max,
+}
+
+/// The answer to an `Agg`; the smallest or largest value is None for an empty range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Aggregate<V> {
+    Count(u64),
+    Min(Option<V>),
+    Max(Option<V>),
+}
+
+impl<V: Ord> Aggregate<V> {
+    /// Computes `agg` over the values of each key in turn, keeping only the answer
+    /// so far rather than the values
+    pub fn over<I: Iterator<Item = Vec<V>>>(agg: Agg, values: I) -> Aggregate<V> {
+        match agg {
+            Agg::Count => Aggregate::Count(values.map(|values| values.len() as u64).sum()),
+            Agg::Min => Aggregate::Min(values.filter_map(|values| values.into_iter().min()).min()),
+            Agg::Max => Aggregate::Max(values.filter_map(|values| values.into_iter().max()).max()),
+        }
+    }
+}
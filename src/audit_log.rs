@@ -0,0 +1,170 @@
+use storage::{Storage, StorageFile};
+use {KeyType, ValueType};
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+/// What a mutation did
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AuditOp {
+    Insert,
+    Delete,
+    SoftDelete,
+    Undelete,
+    Increment,
+}
+
+/// A single entry in the audit log
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry<K, V> {
+    pub seq: u64,      // the sequence number of the write
+    pub at: u64,       // milliseconds since the epoch
+    pub actor: String, // who made the write, as supplied by the caller
+    pub op: AuditOp,
+    pub key: K,
+    pub value: V,
+}
+
+/// An append-only sidecar file recording every mutation. Entries vary in size
+/// (the actor is free-form) so each one is stored as a little-endian u32 length
+/// followed by the bincode-encoded entry.
+pub struct AuditLog<K: KeyType, V: ValueType> {
+    fd: Box<dyn StorageFile>,
+    _k_marker: PhantomData<K>,
+    _v_marker: PhantomData<V>,
+}
+
+impl<K: KeyType, V: ValueType> AuditLog<K, V> {
+    pub fn new(storage: &dyn Storage, file_path: &str) -> Result<AuditLog<K, V>, Box<dyn Error>> {
+        Ok(AuditLog {
+            fd: storage.open(file_path)?,
+            _k_marker: PhantomData,
+            _v_marker: PhantomData,
+        })
+    }
+
+    /// Appends the entries of a batch in a single write, so an entry that can't be
+    /// encoded leaves none of them in the log
+    pub fn append(&mut self, entries: &[AuditEntry<K, V>]) -> Result<(), Box<dyn Error>> {
+        let mut buff = Vec::new();
+
+        for entry in entries {
+            let body = bincode::serialize(entry)?;
+
+            buff.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            buff.extend_from_slice(&body);
+        }
+
+        Ok(self.fd.append(&buff)?)
+    }
+
+    /// Reads back every entry, oldest first
+    pub fn entries(&self) -> Result<Vec<AuditEntry<K, V>>, Box<dyn Error>> {
+        let len = self.fd.len()?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset + 4 <= len {
+            let mut size = [0; 4];
+            self.fd.read_at(&mut size, offset)?;
+            let size = u32::from_le_bytes(size) as u64;
+
+            // a torn entry at the end of the file is simply ignored
+            if offset + 4 + size > len {
+                break;
+            }
+
+            let mut body = vec![0; size as usize];
+            self.fd.read_at(&mut body, offset + 4)?;
+            entries.push(bincode::deserialize(&body)?);
+
+            offset += 4 + size;
+        }
+
+        Ok(entries)
+    }
+
+    pub fn entries_for_key(&self, key: &K) -> Result<Vec<AuditEntry<K, V>>, Box<dyn Error>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|e| e.key == *key)
+            .collect())
+    }
+
+    /// Entries whose time falls within `range` (milliseconds since the epoch)
+    pub fn entries_between(
+        &self,
+        range: Range<u64>,
+    ) -> Result<Vec<AuditEntry<K, V>>, Box<dyn Error>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|e| range.contains(&e.at))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use audit_log::{AuditEntry, AuditLog, AuditOp};
+    use sim_disk::SimDisk;
+    use storage::Storage;
+
+    fn entry(seq: u64, at: u64, key: u32) -> AuditEntry<u32, String> {
+        AuditEntry {
+            seq,
+            at,
+            actor: format!("user-{}", seq),
+            op: AuditOp::Insert,
+            key,
+            value: "v".to_owned(),
+        }
+    }
+
+    #[test]
+    fn query_by_key_and_time() {
+        let disk = SimDisk::new(0);
+        let mut log = AuditLog::<u32, String>::new(&disk, "db.audit").unwrap();
+
+        log.append(&[entry(1, 100, 1), entry(2, 200, 2)]).unwrap();
+        log.append(&[entry(3, 300, 1)]).unwrap();
+
+        let by_key: Vec<u64> = log
+            .entries_for_key(&1)
+            .unwrap()
+            .iter()
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(by_key, [1, 3]);
+
+        let by_time: Vec<u64> = log
+            .entries_between(150..301)
+            .unwrap()
+            .iter()
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(by_time, [2, 3]);
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let disk = SimDisk::new(0);
+
+        {
+            let mut log = AuditLog::<u32, String>::new(&disk, "db.audit").unwrap();
+            log.append(&[entry(1, 100, 1)]).unwrap();
+        }
+
+        // a length prefix with no entry behind it
+        disk.open("db.audit")
+            .unwrap()
+            .append(&[9, 0, 0, 0, 1])
+            .unwrap();
+
+        let log = AuditLog::<u32, String>::new(&disk, "db.audit").unwrap();
+        assert_eq!(log.entries().unwrap(), [entry(1, 100, 1)]);
+    }
+}
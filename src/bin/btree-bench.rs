@@ -0,0 +1,402 @@
+//! Measures a tree of byte string keys and values through the public API, the
+//! way `db_bench` does for LevelDB: each benchmark named runs in turn against the
+//! same tree, on `--threads` threads sharing it, and reports the time per
+//! operation, the throughput and the latency percentiles. The keys and values
+//! come from a seeded generator, so a run can be repeated exactly.
+//!
+//! Usage: btree-bench [--benchmarks=fillseq,fillrandom,readrandom,scan,mixed] [--num=N]
+//!                    [--key-size=N] [--value-size=N] [--threads=N] [--read-percent=N]
+//!                    [--seed=N] [--cache-size=N] [--bloom-bits=N] [--flush-threshold=N]
+//!                    [--sync] [--db=<tree file> [--use-existing]]
+//!
+//! Without `--db` the tree is kept in memory, on a `SimDisk`.
+
+extern crate btree;
+extern crate rand;
+
+use btree::{BTree, LatencyHistogram, Options, SimDisk, SyncPolicy};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::env;
+use std::error::Error;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Tree = Arc<Mutex<BTree<Vec<u8>, Vec<u8>>>>;
+
+const BENCHMARKS: [&str; 5] = ["fillseq", "fillrandom", "readrandom", "scan", "mixed"];
+
+struct Config {
+    benchmarks: Vec<String>,
+    num: u64,          // keys written by the fills, and operations run by the others
+    key_size: usize,   // bytes in each key, at least 8
+    value_size: usize, // bytes in each value
+    threads: u64,      // sharing the tree in each benchmark
+    read_percent: u32, // of the operations `mixed` runs that are reads
+    seed: u64,         // for the keys and values, so a run can be repeated
+    cache_size: Option<usize>,
+    bloom_bits: Option<usize>,
+    flush_threshold: Option<usize>,
+    sync: bool,         // sync the WAL after every write
+    db: Option<String>, // the tree file, or None to keep the tree in memory
+    use_existing: bool, // open the tree at `db` even if it's already there
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            benchmarks: BENCHMARKS.iter().map(|name| name.to_string()).collect(),
+            num: 100_000,
+            key_size: 16,
+            value_size: 100,
+            threads: 1,
+            read_percent: 90,
+            seed: 301,
+            cache_size: None,
+            bloom_bits: None,
+            flush_threshold: None,
+            sync: false,
+            db: None,
+            use_existing: false,
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config::default();
+
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+
+        let number = || -> Result<u64, String> {
+            value
+                .ok_or_else(|| format!("{} needs a value", name))?
+                .parse()
+                .map_err(|_| format!("{} isn't a number: {}", name, arg))
+        };
+
+        match name {
+            "--benchmarks" => {
+                config.benchmarks = value.unwrap_or("").split(',').map(str::to_owned).collect()
+            }
+            "--num" => config.num = number()?,
+            "--key-size" => config.key_size = number()? as usize,
+            "--value-size" => config.value_size = number()? as usize,
+            "--threads" => config.threads = number()?.max(1),
+            "--read-percent" => config.read_percent = number()?.min(100) as u32,
+            "--seed" => config.seed = number()?,
+            "--cache-size" => config.cache_size = Some(number()? as usize),
+            "--bloom-bits" => config.bloom_bits = Some(number()? as usize),
+            "--flush-threshold" => config.flush_threshold = Some(number()? as usize),
+            "--sync" => config.sync = true,
+            "--db" => config.db = value.map(str::to_owned),
+            "--use-existing" => config.use_existing = true,
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    if let Some(unknown) = config
+        .benchmarks
+        .iter()
+        .find(|name| !BENCHMARKS.contains(&name.as_str()))
+    {
+        return Err(format!("Unknown benchmark: {}", unknown));
+    }
+
+    if config.key_size < 8 {
+        return Err("--key-size must be at least 8".to_owned());
+    }
+
+    Ok(config)
+}
+
+/// The key numbered `i`: big-endian at the end of `size` bytes, so keys sort as
+/// their numbers do
+fn key(i: u64, size: usize) -> Vec<u8> {
+    let mut key = vec![0; size];
+    key[size - 8..].copy_from_slice(&i.to_be_bytes());
+    key
+}
+
+fn value(rng: &mut StdRng, size: usize) -> Vec<u8> {
+    let mut value = vec![0; size];
+    rng.fill(&mut value[..]);
+    value
+}
+
+/// What one thread did in a benchmark
+#[derive(Default)]
+struct Done {
+    latency: LatencyHistogram,
+    bytes: u64, // of the keys and values written or read
+    found: u64, // keys a read found
+    reads: u64,
+}
+
+impl Done {
+    fn merge(&mut self, other: &Done) {
+        self.latency.merge(&other.latency);
+        self.bytes += other.bytes;
+        self.found += other.found;
+        self.reads += other.reads;
+    }
+}
+
+/// Times `op`, adding the bytes it returns to what's been done
+fn timed(
+    done: &mut Done,
+    op: impl FnOnce() -> Result<u64, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let bytes = op()?;
+
+    done.latency.record(start.elapsed());
+    done.bytes += bytes;
+
+    Ok(())
+}
+
+/// Runs `thread`'s share of the benchmark `name`, drawing keys and values from `seed`
+fn run_thread(
+    tree: &Tree,
+    config: &Config,
+    name: &str,
+    thread: u64,
+    seed: u64,
+) -> Result<Done, Box<dyn Error>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut done = Done::default();
+    let entry_bytes = (config.key_size + config.value_size) as u64;
+
+    // the threads split the operations between them, and fillseq the keys in order
+    let share = config.num / config.threads + u64::from(thread < config.num % config.threads);
+    let start = thread * (config.num / config.threads) + thread.min(config.num % config.threads);
+
+    match name {
+        "fillseq" => {
+            for i in start..start + share {
+                let (key, value) = (key(i, config.key_size), value(&mut rng, config.value_size));
+                timed(&mut done, || {
+                    tree.lock().unwrap().insert(key, value).map(|_| entry_bytes)
+                })?;
+            }
+        }
+        "fillrandom" => {
+            for _ in 0..share {
+                let (key, value) = (
+                    key(rng.gen_range(0..config.num), config.key_size),
+                    value(&mut rng, config.value_size),
+                );
+                timed(&mut done, || {
+                    tree.lock().unwrap().insert(key, value).map(|_| entry_bytes)
+                })?;
+            }
+        }
+        "readrandom" => {
+            for _ in 0..share {
+                let key = key(rng.gen_range(0..config.num), config.key_size);
+                let mut found = 0;
+
+                timed(&mut done, || {
+                    let values = tree.lock().unwrap().get(&key)?.unwrap_or_default();
+                    found = values.len() as u64;
+                    Ok(values
+                        .iter()
+                        .map(|value| (key.len() + value.len()) as u64)
+                        .sum())
+                })?;
+
+                done.reads += 1;
+                done.found += u64::from(found > 0);
+            }
+        }
+        "scan" => {
+            let tree = tree.lock().unwrap();
+            let mut entries = tree.range(..)?;
+
+            // each step of the scan is an operation
+            loop {
+                let start = Instant::now();
+                let next = entries.next();
+                done.latency.record(start.elapsed());
+
+                match next {
+                    Some((key, values)) => {
+                        done.bytes += values
+                            .iter()
+                            .map(|value| (key.len() + value.len()) as u64)
+                            .sum::<u64>()
+                    }
+                    None => break,
+                }
+            }
+        }
+        "mixed" => {
+            for _ in 0..share {
+                let key = key(rng.gen_range(0..config.num), config.key_size);
+
+                if rng.gen_range(0..100) < config.read_percent {
+                    let mut found = false;
+
+                    timed(&mut done, || {
+                        let values = tree.lock().unwrap().get(&key)?.unwrap_or_default();
+                        found = !values.is_empty();
+                        Ok(values
+                            .iter()
+                            .map(|value| (key.len() + value.len()) as u64)
+                            .sum())
+                    })?;
+
+                    done.reads += 1;
+                    done.found += u64::from(found);
+                } else {
+                    let value = value(&mut rng, config.value_size);
+                    timed(&mut done, || {
+                        tree.lock().unwrap().insert(key, value).map(|_| entry_bytes)
+                    })?;
+                }
+            }
+        }
+        _ => unreachable!("benchmarks are checked when parsed"),
+    }
+
+    Ok(done)
+}
+
+fn run(tree: &Tree, config: &Config, name: &str, index: u64) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+
+    let results: Vec<Result<Done, String>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..config.threads)
+            .map(|thread| {
+                // each thread of each benchmark gets keys and values of its own
+                let seed = config.seed.wrapping_add(index * config.threads + thread);
+                scope.spawn(move || {
+                    run_thread(tree, config, name, thread, seed).map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err("panicked".to_owned())))
+            .collect()
+    });
+
+    let elapsed = started.elapsed();
+    let mut done = Done::default();
+
+    for result in results {
+        done.merge(&result?);
+    }
+
+    report(name, &done, elapsed);
+
+    Ok(())
+}
+
+fn micros(duration: Option<Duration>) -> f64 {
+    duration.unwrap_or_default().as_secs_f64() * 1e6
+}
+
+fn report(name: &str, done: &Done, elapsed: Duration) {
+    let ops = done.latency.count();
+    let seconds = elapsed.as_secs_f64().max(1e-9);
+    let mut line = format!(
+        "{:<12}: {:>10.3} micros/op; {:>10.0} ops/sec; {:>8.1} MB/s; p50 {:.1}us p99 {:.1}us max {:.1}us",
+        name,
+        seconds * 1e6 / ops.max(1) as f64,
+        ops as f64 / seconds,
+        done.bytes as f64 / 1_048_576.0 / seconds,
+        micros(done.latency.percentile(50.0)),
+        micros(done.latency.percentile(99.0)),
+        micros(Some(done.latency.max())),
+    );
+
+    if done.reads > 0 {
+        line += &format!(" ({} of {} found)", done.found, done.reads);
+    }
+
+    println!("{}", line);
+}
+
+fn open(config: &Config) -> Result<Tree, Box<dyn Error>> {
+    let mut options = Options::default();
+
+    if config.db.is_none() {
+        options.storage = Arc::new(SimDisk::new(0));
+    }
+
+    if let Some(cache_size) = config.cache_size {
+        options.cache_size = cache_size;
+    }
+
+    if let Some(flush_threshold) = config.flush_threshold {
+        options.flush_threshold = flush_threshold;
+    }
+
+    if config.sync {
+        options.sync_policy = SyncPolicy::Always;
+    }
+
+    options.bloom_bits_per_key = config.bloom_bits;
+
+    let path = config.db.as_deref().unwrap_or("bench");
+
+    // the benchmarks write to the tree, so one already there is only used when asked
+    if !config.use_existing
+        && (options.storage.exists(path)? || options.storage.exists(&(path.to_owned() + ".wal"))?)
+    {
+        return Err(From::from(format!(
+            "{} already exists, pass --use-existing to benchmark it",
+            path
+        )));
+    }
+
+    // a Vec is stored as its u64 length and then its bytes
+    let tree = BTree::with_options(path, config.key_size + 8, config.value_size + 8, options)?;
+
+    Ok(Arc::new(Mutex::new(tree)))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+
+    let config = match parse_args(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: {} [--benchmarks={}] [--num=N] [--key-size=N] [--value-size=N] [--threads=N] \
+                 [--read-percent=N] [--seed=N] [--cache-size=N] [--bloom-bits=N] [--flush-threshold=N] [--sync] \
+                 [--db=<tree file> [--use-existing]]",
+                args[0],
+                BENCHMARKS.join(",")
+            );
+            process::exit(2);
+        }
+    };
+
+    let tree = open(&config)?;
+
+    println!(
+        "Keys: {} bytes, values: {} bytes, entries: {}, threads: {}, storage: {}",
+        config.key_size,
+        config.value_size,
+        config.num,
+        config.threads,
+        config.db.as_deref().unwrap_or("memory")
+    );
+
+    for (index, name) in config.benchmarks.iter().enumerate() {
+        run(&tree, &config, name, index as u64)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+//! Serves a tree of byte string keys and values over TCP, see `btree::server`, to
+//! Redis clients with `--resp`, see `btree::resp`, or over HTTP with `--http`,
+//! see `btree::http`.
+//!
+//! Usage: btree-server [--resp | --http] <tree file> <address> [key size] [value size]
+
+extern crate btree;
+
+use btree::{http, resp, server, BTree};
+
+use std::env;
+use std::error::Error;
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args: Vec<String> = env::args().collect();
+    let speak_resp = args.iter().any(|arg| arg == "--resp");
+    let speak_http = args.iter().any(|arg| arg == "--http");
+    args.retain(|arg| arg != "--resp" && arg != "--http");
+
+    if args.len() < 3 || (speak_resp && speak_http) {
+        eprintln!(
+            "Usage: {} [--resp | --http] <tree file> <address> [key size] [value size]",
+            args[0]
+        );
+        process::exit(2);
+    }
+
+    let key_size = args.get(3).map_or(Ok(64), |size| size.parse())?;
+    let value_size = args.get(4).map_or(Ok(256), |size| size.parse())?;
+
+    let tree = BTree::<Vec<u8>, Vec<u8>>::new(&args[1], key_size, value_size)?;
+    let listener = TcpListener::bind(&args[2])?;
+
+    eprintln!("Serving {} on {}", args[1], listener.local_addr()?);
+
+    let tree = Arc::new(Mutex::new(tree));
+
+    if speak_resp {
+        resp::serve(tree, listener)
+    } else if speak_http {
+        http::serve(tree, listener)
+    } else {
+        server::serve(tree, listener)
+    }
+}
@@ -0,0 +1,469 @@
+use bloom::{fnv1a, fnv1a_extend, FNV_OFFSET};
+use storage::{Storage, StorageFile};
+use {BTree, KeyType};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::{ErrorKind, Read, Write};
+
+/// The path of the file holding the blobs of the tree at `path`
+pub fn blob_store_path(path: &str) -> String {
+    path.to_owned() + ".blobs"
+}
+
+/// The path a value written by a `BlobWriter` is spooled to until it's finished
+pub fn spool_path(path: &str) -> String {
+    blob_store_path(path) + ".spool"
+}
+
+// a blob's hash, its slot among blobs with the same hash, and its length
+const HEADER_SIZE: u64 = 20;
+
+/// What bincode adds to the bytes of an inline blob: the variant and the length
+pub const INLINE_OVERHEAD: usize = 12;
+
+/// The encoded size of a stored blob, which the value size has to leave room for
+pub const STORED_SIZE: usize = 24;
+
+/// How much of a blob is read or written at once when it isn't held in memory
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The offset and length of each chunk of a blob `len` bytes long
+fn chunks(len: u64) -> impl Iterator<Item = (u64, usize)> {
+    (0..len)
+        .step_by(CHUNK_SIZE)
+        .map(move |at| (at, (len - at).min(CHUNK_SIZE as u64) as usize))
+}
+
+/// A value of a tree holding blobs. Small values are kept inline in the records;
+/// larger ones live once in the blob store, referenced by their hash.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Blob {
+    Inline(Vec<u8>),
+    Stored { hash: u64, slot: u32, len: u64 },
+}
+
+fn missing(hash: u64, slot: u32) -> Box<dyn Error> {
+    From::from(IOError::new(
+        ErrorKind::InvalidData,
+        format!("Blob {:x}/{} is missing from the blob store", hash, slot),
+    ))
+}
+
+/// An append-only file of blob contents, each stored once. Every blob is a header
+/// (the little-endian hash, slot and length) followed by its bytes. The index of
+/// where each one starts is rebuilt by reading the headers when the store is opened.
+pub struct BlobStore {
+    file: Box<dyn StorageFile>,
+    index: HashMap<(u64, u32), (u64, u64)>, // hash and slot to offset and length
+}
+
+impl BlobStore {
+    /// Opens the blob store at `path`, creating an empty one if there isn't one.
+    /// Only the writer may `repair` it, a reader could be looking at a blob that's
+    /// still being appended.
+    pub fn open(
+        storage: &dyn Storage,
+        path: &str,
+        repair: bool,
+    ) -> Result<BlobStore, Box<dyn Error>> {
+        let mut file = storage.open(path)?;
+        let len = file.len()?;
+        let mut index = HashMap::new();
+        let mut offset = 0;
+
+        while offset + HEADER_SIZE <= len {
+            let mut header = [0; HEADER_SIZE as usize];
+            file.read_at(&mut header, offset)?;
+
+            let hash = u64::from_le_bytes(header[..8].try_into()?);
+            let slot = u32::from_le_bytes(header[8..12].try_into()?);
+            let blob_len = u64::from_le_bytes(header[12..].try_into()?);
+
+            if offset + HEADER_SIZE + blob_len > len {
+                break;
+            }
+
+            index.insert((hash, slot), (offset + HEADER_SIZE, blob_len));
+            offset += HEADER_SIZE + blob_len;
+        }
+
+        // a blob torn by a crash was never referenced, its write hadn't returned
+        if offset < len && repair {
+            file.truncate(offset)?;
+        }
+
+        Ok(BlobStore { file, index })
+    }
+
+    /// The size of the file in bytes
+    pub fn len(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.file.len()?)
+    }
+
+    /// The reference to `bytes` if they're already stored
+    pub fn find(&self, bytes: &[u8]) -> Result<Option<Blob>, Box<dyn Error>> {
+        let hash = fnv1a(bytes);
+        let slot = self.find_slot(hash, bytes.len() as u64, |offset| {
+            let mut stored = vec![0; bytes.len()];
+            self.file.read_at(&mut stored, offset)?;
+            Ok(stored == bytes)
+        })?;
+
+        Ok(slot.map(|slot| Blob::Stored {
+            hash,
+            slot,
+            len: bytes.len() as u64,
+        }))
+    }
+
+    /// The slot of the blob with `hash` and length `len` whose bytes, starting at
+    /// the offset given, `matches` says are the ones wanted
+    fn find_slot<F>(
+        &self,
+        hash: u64,
+        len: u64,
+        mut matches: F,
+    ) -> Result<Option<u32>, Box<dyn Error>>
+    where
+        F: FnMut(u64) -> Result<bool, Box<dyn Error>>,
+    {
+        // blobs whose hashes collide take the next free slot
+        for slot in 0.. {
+            match self.index.get(&(hash, slot)) {
+                Some((offset, blob_len)) if *blob_len == len && matches(*offset)? => {
+                    return Ok(Some(slot))
+                }
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Stores `bytes` unless they already are, returning the reference to them. The
+    /// store is synced before returning, so the blob is durable before anything
+    /// that refers to it.
+    pub fn put(&mut self, bytes: &[u8]) -> Result<Blob, Box<dyn Error>> {
+        if let Some(blob) = self.find(bytes)? {
+            return Ok(blob);
+        }
+
+        self.append(fnv1a(bytes), bytes.len() as u64, |file| {
+            Ok(file.append(bytes)?)
+        })
+    }
+
+    /// Like `put`, for the `len` bytes hashing to `hash` in `source`, which are
+    /// compared and copied a chunk at a time rather than read in whole
+    pub fn put_from(
+        &mut self,
+        source: &dyn StorageFile,
+        hash: u64,
+        len: u64,
+    ) -> Result<Blob, Box<dyn Error>> {
+        let existing = self.find_slot(hash, len, |offset| {
+            for (at, chunk_len) in chunks(len) {
+                let (mut source_chunk, mut stored_chunk) = (vec![0; chunk_len], vec![0; chunk_len]);
+                source.read_at(&mut source_chunk, at)?;
+                self.file.read_at(&mut stored_chunk, offset + at)?;
+
+                if source_chunk != stored_chunk {
+                    return Ok(false);
+                }
+            }
+
+            Ok(true)
+        })?;
+
+        if let Some(slot) = existing {
+            return Ok(Blob::Stored { hash, slot, len });
+        }
+
+        self.append(hash, len, |file| {
+            for (at, chunk_len) in chunks(len) {
+                let mut chunk = vec![0; chunk_len];
+                source.read_at(&mut chunk, at)?;
+                file.append(&chunk)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Appends a new blob, whose bytes `write` appends after the header
+    fn append<F>(&mut self, hash: u64, len: u64, write: F) -> Result<Blob, Box<dyn Error>>
+    where
+        F: FnOnce(&mut dyn StorageFile) -> Result<(), Box<dyn Error>>,
+    {
+        let slot = (0..)
+            .find(|slot| !self.index.contains_key(&(hash, *slot)))
+            .unwrap_or(0);
+        let offset = self.file.len()?;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(&hash.to_le_bytes());
+        header.extend_from_slice(&slot.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+
+        self.file.append(&header)?;
+        write(&mut *self.file)?;
+        self.file.sync()?;
+        self.index.insert((hash, slot), (offset + HEADER_SIZE, len));
+
+        Ok(Blob::Stored { hash, slot, len })
+    }
+
+    /// Reads the blob with `hash` in `slot`, if there is one
+    pub fn read(&self, hash: u64, slot: u32) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let (offset, len) = match self.index.get(&(hash, slot)) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+
+        let mut bytes = vec![0; len as usize];
+        self.file.read_at(&mut bytes, offset)?;
+
+        Ok(Some(bytes))
+    }
+
+    /// A reader over the bytes of `blob`, which reads a stored blob from the file
+    /// as it goes
+    pub fn reader(&self, blob: Blob) -> Result<BlobReader<'_>, Box<dyn Error>> {
+        let (offset, len) = match blob {
+            Blob::Inline(bytes) => return Ok(BlobReader::Inline(bytes, 0)),
+            Blob::Stored { hash, slot, .. } => *self
+                .index
+                .get(&(hash, slot))
+                .ok_or_else(|| missing(hash, slot))?,
+        };
+
+        Ok(BlobReader::Stored {
+            file: &*self.file,
+            offset,
+            remaining: len,
+        })
+    }
+
+    /// Writes a new store at `path` holding only the blobs in `keep`, with the same
+    /// hashes and slots, and swaps it in for this one
+    pub fn rewrite(
+        &mut self,
+        storage: &dyn Storage,
+        path: &str,
+        keep: &HashSet<(u64, u32)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let new_path = path.to_owned() + ".new";
+
+        storage.remove_if_exists(&new_path)?;
+
+        let mut new_file = storage.open(&new_path)?;
+        let mut index = HashMap::new();
+        let mut offset = 0;
+
+        for (hash, slot) in keep {
+            let bytes = match self.read(*hash, *slot)? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+
+            let mut buff = Vec::with_capacity(HEADER_SIZE as usize + bytes.len());
+            buff.extend_from_slice(&hash.to_le_bytes());
+            buff.extend_from_slice(&slot.to_le_bytes());
+            buff.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            buff.extend_from_slice(&bytes);
+
+            new_file.append(&buff)?;
+            index.insert((*hash, *slot), (offset + HEADER_SIZE, bytes.len() as u64));
+            offset += buff.len() as u64;
+        }
+
+        new_file.sync()?;
+        storage.rename(&new_path, path)?;
+
+        self.file = new_file;
+        self.index = index;
+
+        Ok(())
+    }
+}
+
+/// Writes a value under a key of a tree of blobs a piece at a time, see
+/// `BTree::insert_streaming`. The bytes are spooled to a file as they're written,
+/// and nothing is inserted until `finish` is called.
+pub struct BlobWriter<'a, K: KeyType + 'a> {
+    tree: &'a mut BTree<K, Blob>,
+    key: K,
+    spool: Box<dyn StorageFile>,
+    buff: Vec<u8>, // written bytes not yet appended to the spool
+    hash: u64,     // the hash of the bytes written so far
+    len: u64,
+}
+
+impl<'a, K: KeyType> BlobWriter<'a, K> {
+    pub fn new(tree: &'a mut BTree<K, Blob>, key: K) -> Result<BlobWriter<'a, K>, Box<dyn Error>> {
+        let path = spool_path(&tree.tree_file_path);
+
+        // a writer dropped without finishing leaves its spool behind
+        tree.storage.remove_if_exists(&path)?;
+        let spool = tree.storage.open(&path)?;
+
+        Ok(BlobWriter {
+            tree,
+            key,
+            spool,
+            buff: Vec::with_capacity(CHUNK_SIZE),
+            hash: FNV_OFFSET,
+            len: 0,
+        })
+    }
+
+    /// Stores the value written and inserts it under the key
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.flush()?;
+
+        let blob = if self.len as usize + INLINE_OVERHEAD <= self.tree.value_size {
+            let mut bytes = vec![0; self.len as usize];
+            self.spool.read_at(&mut bytes, 0)?;
+            Blob::Inline(bytes)
+        } else {
+            self.tree
+                .blob_store_mut()?
+                .put_from(&*self.spool, self.hash, self.len)?
+        };
+
+        self.tree
+            .storage
+            .remove(&spool_path(&self.tree.tree_file_path))?;
+        self.tree.insert(self.key, blob)
+    }
+}
+
+impl<'a, K: KeyType> Write for BlobWriter<'a, K> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hash = fnv1a_extend(self.hash, buf);
+        self.len += buf.len() as u64;
+        self.buff.extend_from_slice(buf);
+
+        if self.buff.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.spool.append(&self.buff)?;
+        self.buff.clear();
+        Ok(())
+    }
+}
+
+/// Reads the bytes of one value of a tree of blobs, see `BTree::get_streaming`
+pub enum BlobReader<'a> {
+    Inline(Vec<u8>, usize), // the bytes, and how many have been read
+    Stored {
+        file: &'a dyn StorageFile,
+        offset: u64,    // where the next unread byte is in the file
+        remaining: u64, // how many bytes are left to read
+    },
+}
+
+impl<'a> BlobReader<'a> {
+    /// How many bytes are left to read
+    pub fn remaining(&self) -> u64 {
+        match self {
+            BlobReader::Inline(bytes, read) => (bytes.len() - read) as u64,
+            BlobReader::Stored { remaining, .. } => *remaining,
+        }
+    }
+}
+
+impl<'a> Read for BlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BlobReader::Inline(bytes, read) => {
+                let n = (&bytes[*read..]).read(buf)?;
+                *read += n;
+                Ok(n)
+            }
+            BlobReader::Stored {
+                file,
+                offset,
+                remaining,
+            } => {
+                let n = buf.len().min(*remaining as usize);
+                file.read_at(&mut buf[..n], *offset)?;
+                *offset += n as u64;
+                *remaining -= n as u64;
+                Ok(n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use blob_store::{Blob, BlobStore};
+    use storage::{FileStorage, Storage};
+    use tests::gen_temp_name;
+
+    use std::collections::HashSet;
+    use std::fs::remove_file;
+    use std::io::Read;
+
+    fn read_all(store: &BlobStore, blob: Blob) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        store.reader(blob).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn blobs_are_stored_once_and_survive_reopening() {
+        let file_path = gen_temp_name() + ".blobs";
+        let large = vec![7u8; 1000];
+
+        {
+            let mut store = BlobStore::open(&FileStorage, &file_path, true).unwrap();
+            let first = store.put(&large).unwrap();
+
+            assert_eq!(store.put(&large).unwrap(), first);
+            assert_eq!(store.len().unwrap(), 1020);
+
+            let other = store.put(b"another blob").unwrap();
+            assert_ne!(other, first);
+            assert_eq!(read_all(&store, Blob::Inline(b"small".to_vec())), b"small");
+        }
+
+        // a torn write at the end is dropped when the store is opened again
+        FileStorage
+            .open(&file_path)
+            .unwrap()
+            .append(&[1, 2, 3])
+            .unwrap();
+
+        let mut store = BlobStore::open(&FileStorage, &file_path, true).unwrap();
+        let first = store.find(&large).unwrap().unwrap();
+
+        assert_eq!(read_all(&store, first.clone()), large);
+        assert_eq!(store.len().unwrap(), 1020 + 20 + 12);
+
+        // rewriting keeps only the blobs asked for, under the same references
+        let keep: HashSet<(u64, u32)> = match first {
+            Blob::Stored { hash, slot, .. } => vec![(hash, slot)].into_iter().collect(),
+            Blob::Inline(_) => unreachable!(),
+        };
+
+        store.rewrite(&FileStorage, &file_path, &keep).unwrap();
+
+        assert_eq!(store.len().unwrap(), 1020);
+        assert_eq!(read_all(&store, first), large);
+        assert_eq!(store.find(b"another blob").unwrap(), None);
+
+        remove_file(&file_path).unwrap();
+    }
+}
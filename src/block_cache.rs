@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Identifies a block: the file it came from and its index within the file
+type BlockId = (u64, u64);
+
+/// A least-recently-used cache of raw blocks read from tree files and runs, holding
+/// at most `capacity` bytes. Blocks are cached as they're stored, so one cache can
+/// hold blocks of any key and value types.
+pub struct BlockCache {
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    capacity: usize,
+    used: usize,
+    tick: u64, // bumped on every access, so older ticks are less recently used
+    hits: u64,
+    misses: u64,
+    blocks: HashMap<BlockId, (Arc<Vec<u8>>, u64)>,
+    by_tick: BTreeMap<u64, BlockId>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            state: Mutex::new(CacheState {
+                capacity,
+                used: 0,
+                tick: 0,
+                hits: 0,
+                misses: 0,
+                blocks: HashMap::new(),
+                by_tick: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.state.lock().unwrap().capacity
+    }
+
+    /// Changes the capacity, evicting blocks if the cache is now over it
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        state.capacity = capacity;
+        state.evict();
+    }
+
+    /// The bytes currently cached
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+
+    /// How many lookups have found their block, and how many haven't
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+
+    pub fn get(&self, file_id: u64, block: u64) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        state.tick += 1;
+
+        let (data, tick) = match state.blocks.get_mut(&(file_id, block)) {
+            Some(entry) => entry,
+            None => {
+                state.misses += 1;
+                return None;
+            }
+        };
+
+        state.hits += 1;
+
+        state.by_tick.remove(tick);
+        state.by_tick.insert(state.tick, (file_id, block));
+        *tick = state.tick;
+
+        Some(data.clone())
+    }
+
+    pub fn insert(&self, file_id: u64, block: u64, data: Arc<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+
+        // a block bigger than the whole cache would only evict everything else
+        if data.len() > state.capacity {
+            return;
+        }
+
+        state.tick += 1;
+
+        let tick = state.tick;
+        let len = data.len();
+
+        if let Some((old, old_tick)) = state.blocks.insert((file_id, block), (data, tick)) {
+            state.used -= old.len();
+            state.by_tick.remove(&old_tick);
+        }
+
+        state.used += len;
+        state.by_tick.insert(tick, (file_id, block));
+        state.evict();
+    }
+}
+
+impl CacheState {
+    fn evict(&mut self) {
+        while self.used > self.capacity {
+            let (_, id) = match self.by_tick.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            if let Some((data, _)) = self.blocks.remove(&id) {
+                self.used -= data.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_cache::BlockCache;
+    use std::sync::Arc;
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = BlockCache::new(30);
+
+        cache.insert(1, 0, Arc::new(vec![0; 10]));
+        cache.insert(1, 1, Arc::new(vec![1; 10]));
+        cache.insert(1, 2, Arc::new(vec![2; 10]));
+
+        // touching block 0 makes block 1 the oldest
+        assert!(cache.get(1, 0).is_some());
+        cache.insert(2, 0, Arc::new(vec![3; 10]));
+
+        assert!(cache.get(1, 1).is_none());
+        assert_eq!(cache.get(1, 0).unwrap()[0], 0);
+        assert_eq!(cache.used(), 30);
+
+        cache.set_capacity(10);
+        assert_eq!(cache.used(), 10);
+        assert!(cache.get(2, 0).is_none());
+        assert_eq!(cache.hits_and_misses(), (2, 2));
+    }
+}
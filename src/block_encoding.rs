@@ -0,0 +1,543 @@
+use wal_file::RecordFile;
+use {KeyType, ValueType};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+/// Every this many records a block restarts with a record stored whole, so one
+/// record can be decoded without decoding the whole block before it
+const RESTART_INTERVAL: usize = 16;
+
+/// Runs of shared bytes shorter than this are cheaper stored as they are
+const MIN_SHARED_RUN: usize = 4;
+
+/// A dictionary is sampled from the first this many times its size of records
+const SAMPLE_FACTOR: usize = 32;
+
+/// Ends every file written with encoded blocks. A plain file of padded records
+/// ends with a zero byte, either padding or the top byte of a timestamp, so it
+/// can't be mistaken for one.
+const FOOTER_MAGIC: &[u8; 8] = b"LSMBTblk";
+
+/// Where each block of a file with encoded blocks starts. It's written after the
+/// last block, followed by its little-endian u64 length and `FOOTER_MAGIC`.
+#[derive(Serialize, Deserialize)]
+pub struct BlockIndex {
+    pub count: u64,      // the number of records in the file
+    offsets: Vec<u64>,   // the start of each block, then the end of the last one
+    dictionary: Vec<u8>, // records can copy runs of bytes from here, empty without one
+}
+
+/// Bytes sampled from the first records of a file, which records can copy runs
+/// of bytes from. Values that are similar but not sorted next to each other
+/// share little with the record before, but a lot with the dictionary.
+struct Dictionary {
+    bytes: Vec<u8>,
+    positions: HashMap<[u8; MIN_SHARED_RUN], usize>, // where each run of bytes first appears
+}
+
+/// Encodes the records of a file a block at a time as they're written
+pub struct BlockWriter {
+    per_block: usize,
+    dictionary_size: Option<usize>, // the size of the dictionary to sample, if any
+    dictionary: Option<Dictionary>,
+    sample: Vec<Vec<u8>>, // the records held back to sample the dictionary from
+    sample_bytes: usize,
+    pending: Vec<Vec<u8>>, // the records of the block being filled
+    index: BlockIndex,
+}
+
+fn corrupt() -> Box<dyn Error> {
+    From::from(IOError::new(
+        ErrorKind::InvalidData,
+        "corrupt encoded block",
+    ))
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    out.push(value as u8);
+}
+
+fn get_varint(bytes: &[u8], pos: &mut usize) -> Result<usize, Box<dyn Error>> {
+    let mut value = 0;
+
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos).ok_or_else(corrupt)?;
+        *pos += 1;
+        value |= usize::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(corrupt())
+}
+
+/// How many bytes from `at` onwards `record` shares with `prev`
+fn shared_run(record: &[u8], prev: &[u8], at: usize) -> usize {
+    record[at..]
+        .iter()
+        .zip(prev.get(at..).unwrap_or(&[]))
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+impl Dictionary {
+    /// Goes through `sample` adding each record that the dictionary doesn't cover
+    /// three quarters of yet, until there are `size` bytes
+    fn sample(sample: &[Vec<u8>], size: usize) -> Dictionary {
+        let mut dictionary = Dictionary {
+            bytes: Vec::new(),
+            positions: HashMap::new(),
+        };
+
+        for record in sample {
+            let room = size - dictionary.bytes.len();
+
+            if room == 0 {
+                break;
+            }
+
+            if dictionary.covered(record) * 4 < record.len() * 3 {
+                dictionary.extend(&record[..record.len().min(room)]);
+            }
+        }
+
+        dictionary
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        let start = self.bytes.len().saturating_sub(MIN_SHARED_RUN - 1);
+        self.bytes.extend_from_slice(bytes);
+
+        for (at, window) in self.bytes[start..].windows(MIN_SHARED_RUN).enumerate() {
+            self.positions
+                .entry([window[0], window[1], window[2], window[3]])
+                .or_insert(start + at);
+        }
+    }
+
+    /// How many bytes of `record` could be copied from the dictionary
+    fn covered(&self, record: &[u8]) -> usize {
+        let (mut at, mut covered) = (0, 0);
+
+        while at < record.len() {
+            match self.find(record, at) {
+                Some((_, len)) => {
+                    covered += len;
+                    at += len;
+                }
+                None => at += 1,
+            }
+        }
+
+        covered
+    }
+
+    /// The offset and length of the longest run of bytes starting at `record[at..]`
+    /// that can be copied from the dictionary, if there's one long enough
+    fn find(&self, record: &[u8], at: usize) -> Option<(usize, usize)> {
+        let window = record.get(at..at + MIN_SHARED_RUN)?;
+        let offset = *self.positions.get(window)?;
+        let len = shared_run(&record[at..], &self.bytes[offset..], 0);
+
+        Some((offset, len))
+    }
+}
+
+/// Appends `record` as its length and then a series of: a run of bytes copied from
+/// the same place in `prev`, a run copied from anywhere in the dictionary, and a
+/// run of literal bytes. Sorted keys of the same length share a prefix; with
+/// bincode's length header in front, keys of different lengths still line up
+/// after their first byte.
+fn encode_record(out: &mut Vec<u8>, record: &[u8], prev: &[u8], dictionary: Option<&Dictionary>) {
+    put_varint(out, record.len());
+
+    let in_dictionary = |at: usize| dictionary.and_then(|dictionary| dictionary.find(record, at));
+    let mut at = 0;
+
+    while at < record.len() {
+        let copy = match shared_run(record, prev, at) {
+            run if run >= MIN_SHARED_RUN => run,
+            _ => 0,
+        };
+
+        let (offset, from_dictionary) = in_dictionary(at + copy).unwrap_or((0, 0));
+        let start = at + copy + from_dictionary;
+        let mut end = start;
+
+        while end < record.len()
+            && shared_run(record, prev, end) < MIN_SHARED_RUN
+            && in_dictionary(end).is_none()
+        {
+            end += 1;
+        }
+
+        put_varint(out, copy);
+        put_varint(out, from_dictionary);
+        if from_dictionary > 0 {
+            put_varint(out, offset);
+        }
+        put_varint(out, end - start);
+        out.extend_from_slice(&record[start..end]);
+        at = end;
+    }
+}
+
+fn decode_record(
+    bytes: &[u8],
+    pos: &mut usize,
+    prev: &[u8],
+    dictionary: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = get_varint(bytes, pos)?;
+    let mut record = Vec::with_capacity(len);
+
+    while record.len() < len {
+        let copy = get_varint(bytes, pos)?;
+        let from_dictionary = get_varint(bytes, pos)?;
+        let offset = if from_dictionary > 0 {
+            get_varint(bytes, pos)?
+        } else {
+            0
+        };
+        let literal = get_varint(bytes, pos)?;
+
+        if copy > 0 {
+            let at = record.len();
+            record.extend_from_slice(prev.get(at..at + copy).ok_or_else(corrupt)?);
+        }
+
+        record.extend_from_slice(
+            dictionary
+                .get(offset..offset + from_dictionary)
+                .ok_or_else(corrupt)?,
+        );
+        record.extend_from_slice(bytes.get(*pos..*pos + literal).ok_or_else(corrupt)?);
+        *pos += literal;
+    }
+
+    if record.len() != len {
+        return Err(corrupt());
+    }
+
+    Ok(record)
+}
+
+/// Encodes the records of one block: the record count, the offset of each restart
+/// point, all little-endian u32s, and then the records
+fn encode_block(records: &[Vec<u8>], dictionary: Option<&Dictionary>) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut restarts = Vec::new();
+
+    for (i, record) in records.iter().enumerate() {
+        let prev: &[u8] = if i % RESTART_INTERVAL == 0 {
+            restarts.push(body.len() as u32);
+            &[]
+        } else {
+            &records[i - 1]
+        };
+
+        encode_record(&mut body, record, prev, dictionary);
+    }
+
+    let mut block = Vec::with_capacity(4 + 4 * restarts.len() + body.len());
+    block.extend_from_slice(&(records.len() as u32).to_le_bytes());
+
+    for restart in restarts {
+        block.extend_from_slice(&restart.to_le_bytes());
+    }
+
+    block.extend_from_slice(&body);
+    block
+}
+
+/// Decodes records `from..to` of an encoded block, each padded out to `record_size`
+/// as they would be in a plain file
+pub fn decode_block(
+    block: &[u8],
+    from: usize,
+    to: usize,
+    record_size: usize,
+    dictionary: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let read_u32 = |at: usize| -> Result<usize, Box<dyn Error>> {
+        let bytes = block.get(at..at + 4).ok_or_else(corrupt)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+
+    let count = read_u32(0)?;
+
+    if from > to || to > count {
+        return Err(corrupt());
+    }
+
+    // start from the last restart point at or before the first record wanted
+    let body = 4 + 4 * count.div_ceil(RESTART_INTERVAL);
+    let restart = from / RESTART_INTERVAL;
+    let mut pos = body + read_u32(4 + 4 * restart)?;
+
+    let mut out = Vec::with_capacity((to - from) * record_size);
+    let mut prev = Vec::new();
+
+    for i in restart * RESTART_INTERVAL..to {
+        if i % RESTART_INTERVAL == 0 {
+            prev.clear();
+        }
+
+        let record = decode_record(block, &mut pos, &prev, dictionary)?;
+
+        if record.len() > record_size {
+            return Err(corrupt());
+        }
+
+        if i >= from {
+            out.extend_from_slice(&record);
+            out.resize(out.len() + record_size - record.len(), 0);
+        }
+
+        prev = record;
+    }
+
+    Ok(out)
+}
+
+impl BlockIndex {
+    /// Reads the index from the footer of `file`, if it was written with encoded blocks
+    pub fn open<K: KeyType, V: ValueType>(
+        file: &RecordFile<K, V>,
+    ) -> Result<Option<BlockIndex>, Box<dyn Error>> {
+        let len = file.byte_len()?;
+
+        if len < 16 {
+            return Ok(None);
+        }
+
+        let footer = file.read_bytes(len - 16, 16)?;
+
+        if &footer[8..] != FOOTER_MAGIC {
+            return Ok(None);
+        }
+
+        let mut index_len = [0; 8];
+        index_len.copy_from_slice(&footer[..8]);
+        let index_len = u64::from_le_bytes(index_len);
+
+        if index_len > len - 16 {
+            return Err(corrupt());
+        }
+
+        let index: BlockIndex =
+            bincode::deserialize(&file.read_bytes(len - 16 - index_len, index_len)?)?;
+
+        if index.offsets.last() != Some(&(len - 16 - index_len)) {
+            return Err(corrupt());
+        }
+
+        Ok(Some(index))
+    }
+
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    /// The number of blocks
+    fn blocks(&self) -> u64 {
+        self.offsets.len() as u64 - 1
+    }
+
+    /// Reads block `block` of `file`, still encoded
+    pub fn read_block<K: KeyType, V: ValueType>(
+        &self,
+        file: &RecordFile<K, V>,
+        block: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        if block >= self.blocks() {
+            return Err(corrupt());
+        }
+
+        let (start, end) = (
+            self.offsets[block as usize],
+            self.offsets[block as usize + 1],
+        );
+
+        file.read_bytes(start, end - start)
+    }
+}
+
+impl BlockWriter {
+    /// Encodes blocks of `per_block` records, sampling a dictionary of up to
+    /// `dictionary_size` bytes for them first if asked to
+    pub fn new(per_block: u64, dictionary_size: Option<usize>) -> BlockWriter {
+        BlockWriter {
+            per_block: per_block as usize,
+            dictionary_size,
+            dictionary: None,
+            sample: Vec::new(),
+            sample_bytes: 0,
+            pending: Vec::new(),
+            index: BlockIndex {
+                count: 0,
+                offsets: vec![0],
+                dictionary: Vec::new(),
+            },
+        }
+    }
+
+    /// The number of records added so far
+    pub fn count(&self) -> u64 {
+        self.index.count
+    }
+
+    /// Adds the next record, as bincode encodes it. Returns any blocks now ready to
+    /// be appended to the file.
+    pub fn add(&mut self, record: Vec<u8>) -> Vec<u8> {
+        self.index.count += 1;
+
+        let size = match self.dictionary_size {
+            Some(size) if self.dictionary.is_none() => size,
+            _ => return self.push(record),
+        };
+
+        // nothing can be encoded until the dictionary has been sampled
+        self.sample_bytes += record.len();
+        self.sample.push(record);
+
+        if self.sample_bytes >= size * SAMPLE_FACTOR {
+            self.sample_dictionary(size)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn sample_dictionary(&mut self, size: usize) -> Vec<u8> {
+        let dictionary = Dictionary::sample(&self.sample, size);
+        self.index.dictionary = dictionary.bytes.clone();
+        self.dictionary = Some(dictionary);
+
+        let mut blocks = Vec::new();
+        for record in std::mem::take(&mut self.sample) {
+            blocks.extend(self.push(record));
+        }
+
+        blocks
+    }
+
+    fn push(&mut self, record: Vec<u8>) -> Vec<u8> {
+        self.pending.push(record);
+
+        if self.pending.len() == self.per_block {
+            self.finish_block()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn finish_block(&mut self) -> Vec<u8> {
+        let block = encode_block(&self.pending, self.dictionary.as_ref());
+        let start = *self.index.offsets.last().unwrap();
+
+        self.index.offsets.push(start + block.len() as u64);
+        self.pending.clear();
+        block
+    }
+
+    /// Returns what's left to append to the file, the last partial block and the
+    /// footer, along with the finished index
+    pub fn finish(mut self) -> Result<(Vec<u8>, BlockIndex), Box<dyn Error>> {
+        // a small file is sampled from everything in it
+        let mut tail = match self.dictionary_size {
+            Some(size) if self.dictionary.is_none() && !self.sample.is_empty() => {
+                self.sample_dictionary(size)
+            }
+            _ => Vec::new(),
+        };
+
+        if !self.pending.is_empty() {
+            tail.extend(self.finish_block());
+        }
+
+        let encoded = bincode::serialize(&self.index)?;
+
+        tail.extend_from_slice(&encoded);
+        tail.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        tail.extend_from_slice(FOOTER_MAGIC);
+
+        Ok((tail, self.index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use block_encoding::{decode_block, encode_block, Dictionary};
+
+    #[test]
+    fn blocks_round_trip_smaller() {
+        let records: Vec<Vec<u8>> = (0..100)
+            .map(|i| {
+                let mut record = (10 + i as u64 % 3).to_le_bytes().to_vec();
+                record.extend_from_slice(format!("https://example.com/{:03}/", i).as_bytes());
+                record.extend_from_slice(&[i as u8; 5]);
+                record
+            })
+            .collect();
+
+        let block = encode_block(&records, None);
+        let size: usize = records.iter().map(|record| record.len()).sum();
+        assert!(
+            block.len() * 2 < size,
+            "{} bytes encoded from {}",
+            block.len(),
+            size
+        );
+
+        let decoded = decode_block(&block, 0, 100, 40, &[]).unwrap();
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(&decoded[i * 40..i * 40 + record.len()], &record[..]);
+        }
+
+        // from the middle of a restart interval
+        let decoded = decode_block(&block, 37, 38, 40, &[]).unwrap();
+        assert_eq!(&decoded[..records[37].len()], &records[37][..]);
+    }
+
+    #[test]
+    fn dictionaries_compress_similar_values() {
+        // the values repeat, but never twice in a row
+        let values = [
+            "{\"status\":\"active\",\"plan\":\"free\"}",
+            "{\"status\":\"closed\",\"plan\":\"team\"}",
+        ];
+        let records: Vec<Vec<u8>> = (0..64u64)
+            .map(|i| {
+                let mut record = i.to_be_bytes().to_vec();
+                record.extend_from_slice(values[i as usize % 2].as_bytes());
+                record
+            })
+            .collect();
+
+        let dictionary = Dictionary::sample(&records, 128);
+        let plain = encode_block(&records, None);
+        let block = encode_block(&records, Some(&dictionary));
+        assert!(
+            block.len() * 2 < plain.len(),
+            "{} bytes with a dictionary, {} without",
+            block.len(),
+            plain.len()
+        );
+
+        let decoded = decode_block(&block, 0, 64, 64, &dictionary.bytes).unwrap();
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(&decoded[i * 64..i * 64 + record.len()], &record[..]);
+        }
+    }
+}
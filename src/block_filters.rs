@@ -0,0 +1,333 @@
+use block_cache::BlockCache;
+use bloom::{self, hash_count, BloomFilter};
+use encoding::Codec;
+use storage::{Storage, StorageFile};
+use KeyType;
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The path of the sidecar holding the filters for the file at `path`. It's laid
+/// out as a little-endian u64 length, the bincode-encoded `FilterIndex`, and then
+/// one fixed-size filter per block of the file, followed by one prefix filter per
+/// block if the file has them.
+pub fn filter_path(path: &str) -> String {
+    path.to_owned() + ".filter"
+}
+
+/// Gets the bytes of a key that prefixes are taken from
+pub type KeyBytes<K> = fn(&K) -> &[u8];
+
+/// What to build filters over in new files
+pub struct FilterSettings<K> {
+    pub bits_per_key: usize,
+    pub prefix: Option<(usize, KeyBytes<K>)>, // also filter on the first this many bytes of each key
+}
+
+impl<K> Clone for FilterSettings<K> {
+    fn clone(&self) -> FilterSettings<K> {
+        *self
+    }
+}
+
+impl<K> Copy for FilterSettings<K> {}
+
+#[derive(Serialize, Deserialize)]
+struct FilterIndex<K> {
+    filter_len: u32,
+    hashes: u32,
+    prefix_len: Option<u32>, // the length of the prefixes in the prefix filters, if there are any
+    first_keys: Vec<K>,      // the key of the first record in each block
+}
+
+/// Builds a bloom filter for each block of a file as its records are written
+pub struct FilterBuilder<K: KeyType> {
+    file: Box<dyn StorageFile>,
+    per_block: u64, // records in a block
+    filter_len: usize,
+    hashes: u32,
+    prefix: Option<(usize, KeyBytes<K>)>,
+    first_keys: Vec<K>,
+    filters: Vec<u8>,        // the filters of the finished blocks, back to back
+    prefix_filters: Vec<u8>, // and their prefix filters
+    current: BloomFilter,
+    current_prefixes: BloomFilter,
+    in_block: u64, // records added to the current block
+}
+
+/// The filters of a file. The first key of every block is kept in memory to
+/// find the block a key would be in; the filters themselves are read from the
+/// sidecar as they're needed, and cached alongside the blocks.
+pub struct BlockFilters<K: KeyType> {
+    file: Box<dyn StorageFile>,
+    id: u64, // tells the filters apart from other blocks in the cache
+    filter_len: usize,
+    hashes: u32,
+    prefix_len: Option<usize>,
+    first_keys: Vec<K>,
+    offset: u64, // where the first filter starts
+}
+
+impl<K: KeyType> FilterBuilder<K> {
+    pub fn new(
+        storage: &dyn Storage,
+        path: &str,
+        per_block: u64,
+        settings: FilterSettings<K>,
+    ) -> Result<FilterBuilder<K>, Box<dyn Error>> {
+        let path = filter_path(path);
+
+        if storage.exists(&path)? {
+            storage.remove(&path)?;
+        }
+
+        let filter_len = (per_block as usize * settings.bits_per_key).div_ceil(8);
+        let hashes = hash_count(settings.bits_per_key);
+
+        Ok(FilterBuilder {
+            file: storage.open(&path)?,
+            per_block,
+            filter_len,
+            hashes,
+            prefix: settings.prefix,
+            first_keys: Vec::new(),
+            filters: Vec::new(),
+            prefix_filters: Vec::new(),
+            current: BloomFilter::new(filter_len, hashes),
+            current_prefixes: BloomFilter::new(filter_len, hashes),
+            in_block: 0,
+        })
+    }
+
+    /// Adds the key of the next record written to the file
+    pub fn add(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        if self.in_block == 0 {
+            self.first_keys.push(key.clone());
+        }
+
+        self.current.insert(&Codec::unbounded().serialize(key)?);
+
+        // a key shorter than the prefixes can't start with any prefix that's filtered
+        if let Some((len, key_bytes)) = self.prefix {
+            if let Some(prefix) = key_bytes(key).get(..len) {
+                self.current_prefixes.insert(prefix);
+            }
+        }
+
+        self.in_block += 1;
+
+        if self.in_block == self.per_block {
+            self.finish_block();
+        }
+
+        Ok(())
+    }
+
+    fn finish_block(&mut self) {
+        let filter = std::mem::replace(
+            &mut self.current,
+            BloomFilter::new(self.filter_len, self.hashes),
+        );
+        let prefixes = std::mem::replace(
+            &mut self.current_prefixes,
+            BloomFilter::new(self.filter_len, self.hashes),
+        );
+
+        self.filters.extend_from_slice(filter.as_bytes());
+        self.prefix_filters.extend_from_slice(prefixes.as_bytes());
+        self.in_block = 0;
+    }
+
+    /// Writes out and syncs the sidecar, returning the filters ready for lookups
+    pub fn finish(mut self, id: u64) -> Result<BlockFilters<K>, Box<dyn Error>> {
+        if self.in_block > 0 {
+            self.finish_block();
+        }
+
+        let prefix_len = self.prefix.map(|(len, _)| len);
+        let index = FilterIndex {
+            filter_len: self.filter_len as u32,
+            hashes: self.hashes,
+            prefix_len: prefix_len.map(|len| len as u32),
+            first_keys: self.first_keys,
+        };
+        let encoded = bincode::serialize(&index)?;
+
+        let mut buff = Vec::with_capacity(8 + encoded.len() + 2 * self.filters.len());
+        buff.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        buff.extend_from_slice(&encoded);
+        buff.extend_from_slice(&self.filters);
+
+        if prefix_len.is_some() {
+            buff.extend_from_slice(&self.prefix_filters);
+        }
+
+        self.file.append(&buff)?;
+        self.file.sync()?;
+
+        Ok(BlockFilters {
+            file: self.file,
+            id,
+            filter_len: self.filter_len,
+            hashes: self.hashes,
+            prefix_len,
+            first_keys: index.first_keys,
+            offset: 8 + encoded.len() as u64,
+        })
+    }
+}
+
+impl<K: KeyType> BlockFilters<K> {
+    /// Loads the filter index for the file at `path`. Anything short of a complete
+    /// sidecar is ignored, the file is then searched without filters.
+    pub fn open(
+        storage: &dyn Storage,
+        path: &str,
+        id: u64,
+    ) -> Result<Option<BlockFilters<K>>, Box<dyn Error>> {
+        let path = filter_path(path);
+
+        if !storage.exists(&path)? {
+            return Ok(None);
+        }
+
+        let file = storage.open(&path)?;
+        let len = file.len()?;
+
+        if len < 8 {
+            return Ok(None);
+        }
+
+        let mut index_len = [0; 8];
+        file.read_at(&mut index_len, 0)?;
+        let index_len = u64::from_le_bytes(index_len);
+
+        if 8 + index_len > len {
+            return Ok(None);
+        }
+
+        let mut index = vec![0; index_len as usize];
+        file.read_at(&mut index, 8)?;
+
+        let index: FilterIndex<K> = match bincode::deserialize(&index) {
+            Ok(index) => index,
+            Err(_) => return Ok(None),
+        };
+
+        let offset = 8 + index_len;
+        let filter_count =
+            index.first_keys.len() as u64 * if index.prefix_len.is_some() { 2 } else { 1 };
+
+        if len != offset + filter_count * u64::from(index.filter_len) {
+            return Ok(None);
+        }
+
+        Ok(Some(BlockFilters {
+            file,
+            id,
+            filter_len: index.filter_len as usize,
+            hashes: index.hashes,
+            prefix_len: index.prefix_len.map(|len| len as usize),
+            first_keys: index.first_keys,
+            offset,
+        }))
+    }
+
+    /// False when the file definitely has no record for `key`
+    pub fn may_contain(&self, key: &K, cache: Option<&BlockCache>) -> Result<bool, Box<dyn Error>> {
+        // the first record for the key is in the last block starting before it,
+        // unless a block starts with it
+        let block = self.first_keys.partition_point(|first| first < key);
+
+        if self.first_keys.get(block) == Some(key) {
+            return Ok(true);
+        }
+
+        if block == 0 {
+            return Ok(false);
+        }
+
+        let bits = self.read_filter(block as u64 - 1, cache)?;
+
+        Ok(bloom::may_contain(
+            &bits,
+            self.hashes,
+            &Codec::unbounded().serialize(key)?,
+        ))
+    }
+
+    /// False when the file definitely has no key starting with `prefix`. Only a
+    /// prefix at least as long as the ones filtered on can be ruled out. Keys have
+    /// to sort the same as their bytes for the blocks to be found.
+    pub fn may_contain_prefix(
+        &self,
+        prefix: &[u8],
+        key_bytes: KeyBytes<K>,
+        cache: Option<&BlockCache>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let len = match self.prefix_len {
+            Some(len) if prefix.len() >= len => len,
+            _ => return Ok(true),
+        };
+
+        // the keys with the prefix are together, starting in the last block that
+        // starts before them and running through the blocks that start with them
+        let first = self
+            .first_keys
+            .partition_point(|first| key_bytes(first) < prefix);
+        let end = first
+            + self.first_keys[first..]
+                .partition_point(|first| key_bytes(first).starts_with(prefix));
+        let blocks = self.first_keys.len() as u64;
+
+        for block in first.saturating_sub(1)..end {
+            let bits = self.read_filter(blocks + block as u64, cache)?;
+
+            if bloom::may_contain(&bits, self.hashes, &prefix[..len]) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Reads the key and prefix filters of `blocks` into `cache`
+    pub fn warm(&self, blocks: Range<u64>, cache: &BlockCache) -> Result<(), Box<dyn Error>> {
+        let count = self.first_keys.len() as u64;
+
+        for block in blocks.start.min(count)..blocks.end.min(count) {
+            self.read_filter(block, Some(cache))?;
+
+            if self.prefix_len.is_some() {
+                self.read_filter(count + block, Some(cache))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the `index`th filter in the sidecar, where the prefix filters follow
+    /// on from the key filters
+    fn read_filter(
+        &self,
+        index: u64,
+        cache: Option<&BlockCache>,
+    ) -> Result<Arc<Vec<u8>>, Box<dyn Error>> {
+        if let Some(bits) = cache.and_then(|cache| cache.get(self.id, index)) {
+            return Ok(bits);
+        }
+
+        let mut bits = vec![0; self.filter_len];
+        self.file
+            .read_at(&mut bits, self.offset + index * self.filter_len as u64)?;
+
+        let bits = Arc::new(bits);
+        if let Some(cache) = cache {
+            cache.insert(self.id, index, bits.clone());
+        }
+
+        Ok(bits)
+    }
+}
@@ -0,0 +1,87 @@
+/// A bloom filter over byte strings. Filters are written to disk, so hashing uses
+/// FNV-1a rather than the std hasher, whose output may change between releases.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+/// The number of hash functions that minimises false positives for the given
+/// number of bits per key
+pub fn hash_count(bits_per_key: usize) -> u32 {
+    ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30)
+}
+
+/// The FNV-1a hash of no bytes, which `fnv1a_extend` starts from
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_extend(FNV_OFFSET, bytes)
+}
+
+/// Carries on hashing from `hash` with `bytes`, for input that arrives in pieces
+pub fn fnv1a_extend(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+impl BloomFilter {
+    pub fn new(len: usize, hashes: u32) -> BloomFilter {
+        BloomFilter {
+            bits: vec![0; len.max(1)],
+            hashes,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for bit in positions(self.bits.len(), self.hashes, item) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+}
+
+/// Checks the filter stored in `bits`. False means `item` was never inserted,
+/// true means it probably was.
+pub fn may_contain(bits: &[u8], hashes: u32, item: &[u8]) -> bool {
+    positions(bits.len(), hashes, item).all(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+/// The bits `item` sets, derived from one hash by double hashing
+fn positions(len: usize, hashes: u32, item: &[u8]) -> impl Iterator<Item = usize> {
+    let hash = fnv1a(item);
+    let delta = hash.rotate_right(17) | 1;
+    let num_bits = len as u64 * 8;
+
+    (0..u64::from(hashes))
+        .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % num_bits) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use bloom::{hash_count, may_contain, BloomFilter};
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let hashes = hash_count(10);
+        let mut filter = BloomFilter::new(1000 * 10 / 8, hashes);
+
+        for i in 0..1000u32 {
+            filter.insert(&i.to_le_bytes());
+        }
+
+        assert!((0..1000u32).all(|i| may_contain(filter.as_bytes(), hashes, &i.to_le_bytes())));
+
+        // 10 bits per key gives about a 1% false positive rate
+        let false_positives = (1000..11_000u32)
+            .filter(|i| may_contain(filter.as_bytes(), hashes, &i.to_le_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
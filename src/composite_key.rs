@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryInto;
+use std::error::Error;
+
+/// A key made of several typed components, such as `(tenant_id, timestamp)`, each
+/// encoded so that the bytes sort like the components do, first one first. Keys
+/// sharing their leading components share a prefix of bytes, so `scan_prefix`
+/// with a key of just those components finds them all. It's stored as its bytes
+/// and their length, so the key size is the length of the longest key plus 8.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CompositeKey(Vec<u8>);
+
+impl CompositeKey {
+    pub fn new() -> CompositeKey {
+        CompositeKey(Vec::new())
+    }
+
+    pub fn push_u32(self, component: u32) -> CompositeKey {
+        self.push_raw(&component.to_be_bytes())
+    }
+
+    pub fn push_u64(self, component: u64) -> CompositeKey {
+        self.push_raw(&component.to_be_bytes())
+    }
+
+    // flipping the sign bit puts the negative numbers first
+    pub fn push_i32(self, component: i32) -> CompositeKey {
+        self.push_u32(component as u32 ^ (1 << 31))
+    }
+
+    pub fn push_i64(self, component: i64) -> CompositeKey {
+        self.push_u64(component as u64 ^ (1 << 63))
+    }
+
+    pub fn push_str(self, component: &str) -> CompositeKey {
+        self.push_bytes(component.as_bytes())
+    }
+
+    /// Adds bytes of any length. Zero bytes are escaped as 0x00 0xFF and the end is
+    /// marked with 0x00 0x00, so a shorter component sorts before a longer one it
+    /// starts, whatever follows either.
+    pub fn push_bytes(mut self, component: &[u8]) -> CompositeKey {
+        for &byte in component {
+            self.0.push(byte);
+
+            if byte == 0 {
+                self.0.push(0xFF);
+            }
+        }
+
+        self.0.extend_from_slice(&[0, 0]);
+        self
+    }
+
+    fn push_raw(mut self, bytes: &[u8]) -> CompositeKey {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// Reads the components back, in the order they were pushed
+    pub fn components(&self) -> Components<'_> {
+        Components { rest: &self.0 }
+    }
+}
+
+impl AsRef<[u8]> for CompositeKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The components of a `CompositeKey`, read as the types they were pushed as
+pub struct Components<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Components<'a> {
+    pub fn next_u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_be_bytes(self.next_raw(4)?.try_into()?))
+    }
+
+    pub fn next_u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_be_bytes(self.next_raw(8)?.try_into()?))
+    }
+
+    pub fn next_i32(&mut self) -> Result<i32, Box<dyn Error>> {
+        Ok((self.next_u32()? ^ (1 << 31)) as i32)
+    }
+
+    pub fn next_i64(&mut self) -> Result<i64, Box<dyn Error>> {
+        Ok((self.next_u64()? ^ (1 << 63)) as i64)
+    }
+
+    pub fn next_str(&mut self) -> Result<String, Box<dyn Error>> {
+        Ok(String::from_utf8(self.next_bytes()?)?)
+    }
+
+    pub fn next_bytes(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+
+        loop {
+            match self.next_raw(1)?[0] {
+                0 => match self.next_raw(1)?[0] {
+                    0 => return Ok(bytes),
+                    0xFF => bytes.push(0),
+                    _ => return Err(From::from("A zero byte in a component isn't escaped")),
+                },
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    /// Whether every component has been read
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    fn next_raw(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.rest.len() < len {
+            return Err(From::from("The key has no more components"));
+        }
+
+        let (raw, rest) = self.rest.split_at(len);
+        self.rest = rest;
+
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use composite_key::CompositeKey;
+    use {BTree, Options, SimDisk};
+
+    use rand::{thread_rng, Rng};
+    use std::sync::Arc;
+
+    fn key(tenant: &[u8], at: i64) -> CompositeKey {
+        CompositeKey::new().push_bytes(tenant).push_i64(at)
+    }
+
+    #[test]
+    fn orders_like_the_components() {
+        let mut rng = thread_rng();
+
+        for _ in 0..10_000 {
+            let a = (vec![0; rng.gen_range(0..3)], rng.gen_range(-3..3));
+            let b = (
+                vec![rng.gen_range(0..2); rng.gen_range(0..3)],
+                rng.gen_range(-3..3),
+            );
+
+            assert_eq!(key(&a.0, a.1).cmp(&key(&b.0, b.1)), a.cmp(&b));
+        }
+
+        let key = CompositeKey::new()
+            .push_str("a\0b")
+            .push_u32(7)
+            .push_i32(-7);
+        let mut components = key.components();
+        assert_eq!(components.next_str().unwrap(), "a\0b");
+        assert_eq!(components.next_u32().unwrap(), 7);
+        assert_eq!(components.next_i32().unwrap(), -7);
+        assert!(components.is_empty());
+    }
+
+    #[test]
+    fn leading_components_are_prefixes() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<CompositeKey, u32>::with_options("db", 32, 4, options).unwrap();
+
+        for (tenant, at) in [("a", 2), ("ab", 1), ("a", -1), ("b", 0)] {
+            btree
+                .insert(CompositeKey::new().push_str(tenant).push_i64(at), 0)
+                .unwrap();
+        }
+
+        let prefix = CompositeKey::new().push_str("a");
+        let times: Vec<i64> = btree
+            .scan_prefix(prefix.as_ref())
+            .unwrap()
+            .map(|(key, _)| {
+                let mut components = key.components();
+                components.next_str().unwrap();
+                components.next_i64().unwrap()
+            })
+            .collect();
+        assert_eq!(times, [-1, 2]);
+    }
+}
@@ -0,0 +1,111 @@
+use wal_file::{KeyValuePair, RecordKind};
+use {KeyType, ValueType};
+
+/// A value that can be counted up by `BTree::increment`
+pub trait Counter {
+    fn zero() -> Self;
+
+    /// The value `delta` more than this one
+    fn add(&self, delta: &Self) -> Self;
+}
+
+macro_rules! impl_counter {
+    ($($t:ty),*) => {
+        $(
+            impl Counter for $t {
+                fn zero() -> $t {
+                    0
+                }
+
+                fn add(&self, delta: &$t) -> $t {
+                    self.wrapping_add(*delta)
+                }
+            }
+        )*
+    };
+}
+
+impl_counter!(i32, i64, u32, u64);
+
+/// How a tree of counters adds up the increments written to it, see `BTree::counters`
+pub struct Counting<V> {
+    zero: fn() -> V,
+    add: fn(&V, &V) -> V,
+}
+
+impl<V> Clone for Counting<V> {
+    fn clone(&self) -> Counting<V> {
+        *self
+    }
+}
+
+impl<V> Copy for Counting<V> {}
+
+impl<V: Counter> Counting<V> {
+    pub fn of() -> Counting<V> {
+        Counting {
+            zero: V::zero,
+            add: V::add,
+        }
+    }
+}
+
+impl<V> Counting<V> {
+    pub fn add(&self, value: &V, delta: &V) -> V {
+        (self.add)(value, delta)
+    }
+
+    /// Folds the increments among a key's `records`, which are newest first, into
+    /// the value they count up to. The increments written since the key's newest
+    /// put or delete add up to a put of their total, added to the put's value, which
+    /// becomes a delete; older increments were overtaken by that put or delete and
+    /// are dropped. The counted value keeps the put's expiry.
+    pub fn fold<K: KeyType>(&self, mut records: Vec<KeyValuePair<K, V>>) -> Vec<KeyValuePair<K, V>>
+    where
+        V: ValueType,
+    {
+        if records.iter().all(|kv| kv.kind != RecordKind::Merge) {
+            return records;
+        }
+
+        let pending = records
+            .iter()
+            .take_while(|kv| kv.kind == RecordKind::Merge)
+            .count();
+        let mut rest = records.split_off(pending);
+        rest.retain(|kv| kv.kind != RecordKind::Merge);
+
+        let newest = match records.first() {
+            Some(newest) => newest.clone(),
+            None => return rest,
+        };
+
+        let delta = records
+            .iter()
+            .rev()
+            .fold((self.zero)(), |sum, kv| self.add(&sum, &kv.value));
+
+        let counted = match rest.first_mut() {
+            Some(base) if base.kind == RecordKind::Put => {
+                base.kind = RecordKind::Delete;
+
+                KeyValuePair {
+                    value: self.add(&base.value, &delta),
+                    kind: RecordKind::Put,
+                    expires_at: base.expires_at,
+                    ..newest
+                }
+            }
+            _ => KeyValuePair {
+                value: delta,
+                kind: RecordKind::Put,
+                expires_at: None,
+                ..newest
+            },
+        };
+
+        let mut folded = vec![counted];
+        folded.append(&mut rest);
+        folded
+    }
+}
@@ -0,0 +1,49 @@
+use set_op::errors_first;
+
+use itertools::{EitherOrBoth, Itertools};
+
+/// How a key differs between two trees, see `BTree::diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diff<K, V> {
+    Added(K, Vec<V>),           // only the other tree has the key, with these values
+    Removed(K, Vec<V>),         // only this tree has it
+    Changed(K, Vec<V>, Vec<V>), // both have it, this tree with the first values and the other with the second
+}
+
+impl<K, V> Diff<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Diff::Added(key, _) | Diff::Removed(key, _) | Diff::Changed(key, _, _) => key,
+        }
+    }
+
+    /// Co-iterates two streams of keys with their values, both in key order, into
+    /// how the second differs from the first, in key order. An error reading either
+    /// stream comes out as soon as it's met.
+    pub fn between<E, A, B>(first: A, second: B) -> impl Iterator<Item = Result<Diff<K, V>, E>>
+    where
+        K: Ord,
+        V: PartialEq,
+        A: Iterator<Item = Result<(K, Vec<V>), E>>,
+        B: Iterator<Item = Result<(K, Vec<V>), E>>,
+    {
+        first
+            .merge_join_by(second, errors_first)
+            .filter_map(|entry| match entry {
+                EitherOrBoth::Both(Ok((key, a)), Ok((_, b))) => {
+                    if a == b {
+                        None
+                    } else {
+                        Some(Ok(Diff::Changed(key, a, b)))
+                    }
+                }
+                EitherOrBoth::Left(Ok((key, a))) => Some(Ok(Diff::Removed(key, a))),
+                EitherOrBoth::Right(Ok((key, b))) => Some(Ok(Diff::Added(key, b))),
+                EitherOrBoth::Left(Err(error)) | EitherOrBoth::Right(Err(error)) => {
+                    Some(Err(error))
+                }
+                // an error is never equal to anything, so never comes paired
+                EitherOrBoth::Both(_, _) => unreachable!(),
+            })
+    }
+}
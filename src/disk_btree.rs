@@ -1,8 +1,23 @@
-use wal_file::{KeyValuePair, RecordFile, RecordFileIterator};
+use block_cache::BlockCache;
+use block_encoding::{decode_block, BlockIndex, BlockWriter};
+use block_filters::{BlockFilters, FilterBuilder, FilterSettings, KeyBytes};
+use encoding::{Codec, Encoding};
+use error::BTreeError;
+use hash_index::{HashIndex, HashIndexBuilder};
+use storage::Storage;
+use wal_file::{
+    decode_key, decode_where, Filtered, KeyRecord, KeyValuePair, RecordFile, RecordFileIterator,
+    ValuePredicate,
+};
+use zone_map::{ZoneMap, ZoneMapBuilder};
 
 use {KeyType, ValueType};
 
 use std::error::Error;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 // use std::iter::Filter;
 
 /*
@@ -33,6 +48,8 @@ struct Node<K: KeyType, V: ValueType> {
 /// | 0x42 0x2b 0x54 0x72 | 0x65 0x65 0x00 0xVV |
 /// | B    +    T    r    | e    e    \0   0xVV |
 /// |-------------------------------------------|
+/// | 0xEE, the records' encoding, from VV 2 on |
+/// |-------------------------------------------|
 /// | the smallest record in bincode format     |
 /// |-------------------------------------------|
 /// | ...                                       |
@@ -43,68 +60,757 @@ struct Node<K: KeyType, V: ValueType> {
 /// |-------------------------------------------|
 /// | root node                                 |
 /// |-------------------------------------------|
-
-// total hack to get things going
+//
+// total hack to get things going: for now the file is just the header and the sorted records
 pub struct OnDiskBTree<K: KeyType, V: ValueType> {
     file: RecordFile<K, V>,
+    id: u64,                                  // tells this file's blocks apart in the cache
+    cache: Option<Arc<BlockCache>>,           // where blocks are cached, if anywhere
+    filters: Option<BlockFilters<K>>,         // bloom filters for each block, if the file has them
+    filter_builder: Option<FilterBuilder<K>>, // builds the filters while the file is written
+    hash_index: Option<HashIndex>,            // finds a key's records without a binary search
+    hash_index_builder: Option<HashIndexBuilder<K>>,
+    zones: Option<ZoneMap<K>>, // the key range of each block, if the file has them
+    zone_builder: Option<ZoneMapBuilder<K>>,
+    blocks: Option<BlockIndex>, // where each block starts, if they're encoded
+    block_writer: Option<BlockWriter>, // encodes the blocks while the file is written
+    version: u8,                // the format the file was written in
 }
 
+/// What to write along with the records of a new file
+pub struct FileOptions<K> {
+    pub filters: Option<FilterSettings<K>>,
+    pub hash_index: bool,
+    pub prefix_compression: bool, // encode blocks as the bytes records share with the one before
+    pub dictionary_size: Option<usize>, // and with a dictionary sampled from the first records
+    pub encoding: Encoding,       // what a new file's records are encoded in
+}
+
+impl<K> Clone for FileOptions<K> {
+    fn clone(&self) -> FileOptions<K> {
+        *self
+    }
+}
+
+impl<K> Copy for FileOptions<K> {}
+
+/// Every file of records starts with these bytes and then its format version, as
+/// laid out above, since there's been a version to stamp
+pub const FILE_HEADER: &[u8; 7] = b"B+Tree\0";
+
+/// The format new files are written in. Files from before there were headers, but
+/// whose records already carried a kind and sequence number, are version 0;
+/// they're still read, and `BTree::migrate` brings them up to date. Older ones
+/// still, of nothing but keys and values, can only be read by `BTree::migrate`.
+/// Version 2 added the encoding of the records to the header.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Opens the records of the file at `file_path`, after its header if it has one,
+/// and returns the format they're in
+fn open_records<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    file_path: &str,
+    key_size: usize,
+    value_size: usize,
+) -> Result<(RecordFile<K, V>, u8), Box<dyn Error>> {
+    let mut file = RecordFile::new(storage, file_path, key_size, value_size)?;
+    let mut len = FILE_HEADER.len() as u64 + 1;
+
+    if file.byte_len()? < len {
+        return Ok((file, 0));
+    }
+
+    let header = file.read_bytes(0, len)?;
+
+    if header[..FILE_HEADER.len()] != FILE_HEADER[..] {
+        return Ok((file, 0));
+    }
+
+    let version = header[FILE_HEADER.len()];
+
+    if version > FORMAT_VERSION {
+        return Err(Box::new(BTreeError::UnsupportedVersion {
+            path: file_path.to_owned(),
+            version,
+        }));
+    }
+
+    // older versions were always written in what's now the legacy encoding
+    if version >= 2 {
+        let id = file.read_bytes(len, 1)?[0];
+        let encoding = Encoding::from_id(id).ok_or_else(|| BTreeError::UnsupportedEncoding {
+            path: file_path.to_owned(),
+            encoding: id,
+        })?;
+
+        file.set_encoding(encoding);
+        len += 1;
+    }
+
+    file.set_header_len(len);
+
+    Ok((file, version))
+}
+
+/// What `OnDiskBTree::salvage` could read of a damaged file
+pub struct Salvaged<K, V> {
+    pub records: Vec<KeyValuePair<K, V>>, // in the order they were found
+    pub lost: u64,       // records that couldn't be read, as far as they can be counted
+    pub bytes_lost: u64, // at the end, too few for a record, or all of a file without its block index
+}
+
+/// Decodes a padded record, unless it's been damaged: a record that decodes at all
+/// is only taken as whole if the padding after it is still zeroed
+pub fn salvage_record<K: KeyType, V: ValueType>(
+    codec: Codec,
+    bytes: &[u8],
+) -> Option<KeyValuePair<K, V>> {
+    let kv: KeyValuePair<K, V> = codec.deserialize(bytes).ok()?;
+    let used = codec.serialized_size(&kv).ok()? as usize;
+
+    match bytes.get(used..) {
+        Some(padding) if padding.iter().all(|byte| *byte == 0) => Some(kv),
+        _ => None,
+    }
+}
+
+/// Records are read into the cache a block of about this many bytes at a time
+const BLOCK_SIZE: usize = 4096;
+
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
 pub struct OnDiskBTreeIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
-    record_iterator: RecordFileIterator<'a, K, V>,
+    records: Records<'a, K, V>,
+}
+
+enum Records<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    Plain(RecordFileIterator<'a, K, V>),
+    Encoded {
+        tree: &'a OnDiskBTree<K, V>,
+        index: u64, // the next record to read, or past the end once a read has failed
+        block: Option<(u64, Vec<u8>)>, // the decoded block it's read from
+    },
 }
 
 impl<K: KeyType, V: ValueType> OnDiskBTree<K, V> {
     pub fn new(
-        file_path: String,
+        storage: &dyn Storage,
+        file_path: &str,
         key_size: usize,
         value_size: usize,
     ) -> Result<OnDiskBTree<K, V>, Box<dyn Error>> {
-        Ok(OnDiskBTree {
-            file: RecordFile::new(&file_path, key_size, value_size)?,
-        })
+        let (file, version) = open_records(storage, file_path, key_size, value_size)?;
+        let mut tree = OnDiskBTree::bare(file, version);
+
+        tree.filters = BlockFilters::open(
+            storage,
+            file_path,
+            NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+        )?;
+        tree.hash_index = HashIndex::open(storage, file_path)?;
+        tree.blocks = BlockIndex::open(&tree.file)?;
+        tree.zones = ZoneMap::open(storage, file_path, tree.count()?.div_ceil(tree.per_block()))?;
+
+        Ok(tree)
+    }
+
+    /// The records of `file`, in format `version`, without any of its sidecars
+    fn bare(file: RecordFile<K, V>, version: u8) -> OnDiskBTree<K, V> {
+        OnDiskBTree {
+            file,
+            id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+            cache: None,
+            filters: None,
+            filter_builder: None,
+            hash_index: None,
+            hash_index_builder: None,
+            zones: None,
+            zone_builder: None,
+            blocks: None,
+            block_writer: None,
+            version,
+        }
+    }
+
+    /// Opens the records of the file at `file_path` without its sidecars, which may
+    /// be missing or damaged, so keys are found by binary search alone
+    pub fn without_sidecars(
+        storage: &dyn Storage,
+        file_path: &str,
+        key_size: usize,
+        value_size: usize,
+    ) -> Result<OnDiskBTree<K, V>, Box<dyn Error>> {
+        let (file, version) = open_records(storage, file_path, key_size, value_size)?;
+        let mut tree = OnDiskBTree::<K, V>::bare(file, version);
+        tree.blocks = BlockIndex::open(&tree.file)?;
+
+        Ok(tree)
+    }
+
+    /// Reads whatever records of the file at `file_path` can still be read, passing
+    /// over the blocks and records that are damaged. The sidecars are left alone, as
+    /// they may be what's damaged, so the records come back unchecked by them.
+    pub fn salvage(
+        storage: &dyn Storage,
+        file_path: &str,
+        key_size: usize,
+        value_size: usize,
+    ) -> Result<Salvaged<K, V>, Box<dyn Error>> {
+        let (file, version) = open_records(storage, file_path, key_size, value_size)?;
+        let mut tree = OnDiskBTree::<K, V>::bare(file, version);
+        let len = tree.file.byte_len()?;
+        let record_size = tree.file.record_size();
+        let per_block = tree.per_block();
+
+        let mut salvaged = Salvaged {
+            records: Vec::new(),
+            lost: 0,
+            bytes_lost: 0,
+        };
+
+        // without its index there's no telling where an encoded block starts
+        tree.blocks = match BlockIndex::open(&tree.file) {
+            Ok(blocks) => blocks,
+            Err(_) => {
+                salvaged.bytes_lost = len;
+                return Ok(salvaged);
+            }
+        };
+
+        let count = match &tree.blocks {
+            Some(blocks) => blocks.count,
+            None => {
+                salvaged.bytes_lost = len % record_size as u64;
+                len / record_size as u64
+            }
+        };
+
+        for block in 0..count.div_ceil(per_block) {
+            let records = per_block.min(count - block * per_block);
+
+            let data = match &tree.blocks {
+                Some(_) => tree.read_block(block),
+                None => tree.file.read_raw(block * per_block, records),
+            };
+
+            let data = match data {
+                Ok(data) => data,
+                Err(_) => {
+                    salvaged.lost += records;
+                    continue;
+                }
+            };
+
+            for bytes in data.chunks(record_size) {
+                match salvage_record(tree.file.codec(), bytes) {
+                    Some(kv) => salvaged.records.push(kv),
+                    None => salvaged.lost += 1,
+                }
+            }
+        }
+
+        Ok(salvaged)
+    }
+
+    /// Opens a file to write a new B+Tree into, along with what `options` asks for
+    pub fn create(
+        storage: &dyn Storage,
+        file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: FileOptions<K>,
+    ) -> Result<OnDiskBTree<K, V>, Box<dyn Error>> {
+        let mut tree = OnDiskBTree::new(storage, file_path, key_size, value_size)?;
+
+        // a new file is stamped with the format it's written in
+        if tree.file.is_new()? {
+            tree.file.append_bytes(FILE_HEADER)?;
+            tree.file
+                .append_bytes(&[FORMAT_VERSION, options.encoding.id()])?;
+            tree.file.set_encoding(options.encoding);
+            tree.file.set_header_len(FILE_HEADER.len() as u64 + 2);
+            tree.version = FORMAT_VERSION;
+        }
+
+        tree.build_zone_map(storage, file_path)?;
+
+        if let Some(settings) = options.filters {
+            tree.build_filters(storage, file_path, settings)?;
+        }
+
+        if options.hash_index {
+            tree.build_hash_index(storage, file_path)?;
+        }
+
+        if options.prefix_compression || options.dictionary_size.is_some() {
+            tree.block_writer = Some(BlockWriter::new(tree.per_block(), options.dictionary_size));
+        }
+
+        Ok(tree)
+    }
+
+    /// Builds a bloom filter for each block as records are inserted into this new
+    /// file, written to a sidecar next to it when the file is synced
+    pub fn build_filters(
+        &mut self,
+        storage: &dyn Storage,
+        file_path: &str,
+        settings: FilterSettings<K>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.filter_builder = Some(FilterBuilder::new(
+            storage,
+            file_path,
+            self.per_block(),
+            settings,
+        )?);
+        Ok(())
+    }
+
+    /// Builds a hash index of the keys as records are inserted into this new file,
+    /// written to a sidecar next to it when the file is synced
+    pub fn build_hash_index(
+        &mut self,
+        storage: &dyn Storage,
+        file_path: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.hash_index_builder = Some(HashIndexBuilder::new(storage, file_path)?);
+        Ok(())
+    }
+
+    /// Builds a zone map of the blocks as records are inserted into this new file,
+    /// written to a sidecar next to it when the file is synced
+    pub fn build_zone_map(
+        &mut self,
+        storage: &dyn Storage,
+        file_path: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.zone_builder = Some(ZoneMapBuilder::new(storage, file_path, self.per_block())?);
+        Ok(())
+    }
+
+    /// The number of records in a block
+    fn per_block(&self) -> u64 {
+        (BLOCK_SIZE / self.file.record_size()).max(1) as u64
+    }
+
+    /// Serves lookups from `cache`. Sequential scans still read the file directly,
+    /// so a compaction doesn't push out the blocks lookups are using.
+    pub fn set_cache(&mut self, cache: Arc<BlockCache>) {
+        self.cache = Some(cache);
+    }
+
+    /// Reads the record at `index`, through the cache when there is one
+    fn read_record(&self, index: u64) -> Result<KeyValuePair<K, V>, Box<dyn Error>> {
+        self.read_record_as(index)
+    }
+
+    /// Reads the record at `index`, decoding its value as a `W`, which must be
+    /// encoded the same as a `V`
+    fn read_record_as<W: ValueType>(
+        &self,
+        index: u64,
+    ) -> Result<KeyValuePair<K, W>, Box<dyn Error>> {
+        let count = self.count()?;
+        let record_size = self.file.record_size();
+        let per_block = self.per_block();
+        let block = index / per_block;
+        let first = block * per_block;
+
+        let cache = match &self.cache {
+            Some(cache) if index < count => cache,
+            _ => {
+                return match &self.blocks {
+                    // only as far into the block as the record
+                    Some(blocks) => {
+                        let within = (index - first) as usize;
+                        let encoded = blocks.read_block(&self.file, block)?;
+                        let data = decode_block(
+                            &encoded,
+                            within,
+                            within + 1,
+                            record_size,
+                            blocks.dictionary(),
+                        )?;
+                        Ok(self.file.codec().deserialize(&data)?)
+                    }
+                    None => Ok(self
+                        .file
+                        .codec()
+                        .deserialize(&self.file.read_payload(index)?.0)?),
+                };
+            }
+        };
+
+        let data = match cache.get(self.id, block) {
+            Some(data) => data,
+            None => {
+                let data = Arc::new(self.read_block(block)?);
+                cache.insert(self.id, block, data.clone());
+                data
+            }
+        };
+
+        let offset = (index - first) as usize * record_size;
+
+        Ok(self
+            .file
+            .codec()
+            .deserialize(&data[offset..offset + record_size])?)
+    }
+
+    /// Reads a whole block, decoding it into padded records if it's encoded
+    fn read_block(&self, block: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let per_block = self.per_block();
+        let first = block * per_block;
+        let records = per_block.min(self.count()?.saturating_sub(first));
+
+        match &self.blocks {
+            Some(blocks) => decode_block(
+                &blocks.read_block(&self.file, block)?,
+                0,
+                records as usize,
+                self.file.record_size(),
+                blocks.dictionary(),
+            ),
+            None => self.file.read_raw(first, records),
+        }
     }
 
     pub fn is_new(&self) -> Result<bool, Box<dyn Error>> {
         self.file.is_new()
     }
 
+    /// The format the file was written in, see `FORMAT_VERSION`
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// What the file's records are encoded in, see `Options::encoding`
+    pub fn encoding(&self) -> Encoding {
+        self.file.encoding()
+    }
+
     /// Returns the number of records in the B+Tree
     pub fn count(&self) -> Result<u64, Box<dyn Error>> {
-        self.file.count()
+        match (&self.block_writer, &self.blocks) {
+            (Some(writer), _) => Ok(writer.count()),
+            (None, Some(blocks)) => Ok(blocks.count),
+            (None, None) => self.file.count(),
+        }
     }
 
+    /// Records must be inserted in sorted order
     pub fn insert_record(&mut self, kv: &KeyValuePair<K, V>) -> Result<(), Box<dyn Error>> {
-        self.file.insert_record(kv)
+        if let Some(builder) = self.filter_builder.as_mut() {
+            builder.add(&kv.key)?;
+        }
+
+        if let Some(builder) = self.hash_index_builder.as_mut() {
+            builder.add(&kv.key)?;
+        }
+
+        if let Some(builder) = self.zone_builder.as_mut() {
+            builder.add(&kv.key);
+        }
+
+        match self.block_writer.as_mut() {
+            Some(writer) => {
+                let blocks = writer.add(self.file.encode_record(kv)?);
+                self.file.append_bytes(&blocks)
+            }
+            None => self.file.insert_record(kv),
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        // an encoded file is finished off with the last block and the footer
+        if let Some(writer) = self.block_writer.take() {
+            let (tail, blocks) = writer.finish()?;
+
+            self.file.append_bytes(&tail)?;
+            self.blocks = Some(blocks);
+        }
+
+        self.file.sync()?;
+
+        // the filters are only written once the file they describe is durable
+        if let Some(builder) = self.filter_builder.take() {
+            self.filters = Some(builder.finish(NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed))?);
+        }
+
+        if let Some(builder) = self.hash_index_builder.take() {
+            self.hash_index = Some(builder.finish()?);
+        }
+
+        if let Some(builder) = self.zone_builder.take() {
+            self.zones = Some(builder.finish()?);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the smallest and largest keys in the B+Tree, if it has any records
+    pub fn key_span(&self) -> Result<Option<(K, K)>, Box<dyn Error>> {
+        let count = self.count()?;
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some((
+            self.read_record(0)?.key,
+            self.read_record(count - 1)?.key,
+        )))
     }
 
-    /*
-        fn get(&self, key: &K) -> bool { //Box<Filter<RecordFileIterator<K,V>, fn(KeyValuePair<K,V>) -> bool>> {
-            // return Box::new(self.into_iter().filter(|rec| &rec.key == key));
-            return true;
+    /// Picks up to `parts - 1` keys that split the records into roughly equal parts
+    pub fn split_keys(&self, parts: usize) -> Result<Vec<K>, Box<dyn Error>> {
+        let count = self.count()?;
+        let mut keys = Vec::new();
+
+        for part in 1..parts as u64 {
+            let index = count * part / parts as u64;
+
+            if index > 0 && index < count {
+                keys.push(self.read_record(index)?.key);
+            }
+        }
+
+        // a key with many values can span several parts
+        keys.dedup();
+
+        Ok(keys)
+    }
+
+    /// Returns the index of the first record with a key >= `key`
+    pub fn lower_bound(&self, key: &K) -> Result<u64, Box<dyn Error>> {
+        self.partition_point(|k| k < key)
+    }
+
+    /// Returns the index of the first record whose key fails `pred`, which must
+    /// hold for every key before some point and for none after it
+    pub fn partition_point(&self, pred: impl Fn(&K) -> bool) -> Result<u64, Box<dyn Error>> {
+        let count = self.count()?;
+
+        // the zone map narrows the search down to a single block
+        let (mut lo, mut hi) = match &self.zones {
+            Some(zones) => {
+                let first = zones.partition_block(&pred) * self.per_block();
+                (first.min(count), (first + self.per_block()).min(count))
+            }
+            None => (0, count),
+        };
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+
+            if pred(&self.read_record(mid)?.key) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        Ok(lo)
+    }
+
+    /// Iterates over the records from `index` onwards
+    pub fn iter_from(&self, index: u64) -> OnDiskBTreeIterator<'_, K, V> {
+        let records = match self.blocks {
+            Some(_) => Records::Encoded {
+                tree: self,
+                index,
+                block: None,
+            },
+            None => Records::Plain(self.file.iter_from(index)),
+        };
+
+        OnDiskBTreeIterator { records }
+    }
+
+    /// Reads the blocks holding the keys in `range`, and their filters, into the
+    /// cache ahead of the lookups that will want them. Returns how many blocks
+    /// weren't cached already.
+    pub fn warm<R: RangeBounds<K>>(&self, range: &R) -> Result<u64, Box<dyn Error>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(0),
+        };
+
+        let lo = match range.start_bound() {
+            Included(start) => self.lower_bound(start)?,
+            Excluded(start) => self.partition_point(|k| k <= start)?,
+            Unbounded => 0,
+        };
+
+        let hi = match range.end_bound() {
+            Included(end) => self.partition_point(|k| k <= end)?,
+            Excluded(end) => self.lower_bound(end)?,
+            Unbounded => self.count()?,
+        };
+
+        if lo >= hi {
+            return Ok(0);
+        }
+
+        let blocks = lo / self.per_block()..hi.div_ceil(self.per_block());
+        let mut read = 0;
+
+        for block in blocks.clone() {
+            if cache.get(self.id, block).is_none() {
+                cache.insert(self.id, block, Arc::new(self.read_block(block)?));
+                read += 1;
+            }
+        }
+
+        if let Some(filters) = &self.filters {
+            filters.warm(blocks, cache)?;
+        }
+
+        Ok(read)
+    }
+
+    /// False when the prefix filters rule out any key starting with `prefix`
+    pub fn may_contain_prefix(
+        &self,
+        prefix: &[u8],
+        key_bytes: KeyBytes<K>,
+    ) -> Result<bool, Box<dyn Error>> {
+        match &self.filters {
+            Some(filters) => filters.may_contain_prefix(prefix, key_bytes, self.cache.as_deref()),
+            None => Ok(true),
         }
-    */
+    }
+
+    /// Returns all the records stored under `key`, in sorted order
+    pub fn get(&self, key: &K) -> Result<Vec<KeyValuePair<K, V>>, Box<dyn Error>> {
+        self.get_as(key)
+    }
 
-    // pub fn contains_key(&self, key: &K) -> bool {
-    //     true
-    // }
+    /// Like `get`, decoding the values as a `W`, which must be encoded the same as a `V`
+    pub fn get_as<W: ValueType>(&self, key: &K) -> Result<Vec<KeyValuePair<K, W>>, Box<dyn Error>> {
+        if self
+            .zones
+            .as_ref()
+            .is_some_and(|zones| !zones.may_contain(key))
+        {
+            return Ok(Vec::new());
+        }
+
+        if let Some(filters) = &self.filters {
+            if !filters.may_contain(key, self.cache.as_deref())? {
+                return Ok(Vec::new());
+            }
+        }
+
+        let count = self.count()?;
+
+        // find the first record with the key, or binary search for the first with
+        // a key >= the one we want
+        let lo = match &self.hash_index {
+            Some(index) => match index.find(key, |index| Ok(self.read_record(index)?.key))? {
+                Some(lo) => lo,
+                None => return Ok(Vec::new()),
+            },
+            None => self.lower_bound(key)?,
+        };
+
+        // then walk forward collecting records until the key changes
+        let mut records = Vec::new();
+
+        for index in lo..count {
+            let kv = self.read_record_as(index)?;
+
+            if kv.key != *key {
+                break;
+            }
+
+            records.push(kv);
+        }
+
+        Ok(records)
+    }
 }
 
-impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a mut OnDiskBTree<K, V> {
-    type Item = KeyValuePair<K, V>;
+impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a OnDiskBTree<K, V> {
+    type Item = Result<KeyValuePair<K, V>, Box<dyn Error>>;
     type IntoIter = OnDiskBTreeIterator<'a, K, V>;
 
     fn into_iter(self) -> Self::IntoIter {
-        OnDiskBTreeIterator {
-            record_iterator: self.file.into_iter(),
+        self.iter_from(0)
+    }
+}
+
+impl<'a, K: KeyType, V: ValueType> OnDiskBTreeIterator<'a, K, V> {
+    /// Iterates over the records from here on, decoding only the keys of those
+    /// whose value fails `predicate`
+    pub fn filtered(
+        mut self,
+        predicate: ValuePredicate,
+    ) -> impl Iterator<Item = Result<Filtered<K, V>, Box<dyn Error>>> + 'a {
+        std::iter::from_fn(move || {
+            self.next_decoded(|codec, bytes| decode_where(codec, bytes, Some(predicate)))
+        })
+    }
+
+    /// Iterates over the keys of the records from here on, without decoding their
+    /// values, which must be encoded as their length followed by their bytes
+    pub fn keys(mut self) -> impl Iterator<Item = Result<KeyRecord<K>, Box<dyn Error>>> + 'a {
+        std::iter::from_fn(move || self.next_decoded(decode_key))
+    }
+
+    /// Reads the next record with `decode`, which is given its encoded bytes. A
+    /// record that can't be read or decoded is returned as an error, and ends the
+    /// iteration.
+    fn next_decoded<R, F>(&mut self, decode: F) -> Option<Result<R, Box<dyn Error>>>
+    where
+        F: FnOnce(Codec, &[u8]) -> Result<R, Box<dyn Error>>,
+    {
+        let (tree, index, block) = match &mut self.records {
+            Records::Plain(records) => return records.next_decoded(decode),
+            Records::Encoded { tree, index, block } => (tree, index, block),
+        };
+
+        let read = || -> Result<Option<R>, Box<dyn Error>> {
+            if *index >= tree.count()? {
+                return Ok(None);
+            }
+
+            // blocks are decoded one at a time, without going through the cache
+            let per_block = tree.per_block();
+            let wanted = *index / per_block;
+
+            if block.as_ref().is_none_or(|(current, _)| *current != wanted) {
+                *block = Some((wanted, tree.read_block(wanted)?));
+            }
+
+            let record_size = tree.file.record_size();
+            let offset = (*index % per_block) as usize * record_size;
+            let data = &block.as_ref().ok_or("the block wasn't read")?.1;
+            let record = data
+                .get(offset..offset + record_size)
+                .ok_or("the block was cut short")?;
+
+            *index += 1;
+
+            decode(tree.file.codec(), record).map(Some)
+        };
+
+        match read() {
+            Ok(record) => record.map(Ok),
+            Err(e) => {
+                *index = u64::MAX;
+                Some(Err(e))
+            }
         }
     }
 }
 
 impl<'a, K: KeyType, V: ValueType> Iterator for OnDiskBTreeIterator<'a, K, V> {
-    type Item = KeyValuePair<K, V>;
+    type Item = Result<KeyValuePair<K, V>, Box<dyn Error>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.record_iterator.next()
+        self.next_decoded(|codec, bytes| Ok(codec.deserialize(bytes)?))
     }
 }
 
@@ -171,3 +877,272 @@ impl<'a, K: KeyType, V: ValueType> Iterator for OnDiskBTreeIterator<'a, K, V> {
 
 
 */
+
+#[cfg(test)]
+mod tests {
+    use block_cache::BlockCache;
+    use block_filters::FilterSettings;
+    use disk_btree::{FileOptions, OnDiskBTree};
+    use encoding::Encoding;
+    use sim_disk::SimDisk;
+    use wal_file::KeyValuePair;
+
+    use itertools::Itertools;
+    use std::sync::Arc;
+
+    #[test]
+    fn filters_skip_missing_keys() {
+        let disk = SimDisk::new(0);
+
+        {
+            let mut tree = OnDiskBTree::<u32, u32>::new(&disk, "db", 4, 4).unwrap();
+            tree.build_filters(
+                &disk,
+                "db",
+                FilterSettings {
+                    bits_per_key: 10,
+                    prefix: None,
+                },
+            )
+            .unwrap();
+
+            for key in (0..2000).step_by(2) {
+                for value in 0..3 {
+                    tree.insert_record(&KeyValuePair::new(key, value)).unwrap();
+                }
+            }
+            tree.sync().unwrap();
+        }
+
+        // the filters are picked up again from the sidecar
+        let tree = OnDiskBTree::<u32, u32>::new(&disk, "db", 4, 4).unwrap();
+        let filters = tree.filters.as_ref().unwrap();
+
+        for key in (0..2000).step_by(2) {
+            assert_eq!(tree.get(&key).unwrap().len(), 3);
+        }
+
+        let passed = (1..2000)
+            .step_by(2)
+            .filter(|key| filters.may_contain(key, None).unwrap())
+            .count();
+        assert!(passed < 50, "{} missing keys got past the filters", passed);
+    }
+
+    #[test]
+    fn prefix_filters_skip_missing_prefixes() {
+        let disk = SimDisk::new(0);
+        let settings = FilterSettings {
+            bits_per_key: 10,
+            prefix: Some((4, String::as_bytes)),
+        };
+
+        let mut tree = OnDiskBTree::<String, u32>::new(&disk, "db", 16, 4).unwrap();
+        tree.build_filters(&disk, "db", settings).unwrap();
+
+        // only the even groups are written
+        for group in (0..200).step_by(2) {
+            for i in 0..20 {
+                tree.insert_record(&KeyValuePair::new(format!("{:03}-{:02}", group, i), 0))
+                    .unwrap();
+            }
+        }
+        tree.sync().unwrap();
+
+        let may_contain = |prefix: String| {
+            tree.may_contain_prefix(prefix.as_bytes(), String::as_bytes)
+                .unwrap()
+        };
+
+        assert!((0..200)
+            .step_by(2)
+            .all(|group| may_contain(format!("{:03}-", group))));
+        assert!((0..200)
+            .step_by(2)
+            .all(|group| may_contain(format!("{:03}-1", group))));
+
+        let passed = (1..200)
+            .step_by(2)
+            .filter(|group| may_contain(format!("{:03}-", group)))
+            .count();
+        assert!(
+            passed < 10,
+            "{} missing prefixes got past the filters",
+            passed
+        );
+
+        // shorter than the filtered prefixes, so it can't be ruled out
+        assert!(may_contain("1".to_owned()));
+    }
+
+    #[test]
+    fn hash_index_finds_every_key() {
+        let disk = SimDisk::new(0);
+
+        {
+            let mut tree = OnDiskBTree::<u32, u32>::new(&disk, "db", 4, 4).unwrap();
+            tree.build_hash_index(&disk, "db").unwrap();
+
+            for key in (0..2000).step_by(2) {
+                for value in 0..3 {
+                    tree.insert_record(&KeyValuePair::new(key, value)).unwrap();
+                }
+            }
+            tree.sync().unwrap();
+        }
+
+        let tree = OnDiskBTree::<u32, u32>::new(&disk, "db", 4, 4).unwrap();
+        assert!(tree.hash_index.is_some());
+
+        for key in 0..2000 {
+            let values: Vec<u32> = tree
+                .get(&key)
+                .unwrap()
+                .into_iter()
+                .map(|kv| kv.value)
+                .collect();
+            assert_eq!(values, if key % 2 == 0 { vec![0, 1, 2] } else { vec![] });
+        }
+    }
+
+    #[test]
+    fn zone_maps_skip_blocks() {
+        let disk = SimDisk::new(0);
+
+        {
+            let mut tree = OnDiskBTree::<u32, u32>::new(&disk, "db", 4, 4).unwrap();
+            tree.build_zone_map(&disk, "db").unwrap();
+
+            for key in (0..20_000).step_by(10) {
+                tree.insert_record(&KeyValuePair::new(key, 0)).unwrap();
+            }
+            tree.sync().unwrap();
+        }
+
+        let mut tree = OnDiskBTree::<u32, u32>::new(&disk, "db", 4, 4).unwrap();
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        tree.set_cache(cache.clone());
+        assert!(tree.zones.is_some());
+
+        // between the last key of the first block and the first key of the second
+        let gap = (tree.per_block() as u32 - 1) * 10 + 5;
+        assert!(tree.get(&gap).unwrap().is_empty());
+        assert!(tree.get(&20_000).unwrap().is_empty());
+        assert_eq!(cache.used(), 0);
+
+        for key in (0..20_000).step_by(10) {
+            assert_eq!(tree.get(&key).unwrap().len(), 1);
+            assert_eq!(
+                tree.lower_bound(&(key + 1)).unwrap(),
+                u64::from(key / 10 + 1)
+            );
+        }
+    }
+
+    #[test]
+    fn encoded_blocks_are_smaller_and_read_back() {
+        let disk = SimDisk::new(0);
+        let key = |i: u32| format!("/var/log/app/{}/{:05}.log", i % 7, i);
+        let options = FileOptions {
+            filters: None,
+            hash_index: false,
+            prefix_compression: true,
+            dictionary_size: None,
+            encoding: Encoding::Legacy,
+        };
+
+        {
+            let mut plain = OnDiskBTree::<String, u32>::new(&disk, "plain", 40, 4).unwrap();
+            let mut encoded =
+                OnDiskBTree::<String, u32>::create(&disk, "encoded", 40, 4, options).unwrap();
+            let mut keys: Vec<String> = (0..1000).map(key).collect();
+            keys.sort();
+
+            for key in keys {
+                plain
+                    .insert_record(&KeyValuePair::new(key.clone(), 1))
+                    .unwrap();
+                encoded.insert_record(&KeyValuePair::new(key, 1)).unwrap();
+            }
+            plain.sync().unwrap();
+            encoded.sync().unwrap();
+        }
+
+        let (plain, encoded) = (
+            disk.contents("plain").unwrap(),
+            disk.contents("encoded").unwrap(),
+        );
+        assert!(
+            encoded.len() * 3 < plain.len(),
+            "{} bytes encoded from {}",
+            encoded.len(),
+            plain.len()
+        );
+
+        let mut tree = OnDiskBTree::<String, u32>::new(&disk, "encoded", 40, 4).unwrap();
+        assert_eq!(tree.count().unwrap(), 1000);
+        assert_eq!(tree.iter_from(0).count(), 1000);
+        assert!(tree
+            .iter_from(0)
+            .map(|kv| kv.unwrap().key)
+            .tuple_windows()
+            .all(|(a, b)| a < b));
+
+        // with and without the cache
+        for _ in 0..2 {
+            for i in (0..1000).step_by(37) {
+                assert_eq!(tree.get(&key(i)).unwrap().len(), 1);
+            }
+            assert!(tree.get(&"/var/log/app/9".to_owned()).unwrap().is_empty());
+
+            tree.set_cache(Arc::new(BlockCache::new(1 << 20)));
+        }
+    }
+
+    #[test]
+    fn files_with_dictionaries_read_back() {
+        let disk = SimDisk::new(0);
+        let value = |i: u32| {
+            [
+                "{\"level\":\"info\",\"msg\":\"ok\"}",
+                "{\"level\":\"warn\",\"msg\":\"slow\"}",
+            ][i as usize % 2]
+        };
+
+        for (path, dictionary_size) in [("prefix", None), ("dictionary", Some(256))] {
+            let options = FileOptions {
+                filters: None,
+                hash_index: false,
+                prefix_compression: true,
+                dictionary_size,
+                encoding: Encoding::Legacy,
+            };
+            let mut tree = OnDiskBTree::<u32, String>::create(&disk, path, 4, 40, options).unwrap();
+
+            for i in 0..2000 {
+                tree.insert_record(&KeyValuePair::new(i, value(i).to_owned()))
+                    .unwrap();
+            }
+            tree.sync().unwrap();
+        }
+
+        let (prefix, dictionary) = (
+            disk.contents("prefix").unwrap(),
+            disk.contents("dictionary").unwrap(),
+        );
+        assert!(
+            dictionary.len() * 3 < prefix.len() * 2,
+            "{} bytes with a dictionary, {} without",
+            dictionary.len(),
+            prefix.len()
+        );
+
+        let tree = OnDiskBTree::<u32, String>::new(&disk, "dictionary", 4, 40).unwrap();
+        assert!(tree
+            .iter_from(0)
+            .map(Result::unwrap)
+            .enumerate()
+            .all(|(i, kv)| kv.key == i as u32 && kv.value == value(kv.key)));
+        assert_eq!(tree.get(&1234).unwrap()[0].value, value(1234));
+    }
+}
@@ -0,0 +1,110 @@
+use error::BTreeError;
+
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The sequence number up to which a BTree's writes are durable, shared with the
+/// handles `insert_async` returns so they can wait for it to move past theirs
+pub struct DurableSeq {
+    state: Mutex<DurableState>,
+    advanced: Condvar,
+}
+
+struct DurableState {
+    seq: u64,
+    closed: bool, // the tree is gone, the seq won't move again
+}
+
+impl DurableSeq {
+    pub fn new(seq: u64) -> DurableSeq {
+        DurableSeq {
+            state: Mutex::new(DurableState { seq, closed: false }),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Records that every write up to `seq` has been synced, waking the waiters
+    pub fn advance(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if seq > state.seq {
+            state.seq = seq;
+            self.advanced.notify_all();
+        }
+    }
+
+    /// The last write that's durable
+    pub fn seq(&self) -> u64 {
+        self.state.lock().unwrap().seq
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.advanced.notify_all();
+    }
+}
+
+/// A write made with `BTree::insert_async`. It's on its way to disk straight away,
+/// but only durable once the WAL sync covering it, made under the tree's sync
+/// policy, by `BTree::sync` or by a flush, has completed.
+pub struct DurableWrite {
+    seq: u64,
+    durable: Arc<DurableSeq>,
+}
+
+impl DurableWrite {
+    pub fn new(seq: u64, durable: Arc<DurableSeq>) -> DurableWrite {
+        DurableWrite { seq, durable }
+    }
+
+    /// The sequence number of the write
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn is_durable(&self) -> bool {
+        self.durable.state.lock().unwrap().seq >= self.seq
+    }
+
+    /// Blocks until the write is durable. Fails with `BTreeError::Closed` if the
+    /// tree is closed before that happens.
+    pub fn wait(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = self.durable.state.lock().unwrap();
+
+        while state.seq < self.seq {
+            if state.closed {
+                return Err(Box::new(BTreeError::Closed));
+            }
+
+            state = self.durable.advanced.wait(state).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Like `wait`, giving up after `timeout`. Returns whether the write is durable.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool, Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.durable.state.lock().unwrap();
+
+        while state.seq < self.seq {
+            let now = Instant::now();
+
+            if state.closed {
+                return Err(Box::new(BTreeError::Closed));
+            } else if now >= deadline {
+                return Ok(false);
+            }
+
+            state = self
+                .durable
+                .advanced
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        Ok(true)
+    }
+}
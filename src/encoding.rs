@@ -0,0 +1,196 @@
+use bincode::{DefaultOptions, Options};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use std::io::{Read, Write};
+
+/// The id of the encoding records are written in, stamped in the header of each
+/// file of them after its format version: bincode, with integers at their full
+/// width and little-endian whatever the platform
+pub const FIXINT_LITTLE_ENDIAN: u8 = 0;
+
+/// The id of bincode with its integers, lengths included, in as few bytes as
+/// their values need, little-endian
+pub const VARINT_LITTLE_ENDIAN: u8 = 1;
+
+/// How the records of new tree files and runs are encoded, see
+/// `Options::encoding`. Files already written keep the encoding stamped in their
+/// header, and are read in it, until a compaction writes their records again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Legacy, // integers at their full width, as every file was written before there was a choice
+    Varint, // integers in as few bytes as they need, so records take up less of their slots
+}
+
+impl Encoding {
+    /// The id stamped in the header of a file in this encoding
+    pub fn id(self) -> u8 {
+        match self {
+            Encoding::Legacy => FIXINT_LITTLE_ENDIAN,
+            Encoding::Varint => VARINT_LITTLE_ENDIAN,
+        }
+    }
+
+    /// The encoding stamped as `id`, if it's one the crate knows
+    pub fn from_id(id: u8) -> Option<Encoding> {
+        match id {
+            FIXINT_LITTLE_ENDIAN => Some(Encoding::Legacy),
+            VARINT_LITTLE_ENDIAN => Some(Encoding::Varint),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `$body` with `$options` bound to the bincode configuration of `$codec`,
+/// whose type differs with its encoding
+macro_rules! with_options {
+    ($codec:expr, |$options:ident| $body:expr) => {{
+        let base = DefaultOptions::new()
+            .with_little_endian()
+            .with_limit($codec.limit)
+            .allow_trailing_bytes();
+
+        match $codec.encoding {
+            Encoding::Legacy => {
+                let $options = base.with_fixint_encoding();
+                $body
+            }
+            Encoding::Varint => {
+                let $options = base.with_varint_encoding();
+                $body
+            }
+        }
+    }};
+}
+
+/// Encodes records, and the keys and values in them, the way they're laid out on
+/// disk. The bincode configuration is spelled out rather than left to bincode's
+/// defaults, which differ between its own APIs, so files move between platforms
+/// and survive upgrades with the same layout. In the legacy encoding it's the
+/// layout `bincode::serialize` has always had, so files from before it was pinned
+/// read the same.
+///
+/// No more than `limit` bytes are read or written, so a damaged length can't ask
+/// for more memory than the record it's in has room for.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    limit: u64,
+    encoding: Encoding,
+}
+
+impl Codec {
+    pub fn new(limit: usize) -> Codec {
+        Codec {
+            limit: limit as u64,
+            encoding: Encoding::Legacy,
+        }
+    }
+
+    /// For keys and values encoded on their own, from memory, to hash or test.
+    /// Those always use the legacy encoding, whatever the files are in, so hashes
+    /// and the bytes predicates are given don't change with it.
+    pub fn unbounded() -> Codec {
+        Codec {
+            limit: u64::MAX,
+            encoding: Encoding::Legacy,
+        }
+    }
+
+    /// The same codec, but in `encoding`
+    pub fn with_encoding(self, encoding: Encoding) -> Codec {
+        Codec { encoding, ..self }
+    }
+
+    pub fn encoding(self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn serialize<T: ?Sized + Serialize>(self, value: &T) -> bincode::Result<Vec<u8>> {
+        with_options!(self, |options| options.serialize(value))
+    }
+
+    pub fn serialize_into<W: Write, T: ?Sized + Serialize>(
+        self,
+        writer: W,
+        value: &T,
+    ) -> bincode::Result<()> {
+        with_options!(self, |options| options.serialize_into(writer, value))
+    }
+
+    pub fn serialized_size<T: ?Sized + Serialize>(self, value: &T) -> bincode::Result<u64> {
+        with_options!(self, |options| options.serialized_size(value))
+    }
+
+    /// Decodes a `T` from the start of `bytes`, ignoring any padding after it
+    pub fn deserialize<'a, T: Deserialize<'a>>(self, bytes: &'a [u8]) -> bincode::Result<T> {
+        with_options!(self, |options| options.deserialize(bytes))
+    }
+
+    pub fn deserialize_from<R: Read, T: DeserializeOwned>(self, reader: R) -> bincode::Result<T> {
+        with_options!(self, |options| options.deserialize_from(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding::{Codec, Encoding};
+    use wal_file::KeyValuePair;
+
+    #[test]
+    fn records_are_encoded_fixed_width_and_little_endian() {
+        let kv = KeyValuePair {
+            seq: 0x0102,
+            ..KeyValuePair::new(7u16, "ab".to_owned())
+        };
+        let bytes = Codec::unbounded().serialize(&kv).unwrap();
+
+        assert_eq!(bytes[..2], [7, 0]);
+        assert_eq!(bytes[2..12], [2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        assert_eq!(bytes[12..16], [0; 4]); // the kind, a Put
+        assert_eq!(bytes[16..24], [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bytes, bincode::serialize(&kv).unwrap());
+
+        // padding after the record is passed over
+        let mut padded = bytes.clone();
+        padded.resize(64, 0);
+        assert_eq!(
+            Codec::new(64)
+                .deserialize::<KeyValuePair<u16, String>>(&padded)
+                .unwrap(),
+            kv
+        );
+
+        // and a damaged length can't make it read past the record
+        padded[2..10].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Codec::new(64)
+            .deserialize::<KeyValuePair<u16, String>>(&padded)
+            .is_err());
+    }
+
+    #[test]
+    fn varint_records_are_smaller_and_read_back() {
+        let kv = KeyValuePair {
+            seq: 300,
+            ..KeyValuePair::new(7u16, "ab".to_owned())
+        };
+        let codec = Codec::new(64).with_encoding(Encoding::Varint);
+        let bytes = codec.serialize(&kv).unwrap();
+
+        // the seq after a one byte key, length, kind, and three bytes of value
+        assert_eq!(bytes[..6], [7, 2, b'a', b'b', 0, 251]);
+        assert_eq!(bytes[6..8], 300u16.to_le_bytes());
+        assert!(bytes.len() * 2 < Codec::unbounded().serialize(&kv).unwrap().len());
+        assert_eq!(
+            codec
+                .deserialize::<KeyValuePair<u16, String>>(&bytes)
+                .unwrap(),
+            kv
+        );
+
+        for encoding in [Encoding::Legacy, Encoding::Varint] {
+            assert_eq!(Encoding::from_id(encoding.id()), Some(encoding));
+        }
+        assert_eq!(Encoding::from_id(7), None);
+    }
+}
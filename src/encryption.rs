@@ -0,0 +1,660 @@
+use storage::{Storage, StorageFile};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io::Error as IOError;
+use std::io::{ErrorKind, Result as IOResult};
+use std::sync::{Arc, Mutex};
+
+/// A 256-bit ChaCha20 key
+pub type Key = [u8; 32];
+
+/// The most bytes of a file sealed in one segment
+const SEGMENT_SIZE: u64 = 4096;
+
+/// The random id each stored file starts with, which every segment's tag covers,
+/// so a segment can't be moved from one file to another under the same key
+const FILE_ID_SIZE: u64 = 16;
+
+/// The header each segment starts with: the id of the key it's encrypted with,
+/// its nonce and its length, all little-endian
+const HEADER_SIZE: u64 = 20;
+
+/// The Poly1305 tag each segment ends with
+const TAG_SIZE: u64 = 16;
+
+/// Where the keys files are encrypted with come from, say a key management
+/// service. Every key a file was written with has to stay available by its id
+/// until the file has been rewritten.
+pub trait KeyProvider: Send + Sync {
+    /// The key new segments are encrypted with, and its id
+    fn current_key(&self) -> IOResult<(u32, Key)>;
+
+    /// The key with `id`, for reading segments encrypted with it
+    fn key(&self, id: u32) -> IOResult<Key>;
+}
+
+/// Keys held in memory. The newest one added is the current one.
+pub struct KeyRing {
+    keys: Mutex<Vec<(u32, Key)>>,
+}
+
+impl KeyRing {
+    pub fn new(id: u32, key: Key) -> KeyRing {
+        KeyRing {
+            keys: Mutex::new(vec![(id, key)]),
+        }
+    }
+
+    /// Makes `key` the current key, keeping the old ones for reading
+    pub fn rotate(&self, id: u32, key: Key) {
+        self.keys.lock().unwrap().push((id, key));
+    }
+}
+
+impl KeyProvider for KeyRing {
+    fn current_key(&self) -> IOResult<(u32, Key)> {
+        Ok(*self.keys.lock().unwrap().last().unwrap())
+    }
+
+    fn key(&self, id: u32) -> IOResult<Key> {
+        let keys = self.keys.lock().unwrap();
+
+        match keys.iter().rev().find(|(key_id, _)| *key_id == id) {
+            Some((_, key)) => Ok(*key),
+            None => Err(IOError::new(
+                ErrorKind::NotFound,
+                format!("No key with id {}", id),
+            )),
+        }
+    }
+}
+
+/// Storage that encrypts and authenticates every file at rest with
+/// ChaCha20-Poly1305. A file is stored as a random id and then a run of sealed
+/// segments of up to 4KiB, each with a header saying which key and nonce it was
+/// encrypted with and how long it is. Its tag covers the header, the ciphertext,
+/// the file's id and where the segment sits in it, so a segment that's been
+/// altered, moved within the file or copied from another one fails to read.
+///
+/// Segments are never added to: each append is sealed in segments of its own, and
+/// cutting a file back part way through a segment seals the bytes kept of it
+/// again. Every segment sealed takes a fresh random 96-bit nonce, so a nonce is
+/// never knowingly used twice under a key; the chance of two colliding stays
+/// negligible until a key has sealed billions of segments. That costs 36 bytes of
+/// header and tag an append, so files written a record at a time, as tree files
+/// in the legacy encoding are, grow the most.
+///
+/// Files written before a key rotation stay readable. Compaction rewrites the
+/// tree file and runs, and flushes empty the WAL, so those drop the old key
+/// without downtime, but the schema, audit log and blob store are never
+/// rewritten and keep the keys they were written in for good. A key can only
+/// leave the provider once `key_ids` lists it for none of a tree's files.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keys: Arc<dyn KeyProvider>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, keys: Arc<dyn KeyProvider>) -> EncryptedStorage {
+        EncryptedStorage { inner, keys }
+    }
+
+    /// The ids of the keys the segments of the file at `path` are encrypted with
+    pub fn key_ids(&self, path: &str) -> IOResult<BTreeSet<u32>> {
+        let file = self.inner.open(path)?;
+
+        Ok(read_segments(&*file)?
+            .segments
+            .iter()
+            .map(|segment| segment.key_id)
+            .collect())
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>> {
+        let file = self.inner.open(path)?;
+        let Stored { id, segments, end } = read_segments(&*file)?;
+
+        Ok(Box::new(EncryptedFile {
+            file,
+            keys: self.keys.clone(),
+            id,
+            segments,
+            end,
+            last_read: Mutex::new(None),
+        }))
+    }
+
+    fn exists(&self, path: &str) -> IOResult<bool> {
+        self.inner.exists(path)
+    }
+
+    fn remove(&self, path: &str) -> IOResult<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> IOResult<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn seal(&self, path: &str, level: u32) -> IOResult<()> {
+        self.inner.seal(path, level)
+    }
+}
+
+/// Where a sealed segment is, and what it was encrypted with
+#[derive(Clone, Copy)]
+struct Segment {
+    start: u64, // where its bytes start in the file as read
+    at: u64,    // where it's stored, header first
+    len: u64,   // how many bytes it holds
+    key_id: u32,
+    nonce: [u8; 12],
+}
+
+/// What `read_segments` finds of a stored file
+struct Stored {
+    id: Option<[u8; FILE_ID_SIZE as usize]>, // None until the first append
+    segments: Vec<Segment>,
+    end: u64, // where the last whole segment ends
+}
+
+struct EncryptedFile {
+    file: Box<dyn StorageFile>,
+    keys: Arc<dyn KeyProvider>,
+    id: Option<[u8; FILE_ID_SIZE as usize]>,
+    segments: Vec<Segment>,
+    end: u64, // where the last whole segment ends when stored
+    last_read: Mutex<Option<(usize, Vec<u8>)>>, // the last segment read, decrypted
+}
+
+/// Finds the id and segments of a stored file, and where the last whole segment
+/// ends. Only appends that were never synced can be torn by a crash: the last
+/// segment cut off before its end, or left as zeroes where a write was lost ahead
+/// of one that landed, which no sealed header is, having a random nonce. Any other
+/// header that doesn't hold together is damage, and fails the open rather than
+/// being taken for the end of the file, which the next append would cut the rest
+/// off at.
+fn read_segments(file: &dyn StorageFile) -> IOResult<Stored> {
+    let stored = file.len()?;
+
+    if stored < FILE_ID_SIZE {
+        return Ok(Stored {
+            id: None,
+            segments: Vec::new(),
+            end: 0,
+        });
+    }
+
+    let mut id = [0; FILE_ID_SIZE as usize];
+    file.read_at(&mut id, 0)?;
+
+    let (mut segments, mut at, mut start) = (Vec::new(), FILE_ID_SIZE, 0);
+
+    while at + HEADER_SIZE <= stored {
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_at(&mut header, at)?;
+
+        let len = u32::from_le_bytes(header[16..].try_into().unwrap()) as u64;
+
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+
+        if len == 0 || len > SEGMENT_SIZE {
+            return Err(IOError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the encrypted segment at byte {} claims to hold {} bytes",
+                    at, len
+                ),
+            ));
+        }
+
+        if at + HEADER_SIZE + len + TAG_SIZE > stored {
+            break;
+        }
+
+        segments.push(Segment {
+            start,
+            at,
+            len,
+            key_id: u32::from_le_bytes(header[..4].try_into().unwrap()),
+            nonce: header[4..16].try_into().unwrap(),
+        });
+        at += HEADER_SIZE + len + TAG_SIZE;
+        start += len;
+    }
+
+    Ok(Stored {
+        id: Some(id),
+        segments,
+        end: at,
+    })
+}
+
+/// What a segment's tag covers on top of its ciphertext: the id of its file, its
+/// index and where its bytes start in the file, and its header
+fn associated_data(file_id: &[u8], index: usize, start: u64, header: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(file_id.len() + 16 + header.len());
+
+    aad.extend_from_slice(file_id);
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad.extend_from_slice(&start.to_le_bytes());
+    aad.extend_from_slice(header);
+    aad
+}
+
+impl EncryptedFile {
+    /// Where the bytes in segments end
+    fn sealed_len(&self) -> u64 {
+        self.segments.last().map_or(0, |last| last.start + last.len)
+    }
+
+    /// Reads, checks and decrypts the segment at `index`
+    fn open_segment(&self, index: usize) -> IOResult<Vec<u8>> {
+        let mut last_read = self.last_read.lock().unwrap();
+
+        if let Some((last, bytes)) = &*last_read {
+            if *last == index {
+                return Ok(bytes.clone());
+            }
+        }
+
+        let segment = self.segments[index];
+        let mut stored = vec![0; (HEADER_SIZE + segment.len + TAG_SIZE) as usize];
+        self.file.read_at(&mut stored, segment.at)?;
+
+        let key = self.keys.key(segment.key_id)?;
+        let (header, sealed) = stored.split_at(HEADER_SIZE as usize);
+        let file_id = self.id.unwrap_or_default();
+
+        let bytes = ChaCha20Poly1305::new(&key.into())
+            .decrypt(
+                Nonce::from_slice(&segment.nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &associated_data(&file_id, index, segment.start, header),
+                },
+            )
+            .map_err(|_| {
+                IOError::new(
+                    ErrorKind::InvalidData,
+                    "an encrypted segment failed its authentication",
+                )
+            })?;
+
+        *last_read = Some((index, bytes.clone()));
+        Ok(bytes)
+    }
+
+    /// Encrypts `bytes` as the segment at `index`, starting at `start` in the file,
+    /// under the current key and a fresh nonce
+    fn seal_segment(
+        &self,
+        index: usize,
+        start: u64,
+        bytes: &[u8],
+        stored: &mut Vec<u8>,
+    ) -> IOResult<Segment> {
+        let (key_id, key) = self.keys.current_key()?;
+        let nonce: [u8; 12] = rand::random();
+        let at = stored.len();
+
+        stored.extend_from_slice(&key_id.to_le_bytes());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+
+        let file_id = self.id.unwrap_or_default();
+        let sealed = ChaCha20Poly1305::new(&key.into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: bytes,
+                    aad: &associated_data(&file_id, index, start, &stored[at..]),
+                },
+            )
+            .map_err(|_| IOError::other("a segment couldn't be encrypted"))?;
+        stored.extend_from_slice(&sealed);
+
+        Ok(Segment {
+            start,
+            at: self.end + at as u64,
+            len: bytes.len() as u64,
+            key_id,
+            nonce,
+        })
+    }
+
+    /// Appends `bytes` to the file in as many segments as they need
+    fn seal(&mut self, bytes: &[u8]) -> IOResult<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        // only a last segment torn by a crash is dropped, its append never synced; a
+        // damaged one anywhere failed the open
+        if self.file.len()? != self.end {
+            self.file.truncate(self.end)?;
+        }
+
+        let mut stored = Vec::new();
+
+        if self.id.is_none() {
+            let id: [u8; FILE_ID_SIZE as usize] = rand::random();
+            stored.extend_from_slice(&id);
+            self.id = Some(id);
+        }
+
+        let mut sealed = Vec::new();
+        let mut start = self.sealed_len();
+
+        for bytes in bytes.chunks(SEGMENT_SIZE as usize) {
+            let index = self.segments.len() + sealed.len();
+            sealed.push(self.seal_segment(index, start, bytes, &mut stored)?);
+            start += bytes.len() as u64;
+        }
+
+        self.file.append(&stored)?;
+        self.segments.extend(sealed);
+        self.end += stored.len() as u64;
+        Ok(())
+    }
+}
+
+impl StorageFile for EncryptedFile {
+    fn len(&self) -> IOResult<u64> {
+        Ok(self.sealed_len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()> {
+        if offset + buf.len() as u64 > self.len()? {
+            return Err(IOError::new(
+                ErrorKind::UnexpectedEof,
+                "read past the end of the file",
+            ));
+        }
+
+        let mut done = 0;
+
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let index = self.segments.partition_point(|segment| segment.start <= at) - 1;
+            let segment = self.segments[index];
+            let bytes = self.open_segment(index)?;
+            let from = (at - segment.start) as usize;
+            let len = (bytes.len() - from).min(buf.len() - done);
+
+            buf[done..done + len].copy_from_slice(&bytes[from..from + len]);
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, buf: &[u8]) -> IOResult<()> {
+        self.seal(buf)
+    }
+
+    /// Cutting back part way through a segment seals the bytes kept of it again,
+    /// under a new nonce, rather than storing new bytes under the old one
+    fn truncate(&mut self, len: u64) -> IOResult<()> {
+        // only what a crash tore off goes, if there's nothing to cut
+        if len >= self.sealed_len() {
+            if self.file.len()? != self.end {
+                self.file.truncate(self.end)?;
+            }
+
+            return Ok(());
+        }
+
+        let index = self
+            .segments
+            .partition_point(|segment| segment.start + segment.len <= len);
+        let segment = self.segments[index];
+        let kept = match len - segment.start {
+            0 => Vec::new(),
+            kept => self.open_segment(index)?[..kept as usize].to_vec(),
+        };
+
+        self.file.truncate(segment.at)?;
+        self.segments.truncate(index);
+        self.end = segment.at;
+        *self.last_read.lock().unwrap() = None;
+
+        self.seal(&kept)
+    }
+
+    fn sync(&mut self) -> IOResult<()> {
+        self.file.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encryption::{read_segments, EncryptedStorage, KeyRing, FILE_ID_SIZE};
+    use {BTree, DynamicOptions, Options, SimDisk, Storage, SyncPolicy};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn files_are_encrypted_and_keys_rotate_with_compaction() {
+        let disk = Arc::new(SimDisk::new(0));
+        let keys = Arc::new(KeyRing::new(1, [7; 32]));
+        let storage = Arc::new(EncryptedStorage::new(disk.clone(), keys.clone()));
+        let options = Options {
+            storage: storage.clone(),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, String>::with_options("db", 4, 32, options.clone()).unwrap();
+
+        for i in 0..500 {
+            btree.insert(i, format!("secret {}", i)).unwrap();
+        }
+        btree.flush().unwrap();
+
+        let file = disk.open("db").unwrap();
+        let mut stored = vec![0; file.len().unwrap() as usize];
+        file.read_at(&mut stored, 0).unwrap();
+
+        let stored_file = read_segments(&*file).unwrap();
+        let (segments, end) = (stored_file.segments, stored_file.end);
+        assert_eq!(end, stored.len() as u64);
+        assert_eq!(
+            segments.iter().map(|segment| segment.len).sum::<u64>(),
+            storage.open("db").unwrap().len().unwrap()
+        );
+        assert!(!stored.windows(6).any(|bytes| bytes == b"secret"));
+        assert_eq!(
+            storage
+                .key_ids("db")
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [1]
+        );
+
+        // what's written from now on uses the new key, and compaction rewrites the rest
+        keys.rotate(2, [9; 32]);
+        btree.insert(500, "secret 500".to_owned()).unwrap();
+        assert_eq!(
+            storage
+                .key_ids("db.wal")
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [2]
+        );
+
+        btree.flush().unwrap();
+        assert_eq!(
+            storage
+                .key_ids("db")
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [2]
+        );
+        drop(btree);
+
+        let btree = BTree::<u32, String>::with_options("db", 4, 32, options).unwrap();
+        assert_eq!(btree.get(&42).unwrap(), Some(vec!["secret 42".to_owned()]));
+        assert_eq!(
+            btree.get(&500).unwrap(),
+            Some(vec!["secret 500".to_owned()])
+        );
+    }
+
+    #[test]
+    fn altered_segments_fail_to_read() {
+        let disk = Arc::new(SimDisk::new(0));
+        let storage = EncryptedStorage::new(disk.clone(), Arc::new(KeyRing::new(1, [7; 32])));
+        {
+            let mut file = storage.open("file").unwrap();
+            file.append(&[1; 6000]).unwrap();
+            file.sync().unwrap();
+        }
+
+        let mut stored = disk.contents("file").unwrap();
+        stored[5000] ^= 1;
+        let mut file = disk.open("file").unwrap();
+        file.truncate(0).unwrap();
+        file.append(&stored).unwrap();
+
+        let file = storage.open("file").unwrap();
+        let mut buf = [0; 10];
+        file.read_at(&mut buf, 100).unwrap();
+        assert_eq!(buf, [1; 10]);
+        assert!(file.read_at(&mut buf, 5000).is_err());
+    }
+
+    #[test]
+    fn segments_copied_from_another_file_fail_to_read() {
+        let disk = Arc::new(SimDisk::new(0));
+        let storage = EncryptedStorage::new(disk.clone(), Arc::new(KeyRing::new(1, [7; 32])));
+
+        for (path, byte) in [("a", 1), ("b", 2)] {
+            let mut file = storage.open(path).unwrap();
+            file.append(&[byte; 100]).unwrap();
+            file.sync().unwrap();
+        }
+
+        // b's segment, under the same key and at the same offset, in place of a's
+        let (a, b) = (disk.contents("a").unwrap(), disk.contents("b").unwrap());
+        let mut file = disk.open("a").unwrap();
+        file.truncate(0).unwrap();
+        file.append(&a[..FILE_ID_SIZE as usize]).unwrap();
+        file.append(&b[FILE_ID_SIZE as usize..]).unwrap();
+
+        let file = storage.open("a").unwrap();
+        assert!(file.read_at(&mut [0; 10], 0).is_err());
+    }
+
+    #[test]
+    fn a_damaged_segment_header_fails_the_open_rather_than_ending_the_file() {
+        let disk = Arc::new(SimDisk::new(0));
+        let storage = EncryptedStorage::new(disk.clone(), Arc::new(KeyRing::new(1, [7; 32])));
+        {
+            let mut file = storage.open("file").unwrap();
+            file.append(&[1; 6000]).unwrap();
+            file.append(&[2; 100]).unwrap();
+            file.sync().unwrap();
+        }
+
+        // the length of the first segment, the id and the rest of its header before it
+        let mut stored = disk.contents("file").unwrap();
+        stored[FILE_ID_SIZE as usize + 17] ^= 0x80;
+        let mut file = disk.open("file").unwrap();
+        file.truncate(0).unwrap();
+        file.append(&stored).unwrap();
+
+        let error = storage.open("file").err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // and nothing after it was cut off
+        assert_eq!(disk.contents("file").unwrap(), stored);
+    }
+
+    #[test]
+    fn cutting_a_segment_short_seals_what_is_left_under_a_new_nonce() {
+        let disk = Arc::new(SimDisk::new(0));
+        let storage = EncryptedStorage::new(disk.clone(), Arc::new(KeyRing::new(1, [7; 32])));
+        let nonces = || {
+            let stored = disk.open("wal").unwrap();
+            read_segments(&*stored)
+                .unwrap()
+                .segments
+                .iter()
+                .map(|segment| segment.nonce)
+                .collect::<Vec<_>>()
+        };
+
+        let mut file = storage.open("wal").unwrap();
+        file.append(&[1; 100]).unwrap();
+        file.sync().unwrap();
+        let before = nonces();
+
+        // as recovering a torn log does, then carrying on writing to it
+        file.truncate(60).unwrap();
+        file.append(&[2; 40]).unwrap();
+        file.sync().unwrap();
+        drop(file);
+
+        let after = nonces();
+        assert_eq!(after.len(), 2);
+        assert!(!after.contains(&before[0]));
+
+        let file = storage.open("wal").unwrap();
+        let mut buf = [0; 100];
+        file.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..60], &[1; 60][..]);
+        assert_eq!(&buf[60..], &[2; 40][..]);
+    }
+
+    #[test]
+    fn writes_torn_by_a_crash_are_dropped_on_recovery() {
+        for seed in 0..20 {
+            let disk = SimDisk::new(seed);
+            let storage = Arc::new(EncryptedStorage::new(
+                Arc::new(disk.clone()),
+                Arc::new(KeyRing::new(1, [7; 32])),
+            ));
+            let options = Options {
+                storage,
+                sync_policy: SyncPolicy::Always,
+                ..Options::default()
+            };
+
+            {
+                let mut btree =
+                    BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+                for i in 0..10 {
+                    btree.insert(i, i).unwrap();
+                }
+
+                // written but never synced
+                btree
+                    .set_options(DynamicOptions {
+                        sync_policy: SyncPolicy::Never,
+                        ..btree.dynamic_options()
+                    })
+                    .unwrap();
+                for i in 10..20 {
+                    btree.insert(i, i).unwrap();
+                }
+            }
+            disk.crash();
+
+            let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+            for i in 0..10 {
+                assert_eq!(btree.get(&i).unwrap(), Some(vec![i]));
+            }
+            btree.insert(20, 20).unwrap();
+            assert_eq!(btree.get(&20).unwrap(), Some(vec![20]));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt;
+
+/// The errors the BTree itself raises, as opposed to I/O or encoding errors. They
+/// are returned boxed like every other error, use `downcast_ref::<BTreeError>()`
+/// to tell them apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BTreeError {
+    /// The memtable is over its soft limit; the write was not attempted
+    Busy { pending_bytes: usize },
+    /// The memtable is over its hard limit; writes are refused until it flushes
+    Stalled { pending_bytes: usize },
+    /// The insert would take the tree's files past `Options::disk_quota`
+    QuotaExceeded { disk_bytes: u64, max_bytes: u64 },
+    /// The insert would give a key more values than `Options::value_cap` allows
+    ValueCapReached { max_values: usize },
+    /// A transaction waited too long for a key's lock, as it would in a deadlock
+    LockTimeout,
+    /// The tree was closed before a write waited on was made durable
+    Closed,
+    /// The tree was opened with a different `Schema` from the one it was created with
+    SchemaMismatch {
+        created_as: String,
+        opened_as: String,
+    },
+    /// The tree was created folding the case of its keys, and opened without, or
+    /// the other way around
+    FoldCaseMismatch { created_folding: bool },
+    /// A compaction was stopped through `CompactionMonitor::cancel`, leaving the
+    /// files it was merging as they were
+    Cancelled,
+    /// A file was written in a format version newer than this version of the crate
+    /// can read
+    UnsupportedVersion { path: String, version: u8 },
+    /// A file's records were written in an encoding this version of the crate
+    /// doesn't know
+    UnsupportedEncoding { path: String, encoding: u8 },
+    /// The tree at `path` was written before its files had headers and its records
+    /// a kind and sequence number, and can't be opened until `BTree::migrate` brings
+    /// it up to date
+    LegacyFormat { path: String },
+}
+
+impl fmt::Display for BTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BTreeError::Busy { pending_bytes } => {
+                write!(f, "Busy: {} bytes are waiting to be flushed", pending_bytes)
+            }
+            BTreeError::Stalled { pending_bytes } => {
+                write!(
+                    f,
+                    "Writes stalled: {} bytes are waiting to be flushed",
+                    pending_bytes
+                )
+            }
+            BTreeError::QuotaExceeded {
+                disk_bytes,
+                max_bytes,
+            } => {
+                write!(
+                    f,
+                    "Quota exceeded: the tree takes up {} of its {} bytes",
+                    disk_bytes, max_bytes
+                )
+            }
+            BTreeError::ValueCapReached { max_values } => {
+                write!(
+                    f,
+                    "Value cap reached: a key can hold at most {} values",
+                    max_values
+                )
+            }
+            BTreeError::LockTimeout => write!(f, "Timed out waiting for a key's lock"),
+            BTreeError::Closed => write!(f, "The tree was closed before the write was synced"),
+            BTreeError::SchemaMismatch {
+                created_as,
+                opened_as,
+            } => {
+                write!(
+                    f,
+                    "Schema mismatch: the tree was created as {} but opened as {}",
+                    created_as, opened_as
+                )
+            }
+            BTreeError::FoldCaseMismatch {
+                created_folding: true,
+            } => {
+                write!(
+                    f,
+                    "The tree's keys are case-insensitive, open it with BTree::case_insensitive"
+                )
+            }
+            BTreeError::FoldCaseMismatch {
+                created_folding: false,
+            } => {
+                write!(
+                    f,
+                    "The tree's keys are case-sensitive, open it with BTree::with_options"
+                )
+            }
+            BTreeError::Cancelled => write!(f, "The compaction was cancelled"),
+            BTreeError::UnsupportedVersion { path, version } => {
+                write!(
+                    f,
+                    "{} is in format version {}, newer than this version can read",
+                    path, version
+                )
+            }
+            BTreeError::UnsupportedEncoding { path, encoding } => {
+                write!(
+                    f,
+                    "{} has its records in encoding {}, which this version can't read",
+                    path, encoding
+                )
+            }
+            BTreeError::LegacyFormat { path } => {
+                write!(
+                    f,
+                    "{} is in a format from before files had headers, BTree::migrate brings it up to date",
+                    path
+                )
+            }
+        }
+    }
+}
+
+impl Error for BTreeError {}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use std::thread;
+
+/// One sub-compaction, borrowing from the compaction it's part of
+pub type Job<'a> = Box<dyn FnOnce() + Send + 'a>;
+
+/// Runs the sub-compactions of a compaction side by side, up to
+/// `CompactionOptions::max_jobs` at a time. The jobs borrow from the compaction
+/// that hands them over, so `run_all` must only return once every one of them
+/// has finished; a scoped pool, such as `rayon::scope`, fits. Flushes of the
+/// memtable aren't split up, and run on the thread making the write.
+pub trait CompactionExecutor: Send + Sync {
+    fn run_all(&self, jobs: Vec<Job<'_>>);
+}
+
+/// Runs on each thread a `ThreadExecutor` starts, before its job
+pub type ThreadStart = Arc<dyn Fn() + Send + Sync>;
+
+/// The default executor: a thread for each job, started when it's needed
+#[derive(Clone)]
+pub struct ThreadExecutor {
+    name: String,
+    on_start: Option<ThreadStart>,
+}
+
+impl Default for ThreadExecutor {
+    fn default() -> ThreadExecutor {
+        ThreadExecutor::new("btree-compaction")
+    }
+}
+
+impl ThreadExecutor {
+    /// Names the threads `name` followed by the job's number
+    pub fn new(name: &str) -> ThreadExecutor {
+        ThreadExecutor {
+            name: name.to_owned(),
+            on_start: None,
+        }
+    }
+
+    /// Runs `on_start` at the start of every thread, say to lower its priority
+    /// or pin it to some cores
+    pub fn on_thread_start<F>(mut self, on_start: F) -> ThreadExecutor
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_start = Some(Arc::new(on_start));
+        self
+    }
+}
+
+impl CompactionExecutor for ThreadExecutor {
+    fn run_all(&self, jobs: Vec<Job<'_>>) {
+        thread::scope(|scope| {
+            let mut handles = Vec::new();
+
+            for (i, job) in jobs.into_iter().enumerate() {
+                let on_start = self.on_start.clone();
+                let job = move || {
+                    if let Some(on_start) = on_start {
+                        on_start();
+                    }
+
+                    job()
+                };
+
+                // a job that panics, or doesn't get a thread, leaves its output
+                // unset for the compaction to fail on
+                if let Ok(handle) = thread::Builder::new()
+                    .name(format!("{}-{}", self.name, i))
+                    .spawn_scoped(scope, job)
+                {
+                    handles.push(handle);
+                }
+            }
+
+            for handle in handles {
+                let _ = handle.join();
+            }
+        });
+    }
+}
@@ -0,0 +1,149 @@
+use bloom::fnv1a;
+use encoding::Codec;
+use storage::{Storage, StorageFile};
+use KeyType;
+
+use std::convert::TryInto;
+use std::error::Error;
+
+/// The path of the sidecar holding the hash index for the file at `path`. It's a
+/// little-endian u64 bucket count followed by the buckets, each the hash of a key
+/// and one more than the index of its first record, or zeros when empty.
+pub fn hash_index_path(path: &str) -> String {
+    path.to_owned() + ".hash"
+}
+
+const BUCKET_SIZE: u64 = 16;
+
+/// Builds the hash index of a file as its records are written
+pub struct HashIndexBuilder<K: KeyType> {
+    file: Box<dyn StorageFile>,
+    last_key: Option<K>,
+    next_index: u64,          // the index of the next record written
+    entries: Vec<(u64, u64)>, // the hash and first record of each key
+}
+
+/// A hash index from each key of a file to its first record, so a lookup is a
+/// bucket read or two instead of a binary search
+pub struct HashIndex {
+    file: Box<dyn StorageFile>,
+    buckets: u64,
+}
+
+fn hash<K: KeyType>(key: &K) -> Result<u64, Box<dyn Error>> {
+    Ok(fnv1a(&Codec::unbounded().serialize(key)?))
+}
+
+impl<K: KeyType> HashIndexBuilder<K> {
+    pub fn new(storage: &dyn Storage, path: &str) -> Result<HashIndexBuilder<K>, Box<dyn Error>> {
+        let path = hash_index_path(path);
+
+        storage.remove_if_exists(&path)?;
+
+        Ok(HashIndexBuilder {
+            file: storage.open(&path)?,
+            last_key: None,
+            next_index: 0,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Adds the key of the next record written to the file
+    pub fn add(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        if self.last_key.as_ref() != Some(key) {
+            self.entries.push((hash(key)?, self.next_index));
+            self.last_key = Some(key.clone());
+        }
+
+        self.next_index += 1;
+
+        Ok(())
+    }
+
+    /// Writes out and syncs the sidecar, returning the index ready for lookups
+    pub fn finish(mut self) -> Result<HashIndex, Box<dyn Error>> {
+        // at most half full, so probes stay short
+        let buckets = (self.entries.len() as u64 * 2).next_power_of_two();
+        let mut table = vec![0; (buckets * BUCKET_SIZE) as usize];
+
+        for (hash, index) in self.entries {
+            let mut bucket = hash & (buckets - 1);
+
+            while table[(bucket * BUCKET_SIZE) as usize + 8..][..8] != [0; 8] {
+                bucket = (bucket + 1) & (buckets - 1);
+            }
+
+            let offset = (bucket * BUCKET_SIZE) as usize;
+            table[offset..offset + 8].copy_from_slice(&hash.to_le_bytes());
+            table[offset + 8..offset + 16].copy_from_slice(&(index + 1).to_le_bytes());
+        }
+
+        self.file.append(&buckets.to_le_bytes())?;
+        self.file.append(&table)?;
+        self.file.sync()?;
+
+        Ok(HashIndex {
+            file: self.file,
+            buckets,
+        })
+    }
+}
+
+impl HashIndex {
+    /// Loads the hash index for the file at `path`, ignoring an incomplete one
+    pub fn open(storage: &dyn Storage, path: &str) -> Result<Option<HashIndex>, Box<dyn Error>> {
+        let path = hash_index_path(path);
+
+        if !storage.exists(&path)? {
+            return Ok(None);
+        }
+
+        let file = storage.open(&path)?;
+        let len = file.len()?;
+
+        if len < 8 {
+            return Ok(None);
+        }
+
+        let mut buckets = [0; 8];
+        file.read_at(&mut buckets, 0)?;
+        let buckets = u64::from_le_bytes(buckets);
+
+        if !buckets.is_power_of_two() || len != 8 + buckets * BUCKET_SIZE {
+            return Ok(None);
+        }
+
+        Ok(Some(HashIndex { file, buckets }))
+    }
+
+    /// Returns the index of the first record for `key`, using `key_at` to tell it
+    /// apart from other keys with the same hash
+    pub fn find<K: KeyType>(
+        &self,
+        key: &K,
+        key_at: impl Fn(u64) -> Result<K, Box<dyn Error>>,
+    ) -> Result<Option<u64>, Box<dyn Error>> {
+        let hash = hash(key)?;
+        let mut bucket = hash & (self.buckets - 1);
+
+        for _ in 0..self.buckets {
+            let mut buff = [0; BUCKET_SIZE as usize];
+            self.file.read_at(&mut buff, 8 + bucket * BUCKET_SIZE)?;
+
+            let bucket_hash = u64::from_le_bytes(buff[..8].try_into()?);
+            let index = u64::from_le_bytes(buff[8..].try_into()?);
+
+            if index == 0 {
+                return Ok(None);
+            }
+
+            if bucket_hash == hash && key_at(index - 1)? == *key {
+                return Ok(Some(index - 1));
+            }
+
+            bucket = (bucket + 1) & (self.buckets - 1);
+        }
+
+        Ok(None)
+    }
+}
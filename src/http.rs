@@ -0,0 +1,379 @@
+use BTree;
+
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// request bodies larger than this are refused rather than allocated
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+// how many keys a range returns when the request doesn't say
+const DEFAULT_RANGE_LIMIT: usize = 1000;
+
+/// A response to send back: the status line's code and reason, and the body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn json(body: String) -> Response {
+        Response {
+            status: 200,
+            reason: "OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: &str) -> Response {
+        Response {
+            status,
+            reason,
+            content_type: "application/json",
+            body: format!("{{\"error\":{}}}", json_string(message.as_bytes())),
+        }
+    }
+}
+
+/// Answers HTTP requests made to `listener`, a thread per connection, until
+/// accepting fails. Keys and values are byte strings, sent as the UTF-8 in JSON
+/// bodies.
+///
+/// - `GET /keys/{key}` returns the values of a key
+/// - `PUT /keys/{key}` adds the request body to them, `DELETE /keys/{key}` takes
+///   it away
+/// - `GET /range?start=&end=&limit=` returns the keys from `start` up to, but not
+///   including, `end`, with their values
+/// - `GET /metrics`, with the `metrics` feature, is a Prometheus scrape endpoint
+pub fn serve(
+    tree: Arc<Mutex<BTree<Vec<u8>, Vec<u8>>>>,
+    listener: TcpListener,
+) -> Result<(), Box<dyn Error>> {
+    for stream in listener.incoming() {
+        let (stream, tree) = (stream?, tree.clone());
+
+        thread::spawn(move || {
+            let _ = handle_connection(&tree, stream);
+        });
+    }
+
+    Ok(())
+}
+
+/// Answers the one request on a connection, then closes it
+fn handle_connection(
+    tree: &Mutex<BTree<Vec<u8>, Vec<u8>>>,
+    mut stream: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let response = match read_request(&mut reader) {
+        Ok((method, target, body)) => match tree.lock() {
+            Ok(mut tree) => handle(&mut tree, &method, &target, body)
+                .unwrap_or_else(|e| Response::error(500, "Internal Server Error", &e.to_string())),
+            Err(_) => Response::error(
+                500,
+                "Internal Server Error",
+                "a request panicked while holding the tree",
+            ),
+        },
+        Err(e) => Response::error(400, "Bad Request", &e.to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+
+    Ok(stream.flush()?)
+}
+
+/// Reads the method, target and body of a request
+fn read_request<R: BufRead>(reader: &mut R) -> Result<(String, String, Vec<u8>), Box<dyn Error>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => {
+            return Err(From::from(IOError::new(
+                ErrorKind::InvalidData,
+                "malformed request line",
+            )))
+        }
+    };
+
+    let mut content_length = 0;
+
+    loop {
+        let mut header = String::new();
+
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| "invalid Content-Length")?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(From::from(IOError::new(
+            ErrorKind::InvalidData,
+            "request body too large",
+        )));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok((method, target, body))
+}
+
+/// Answers one request against the tree
+pub fn handle(
+    tree: &mut BTree<Vec<u8>, Vec<u8>>,
+    method: &str,
+    target: &str,
+    body: Vec<u8>,
+) -> Result<Response, Box<dyn Error>> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = percent_decode(key);
+
+        return Ok(match method {
+            "GET" => match tree.get(&key)? {
+                Some(values) => Response::json(entry_json(&key, &values)),
+                None => Response::error(404, "Not Found", "no such key"),
+            },
+            "PUT" => {
+                tree.insert(key, body)?;
+                Response::json("{}".to_owned())
+            }
+            "DELETE" => {
+                tree.delete(key, body)?;
+                Response::json("{}".to_owned())
+            }
+            _ => Response::error(405, "Method Not Allowed", "keys take GET, PUT and DELETE"),
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        if path == "/metrics" && method == "GET" {
+            return Ok(Response {
+                content_type: "text/plain; version=0.0.4",
+                body: ::metrics::encode(&tree.stats()),
+                ..Response::json(String::new())
+            });
+        }
+    }
+
+    if path != "/range" {
+        return Ok(Response::error(404, "Not Found", "no such endpoint"));
+    }
+
+    if method != "GET" {
+        return Ok(Response::error(
+            405,
+            "Method Not Allowed",
+            "ranges take GET",
+        ));
+    }
+
+    let (mut start, mut end): (Bound<Vec<u8>>, Bound<Vec<u8>>) = (Unbounded, Unbounded);
+    let mut limit = DEFAULT_RANGE_LIMIT;
+
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+
+        match name {
+            "start" => start = Included(percent_decode(value)),
+            "end" => end = Excluded(percent_decode(value)),
+            "limit" => match value.parse() {
+                Ok(n) => limit = n,
+                Err(_) => return Ok(Response::error(400, "Bad Request", "invalid limit")),
+            },
+            _ => return Ok(Response::error(400, "Bad Request", "unknown parameter")),
+        }
+    }
+
+    let entries: Vec<String> = tree
+        .range((start, end))?
+        .take(limit)
+        .map(|(key, values)| entry_json(&key, &values))
+        .collect();
+
+    Ok(Response::json(format!("[{}]", entries.join(","))))
+}
+
+fn entry_json(key: &[u8], values: &[Vec<u8>]) -> String {
+    let values: Vec<String> = values.iter().map(|value| json_string(value)).collect();
+
+    format!(
+        "{{\"key\":{},\"values\":[{}]}}",
+        json_string(key),
+        values.join(",")
+    )
+}
+
+/// The bytes as a JSON string, replacing anything that isn't UTF-8
+fn json_string(bytes: &[u8]) -> String {
+    let mut json = String::with_capacity(bytes.len() + 2);
+    json.push('"');
+
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+/// Decodes the `%XX` escapes in a URL component, and `+` as a space
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{handle, serve};
+    use {BTree, Options, SimDisk};
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn keys_and_ranges_are_served_as_json() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut tree = BTree::<Vec<u8>, Vec<u8>>::with_options("db", 24, 24, options).unwrap();
+
+        for key in ["a", "b", "c d"] {
+            let target = format!("/keys/{}", key.replace(' ', "%20"));
+            handle(&mut tree, "PUT", &target, b"one".to_vec()).unwrap();
+            handle(&mut tree, "PUT", &target, b"two \"quoted\"".to_vec()).unwrap();
+        }
+        handle(&mut tree, "DELETE", "/keys/b", b"one".to_vec()).unwrap();
+
+        assert_eq!(
+            handle(&mut tree, "GET", "/keys/b", Vec::new())
+                .unwrap()
+                .body,
+            r#"{"key":"b","values":["two \"quoted\""]}"#
+        );
+        assert_eq!(
+            handle(&mut tree, "GET", "/keys/z", Vec::new())
+                .unwrap()
+                .status,
+            404
+        );
+
+        let range = handle(&mut tree, "GET", "/range?start=b&end=d&limit=1", Vec::new()).unwrap();
+        assert_eq!(range.body, r#"[{"key":"b","values":["two \"quoted\""]}]"#);
+
+        let range = handle(&mut tree, "GET", "/range?start=c+d", Vec::new()).unwrap();
+        assert_eq!(
+            range.body,
+            r#"[{"key":"c d","values":["one","two \"quoted\""]}]"#
+        );
+
+        assert_eq!(
+            handle(&mut tree, "POST", "/range", Vec::new())
+                .unwrap()
+                .status,
+            405
+        );
+        assert_eq!(
+            handle(&mut tree, "GET", "/stats", Vec::new())
+                .unwrap()
+                .status,
+            404
+        );
+    }
+
+    #[test]
+    fn requests_are_answered_over_tcp() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = Arc::new(Mutex::new(
+            BTree::with_options("db", 24, 24, options).unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || serve(tree, listener).map_err(|e| e.to_string()));
+
+        let request = |text: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(text.as_bytes()).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let put =
+            request("PUT /keys/k HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nvalue");
+        assert!(put.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let get = request("GET /keys/k HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(get.ends_with("\r\n\r\n{\"key\":\"k\",\"values\":[\"value\"]}"));
+    }
+}
@@ -0,0 +1,199 @@
+use storage::{Storage, StorageFile};
+
+use std::cell::Cell;
+use std::io::Result as IOResult;
+use std::sync::Arc;
+
+/// What was read from storage, through the block cache's misses and everything
+/// else that goes to the files
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoStats {
+    pub files: u64, // files on disk a get or scan had to read from, or a compaction merged
+    pub reads: u64, // calls to read from a file
+    pub bytes_read: u64, // and the bytes they returned
+}
+
+impl IoStats {
+    /// What's been read since `start` was taken
+    pub fn since(self, start: IoStats) -> IoStats {
+        IoStats {
+            files: self.files - start.files,
+            reads: self.reads - start.reads,
+            bytes_read: self.bytes_read - start.bytes_read,
+        }
+    }
+
+    pub fn add(&mut self, other: IoStats) {
+        self.files += other.files;
+        self.reads += other.reads;
+        self.bytes_read += other.bytes_read;
+    }
+}
+
+thread_local! {
+    // counted per thread, so reads by lookups running side by side aren't mixed up
+    static READ: Cell<IoStats> = Cell::new(IoStats::default());
+}
+
+/// What this thread has read so far, to take the difference of around an operation
+pub fn current() -> IoStats {
+    READ.with(|read| read.get())
+}
+
+/// Counts reads done for this thread by another, such as a sub-compaction
+pub fn add(io: IoStats) {
+    READ.with(|read| {
+        let mut total = read.get();
+        total.add(io);
+        read.set(total);
+    });
+}
+
+/// Counts one more file as touched by the operation running on this thread
+pub fn count_file() {
+    add(IoStats {
+        files: 1,
+        ..IoStats::default()
+    });
+}
+
+/// The records of a file being scanned, counting the file as touched the first
+/// time getting one goes to disk
+pub struct Touching<I> {
+    records: I,
+    touched: bool,
+}
+
+impl<I> Touching<I> {
+    pub fn new(records: I, touched: bool) -> Touching<I> {
+        Touching { records, touched }
+    }
+}
+
+impl<I: Iterator> Iterator for Touching<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        if self.touched {
+            return self.records.next();
+        }
+
+        let reads = current().reads;
+        let record = self.records.next();
+
+        if current().reads > reads {
+            self.touched = true;
+            count_file();
+        }
+
+        record
+    }
+}
+
+/// An iterator from `BTree::range_with`, adding up what advancing it reads when
+/// the read options asked for I/O stats
+pub struct IoCounted<'a, K, V> {
+    items: Box<dyn Iterator<Item = (K, Vec<V>)> + 'a>,
+    io: Option<IoStats>,
+}
+
+impl<'a, K, V> IoCounted<'a, K, V> {
+    /// With what was read before the first item, setting up the iterator
+    pub fn new(
+        items: Box<dyn Iterator<Item = (K, Vec<V>)> + 'a>,
+        io: Option<IoStats>,
+    ) -> IoCounted<'a, K, V> {
+        IoCounted { items, io }
+    }
+
+    /// What's been read so far, if the read options asked for it
+    pub fn io_stats(&self) -> Option<IoStats> {
+        self.io
+    }
+}
+
+impl<'a, K, V> Iterator for IoCounted<'a, K, V> {
+    type Item = (K, Vec<V>);
+
+    fn next(&mut self) -> Option<(K, Vec<V>)> {
+        let io = match self.io.as_mut() {
+            Some(io) => io,
+            None => return self.items.next(),
+        };
+
+        let start = current();
+        let item = self.items.next();
+        io.add(current().since(start));
+
+        item
+    }
+}
+
+/// Storage that counts the reads made through it against the reading thread
+pub struct CountingStorage {
+    inner: Arc<dyn Storage>,
+}
+
+impl CountingStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> CountingStorage {
+        CountingStorage { inner }
+    }
+}
+
+impl Storage for CountingStorage {
+    fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>> {
+        Ok(Box::new(CountingFile {
+            file: self.inner.open(path)?,
+        }))
+    }
+
+    fn exists(&self, path: &str) -> IOResult<bool> {
+        self.inner.exists(path)
+    }
+
+    fn remove(&self, path: &str) -> IOResult<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> IOResult<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn seal(&self, path: &str, level: u32) -> IOResult<()> {
+        self.inner.seal(path, level)
+    }
+}
+
+struct CountingFile {
+    file: Box<dyn StorageFile>,
+}
+
+impl StorageFile for CountingFile {
+    fn len(&self) -> IOResult<u64> {
+        self.file.len()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()> {
+        self.file.read_at(buf, offset)?;
+
+        add(IoStats {
+            reads: 1,
+            bytes_read: buf.len() as u64,
+            ..IoStats::default()
+        });
+
+        Ok(())
+    }
+
+    fn append(&mut self, buf: &[u8]) -> IOResult<()> {
+        self.file.append(buf)
+    }
+
+    fn truncate(&mut self, len: u64) -> IOResult<()> {
+        self.file.truncate(len)
+    }
+
+    fn sync(&mut self) -> IOResult<()> {
+        self.file.sync()
+    }
+}
@@ -0,0 +1,37 @@
+use encoding::Codec;
+
+use std::error::Error;
+use std::io::Read;
+use std::marker::PhantomData;
+
+/// A value read by `BTree::get_lazy`, held as the bytes it's stored as until
+/// `value` is asked for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LazyValue<V> {
+    bytes: Vec<u8>, // what's stored after the value's length
+    _marker: PhantomData<V>,
+}
+
+impl<V: for<'de> serde::Deserialize<'de>> LazyValue<V> {
+    pub fn new(bytes: Vec<u8>) -> LazyValue<V> {
+        LazyValue {
+            bytes,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The value's bytes, as a string's UTF-8 or a byte vector's contents
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Decodes the value
+    pub fn value(&self) -> Result<V, Box<dyn Error>> {
+        let len = (self.bytes.len() as u64).to_le_bytes();
+
+        Ok(
+            Codec::new(len.len() + self.bytes.len())
+                .deserialize_from(len.chain(&self.bytes[..]))?,
+        )
+    }
+}
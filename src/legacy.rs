@@ -0,0 +1,237 @@
+use disk_btree::{salvage_record, FILE_HEADER};
+use encoding::Codec;
+use schema::schema_path;
+use storage::Storage;
+use wal_file::{KeyValuePair, RecordFile, RECORD_OVERHEAD};
+use {KeyType, ValueType};
+
+use std::error::Error;
+
+/// Where a file is moved while `BTree::migrate` rewrites it, and where it stays
+/// if the migration is cut off, until it's run again
+fn set_aside_path(path: &str) -> String {
+    path.to_owned() + ".legacy"
+}
+
+/// The tree file and WAL of a tree
+fn tree_files(tree_file_path: &str) -> [String; 2] {
+    [
+        tree_file_path.to_owned(),
+        tree_file_path.to_owned() + ".wal",
+    ]
+}
+
+/// The keys and values of a file in the legacy layout, in the order written
+type LegacyRecords<K, V> = Vec<(K, V)>;
+
+/// Reads the whole of the file at `path`, or None if there isn't one
+fn read_file(storage: &dyn Storage, path: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    if !storage.exists(path)? {
+        return Ok(None);
+    }
+
+    let file = storage.open(path)?;
+    let mut bytes = vec![0; file.len()? as usize];
+    file.read_at(&mut bytes, 0)?;
+
+    Ok(Some(bytes))
+}
+
+/// Decodes a file as the crate wrote both its tree file and WAL before files had
+/// headers, records carried a kind, sequence number and timestamps, or the WAL
+/// was framed: each record a key and a value in the legacy encoding, padded with
+/// zeroes to `key_size + value_size`. None if the file is empty or isn't laid out
+/// that way.
+fn legacy_records<K: KeyType, V: ValueType>(
+    bytes: &[u8],
+    key_size: usize,
+    value_size: usize,
+) -> Option<LegacyRecords<K, V>> {
+    let record_size = key_size + value_size;
+
+    if bytes.is_empty() || !bytes.len().is_multiple_of(record_size) {
+        return None;
+    }
+
+    // a struct of the key and value then, which bincode lays out as it does the tuple
+    let codec = Codec::new(record_size);
+
+    bytes
+        .chunks(record_size)
+        .map(|record| {
+            let (key, value): (K, V) = codec.deserialize(record).ok()?;
+            let used = codec.serialized_size(&(&key, &value)).ok()? as usize;
+
+            record[used..]
+                .iter()
+                .all(|byte| *byte == 0)
+                .then_some((key, value))
+        })
+        .collect()
+}
+
+/// Whether a tree file without a header is one of format version 0, its records
+/// already carrying a kind and sequence number. Its records are only taken as
+/// such if every one of them decodes, padding and all, since a legacy file of
+/// fixed-size keys and values decodes in any layout on its own.
+fn is_version_zero<K: KeyType, V: ValueType>(
+    bytes: &[u8],
+    key_size: usize,
+    value_size: usize,
+) -> bool {
+    let record_size = key_size + value_size + RECORD_OVERHEAD;
+    let codec = Codec::new(record_size);
+
+    bytes.len().is_multiple_of(record_size)
+        && bytes
+            .chunks(record_size)
+            .all(|record| salvage_record::<K, V>(codec, record).is_some())
+}
+
+/// The records of the file at `path` if it's in the legacy layout. `framed` says
+/// whether it's a WAL, which is legacy only when not even its first write can be
+/// read as one.
+fn read_legacy_file<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    path: &str,
+    key_size: usize,
+    value_size: usize,
+    framed: bool,
+) -> Result<Option<LegacyRecords<K, V>>, Box<dyn Error>> {
+    let bytes = match read_file(storage, path)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+
+    let current = if framed {
+        RecordFile::<K, V>::new_log(storage, path, key_size, value_size)?
+            .replay_from(0)
+            .next_offset
+            > 0
+    } else {
+        bytes.starts_with(FILE_HEADER) || is_version_zero::<K, V>(&bytes, key_size, value_size)
+    };
+
+    if current {
+        return Ok(None);
+    }
+
+    Ok(legacy_records(&bytes, key_size, value_size))
+}
+
+/// Whether the tree at `tree_file_path` has files in the legacy layout, which the
+/// tree can't be opened with until `BTree::migrate` brings them up to date: its
+/// tree file or WAL, or those a migration cut off part way set aside. Only a tree
+/// without a schema file is looked into, since every tree opened to write to since
+/// records the schema it was created with.
+pub fn is_legacy<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    key_size: usize,
+    value_size: usize,
+) -> Result<bool, Box<dyn Error>> {
+    for path in &tree_files(tree_file_path) {
+        if storage.exists(&set_aside_path(path))? {
+            return Ok(true);
+        }
+    }
+
+    if storage.exists(&schema_path(tree_file_path))? {
+        return Ok(false);
+    }
+
+    for (path, framed) in tree_files(tree_file_path).iter().zip([false, true]) {
+        if read_legacy_file::<K, V>(storage, path, key_size, value_size, framed)?.is_some() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// The records of a tree in the legacy layout, see `read_legacy`
+pub struct LegacyTree<K, V> {
+    pub files: u64, // how many of its files were in the legacy layout
+    pub records: Vec<KeyValuePair<K, V>>,
+}
+
+/// Reads the records of the tree at `tree_file_path` if it's in the legacy
+/// layout, from the files a migration cut off part way set aside if there are
+/// any. They're given sequence numbers in the order they were written in: the
+/// tree file's first, then the WAL's, all written at `now`.
+pub fn read_legacy<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    key_size: usize,
+    value_size: usize,
+    now: u64,
+) -> Result<Option<LegacyTree<K, V>>, Box<dyn Error>> {
+    if !is_legacy::<K, V>(storage, tree_file_path, key_size, value_size)? {
+        return Ok(None);
+    }
+
+    let mut tree = LegacyTree {
+        files: 0,
+        records: Vec::new(),
+    };
+
+    for (path, framed) in tree_files(tree_file_path).iter().zip([false, true]) {
+        let set_aside = set_aside_path(path);
+
+        let records = if storage.exists(&set_aside)? {
+            legacy_records(
+                &read_file(storage, &set_aside)?.unwrap_or_default(),
+                key_size,
+                value_size,
+            )
+        } else {
+            read_legacy_file::<K, V>(storage, path, key_size, value_size, framed)?
+        };
+
+        if let Some(records) = records {
+            tree.files += 1;
+
+            for (key, value) in records {
+                tree.records.push(KeyValuePair {
+                    seq: tree.records.len() as u64 + 1,
+                    written_at: now,
+                    ..KeyValuePair::new(key, value)
+                });
+            }
+        }
+    }
+
+    Ok(Some(tree))
+}
+
+/// Moves those of the tree file and WAL of a tree that are in the legacy layout
+/// out of the way, so a tree in the current format can be written in their place.
+/// They're kept until `remove_set_aside` is called, once their records are durable
+/// in the new tree.
+pub fn set_aside<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    key_size: usize,
+    value_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    for (path, framed) in tree_files(tree_file_path).iter().zip([false, true]) {
+        let set_aside = set_aside_path(path);
+
+        if !storage.exists(&set_aside)?
+            && read_legacy_file::<K, V>(storage, path, key_size, value_size, framed)?.is_some()
+        {
+            storage.rename(path, &set_aside)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the files `set_aside` moved out of the way
+pub fn remove_set_aside(storage: &dyn Storage, tree_file_path: &str) -> Result<(), Box<dyn Error>> {
+    for path in &tree_files(tree_file_path) {
+        storage.remove_if_exists(&set_aside_path(path))?;
+    }
+
+    Ok(())
+}
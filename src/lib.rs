@@ -1,261 +1,6720 @@
-extern crate serde;
+extern crate bincode;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
 extern crate itertools;
 extern crate rand;
-extern crate bincode;
+#[cfg(feature = "s3")]
+extern crate s3;
+extern crate serde;
 
+#[cfg(feature = "accent-folding")]
+mod accent_folding;
+mod aggregate;
+mod audit_log;
+mod blob_store;
+mod block_cache;
+mod block_encoding;
+mod block_filters;
+mod bloom;
+mod clock;
+mod composite_key;
+mod counter;
+mod diff;
 mod disk_btree;
+mod durability;
+mod encoding;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error;
+mod executor;
+mod fixed_key;
+mod hash_index;
+#[cfg(feature = "server")]
+pub mod http;
+mod io_stats;
+mod lazy_value;
+mod legacy;
+mod lru;
+mod lz4;
+mod maintenance;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 mod multi_map;
+#[cfg(feature = "object-store")]
+mod object_store;
+mod options;
+mod prepared;
+mod progress;
+mod rate_limiter;
+mod read_only;
+mod repair;
+#[cfg(feature = "server")]
+pub mod resp;
+mod runs;
+#[cfg(feature = "s3")]
+mod s3_store;
+mod schema;
+#[cfg(feature = "server")]
+pub mod server;
+mod set_op;
+mod sharded;
+mod sim_disk;
+mod stats;
+mod storage;
+mod time_series;
+mod transaction;
+mod tree_file_reader;
+mod value_list;
 mod wal_file;
+mod write_batch;
+mod write_buffer;
+mod zone_map;
 
-use disk_btree::OnDiskBTree;
+#[cfg(feature = "accent-folding")]
+pub use accent_folding::AccentFoldedString;
+pub use aggregate::{Agg, Aggregate};
+pub use audit_log::{AuditEntry, AuditOp};
+pub use blob_store::{Blob, BlobReader, BlobWriter};
+pub use block_cache::BlockCache;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use composite_key::{Components, CompositeKey};
+pub use counter::Counter;
+pub use diff::Diff;
+pub use disk_btree::FORMAT_VERSION;
+pub use durability::DurableWrite;
+pub use encoding::Encoding;
+#[cfg(feature = "encryption")]
+pub use encryption::{EncryptedStorage, Key, KeyProvider, KeyRing};
+pub use error::BTreeError;
+pub use executor::{CompactionExecutor, Job, ThreadExecutor, ThreadStart};
+pub use fixed_key::FixedKey;
+pub use io_stats::{IoCounted, IoStats};
+pub use lazy_value::LazyValue;
+pub use maintenance::MaintenanceTimer;
+#[cfg(feature = "object-store")]
+pub use object_store::{MemoryObjectStore, ObjectStorage, ObjectStore};
+pub use options::{
+    CapPolicy, CompactionOptions, CompactionPriority, Consistency, DiskQuota, DynamicOptions,
+    Options, QuotaPolicy, ReadOptions, SyncPolicy, ValueCap, VersionRetention, WriteThrottle,
+};
+pub use prepared::PrepareToken;
+pub use progress::{CompactionMonitor, CompactionProgress};
+pub use rate_limiter::RateLimiter;
+pub use read_only::ReadOnlyBTree;
+pub use repair::RepairReport;
+#[cfg(feature = "s3")]
+pub use s3_store::S3ObjectStore;
+pub use schema::Schema;
+pub use set_op::SetOp;
+pub use sharded::{ConcurrentBTree, Partitioning, ShardedBTree};
+pub use sim_disk::SimDisk;
+pub use stats::{LatencyHistogram, SlowOp, SlowOpKind, Stats};
+pub use storage::{FileStorage, Storage, StorageFile};
+pub use time_series::TimeSeriesBTree;
+pub use transaction::{Conflict, Transaction, TransactionalBTree};
+pub use tree_file_reader::{FileCheck, TreeFileReader};
+pub use value_list::{ListBTree, Sequenced};
+pub use wal_file::{KeyValuePair, RecordKind, RecoveryReport, ReplaySummary, ValuePredicate};
+pub use write_batch::WriteBatch;
+pub use write_buffer::WriteBufferManager;
+
+use audit_log::AuditLog;
+use blob_store::{blob_store_path, BlobStore, INLINE_OVERHEAD, STORED_SIZE};
+use block_filters::{filter_path, FilterSettings, KeyBytes};
+use bloom::{fnv1a_extend, FNV_OFFSET};
+use counter::Counting;
+use disk_btree::{FileOptions, OnDiskBTree};
+use durability::DurableSeq;
+use encoding::Codec;
+use hash_index::hash_index_path;
+use io_stats::{CountingStorage, Touching};
+use legacy::{is_legacy, read_legacy, remove_set_aside, set_aside};
+use lru::LruKeys;
 use multi_map::MultiMap;
-use wal_file::{KeyValuePair, RecordFile};
+use prepared::{prepared_log_path, PreparedLog};
+use progress::ProgressState;
+use read_only::{read_generation, write_generation};
+use runs::{manifest_path, read_manifest, write_manifest, Run};
+use schema::check_schema;
+use transaction::OpenTransactions;
+use wal_file::{Filtered, KeyRecord, RecordFile, RECORD_OVERHEAD};
+use write_buffer::WriteBufferShare;
+use zone_map::zone_map_path;
 
-use std::error::Error;
-use itertools::merge;
+use itertools::{merge, process_results, Itertools};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::{Range, RangeBounds};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+// the default flush threshold
 const MAX_MEMORY_ITEMS: usize = 1000;
 
+// the paths of the files that can sit beside a tree file, describing it
+const SIDECARS: [fn(&str) -> String; 3] = [filter_path, hash_index_path, zone_map_path];
+
+/// Fails with `BTreeError::LegacyFormat` if the tree at `tree_file_path` has files
+/// in the legacy layout, which only `BTree::migrate` can read
+fn refuse_legacy<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    key_size: usize,
+    value_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    if is_legacy::<K, V>(storage, tree_file_path, key_size, value_size)? {
+        return Err(Box::new(BTreeError::LegacyFormat {
+            path: tree_file_path.to_owned(),
+        }));
+    }
+
+    Ok(())
+}
+
+/// Seals a finished file of `level` and whichever of its sidecars it has, see
+/// `Storage::seal`
+fn seal_with_sidecars(storage: &dyn Storage, path: &str, level: u32) -> Result<(), Box<dyn Error>> {
+    storage.seal(path, level)?;
+
+    for sidecar in SIDECARS {
+        if storage.exists(&sidecar(path))? {
+            storage.seal(&sidecar(path), level)?;
+        }
+    }
+
+    Ok(())
+}
+
 // specify the types for the keys & values
-pub trait KeyType: Eq + Ord + Clone + Serialize + for<'de> Deserialize<'de> {}
-pub trait ValueType: Ord + Clone + Serialize + for<'de> Deserialize<'de> {}
+pub trait KeyType: Eq + Ord + Clone + Send + Sync + Serialize + for<'de> Deserialize<'de> {}
+pub trait ValueType: Ord + Clone + Send + Sync + Serialize + for<'de> Deserialize<'de> {}
 
 // provide generic implementations
 
-impl<T> KeyType for T where T: Eq + Ord + Clone + Serialize + for<'de> Deserialize<'de> {}
-impl<T> ValueType for T where T: Ord + Clone + Serialize + for<'de> Deserialize<'de> {}
+impl<T> KeyType for T where T: Eq + Ord + Clone + Send + Sync + Serialize + for<'de> Deserialize<'de>
+{}
+impl<T> ValueType for T where T: Ord + Clone + Send + Sync + Serialize + for<'de> Deserialize<'de> {}
+
+/// Checks a write before it's logged, see `BTree::add_pre_write_hook`
+pub type PreWriteHook<K, V> = Box<dyn Fn(&K, &V) -> Result<(), Box<dyn Error>> + Send + Sync>;
+
+/// Told of each write once it's committed, with its sequence number, see
+/// `BTree::add_post_commit_hook`
+pub type PostCommitHook<K, V> = Box<dyn Fn(&K, &V, u64) + Send + Sync>;
+
+/// Runs on each operation slower than `Options::slow_op_threshold`
+pub type SlowOpHook = Box<dyn Fn(&SlowOp) + Send + Sync>;
+
+/// Runs as a compaction makes progress, see `BTree::add_compaction_progress_hook`
+pub type ProgressHook = Box<dyn Fn(&CompactionProgress) + Send + Sync>;
+
+/// The compaction progress hooks run each time this many more bytes are merged
+const PROGRESS_STEP: u64 = 1 << 20;
+
+/// What a read returned, and what it read from the files if the read options
+/// asked for that
+pub type WithIo<T> = (T, Option<IoStats>);
+
+// turns a key into the one it's stored as, for case-insensitive trees
+type KeyFold<K> = fn(&K) -> K;
+
+/// A write to a watched key, see `BTree::watch`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<K, V> {
+    pub key: K,
+    pub value: V, // the value put or deleted
+    pub seq: u64, // the sequence number of the write
+}
+
+/// A single write of a value, as returned by `get_versions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version<V> {
+    pub value: V,
+    pub kind: RecordKind, // whether this write put or deleted the value
+    pub seq: u64,         // the sequence number of the write
+    pub written_at: u64,  // milliseconds since the epoch
+}
+
+/// A past point to read the tree at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadPoint {
+    Seq(u64),       // right after the write with this sequence number
+    Timestamp(u64), // at this many milliseconds since the epoch
+}
+
+/// A read-only view of a BTree at a past point, see `BTree::snapshot_at`
+pub struct Snapshot<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    tree: &'a BTree<K, V>,
+    point: ReadPoint,
+}
+
+impl<'a, K: KeyType, V: ValueType> Snapshot<'a, K, V> {
+    pub fn get(&self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.tree.get_at(key, self.point)
+    }
+
+    pub fn point(&self) -> ReadPoint {
+        self.point
+    }
+}
 
 /// This struct holds all the pieces of the BTree mechanism
 pub struct BTree<K: KeyType, V: ValueType> {
-    tree_file_path: String,       // the path to the tree file
-    key_size: usize,              // the size of the key in bytes
-    value_size: usize,            // the size of the value in bytes
+    tree_file_path: String,                           // the path to the tree file
+    key_size: usize,                                  // the size of the key in bytes
+    value_size: usize,                                // the size of the value in bytes
+    storage: Arc<dyn Storage>,                        // where all the files live
+    clock: Arc<dyn Clock>,                            // the time used for TTLs
+    last_seq: u64,                                    // the sequence number of the last write
+    versioning: Option<VersionRetention>,             // whether, and how much, history is kept
+    audit_log: Option<AuditLog<K, V>>,                // a record of every mutation, when turned on
+    soft_delete_window: Duration, // how long soft-deleted values are kept around
+    expired_compaction_trigger: Option<usize>, // how many expired records on disk force a compaction
+    l0_compaction_trigger: Option<usize>, // how many L0 runs force a compaction, if flushes write runs
+    wal_flush_trigger: Option<u64>,       // how big the WAL can get before the memtable is flushed
+    idle_flush: Option<Duration>, // how long the memtable can go without a write before it's flushed
+    last_write_at: u64,           // when the last write was committed, for idle_flush
+    wal_compression: bool,        // whether writes to the WAL are compressed
+    disk_expiries: Vec<u64>,      // when each TTL'd record in the tree file expires, sorted
+    write_throttle: Option<WriteThrottle>, // limits on how far the memtable can fall behind
+    spill_memtable: bool,         // whether a memtable at the hard limit is spilled to a run
+    write_buffer: Option<WriteBufferShare>, // this tree's part of a memtable budget shared with others
+    compaction_limiter: Option<RateLimiter>, // caps the I/O compaction does
+    compaction: CompactionOptions,          // threads and priorities for compaction
+    compaction_executor: Arc<dyn CompactionExecutor>, // runs the sub-compactions
+    flush_threshold: usize,                 // how many writes the memtable takes before a flush
+    sync_policy: SyncPolicy,                // when the WAL is synced
+    last_wal_sync: u64, // when the WAL was last synced, for SyncPolicy::Interval
+    durable: Arc<DurableSeq>, // how far writes have been synced, for insert_async
+    block_cache: Arc<BlockCache>, // blocks of the tree file and runs read by lookups
+    bloom_bits_per_key: Option<usize>, // the size of the bloom filters written with new files
+    prefix_bloom: Option<(usize, KeyBytes<K>)>, // the prefix length to filter on in new files
+    fold_key: Option<KeyFold<K>>, // what keys are stored as, when they're case-insensitive
+    counting: Option<Counting<V>>, // how increments add up, in a tree of counters
+    hash_index: bool,   // whether compaction writes a hash index of the tree file
+    prefix_compression: bool, // whether new files are written with encoded blocks
+    compression_dictionary: Option<usize>, // the size of the dictionary sampled for each new file
+    encoding: Encoding, // what the records of new files are encoded in
+    blob_store: Option<BlobStore>, // the large values of a tree of blobs, each stored once
+    disk_quota: Option<DiskQuota>, // the most the files may take up
+    value_cap: Option<ValueCap>, // the most values a key may hold
+    lru: Option<Mutex<LruKeys<K>>>, // the keys by when they were last used, in LRU cache mode
+    flushed_bytes: u64, // taken up by all but the WAL and blobs, as of the last flush
+    generation: u64,    // odd while files are being replaced, see `read_only`
+    replay_summary: ReplaySummary, // what opening the tree found in the WAL
+    recovery: RecoveryReport, // and what it did about it
+    prepared_log: Option<PreparedLog<K, V>>, // batches prepared for two-phase commits, once there's been one
+    open_transactions: Option<Arc<OpenTransactions>>, // the optimistic ones, when a TransactionalBTree holds the tree
+    stats: Stats,                                     // counters of the work done since opening
+    get_latency: Mutex<LatencyHistogram>, // kept apart from the stats, as lookups only borrow the tree
+    pre_write_hooks: Vec<PreWriteHook<K, V>>, // run before each write is logged
+    post_commit_hooks: Vec<PostCommitHook<K, V>>, // run after each write is committed
+    slow_op_threshold: Option<Duration>,  // operations slower than this go to the slow op hooks
+    slow_op_hooks: Vec<SlowOpHook>,
+    progress: Arc<ProgressState>, // of the running compaction, shared with the compaction monitors
+    progress_hooks: Vec<ProgressHook>,
     wal_file: RecordFile<K, V>,   // write-ahead log for in-memory items
     mem_tree: MultiMap<K, V>,     // in-memory multimap that gets merged with the on-disk BTree
     tree_file: OnDiskBTree<K, V>, // the file backing the whole thing
+    runs: Vec<Run<K, V>>,         // L0 runs flushed since the last compaction, oldest first
 }
 
 impl<K: KeyType, V: ValueType> BTree<K, V> {
     pub fn new(
-        tree_file_path: &String,
+        tree_file_path: &str,
         key_size: usize,
         value_size: usize,
     ) -> Result<BTree<K, V>, Box<dyn Error>> {
-        // create our in-memory multimap
-        let mut mem_tree = MultiMap::<K, V>::new();
-
-        // construct the path to the WAL file for the in-memory multimap
-        let wal_file_path = tree_file_path.to_owned() + ".wal";
-
-        // construct our WAL file
-        let mut wal_file = RecordFile::<K, V>::new(&wal_file_path, key_size, value_size)?;
+        BTree::with_options(tree_file_path, key_size, value_size, Options::default())
+    }
 
-        // if we have a WAL file, replay it into the mem_tree
-        if wal_file.is_new()? {
-            for kv in &mut wal_file {
-                mem_tree.insert(kv.key, kv.value);
-            }
-        }
+    pub fn with_options(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<BTree<K, V>, Box<dyn Error>> {
+        BTree::open_writer(tree_file_path, key_size, value_size, options, None, None)
+    }
 
-        // open the data file
-        let tree_file = OnDiskBTree::<K, V>::new(tree_file_path.to_owned(), key_size, value_size)?;
+    /// Opens a tree whose files are too damaged for `with_options` to open it, for
+    /// when there's nothing better to restore from. Whatever records can still be
+    /// read are kept, the damaged blocks and records passed over, and each file is
+    /// written again with its index and filters rebuilt from what survived. The
+    /// report says which files were damaged and how much of them was lost.
+    pub fn open_with_repair(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<(BTree<K, V>, RepairReport), Box<dyn Error>> {
+        // repairing would take a legacy layout for damage and throw its records away
+        refuse_legacy::<K, V>(&*options.storage, tree_file_path, key_size, value_size)?;
 
-        Ok(BTree {
-            tree_file_path: tree_file_path.clone(),
+        let report = repair::repair::<K, V>(
+            &*options.storage,
+            tree_file_path,
             key_size,
             value_size,
-            tree_file,
-            wal_file,
-            mem_tree,
-        })
+            &options,
+        )?;
+
+        Ok((
+            BTree::with_options(tree_file_path, key_size, value_size, options)?,
+            report,
+        ))
     }
 
-    /// Inserts a key into the BTree
-    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
-        let record = KeyValuePair { key, value };
+    /// Brings the files of the tree at `tree_file_path` written by older versions
+    /// of the crate, in a format before `FORMAT_VERSION`, up to date, along with
+    /// those in another encoding than `options.encoding`. They're merged into a new
+    /// tree file, written beside the old one and swapped in as a flush would, so
+    /// what's already current is left be. Returns how many files were out of date.
+    ///
+    /// A tree from before files had headers, whose tree file and WAL hold nothing
+    /// but keys and values, is read whole, its files set aside while a tree in the
+    /// current format is written in their place, and removed once it's durable. If
+    /// that's cut off, the tree can't be opened until this is run again. The tree
+    /// mustn't be open while it runs; `migrate_to` leaves it be instead.
+    pub fn migrate(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<u64, Box<dyn Error>> {
+        let storage = options.storage.clone();
+        let now = options.clock.now_millis();
 
-        self.wal_file.insert_record(&record)?;
+        if let Some(legacy) =
+            read_legacy::<K, V>(&*storage, tree_file_path, key_size, value_size, now)?
+        {
+            set_aside::<K, V>(&*storage, tree_file_path, key_size, value_size)?;
+
+            let mut btree =
+                BTree::open_current(tree_file_path, key_size, value_size, options, None, None)?;
+
+            let mut written = false;
+
+            for file in btree.disk_files() {
+                written |= !file.is_new()?;
+            }
 
-        let KeyValuePair { key, value } = record;
+            // a migration cut off after its tree was written only has to clean up
+            if !written {
+                btree.import(legacy.records)?;
+            }
 
-        let size = self.mem_tree.insert(key, value);
+            drop(btree);
+            remove_set_aside(&*storage, tree_file_path)?;
 
-        if size > MAX_MEMORY_ITEMS {
-            self.compact()?;
+            return Ok(legacy.files);
         }
 
-        Ok(())
-    }
+        let mut btree = BTree::<K, V>::with_options(tree_file_path, key_size, value_size, options)?;
 
-    pub fn get(&self, key: &K) -> Option<std::collections::btree_set::Iter<V>> {
-        self.mem_tree.get(key).map(|btree| btree)
+        let mut outdated = 0;
+
+        for file in btree.disk_files() {
+            if (file.version() < FORMAT_VERSION || file.encoding() != btree.encoding)
+                && !file.is_new()?
+            {
+                outdated += 1;
+            }
+        }
+
+        if outdated > 0 {
+            btree.flush()?;
+        }
+
+        Ok(outdated)
     }
 
-    /// Merges the records on disk with the records in memory
-    fn compact(&mut self) -> Result<(), Box<dyn Error>> {
-        // create a new on-disk BTree
-        let mut new_tree_file = OnDiskBTree::<K, V>::new(
-            self.tree_file_path.to_owned() + ".new",
-            self.key_size,
-            self.value_size,
-        )?;
+    /// Writes a copy of the tree at `from` into a new tree at `to`, in the current
+    /// format and `options.encoding`, leaving `from` as it was. A tree in the
+    /// current format is read the way a `ReadOnlyBTree` reads it, so its writer can
+    /// keep it open meanwhile, and the copy holds what had been written when it was
+    /// opened. Each record is copied as it was written, sequence number and
+    /// timestamps and all, and so are the tree's blobs. Returns how many records
+    /// were copied.
+    pub fn migrate_to(
+        from: &str,
+        to: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<u64, Box<dyn Error>> {
+        let storage = options.storage.clone();
 
-        // get an iterator for the in-memory items
-        let mem_iter = self.mem_tree.into_iter();
+        if storage.exists(to)? {
+            return Err(From::from(format!("There's already a tree at {}", to)));
+        }
+
+        let now = options.clock.now_millis();
+        let records = match read_legacy::<K, V>(&*storage, from, key_size, value_size, now)? {
+            Some(legacy) => legacy.records,
+            None => {
+                ReadOnlyBTree::<K, V>::with_options(from, key_size, value_size, options.clone())?
+                    .all_records()?
+            }
+        };
+        let copied = records.len() as u64;
 
-        // get an iterator to the on-disk items
-        let disk_iter = self.tree_file.into_iter();
+        // blobs are only ever appended, so those the records refer to are all there
+        if storage.exists(&blob_store_path(from))? {
+            let blobs = storage.open(&blob_store_path(from))?;
+            let mut bytes = vec![0; blobs.len()? as usize];
+            blobs.read_at(&mut bytes, 0)?;
 
-        for kv in merge(mem_iter, disk_iter) {
-            new_tree_file.insert_record(&kv)?;
+            let mut copy = storage.open(&blob_store_path(to))?;
+            copy.append(&bytes)?;
+            copy.sync()?;
         }
 
-        Ok(())
+        BTree::open_writer(to, key_size, value_size, options, None, None)?.import(records)?;
+
+        Ok(copied)
     }
-}
 
-#[cfg(test)]
-#[allow(unused_must_use)]
-mod tests {
-    use rand::{thread_rng, Rng};
-    use std::collections::BTreeSet;
-    use std::fs;
-    use std::fs::OpenOptions;
-    use rand::distributions::Alphanumeric;
-    use BTree;
+    /// Stores `records` as they are, sequence numbers and timestamps and all, in a
+    /// tree with nothing in it yet, and flushes them to its tree file
+    fn import(&mut self, mut records: Vec<KeyValuePair<K, V>>) -> Result<(), Box<dyn Error>> {
+        // in the order they were written, so each one supersedes the writes before it
+        records.sort_by_key(|kv| kv.seq);
 
-    pub fn gen_temp_name() -> String {
-        let file_name: String = thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect();
+        for kv in records {
+            self.last_seq = self.last_seq.max(kv.seq);
+            self.mem_tree.insert_record(kv);
+        }
 
-        String::from("/tmp/") + &file_name + &String::from(".btr")
+        self.durable.advance(self.last_seq);
+        self.flush()
     }
 
-    fn remove_files(file_path: String) {
-        fs::remove_file(&file_path);
-        fs::remove_file(file_path + ".wal");
+    /// Every record held in memory or on disk, those superseded included
+    fn all_records(&self) -> Result<Vec<KeyValuePair<K, V>>, Box<dyn Error>> {
+        let mut records: Vec<KeyValuePair<K, V>> = self.mem_tree.iter().collect();
+        records.extend(self.mem_tree.superseded().iter().cloned());
+
+        for file in self.disk_files() {
+            for kv in file {
+                records.push(kv?);
+            }
+        }
+
+        Ok(records)
     }
 
-    #[test]
-    fn new_blank_file() {
-        let file_path = gen_temp_name();
+    /// Opens the tree to write to, storing keys folded by `fold_key` if it's given,
+    /// and adding up increments with `counting`
+    fn open_writer(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+        fold_key: Option<KeyFold<K>>,
+        counting: Option<Counting<V>>,
+    ) -> Result<BTree<K, V>, Box<dyn Error>> {
+        // before anything's written, so a WAL that can't be parsed isn't truncated
+        refuse_legacy::<K, V>(&*options.storage, tree_file_path, key_size, value_size)?;
 
-        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        BTree::open_current(
+            tree_file_path,
+            key_size,
+            value_size,
+            options,
+            fold_key,
+            counting,
+        )
+    }
 
-        // make sure our two files were created
-        let btf = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .create(false)
-            .open(&file_path)
-            .unwrap();
-        assert_eq!(btf.metadata().unwrap().len(), 0);
+    /// Opens the tree to write to as `open_writer` does, without looking for files
+    /// in the legacy layout, which `migrate` has already set aside
+    fn open_current(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+        fold_key: Option<KeyFold<K>>,
+        counting: Option<Counting<V>>,
+    ) -> Result<BTree<K, V>, Box<dyn Error>> {
+        let mut btree = BTree::open(
+            tree_file_path,
+            key_size,
+            value_size,
+            options,
+            false,
+            fold_key,
+            counting,
+        )?;
+        let stale_tree_file = btree
+            .storage
+            .exists(&(btree.tree_file_path.to_owned() + ".new"))?;
 
-        let wal = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .create(false)
-            .open(file_path.to_owned() + ".wal")
-            .unwrap();
-        assert_eq!(wal.metadata().unwrap().len(), 0);
+        // an install that didn't finish was put right by opening the tree, and what
+        // was being written for one that never started is thrown away
+        if btree.generation % 2 == 1 || stale_tree_file {
+            btree.remove_new_tree_file()?;
+            btree.recovery.stale_compaction_cleaned = true;
+        }
 
-        // make sure they think they're new too
-        assert!(btree.wal_file.is_new().unwrap());
-        assert_eq!(btree.wal_file.count().unwrap(), 0);
+        if btree.generation % 2 == 1 {
+            btree.bump_generation()?;
+        }
 
-        assert!(btree.tree_file.is_new().unwrap());
-        assert_eq!(btree.tree_file.count().unwrap(), 0);
+        btree.recovery.prepared_batches_applied = btree.recover_prepared()?;
+        btree.warm_lru()?;
 
-        remove_files(file_path); // remove files assuming it all went well
+        Ok(btree)
     }
 
-    #[test]
-    fn new_existing_file() {
-        let file_path = gen_temp_name();
+    /// Tracks the keys already stored, in LRU cache mode, as used in the order they
+    /// were last written, and evicts any past the capacity. Reads before the tree
+    /// was last closed aren't known.
+    fn warm_lru(&mut self) -> Result<(), Box<dyn Error>> {
+        let lru = match &self.lru {
+            Some(lru) => lru,
+            None => return Ok(()),
+        };
 
-        // scoped so it is cleaned up
-        {
-            BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        let mut keys = Vec::new();
+
+        for entry in self.try_range(..)? {
+            let (key, _) = entry?;
+
+            keys.push((self.last_write_to(&key)?, key));
         }
 
-        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        keys.sort_unstable();
 
-        // check our file lengths from the struct
-        assert_eq!(btree.tree_file.count().unwrap(), 0);
-        assert_eq!(btree.wal_file.count().unwrap(), 0);
+        let mut lru = lru.lock().unwrap();
 
-        remove_files(file_path); // remove files assuming it all went well
+        for (_, key) in keys {
+            lru.touch(&key);
+        }
+
+        drop(lru);
+        self.evict_lru()
     }
 
-    #[test]
-    fn insert_new_u8() {
-        let file_path = gen_temp_name();
+    /// Deletes the least recently used keys, in LRU cache mode, until there are no
+    /// more than the capacity
+    fn evict_lru(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            let key = match self
+                .lru
+                .as_ref()
+                .and_then(|lru| lru.lock().unwrap().pop_excess())
+            {
+                Some(key) => key,
+                None => return Ok(()),
+            };
 
-        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+            // a key whose values have all expired has nothing left to delete
+            if !self.live_values(&key, None)?.is_empty() {
+                self.replace(key, None)?;
+                self.stats.evictions += 1;
+            }
+        }
+    }
 
-        btree.insert(2, 3).unwrap(); // insert into a new file
+    /// Marks the keys `written` puts into as the most recently used, in LRU cache
+    /// mode, and stops tracking those left empty
+    fn track_lru(&mut self, written: Vec<(K, bool)>) -> Result<(), Box<dyn Error>> {
+        let lru = match &self.lru {
+            Some(lru) => lru,
+            None => return Ok(()),
+        };
 
-        assert_eq!(btree.wal_file.count().unwrap(), 1);
-        assert!(btree.mem_tree.contains_key(&2));
+        for (key, put) in written {
+            if put {
+                lru.lock().unwrap().touch(&key);
+            } else if self.live_values(&key, None)?.is_empty() {
+                lru.lock().unwrap().forget(&key);
+            }
+        }
 
-        remove_files(file_path); // remove files assuming it all went well
+        self.evict_lru()
     }
 
-    #[test]
-    fn insert_new_str() {
-        let file_path = gen_temp_name();
+    /// Opens the log of prepared batches, if there is one, and finishes the commits
+    /// it records that a crash kept from reaching the WAL, returning how many there were
+    fn recover_prepared(&mut self) -> Result<u64, Box<dyn Error>> {
+        let path = prepared_log_path(&self.tree_file_path);
 
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        if !self.storage.exists(&path)? {
+            return Ok(0);
+        }
 
-        // insert into a new file
-        btree
-            .insert("Hello".to_owned(), "World".to_owned())
-            .unwrap();
+        let committed = self
+            .prepared_log
+            .insert(PreparedLog::open(&*self.storage, &path)?)
+            .take_committed();
+        let mut applied = 0;
 
-        assert!(!btree.wal_file.is_new().unwrap());
-        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+        for batch in committed {
+            // a batch made it if any write from its seqs on did
+            if batch.first_seq <= self.last_seq {
+                continue;
+            }
 
-        remove_files(file_path); // remove files assuming it all went well
+            self.last_seq = batch.first_seq - 1;
+
+            let ops = batch.records.iter().map(|kv| audit_op(kv.kind)).collect();
+            self.commit_records(batch.records, ops, "")?;
+            applied += 1;
+        }
+
+        self.sync()?;
+
+        if let Some(log) = self.prepared_log.as_mut() {
+            log.clear_if_done()?;
+        }
+
+        Ok(applied)
     }
 
-    #[test]
-    fn get_returns_an_iter() {
-        let file_path = gen_temp_name();
+    /// Opens the tree's files, for a writer or a `ReadOnlyBTree`, which mustn't
+    /// change them
+    fn open(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+        read_only: bool,
+        fold_key: Option<KeyFold<K>>,
+        counting: Option<Counting<V>>,
+    ) -> Result<BTree<K, V>, Box<dyn Error>> {
+        let Options {
+            storage,
+            clock,
+            versioning,
+            audit,
+            soft_delete_window,
+            expired_compaction_trigger,
+            l0_compaction_trigger,
+            wal_flush_trigger,
+            idle_flush,
+            wal_compression,
+            write_throttle,
+            spill_memtable,
+            write_buffer_manager,
+            compaction_rate_limit,
+            compaction,
+            compaction_executor,
+            flush_threshold,
+            sync_policy,
+            cache_size,
+            block_cache,
+            bloom_bits_per_key,
+            hash_index,
+            prefix_compression,
+            compression_dictionary,
+            encoding,
+            disk_quota,
+            value_cap,
+            lru_capacity,
+            schema,
+            slow_op_threshold,
+        } = options;
 
-        // setup tree
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        // reads are counted against the thread making them, for the slow op reports
+        let storage: Arc<dyn Storage> = Arc::new(CountingStorage::new(storage));
 
-        // expected return set
-        let mut expected: BTreeSet<String> = BTreeSet::new();
-        expected.insert("World".to_string());
+        let schema = schema.unwrap_or_else(Schema::of::<K, V>);
+        check_schema(
+            &*storage,
+            tree_file_path,
+            &schema,
+            fold_key.is_some(),
+            read_only,
+        )?;
 
-        btree.insert("Hello".to_owned(), "World".to_owned());
+        // create our in-memory multimap
+        let mut mem_tree = new_mem_tree(&versioning, counting);
 
-        // get the set at the hello key
-        let set_at_hello: Vec<String> = btree.get(&"Hello".to_string()).unwrap().cloned().collect();
+        // construct the path to the WAL file for the in-memory multimap
+        let wal_file_path = tree_file_path.to_owned() + ".wal";
 
-        assert_eq!(set_at_hello, ["World".to_string()]);
+        // construct our WAL file
+        let mut wal_file =
+            RecordFile::<K, V>::new_log(&*storage, &wal_file_path, key_size, value_size)?;
 
-        remove_files(file_path); // remove files assuming it all went well
-    }
+        let mut last_seq = 0;
 
-    #[test]
-    fn insert_multiple() {
-        let file_path = gen_temp_name();
+        // replay the committed writes in the WAL into the mem_tree
+        let replay = wal_file.replay_from(0);
+        let replay_summary = ReplaySummary {
+            records: replay.records.len() as u64,
+            writes: replay.writes,
+            aborted: replay.aborted,
+            discarded_bytes: wal_file.byte_len()? - replay.next_offset,
+        };
+        let (next_offset, compressed) = (replay.next_offset, replay.compressed);
 
-        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+        for kv in replay.records {
+            last_seq = last_seq.max(kv.seq);
+            mem_tree.insert_record(kv);
+        }
 
-        // insert into a new file
-        btree
-            .insert("Hello".to_owned(), "World".to_owned())
-            .unwrap();
-        assert!(!btree.wal_file.is_new().unwrap());
+        // what follows the last commit would otherwise be taken for the start of the next write
+        if replay_summary.discarded_bytes > 0 && !read_only {
+            wal_file.truncate_at(next_offset)?;
+        }
 
-        btree
-            .insert("Hello".to_owned(), "Everyone".to_owned())
-            .unwrap();
-        assert!(!btree.wal_file.is_new().unwrap());
+        // a WAL written with compression stays readable after it's turned off
+        if wal_compression || compressed {
+            wal_file.set_compression(wal_compression);
+        }
 
-        remove_files(file_path); // remove files assuming it all went well
+        wal_file.set_coalescing(coalesces(sync_policy))?;
+
+        let block_cache = block_cache.unwrap_or_else(|| Arc::new(BlockCache::new(cache_size)));
+
+        // open the data file
+        let mut tree_file =
+            OnDiskBTree::<K, V>::new(&*storage, tree_file_path, key_size, value_size)?;
+        tree_file.set_cache(block_cache.clone());
+
+        let audit_log = if audit {
+            Some(AuditLog::new(
+                &*storage,
+                &(tree_file_path.to_owned() + ".audit"),
+            )?)
+        } else {
+            None
+        };
+
+        let mut disk_expiries = Vec::new();
+
+        // sequence numbers keep counting up from the newest record we have
+        for kv in &tree_file {
+            let kv = kv?;
+            last_seq = last_seq.max(kv.seq);
+            disk_expiries.extend(kv.expires_at);
+        }
+
+        disk_expiries.sort_unstable();
+
+        // only trees of blobs that have stored a large value have one
+        let blob_store_file = blob_store_path(tree_file_path);
+        let blob_store = if storage.exists(&blob_store_file)? {
+            Some(BlobStore::open(&*storage, &blob_store_file, !read_only)?)
+        } else {
+            None
+        };
+
+        let mut runs = Vec::new();
+
+        for id in read_manifest(&*storage, tree_file_path)? {
+            let mut run = Run::open(&*storage, tree_file_path, id, key_size, value_size)?;
+            run.file.set_cache(block_cache.clone());
+
+            for kv in &run.file {
+                last_seq = last_seq.max(kv?.seq);
+            }
+
+            runs.push(run);
+        }
+
+        let compaction_limiter =
+            compaction_rate_limit.map(|rate| RateLimiter::new(rate, clock.clone()));
+        let last_wal_sync = clock.now_millis();
+        let generation = read_generation(&*storage, tree_file_path)?;
+
+        // a reader's memtable only ever holds what the writer has already counted
+        let write_buffer = write_buffer_manager
+            .filter(|_| !read_only)
+            .map(|manager| WriteBufferManager::join(&manager));
+
+        let mut btree = BTree {
+            tree_file_path: tree_file_path.to_owned(),
+            key_size,
+            value_size,
+            storage,
+            clock,
+            last_seq,
+            versioning,
+            audit_log,
+            soft_delete_window,
+            expired_compaction_trigger,
+            l0_compaction_trigger,
+            wal_flush_trigger,
+            idle_flush,
+            last_write_at: last_wal_sync,
+            wal_compression,
+            disk_expiries,
+            write_throttle,
+            spill_memtable,
+            write_buffer,
+            compaction_limiter,
+            compaction,
+            compaction_executor,
+            flush_threshold,
+            sync_policy,
+            last_wal_sync,
+            durable: Arc::new(DurableSeq::new(last_seq)),
+            block_cache,
+            bloom_bits_per_key,
+            prefix_bloom: None,
+            fold_key,
+            counting,
+            hash_index,
+            prefix_compression,
+            compression_dictionary,
+            encoding,
+            blob_store,
+            disk_quota,
+            value_cap,
+            lru: lru_capacity.map(|capacity| Mutex::new(LruKeys::new(capacity))),
+            flushed_bytes: 0,
+            generation,
+            replay_summary,
+            recovery: RecoveryReport {
+                records_replayed: replay_summary.records,
+                writes_replayed: replay_summary.writes,
+                torn_tail_truncated: replay_summary.discarded_bytes > 0 && !read_only,
+                ..RecoveryReport::default()
+            },
+            prepared_log: None,
+            open_transactions: None,
+            stats: Stats::default(),
+            get_latency: Mutex::new(LatencyHistogram::default()),
+            pre_write_hooks: Vec::new(),
+            post_commit_hooks: Vec::new(),
+            slow_op_threshold,
+            slow_op_hooks: Vec::new(),
+            progress: Arc::new(ProgressState::default()),
+            progress_hooks: Vec::new(),
+            tree_file,
+            runs,
+            wal_file,
+            mem_tree,
+        };
+
+        btree.measure_flushed_files()?;
+
+        Ok(btree)
+    }
+
+    /// Inserts a key into the BTree
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.insert_record(KeyValuePair::new(key, value), AuditOp::Insert, "")
+    }
+
+    /// Inserts every key and value from `entries` as one write: they're logged to
+    /// the WAL together, with one sync, and go into the memtable together, which is
+    /// much cheaper than inserting them one at a time when loading a lot of data
+    pub fn insert_all<I>(&mut self, entries: I) -> Result<(), Box<dyn Error>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let records: Vec<KeyValuePair<K, V>> = entries
+            .into_iter()
+            .map(|(key, value)| KeyValuePair::new(key, value))
+            .collect();
+
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let ops = vec![AuditOp::Insert; records.len()];
+
+        self.write_records(records, ops, "")
+    }
+
+    /// Loads `entries`, in any order and as many as won't fit in memory, straight
+    /// into the tree file, bypassing the WAL and memtable. They're sorted
+    /// `records_per_run` at a time into temporary runs, which are merged with the
+    /// tree file in one pass. Hooks, the audit log and the disk quota don't see the
+    /// load. Returns how many entries were loaded.
+    pub fn bulk_load<I>(
+        &mut self,
+        entries: I,
+        records_per_run: usize,
+    ) -> Result<u64, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        // everything else goes into the tree file first, so there's only it to merge with
+        self.flush()?;
+
+        let spill_path = self.tree_file_path.to_owned() + ".load";
+        let plain = FileOptions {
+            filters: None,
+            hash_index: false,
+            prefix_compression: false,
+            dictionary_size: None,
+            encoding: self.encoding,
+        };
+        let written_at = self.clock.now_millis();
+        let mut spills = Vec::new();
+        let mut loaded = 0;
+
+        for chunk in &entries.into_iter().chunks(records_per_run.max(1)) {
+            let mut records: Vec<KeyValuePair<K, V>> = chunk
+                .map(|(key, value)| {
+                    loaded += 1;
+
+                    KeyValuePair {
+                        seq: self.last_seq + loaded,
+                        written_at,
+                        ..KeyValuePair::new(self.fold(&key).into_owned(), value)
+                    }
+                })
+                .collect();
+            records.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let id = spills.len() as u64 + 1;
+            spills.push(Run::create(
+                &*self.storage,
+                &spill_path,
+                id,
+                self.key_size,
+                self.value_size,
+                plain,
+                records.into_iter(),
+            )?);
+        }
+
+        let mut new_tree_file = self.create_new_tree_file()?;
+        let mut disk_expiries = Vec::new();
+
+        let sources = std::iter::once(&self.tree_file).chain(spills.iter().map(|run| &run.file));
+        let merged = sources.map(|file| file.into_iter()).kmerge_by(read_before);
+        let key_compaction = self.key_compaction((Unbounded, Unbounded));
+
+        // a record that can't be read leaves the tree file as it was
+        process_results(merged, |merged| -> Result<(), Box<dyn Error>> {
+            for kv in key_compaction.run(merged) {
+                new_tree_file.insert_record(&kv)?;
+                disk_expiries.extend(kv.expires_at);
+            }
+
+            Ok(())
+        })??;
+
+        self.replace_tree_file(new_tree_file, disk_expiries)?;
+
+        for run in spills {
+            Run::<K, V>::remove(&*self.storage, &spill_path, run.id)?;
+        }
+
+        self.last_seq += loaded;
+        self.stats.writes += loaded;
+        self.bump_generation()?;
+        self.durable.advance(self.last_seq);
+        self.measure_flushed_files()?;
+
+        Ok(loaded)
+    }
+
+    /// Like `insert`, returning a handle that can be waited on until the write is
+    /// durable. With `SyncPolicy::Interval` that's once a later write, or `sync()`,
+    /// syncs the WAL; with `SyncPolicy::Never` only `sync()` and flushes make it so.
+    pub fn insert_async(&mut self, key: K, value: V) -> Result<DurableWrite, Box<dyn Error>> {
+        self.insert(key, value)?;
+
+        Ok(DurableWrite::new(self.last_seq, self.durable.clone()))
+    }
+
+    /// Syncs the WAL now, whatever the sync policy, making every write so far durable
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.wal_file.sync()?;
+        self.last_wal_sync = self.clock.now_millis();
+        self.durable.advance(self.last_seq);
+
+        Ok(())
+    }
+
+    /// Like `insert`, but rather than being delayed when the memtable is over its
+    /// soft limit the write isn't attempted and `BTreeError::Busy` is returned
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        let pending_bytes = self.pending_bytes();
+
+        if self
+            .write_throttle
+            .is_some_and(|t| pending_bytes > t.soft_limit)
+        {
+            return Err(Box::new(BTreeError::Busy { pending_bytes }));
+        }
+
+        self.insert(key, value)
+    }
+
+    /// Inserts a key into the BTree, recording `actor` as the one who made the write
+    /// in the audit log
+    pub fn insert_audited(&mut self, key: K, value: V, actor: &str) -> Result<(), Box<dyn Error>> {
+        self.insert_record(KeyValuePair::new(key, value), AuditOp::Insert, actor)
+    }
+
+    /// Inserts a key into the BTree that is no longer returned once `ttl` has passed
+    pub fn insert_with_ttl(
+        &mut self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let expires_at = self.clock.now_millis() + ttl.as_millis() as u64;
+
+        let record = KeyValuePair {
+            expires_at: Some(expires_at),
+            ..KeyValuePair::new(key, value)
+        };
+
+        self.insert_record(record, AuditOp::Insert, "")
+    }
+
+    /// Replaces every value of `key` with `value`, returning the values it had. The
+    /// old values' deletes and the insert are logged as one write, so a reader never
+    /// sees the key with neither, or both.
+    pub fn insert_replace(&mut self, key: K, value: V) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.replace(key, Some(value))
+    }
+
+    /// Removes every value of `key`, returning them. The deletes are logged as one
+    /// write, so the values can be moved to another key with no risk of a crash
+    /// leaving only some of them behind.
+    pub fn take(&mut self, key: &K) -> Result<Option<BTreeSet<V>>, Box<dyn Error>> {
+        Ok(self
+            .replace(key.clone(), None)?
+            .map(|values| values.into_iter().collect()))
+    }
+
+    /// Deletes every value of `key` and inserts `value`, if there is one, in one
+    /// write, returning the values deleted
+    fn replace(&mut self, key: K, value: Option<V>) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        let previous = self.get(&key)?;
+
+        let mut records: Vec<KeyValuePair<K, V>> = previous
+            .iter()
+            .flatten()
+            .map(|old| KeyValuePair {
+                kind: RecordKind::Delete,
+                ..KeyValuePair::new(key.clone(), old.clone())
+            })
+            .collect();
+        let mut ops = vec![AuditOp::Delete; records.len()];
+
+        if let Some(value) = value {
+            records.push(KeyValuePair::new(key, value));
+            ops.push(AuditOp::Insert);
+        }
+
+        if !records.is_empty() {
+            self.write_records(records, ops, "")?;
+        }
+
+        Ok(previous)
+    }
+
+    /// Removes `value` from the values associated with `key`
+    pub fn delete(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.delete_audited(key, value, "")
+    }
+
+    /// Like `delete`, recording `actor` as the one who made the write in the audit log
+    pub fn delete_audited(&mut self, key: K, value: V, actor: &str) -> Result<(), Box<dyn Error>> {
+        let record = KeyValuePair {
+            kind: RecordKind::Delete,
+            ..KeyValuePair::new(key, value)
+        };
+
+        self.insert_record(record, AuditOp::Delete, actor)
+    }
+
+    /// Hides every value of `key` from reads, all in one write, so a crash can't
+    /// leave some hidden and others not. The values can be brought back with
+    /// `undelete` until the soft delete window in the options has passed, after
+    /// which compaction purges them. Returns whether there was anything to hide.
+    pub fn soft_delete(&mut self, key: &K) -> Result<bool, Box<dyn Error>> {
+        let now = self.clock.now_millis();
+
+        // the tombstone keeps the value and its expiry, so it's all undelete needs
+        let tombstones: Vec<KeyValuePair<K, V>> = self
+            .current_records(key, None)?
+            .into_iter()
+            .filter(|kv| kv.is_live(now))
+            .map(|kv| KeyValuePair {
+                kind: RecordKind::SoftDelete,
+                expires_at: kv.expires_at,
+                ..KeyValuePair::new(key.clone(), kv.value)
+            })
+            .collect();
+
+        if tombstones.is_empty() {
+            return Ok(false);
+        }
+
+        let ops = vec![AuditOp::SoftDelete; tombstones.len()];
+        self.write_records(tombstones, ops, "")?;
+
+        Ok(true)
+    }
+
+    /// Restores the values of `key` hidden by `soft_delete`, as long as they haven't
+    /// been purged, all in one write. Returns whether anything was restored.
+    pub fn undelete(&mut self, key: &K) -> Result<bool, Box<dyn Error>> {
+        let restored: Vec<KeyValuePair<K, V>> = self
+            .current_records(key, None)?
+            .into_iter()
+            .filter(|kv| kv.kind == RecordKind::SoftDelete)
+            .map(|kv| KeyValuePair {
+                expires_at: kv.expires_at,
+                ..KeyValuePair::new(key.clone(), kv.value)
+            })
+            .collect();
+
+        if restored.is_empty() {
+            return Ok(false);
+        }
+
+        let ops = vec![AuditOp::Undelete; restored.len()];
+        self.write_records(restored, ops, "")?;
+
+        Ok(true)
+    }
+
+    /// Runs `hook` on the key and value of every write, deletes included, before it's
+    /// written to the WAL. An error from the hook is returned without the write
+    /// being made, so a hook can validate what's written.
+    pub fn add_pre_write_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&K, &V) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+    {
+        self.pre_write_hooks.push(Box::new(hook));
+    }
+
+    /// Runs `hook` on the key, value and sequence number of every write, deletes
+    /// included, once it's in the WAL and the memtable, for keeping something
+    /// outside the tree, such as an index, up to date
+    pub fn add_post_commit_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&K, &V, u64) + Send + Sync + 'static,
+    {
+        self.post_commit_hooks.push(Box::new(hook));
+    }
+
+    /// Runs `hook` on each get, write, flush and compaction that takes longer than
+    /// `Options::slow_op_threshold`, with what it read, to find out what makes the
+    /// slow ones slow
+    pub fn add_slow_op_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&SlowOp) + Send + Sync + 'static,
+    {
+        self.slow_op_hooks.push(Box::new(hook));
+    }
+
+    /// Runs `hook` as a compaction merges records, a megabyte at a time and once it
+    /// has merged them all, so a long one can be told apart from a stuck one
+    pub fn add_compaction_progress_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&CompactionProgress) + Send + Sync + 'static,
+    {
+        self.progress_hooks.push(Box::new(hook));
+    }
+
+    /// Returns a handle for watching compactions from another thread, as calls to
+    /// the tree wait while one runs
+    pub fn compaction_monitor(&self) -> CompactionMonitor {
+        CompactionMonitor::new(self.progress.clone())
+    }
+
+    /// Hands `op` to the slow op hooks if it went over the threshold
+    fn report_if_slow(&self, op: SlowOp) {
+        if self
+            .slow_op_threshold
+            .is_some_and(|threshold| op.took >= threshold)
+        {
+            for hook in &self.slow_op_hooks {
+                hook(&op);
+            }
+        }
+    }
+
+    /// Returns a channel that's sent every write made to `key` from now on, inserts
+    /// and deletes alike, once it's committed. Dropping the receiver stops the
+    /// sends, though the hook behind it stays until the tree is closed.
+    pub fn watch(&mut self, key: K) -> Receiver<Change<K, V>>
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let key = self.fold(&key).into_owned();
+
+        self.watch_matching(move |changed| *changed == key)
+    }
+
+    fn watch_matching<F>(&mut self, matches: F) -> Receiver<Change<K, V>>
+    where
+        F: Fn(&K) -> bool + Send + Sync + 'static,
+        K: 'static,
+        V: 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        self.add_post_commit_hook(move |key, value, seq| {
+            if matches(key) {
+                let _ = sender.send(Change {
+                    key: key.clone(),
+                    value: value.clone(),
+                    seq,
+                });
+            }
+        });
+
+        receiver
+    }
+
+    /// Applies every write in `batch` at once: they're logged to the WAL together,
+    /// so a crash part way leaves none of them
+    pub fn write(&mut self, batch: WriteBatch<K, V>) -> Result<(), Box<dyn Error>> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let records = batch.into_records();
+        let ops = records.iter().map(|kv| audit_op(kv.kind)).collect();
+
+        self.write_records(records, ops, "")
+    }
+
+    /// The first phase of a two-phase commit: checks `batch` can be written, against
+    /// the quota and pre-write hooks, and makes it durable, but not visible. It's
+    /// applied by `commit` or thrown away by `rollback`, even after a crash; the
+    /// batches still waiting when the tree is opened are listed by `prepared`.
+    pub fn prepare(&mut self, batch: WriteBatch<K, V>) -> Result<PrepareToken, Box<dyn Error>> {
+        let mut records = batch.into_records();
+        self.fold_keys(&mut records);
+
+        let ops: Vec<AuditOp> = records.iter().map(|kv| audit_op(kv.kind)).collect();
+
+        self.admit(&records, &ops)?;
+
+        let log = match self.prepared_log.take() {
+            Some(log) => log,
+            None => PreparedLog::open(&*self.storage, &prepared_log_path(&self.tree_file_path))?,
+        };
+
+        self.prepared_log.insert(log).prepare(records)
+    }
+
+    /// Applies a prepared batch and syncs the WAL. The decision is recorded first, so
+    /// if a crash comes before the batch reaches the WAL it's applied when the tree
+    /// is opened again.
+    pub fn commit(&mut self, token: PrepareToken) -> Result<(), Box<dyn Error>> {
+        let first_seq = self.last_seq + 1;
+        let records = self.prepared_log_mut(token)?.commit(token, first_seq)?;
+        let ops = records.iter().map(|kv| audit_op(kv.kind)).collect();
+
+        self.commit_records(records, ops, "")?;
+        self.sync()?;
+
+        self.prepared_log_mut(token)?.clear_if_done()
+    }
+
+    /// Throws a prepared batch away
+    pub fn rollback(&mut self, token: PrepareToken) -> Result<(), Box<dyn Error>> {
+        let log = self.prepared_log_mut(token)?;
+
+        log.rollback(token)?;
+        log.clear_if_done()
+    }
+
+    /// The batches prepared and not yet committed or rolled back
+    pub fn prepared(&self) -> Vec<PrepareToken> {
+        self.prepared_log
+            .as_ref()
+            .map_or_else(Vec::new, |log| log.waiting())
+    }
+
+    fn prepared_log_mut(
+        &mut self,
+        token: PrepareToken,
+    ) -> Result<&mut PreparedLog<K, V>, Box<dyn Error>> {
+        match self.prepared_log.as_mut() {
+            Some(log) => Ok(log),
+            None => Err(From::from(format!("Batch {} isn't prepared", token.id()))),
+        }
+    }
+
+    /// Assigns the record the next sequence number and a timestamp, then logs and stores it
+    fn insert_record(
+        &mut self,
+        record: KeyValuePair<K, V>,
+        op: AuditOp,
+        actor: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_records(vec![record], vec![op], actor)
+    }
+
+    /// Logs `records`, made by the matching `ops`, as one write and stores them
+    fn write_records(
+        &mut self,
+        mut records: Vec<KeyValuePair<K, V>>,
+        mut ops: Vec<AuditOp>,
+        actor: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.fold_keys(&mut records);
+        self.cap_values(&mut records, &mut ops)?;
+        self.throttle()?;
+        self.admit(&records, &ops)?;
+
+        let written = self.lru.is_some().then(|| {
+            records
+                .iter()
+                .map(|kv| (kv.key.clone(), kv.kind == RecordKind::Put))
+                .collect()
+        });
+
+        self.commit_records(records, ops, actor)?;
+        self.track_lru(written.unwrap_or_default())
+    }
+
+    /// Stores the records under their folded keys, in a case-insensitive tree
+    fn fold_keys(&self, records: &mut [KeyValuePair<K, V>]) {
+        if let Some(fold_key) = self.fold_key {
+            for record in records {
+                record.key = fold_key(&record.key);
+            }
+        }
+    }
+
+    /// Holds the keys `records` insert into to `Options::value_cap`, deleting their
+    /// oldest values in the same write, or refusing it
+    fn cap_values(
+        &self,
+        records: &mut Vec<KeyValuePair<K, V>>,
+        ops: &mut Vec<AuditOp>,
+    ) -> Result<(), Box<dyn Error>> {
+        let cap = match self.value_cap {
+            Some(cap) => cap,
+            None => return Ok(()),
+        };
+
+        let keys: Vec<K> = records
+            .iter()
+            .filter(|kv| kv.kind == RecordKind::Put)
+            .map(|kv| kv.key.clone())
+            .sorted()
+            .dedup()
+            .collect();
+
+        for key in keys {
+            // the values the key will hold, oldest first; rewriting one makes it the newest
+            let mut values: Vec<(V, u64)> = self.live_values(&key, None)?.into_iter().collect();
+            values.sort_by_key(|(_, seq)| *seq);
+
+            for kv in records.iter().filter(|kv| kv.key == key) {
+                values.retain(|(value, _)| *value != kv.value);
+
+                if kv.kind == RecordKind::Put {
+                    values.push((kv.value.clone(), u64::MAX));
+                }
+            }
+
+            if values.len() <= cap.max_values {
+                continue;
+            }
+
+            if cap.when_full == CapPolicy::Reject {
+                return Err(Box::new(BTreeError::ValueCapReached {
+                    max_values: cap.max_values,
+                }));
+            }
+
+            for (value, _) in values.drain(..values.len() - cap.max_values) {
+                records.push(KeyValuePair {
+                    kind: RecordKind::Delete,
+                    ..KeyValuePair::new(key.clone(), value)
+                });
+                ops.push(AuditOp::Delete);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The key `key` is stored as
+    fn fold<'a>(&self, key: &'a K) -> Cow<'a, K> {
+        match self.fold_key {
+            Some(fold_key) => Cow::Owned(fold_key(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// The bounds of `range`, as the keys are stored
+    fn fold_range<R: RangeBounds<K>>(&self, range: &R) -> (Bound<K>, Bound<K>) {
+        let fold = |bound: Bound<&K>| bound.map(|key| self.fold(key).into_owned());
+
+        (fold(range.start_bound()), fold(range.end_bound()))
+    }
+
+    fn span<R: RangeBounds<K>>(&self, range: &R) -> KeySpan<K> {
+        let (start, end) = self.fold_range(range);
+
+        KeySpan::Range(start, end)
+    }
+
+    /// Checks that `records` may be written: against the quota, if they insert
+    /// anything, and with the pre-write hooks
+    fn admit(
+        &mut self,
+        records: &[KeyValuePair<K, V>],
+        ops: &[AuditOp],
+    ) -> Result<(), Box<dyn Error>> {
+        if ops.contains(&AuditOp::Insert) {
+            self.check_quota()?;
+        }
+
+        for record in records {
+            for hook in &self.pre_write_hooks {
+                hook(&record.key, &record.value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Assigns the records the next sequence numbers and a timestamp, then logs and
+    /// stores them
+    fn commit_records(
+        &mut self,
+        mut records: Vec<KeyValuePair<K, V>>,
+        ops: Vec<AuditOp>,
+        actor: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let (started, io) = (Instant::now(), io_stats::current());
+        let written_at = self.clock.now_millis();
+        let key_size = records
+            .iter()
+            .map(|kv| Codec::unbounded().serialized_size(&kv.key))
+            .try_fold(None, |max, size| {
+                size.map(|size| max.max(Some(size as usize)))
+            })?;
+        let count = records.len() as u64;
+
+        for (i, record) in records.iter_mut().enumerate() {
+            record.seq = self.last_seq + 1 + i as u64;
+            record.written_at = written_at;
+        }
+
+        let appended = self.wal_file.appended_len()?;
+        self.wal_file.insert_records(&records)?;
+        self.last_seq += records.len() as u64;
+        self.stats.writes += records.len() as u64;
+        self.stats.wal_bytes += self.wal_file.appended_len()? - appended;
+        self.sync_wal(written_at)?;
+
+        let mut size = self.mem_tree.size();
+        let mut audit_entries = Vec::new();
+        let mut committed = Vec::new(); // for the post-commit hooks
+
+        // the whole batch goes into the memtable before anything else can fail, so
+        // a failed audit write can't leave half of it out
+        for (record, op) in records.into_iter().zip(ops) {
+            if self.audit_log.is_some() {
+                audit_entries.push(AuditEntry {
+                    seq: record.seq,
+                    at: record.written_at,
+                    actor: actor.to_owned(),
+                    op,
+                    key: record.key.clone(),
+                    value: record.value.clone(),
+                });
+            }
+
+            if !self.post_commit_hooks.is_empty() {
+                committed.push((record.key.clone(), record.value.clone(), record.seq));
+            }
+
+            size = self.mem_tree.insert_record(record);
+        }
+
+        self.last_write_at = written_at;
+
+        for (key, value, seq) in &committed {
+            for hook in &self.post_commit_hooks {
+                hook(key, value, *seq);
+            }
+        }
+
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            audit_log.append(&audit_entries)?;
+        }
+
+        // replaying the WAL is what makes recovery slow, so its size is bounded too
+        let wal_full = match self.wal_flush_trigger {
+            Some(trigger) => self.wal_file.appended_len()? >= trigger,
+            None => false,
+        };
+
+        let compacted = if size > self.flush_threshold || wal_full || self.report_pending() {
+            self.flush_memtable()
+        } else {
+            self.maintain().map(|_| ())
+        };
+
+        // the write is committed either way, and a later one tries the flush again
+        match compacted {
+            Err(e) if e.downcast_ref::<BTreeError>() == Some(&BTreeError::Cancelled) => {}
+            compacted => compacted?,
+        }
+
+        self.stats.insert_latency.record(started.elapsed());
+        self.report_if_slow(SlowOp {
+            kind: SlowOpKind::Write,
+            took: started.elapsed(),
+            key_size,
+            records: count,
+            io: io_stats::current().since(io),
+        });
+
+        Ok(())
+    }
+
+    /// Tells the write buffer manager, if there is one, how much is waiting in the
+    /// memtable, and returns whether it says to flush
+    fn report_pending(&self) -> bool {
+        self.write_buffer
+            .as_ref()
+            .is_some_and(|share| share.report(self.pending_bytes()))
+    }
+
+    /// Syncs the WAL if the sync policy says a write made at `now` should be
+    fn sync_wal(&mut self, now: u64) -> Result<(), Box<dyn Error>> {
+        let due = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => {
+                now.saturating_sub(self.last_wal_sync) >= interval.as_millis() as u64
+            }
+        };
+
+        if due {
+            self.wal_file.sync()?;
+            self.last_wal_sync = now;
+            self.durable.advance(self.last_seq);
+        }
+
+        Ok(())
+    }
+
+    /// The options currently in force that `set_options()` can change
+    pub fn dynamic_options(&self) -> DynamicOptions {
+        DynamicOptions {
+            flush_threshold: self.flush_threshold,
+            sync_policy: self.sync_policy,
+            write_throttle: self.write_throttle,
+            compaction_rate_limit: self
+                .compaction_limiter
+                .as_ref()
+                .map(|limiter| limiter.bytes_per_sec()),
+            cache_size: self.block_cache.capacity(),
+        }
+    }
+
+    /// Changes options on the open BTree. A lower flush threshold or cache size takes
+    /// effect straight away, flushing the memtable or evicting blocks as needed. A
+    /// block cache shared with other trees is resized for all of them.
+    pub fn set_options(&mut self, options: DynamicOptions) -> Result<(), Box<dyn Error>> {
+        self.flush_threshold = options.flush_threshold;
+        self.sync_policy = options.sync_policy;
+        self.wal_file.set_coalescing(coalesces(self.sync_policy))?;
+        self.write_throttle = options.write_throttle;
+        self.block_cache.set_capacity(options.cache_size);
+
+        match (
+            options.compaction_rate_limit,
+            self.compaction_limiter.as_ref(),
+        ) {
+            (Some(rate), Some(limiter)) => limiter.set_bytes_per_sec(rate),
+            (Some(rate), None) => {
+                self.compaction_limiter = Some(RateLimiter::new(rate, self.clock.clone()))
+            }
+            (None, _) => self.compaction_limiter = None,
+        }
+
+        if self.mem_tree.size() > self.flush_threshold {
+            self.flush_memtable()?;
+        }
+
+        Ok(())
+    }
+
+    /// What opening the tree found in the WAL: how much was replayed, and what was
+    /// dropped after the last committed write
+    pub fn replay_summary(&self) -> ReplaySummary {
+        self.replay_summary
+    }
+
+    /// What opening the tree did to recover it: the writes replayed from the WAL,
+    /// the prepared batches finished, and what was cut off or cleaned up after a
+    /// crash. Handles opened read-only never change the files, so only replay.
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.recovery
+    }
+
+    /// The bytes held in the memtable waiting to be flushed
+    pub fn pending_bytes(&self) -> usize {
+        self.mem_tree.size() * self.record_size()
+    }
+
+    /// Counters of the work done since the tree was opened. The cache counters are
+    /// for the whole block cache, so include other trees sharing it.
+    pub fn stats(&self) -> Stats {
+        let (cache_hits, cache_misses) = self.block_cache.hits_and_misses();
+
+        Stats {
+            cache_hits,
+            cache_misses,
+            pending_bytes: self.pending_bytes(),
+            cache_bytes: self.block_cache.used(),
+            get_latency: *self.get_latency.lock().unwrap(),
+            compaction_progress: self.progress.current(),
+            ..self.stats
+        }
+    }
+
+    /// What to write along with new runs; the tree file may also get a hash index
+    fn file_options(&self) -> FileOptions<K> {
+        FileOptions {
+            filters: self.bloom_bits_per_key.map(|bits_per_key| FilterSettings {
+                bits_per_key,
+                prefix: self.prefix_bloom,
+            }),
+            hash_index: false,
+            prefix_compression: self.prefix_compression,
+            dictionary_size: self.compression_dictionary,
+            encoding: self.encoding,
+        }
+    }
+
+    /// The size of a single record in the WAL and tree files
+    fn record_size(&self) -> usize {
+        self.key_size + self.value_size + RECORD_OVERHEAD
+    }
+
+    /// Delays or refuses a write according to the write throttle, if there is one
+    fn throttle(&mut self) -> Result<(), Box<dyn Error>> {
+        let throttle = match self.write_throttle {
+            Some(throttle) => throttle,
+            None => return Ok(()),
+        };
+
+        let pending_bytes = self.pending_bytes();
+
+        // an overflow run is a quick sequential write, with none of a flush's merging
+        if pending_bytes >= throttle.hard_limit && self.spill_memtable {
+            self.stats.spills += 1;
+
+            return self.write_run(FileOptions {
+                filters: None,
+                hash_index: false,
+                prefix_compression: false,
+                dictionary_size: None,
+                encoding: self.encoding,
+            });
+        }
+
+        if pending_bytes >= throttle.hard_limit {
+            self.stats.stalled_writes += 1;
+            return Err(Box::new(BTreeError::Stalled { pending_bytes }));
+        }
+
+        if pending_bytes > throttle.soft_limit {
+            let over = (pending_bytes - throttle.soft_limit) as f64;
+            let range = (throttle.hard_limit - throttle.soft_limit) as f64;
+            let delay = throttle.max_delay.mul_f64(over / range);
+
+            self.clock.sleep(delay);
+            self.stats.stall_time += delay;
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `BTreeError::QuotaExceeded` if another record would take the files
+    /// past the quota, compacting first if the quota's policy says to
+    fn check_quota(&mut self) -> Result<(), Box<dyn Error>> {
+        let quota = match self.disk_quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        let mut disk_bytes = self.quota_bytes()?;
+
+        // only compact when there's something new to merge, or every rejected insert would
+        let compactable = self.mem_tree.size() > 0 || !self.runs.is_empty();
+
+        if disk_bytes > quota.max_bytes && quota.when_full == QuotaPolicy::Compact && compactable {
+            self.compact(CompactionJob::Manual)?;
+            disk_bytes = self.quota_bytes()?;
+        }
+
+        if disk_bytes > quota.max_bytes {
+            return Err(Box::new(BTreeError::QuotaExceeded {
+                disk_bytes,
+                max_bytes: quota.max_bytes,
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// What the files will take up with one more record in the WAL
+    fn quota_bytes(&self) -> Result<u64, Box<dyn Error>> {
+        let wal_bytes = self.wal_file.appended_len()? + self.wal_file.record_size() as u64;
+        let blob_bytes = self
+            .blob_store
+            .as_ref()
+            .map_or(Ok(0), |blob_store| blob_store.len())?;
+
+        Ok(self.flushed_bytes + wal_bytes + blob_bytes)
+    }
+
+    /// The bytes the tree's files take up on disk: the tree file, runs, WAL, blobs
+    /// and every sidecar
+    pub fn disk_size(&self) -> Result<u64, Box<dyn Error>> {
+        let wal_file_path = self.tree_file_path.to_owned() + ".wal";
+
+        self.files_size(&[wal_file_path, blob_store_path(&self.tree_file_path)])
+    }
+
+    /// Re-measures the files that only change in a flush or compaction, when there's
+    /// a quota to check them against
+    fn measure_flushed_files(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.disk_quota.is_some() {
+            self.flushed_bytes = self.files_size(&[])?;
+        }
+
+        Ok(())
+    }
+
+    /// The size of the tree file, runs, manifest, audit log and sidecars, plus `others`
+    fn files_size(&self, others: &[String]) -> Result<u64, Box<dyn Error>> {
+        let mut files = vec![self.tree_file_path.clone()];
+        files.extend(
+            self.runs
+                .iter()
+                .map(|run| Run::<K, V>::path(&self.tree_file_path, run.id)),
+        );
+
+        let mut paths: Vec<String> = files
+            .iter()
+            .flat_map(|file| SIDECARS.iter().map(move |sidecar| sidecar(file)))
+            .collect();
+        paths.extend(files);
+        paths.push(manifest_path(&self.tree_file_path));
+        paths.push(self.tree_file_path.to_owned() + ".audit");
+        paths.extend(others.iter().cloned());
+
+        let mut size = 0;
+
+        for path in paths {
+            if self.storage.exists(&path)? {
+                size += self.storage.open(&path)?.len()?;
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Empties a full memtable: into a new L0 run when flushes write runs, otherwise
+    /// straight into the tree file
+    fn flush_memtable(&mut self) -> Result<(), Box<dyn Error>> {
+        let (started, io) = (Instant::now(), io_stats::current());
+        let records = self.mem_tree.size() as u64;
+        self.stats.flushes += 1;
+
+        match self.l0_compaction_trigger {
+            Some(trigger) => {
+                self.write_run(self.file_options())?;
+
+                // every run is another file each read has to look in
+                if self.runs.len() > trigger {
+                    self.compact(CompactionJob::Flush)?;
+                }
+            }
+            None => self.compact(CompactionJob::Flush)?,
+        }
+
+        self.stats.flush_latency.record(started.elapsed());
+        self.report_if_slow(SlowOp {
+            kind: SlowOpKind::Flush,
+            took: started.elapsed(),
+            key_size: None,
+            records,
+            io: io_stats::current().since(io),
+        });
+
+        Ok(())
+    }
+
+    /// Writes the memtable out as it is to a new L0 run, with `file_options`, and
+    /// empties it and the WAL
+    fn write_run(&mut self, file_options: FileOptions<K>) -> Result<(), Box<dyn Error>> {
+        // the old writes kept for versioning aren't sorted yet
+        let mut superseded = self.mem_tree.superseded().to_vec();
+        superseded.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let id = self.runs.last().map_or(0, |run| run.id) + 1;
+        let mut run = Run::create(
+            &*self.storage,
+            &self.tree_file_path,
+            id,
+            self.key_size,
+            self.value_size,
+            file_options,
+            merge(&mut self.mem_tree, superseded),
+        )?;
+        seal_with_sidecars(
+            &*self.storage,
+            &Run::<K, V>::path(&self.tree_file_path, id),
+            0,
+        )?;
+
+        run.file.set_cache(self.block_cache.clone());
+        self.runs.push(run);
+        self.bump_generation()?;
+        self.save_manifest()?;
+
+        // everything in memory is now on disk
+        self.wal_file.truncate()?;
+        self.bump_generation()?;
+        self.mem_tree = new_mem_tree(&self.versioning, self.counting);
+        self.durable.advance(self.last_seq);
+        self.report_pending();
+        self.measure_flushed_files()
+    }
+
+    /// Moves the generation on, into or out of replacing files
+    fn bump_generation(&mut self) -> Result<(), Box<dyn Error>> {
+        self.generation += 1;
+        write_generation(&*self.storage, &self.tree_file_path, self.generation)
+    }
+
+    fn save_manifest(&self) -> Result<(), Box<dyn Error>> {
+        let ids: Vec<u64> = self.runs.iter().map(|run| run.id).collect();
+        write_manifest(&*self.storage, &self.tree_file_path, &ids)
+    }
+
+    /// Merges everything in memory and in L0 runs into the tree file, emptying the
+    /// memtable and WAL
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.compact(CompactionJob::Flush)
+    }
+
+    /// Compacts only the keys within `range`: their deleted, expired and
+    /// out-of-retention records are dropped, while writes to other keys stay in
+    /// memory. Useful after a burst of deletes in one part of the key space. Returns
+    /// false without touching anything when no key in memory falls in the range and
+    /// the range lies wholly outside the keys on disk.
+    pub fn compact_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<bool, Box<dyn Error>> {
+        self.compact_within(&self.fold_range(&range), CompactionJob::Manual)
+    }
+
+    /// Runs any sync, flush or compaction that has become due without waiting for
+    /// the next write: syncing writes an interval sync policy has held back once the
+    /// interval is up, flushing a memtable left idle for `Options::idle_flush`, and
+    /// reclaiming the space of records that have expired. Returns whether a flush or
+    /// compaction ran. Embedders with idle periods can call this from a timer, or
+    /// have a `MaintenanceTimer` do it.
+    pub fn maintain(&mut self) -> Result<bool, Box<dyn Error>> {
+        let now = self.clock.now_millis();
+
+        if coalesces(self.sync_policy) && self.durable.seq() < self.last_seq {
+            self.sync_wal(now)?;
+        }
+
+        let idle = self
+            .idle_flush
+            .is_some_and(|idle| now.saturating_sub(self.last_write_at) >= idle.as_millis() as u64);
+
+        if idle && self.mem_tree.size() > 0 {
+            self.flush_memtable()?;
+            return Ok(true);
+        }
+
+        let expired = self.disk_expiries.partition_point(|at| *at <= now)
+            + self
+                .runs
+                .iter()
+                .map(|run| run.expiries.partition_point(|at| *at <= now))
+                .sum::<usize>();
+
+        if self
+            .expired_compaction_trigger
+            .is_some_and(|trigger| expired >= trigger)
+        {
+            self.compact(CompactionJob::Expiry)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Reads the blocks and bloom filters covering the keys in `range`, in the tree
+    /// file and every run, into the block cache, so a freshly opened tree doesn't
+    /// serve its first lookups from disk. The zone maps and block indexes are kept
+    /// in memory from the open anyway. Returns how many blocks were read; a range
+    /// bigger than the cache only leaves its last blocks cached.
+    pub fn warm<R: RangeBounds<K>>(&self, range: R) -> Result<u64, Box<dyn Error>> {
+        let range = self.fold_range(&range);
+
+        self.disk_files().map(|file| file.warm(&range)).sum()
+    }
+
+    /// Returns all the unique values associated with `key`, from both memory and disk
+    pub fn get(&self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        let values = self.get_visible(key, None)?;
+
+        // in LRU cache mode a read makes the key the last to be evicted
+        if let (Some(lru), Some(_)) = (&self.lru, &values) {
+            lru.lock().unwrap().touch(&self.fold(key));
+        }
+
+        Ok(values)
+    }
+
+    /// Returns the values of `key`, or if it has none, calls `loader` for one and
+    /// inserts it, through the WAL like any write, so the next read finds it. An
+    /// error from `loader` is returned and nothing is inserted. Nothing here stops
+    /// two handles sharing the tree from loading the same key at once; only
+    /// `ConcurrentBTree::get_or_load` has a miss wait for a load already under way.
+    pub fn get_or_load<F>(&mut self, key: &K, loader: F) -> Result<Vec<V>, Box<dyn Error>>
+    where
+        F: FnOnce(&K) -> Result<V, Box<dyn Error>>,
+    {
+        if let Some(values) = self.get(key)? {
+            return Ok(values);
+        }
+
+        let value = loader(key)?;
+        self.insert(key.clone(), value.clone())?;
+
+        Ok(vec![value])
+    }
+
+    /// Like `get`, seeing only the writes `options` allow, and also returning what
+    /// the lookup read from the files if they ask for it: how many files it
+    /// touched, and the reads and bytes it took
+    pub fn get_with(
+        &self,
+        key: &K,
+        options: &ReadOptions,
+    ) -> Result<WithIo<Option<Vec<V>>>, Box<dyn Error>> {
+        let io = io_stats::current();
+        let values = match self.read_point(options)? {
+            None => self.get(key)?,
+            point => self.get_visible(key, point)?,
+        };
+
+        Ok((
+            values,
+            options
+                .collect_io_stats
+                .then(|| io_stats::current().since(io)),
+        ))
+    }
+
+    /// The point the reads `options` set up see the tree at, or None for the latest.
+    /// Reading at the durable point needs the writes it supersedes kept, as
+    /// versioning keeps them, or a value re-written since its last sync would be
+    /// missed.
+    fn read_point(&self, options: &ReadOptions) -> Result<Option<ReadPoint>, Box<dyn Error>> {
+        match options.consistency {
+            Consistency::Latest => Ok(None),
+            Consistency::DurableOnly if self.versioning.is_none() => Err(From::from(IOError::new(
+                ErrorKind::Unsupported,
+                "Reading only durable writes needs versioning turned on in the options",
+            ))),
+            Consistency::DurableOnly => Ok(Some(ReadPoint::Seq(self.durable.seq()))),
+            Consistency::SnapshotSeq(seq) => Ok(Some(ReadPoint::Seq(seq))),
+        }
+    }
+
+    /// Returns the values `key` had at a past point. Without versioning turned on,
+    /// a value that has been re-written since that point isn't found.
+    pub fn get_at(&self, key: &K, point: ReadPoint) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.get_visible(key, Some(point))
+    }
+
+    /// Returns the values of each of `keys`, in the same order. The keys are looked
+    /// up one at a time, just as `get` would, so it's the zone maps of `get` that
+    /// keep a file from being read for a key outside its blocks' key ranges.
+    pub fn multi_get(&self, keys: &[K]) -> Result<Vec<Option<Vec<V>>>, Box<dyn Error>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Returns a read-only view of the tree as it was right after the write with
+    /// sequence number `seq`
+    pub fn snapshot_at(&self, seq: u64) -> Snapshot<'_, K, V> {
+        Snapshot {
+            tree: self,
+            point: ReadPoint::Seq(seq),
+        }
+    }
+
+    /// Returns the value most recently written under `key`
+    pub fn get_latest(&self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        let values = self.live_values(key, None)?;
+
+        Ok(values
+            .into_iter()
+            .max_by_key(|(_, seq)| *seq)
+            .map(|(value, _)| value))
+    }
+
+    /// Returns the values under `key`, most recently written first
+    pub fn get_by_recency(&self, key: &K) -> Result<impl Iterator<Item = V>, Box<dyn Error>> {
+        let mut values: Vec<(V, u64)> = self.live_values(key, None)?.into_iter().collect();
+
+        values.sort_by_key(|(_, seq)| Reverse(*seq));
+
+        Ok(values.into_iter().map(|(value, _)| value))
+    }
+
+    /// Returns every retained write under `key`, newest first. Unless versioning is
+    /// turned on in the options this is just the latest write of each value.
+    pub fn get_versions(&self, key: &K) -> Result<Vec<Version<V>>, Box<dyn Error>> {
+        Ok(self
+            .records_for(key)?
+            .into_iter()
+            .map(|kv| Version {
+                value: kv.value,
+                kind: kv.kind,
+                seq: kv.seq,
+                written_at: kv.written_at,
+            })
+            .collect())
+    }
+
+    /// A hash of what the tree holds: every live key and its values, as `range`
+    /// reads them. It doesn't depend on how the writes are split between memory and
+    /// the files, or the order a key's values were written in, so two replicas or a
+    /// tree and its backup can be compared by their digests alone.
+    pub fn digest(&self) -> Result<u64, Box<dyn Error>> {
+        let codec = Codec::unbounded();
+        let mut hash = FNV_OFFSET;
+
+        // every piece goes in after its length, so pieces can't run into each other
+        let mut add = |bytes: &[u8]| {
+            hash = fnv1a_extend(hash, &(bytes.len() as u64).to_le_bytes());
+            hash = fnv1a_extend(hash, bytes);
+        };
+
+        for entry in self.try_range(..)? {
+            let (key, values) = entry?;
+
+            let mut values = values
+                .iter()
+                .map(|value| codec.serialize(value))
+                .collect::<Result<Vec<_>, _>>()?;
+            values.sort_unstable();
+
+            add(&codec.serialize(&key)?);
+            add(&(values.len() as u64).to_le_bytes());
+
+            for value in &values {
+                add(value);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// Returns the keys within `range` and their values, in key order. The files on
+    /// disk are read as the iterator is advanced, and it ends at a record that
+    /// can't be read.
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = (K, Vec<V>)> + '_, Box<dyn Error>> {
+        Ok(self.try_range(range)?.map_while(Result::ok))
+    }
+
+    /// Like `range`, but a record that can't be read is returned as an error, for
+    /// the reads whose results are written back or relied on being complete
+    pub fn try_range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = ReadEntry<K, V>> + '_, Box<dyn Error>> {
+        self.scan(self.span(&range), self.disk_files().collect(), None, None)
+    }
+
+    /// Like `range`, seeing only the writes `options` allow, and adding up what
+    /// advancing the iterator reads from the files if they ask for it, see
+    /// `IoCounted::io_stats`. The writes it sees are fixed when it's made, so a
+    /// cursor at a snapshot or the durable point doesn't pick up later ones.
+    pub fn range_with<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<IoCounted<'_, K, V>, Box<dyn Error>> {
+        let io = io_stats::current();
+        let point = self.read_point(options)?;
+        let items = self.scan(self.span(&range), self.disk_files().collect(), None, point)?;
+        let items = Box::new(items.map_while(Result::ok));
+
+        Ok(IoCounted::new(
+            items,
+            options
+                .collect_io_stats
+                .then(|| io_stats::current().since(io)),
+        ))
+    }
+
+    /// Like `range`, returning only the values that pass `predicate`, and the keys
+    /// with any. It's given each value's bincode encoding before the value is
+    /// decoded, so a scan that keeps few values doesn't pay to decode the rest.
+    pub fn range_where<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        predicate: ValuePredicate,
+    ) -> Result<impl Iterator<Item = (K, Vec<V>)> + '_, Box<dyn Error>> {
+        let items = self.scan(
+            self.span(&range),
+            self.disk_files().collect(),
+            Some(predicate),
+            None,
+        )?;
+
+        Ok(items.map_while(Result::ok))
+    }
+
+    /// Computes `agg` over the values of the keys in `range` as they're merged from
+    /// memory and disk, without collecting them. The zone maps find where the range
+    /// starts in each file, so only the blocks holding it are read. A record that
+    /// can't be read fails the aggregate rather than leaving it short.
+    pub fn aggregate<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        agg: Agg,
+    ) -> Result<Aggregate<V>, Box<dyn Error>> {
+        process_results(self.try_range(range)?, |entries| {
+            Aggregate::over(agg, entries.map(|(_, values)| values))
+        })
+    }
+
+    /// Merge-joins this tree with `other` as both are scanned in key order, keeping
+    /// the key and value pairs `op` says to, so neither tree is held in memory. A
+    /// record of either that can't be read is an error rather than the end of it.
+    pub fn combine<'a>(
+        &'a self,
+        other: &'a BTree<K, V>,
+        op: SetOp,
+    ) -> Result<impl Iterator<Item = ReadEntry<K, V>> + 'a, Box<dyn Error>> {
+        Ok(op.apply(self.try_range(..)?, other.try_range(..)?))
+    }
+
+    /// How `other` differs from this tree, key by key, found by scanning both in key
+    /// order. Empty when they hold the same keys and values, as replicas should. A
+    /// record of either that can't be read is an error, not a key gone missing.
+    pub fn diff<'a>(
+        &'a self,
+        other: &'a BTree<K, V>,
+    ) -> Result<impl Iterator<Item = ReadDiff<K, V>> + 'a, Box<dyn Error>> {
+        Ok(Diff::between(self.try_range(..)?, other.try_range(..)?))
+    }
+
+    /// Like `combine`, writing what's kept to a new tree at `tree_file_path`, with
+    /// this tree's key and value sizes, a key at a time. The new tree is returned
+    /// flushed.
+    pub fn combine_into(
+        &self,
+        other: &BTree<K, V>,
+        op: SetOp,
+        tree_file_path: &str,
+        options: Options,
+    ) -> Result<BTree<K, V>, Box<dyn Error>> {
+        let mut combined =
+            BTree::with_options(tree_file_path, self.key_size, self.value_size, options)?;
+
+        for entry in self.combine(other, op)? {
+            let (key, values) = entry?;
+            combined.insert_all(values.into_iter().map(|value| (key.clone(), value)))?;
+        }
+
+        combined.flush()?;
+
+        Ok(combined)
+    }
+
+    /// Splits the keys into new trees along `split_keys`, which must be in order, in
+    /// one pass over this tree: the first holds the keys before the first split key,
+    /// each next one those from its split key up to the one after. They're written
+    /// to `shard0`, `shard1` and so on in `output_dir`, which must exist, with this
+    /// tree's key and value sizes, and returned flushed.
+    pub fn split(
+        &self,
+        split_keys: &[K],
+        output_dir: &str,
+        options: Options,
+    ) -> Result<Vec<BTree<K, V>>, Box<dyn Error>> {
+        if split_keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(From::from(IOError::new(
+                ErrorKind::InvalidInput,
+                "The split keys must be in ascending order",
+            )));
+        }
+
+        let mut shards = (0..=split_keys.len())
+            .map(|i| {
+                let path = format!("{}/shard{}", output_dir, i);
+                BTree::with_options(&path, self.key_size, self.value_size, options.clone())
+            })
+            .collect::<Result<Vec<BTree<K, V>>, Box<dyn Error>>>()?;
+
+        let mut shard = 0;
+
+        for entry in self.try_range(..)? {
+            let (key, values) = entry?;
+
+            while shard < split_keys.len() && key >= split_keys[shard] {
+                shard += 1;
+            }
+
+            shards[shard].insert_all(values.into_iter().map(|value| (key.clone(), value)))?;
+        }
+
+        for shard in &mut shards {
+            shard.flush()?;
+        }
+
+        Ok(shards)
+    }
+
+    /// The tree file and the runs
+    fn disk_files(&self) -> impl Iterator<Item = &OnDiskBTree<K, V>> {
+        std::iter::once(&self.tree_file).chain(self.runs.iter().map(|run| &run.file))
+    }
+
+    /// Reads the keys in `span` from memory and `files`, with the values they have
+    /// at `point` (or now), and that pass `predicate` if there is one. Every write of
+    /// a value is judged alike, so leaving out the ones that fail doesn't change
+    /// what's live.
+    fn scan<'a>(
+        &'a self,
+        span: KeySpan<K>,
+        files: Vec<&'a OnDiskBTree<K, V>>,
+        predicate: Option<ValuePredicate>,
+        point: Option<ReadPoint>,
+    ) -> Result<impl Iterator<Item = ReadEntry<K, V>> + 'a, Box<dyn Error>> {
+        let span = Rc::new(span);
+        let mut sources: Vec<Box<dyn Iterator<Item = ReadRecord<K, V>> + 'a>> = Vec::new();
+
+        // an increment on its own says nothing of the count, so a tree of counters
+        // judges the values counted up to instead
+        let counting = self.counting;
+        let (write_predicate, count_predicate) = match counting {
+            Some(_) => (None, predicate),
+            None => (predicate, None),
+        };
+
+        for file in files {
+            let reads = io_stats::current().reads;
+            let start = file.partition_point(|key| span.before(key))?;
+            let touched = io_stats::current().reads > reads;
+            let span = span.clone();
+
+            if touched {
+                io_stats::count_file();
+            }
+
+            match write_predicate {
+                Some(predicate) => sources.push(Box::new(Touching::new(
+                    file.iter_from(start)
+                        .filtered(predicate)
+                        .take_while(
+                            move |record| !matches!(record, Ok(record) if span.after(record.key())),
+                        )
+                        .filter_map(|record| record.map(Filtered::kept).transpose()),
+                    touched,
+                ))),
+                None => sources.push(Box::new(Touching::new(
+                    file.iter_from(start)
+                        .take_while(move |kv| !matches!(kv, Ok(kv) if span.after(&kv.key))),
+                    touched,
+                ))),
+            }
+        }
+
+        let (start_span, end_span) = (span.clone(), span.clone());
+        sources.push(Box::new(
+            self.mem_tree
+                .iter()
+                .skip_while(move |kv| start_span.before(&kv.key))
+                .take_while(move |kv| !end_span.after(&kv.key))
+                .filter(move |kv| passes(write_predicate, &kv.value))
+                .map(Ok),
+        ));
+
+        // the old writes kept for versioning aren't sorted
+        let mut superseded: Vec<KeyValuePair<K, V>> = self
+            .mem_tree
+            .superseded()
+            .iter()
+            .filter(|kv| {
+                !span.before(&kv.key) && !span.after(&kv.key) && passes(write_predicate, &kv.value)
+            })
+            .cloned()
+            .collect();
+        superseded.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        sources.push(Box::new(superseded.into_iter().map(Ok)));
+
+        let now = match point {
+            Some(ReadPoint::Timestamp(millis)) => millis,
+            _ => self.clock.now_millis(),
+        };
+
+        let merged = sources
+            .into_iter()
+            .kmerge_by(read_before)
+            .peekable()
+            .batching(|records| {
+                let first = match records.next()? {
+                    Ok(kv) => kv,
+                    Err(e) => return Some(Err(e)),
+                };
+                let mut group = vec![first];
+
+                while let Some(Ok(kv)) =
+                    records.next_if(|kv| matches!(kv, Ok(kv) if kv.key == group[0].key))
+                {
+                    group.push(kv);
+                }
+
+                Some(Ok(group))
+            });
+
+        Ok(until_error(merged).filter_map(move |records| {
+            let mut records = match records {
+                Ok(records) => records,
+                Err(e) => return Some(Err(e)),
+            };
+            let key = records[0].key.clone();
+            newest_first(&mut records);
+
+            let values: Vec<V> = newest_per_value(records, point, counting)
+                .into_iter()
+                .filter(|kv| kv.is_live(now) && passes(count_predicate, &kv.value))
+                .map(|kv| kv.value)
+                .collect();
+
+            if values.is_empty() {
+                None
+            } else {
+                Some(Ok((key, values)))
+            }
+        }))
+    }
+
+    fn get_visible(
+        &self,
+        key: &K,
+        point: Option<ReadPoint>,
+    ) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        let (started, io) = (Instant::now(), io_stats::current());
+        let values = self.live_values(key, point)?;
+
+        self.get_latency.lock().unwrap().record(started.elapsed());
+        self.report_if_slow(SlowOp {
+            kind: SlowOpKind::Get,
+            took: started.elapsed(),
+            key_size: Some(Codec::unbounded().serialized_size(key)? as usize),
+            records: 0,
+            io: io_stats::current().since(io),
+        });
+
+        if values.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(values.into_keys().collect()))
+        }
+    }
+
+    /// Collects the values under `key` that are visible at `point` (or now), along
+    /// with the sequence number they were last written at
+    fn live_values(
+        &self,
+        key: &K,
+        point: Option<ReadPoint>,
+    ) -> Result<BTreeMap<V, u64>, Box<dyn Error>> {
+        // expiry is judged at the time being read, or now when reading by sequence number
+        let now = match point {
+            Some(ReadPoint::Timestamp(millis)) => millis,
+            _ => self.clock.now_millis(),
+        };
+
+        Ok(self
+            .current_records(key, point)?
+            .into_iter()
+            .filter(|kv| kv.is_live(now))
+            .map(|kv| (kv.value, kv.seq))
+            .collect())
+    }
+
+    /// The newest write of each value under `key` as of `point` (or now), which may
+    /// be a deletion or have expired
+    fn current_records(
+        &self,
+        key: &K,
+        point: Option<ReadPoint>,
+    ) -> Result<Vec<KeyValuePair<K, V>>, Box<dyn Error>> {
+        Ok(newest_per_value(
+            self.records_for(key)?,
+            point,
+            self.counting,
+        ))
+    }
+
+    /// The sequence number of the newest write under `key` still held in memory or
+    /// on disk
+    fn last_write_to(&self, key: &K) -> Result<Option<u64>, Box<dyn Error>> {
+        Ok(self.records_for(key)?.first().map(|kv| kv.seq))
+    }
+
+    /// Every write under `key` still held in memory or on disk, newest first
+    fn records_for(&self, key: &K) -> Result<Vec<KeyValuePair<K, V>>, Box<dyn Error>> {
+        let key = &*self.fold(key);
+        let mut records = Vec::new();
+
+        // a file counts as touched when the cache, zone map and filters didn't spare reading it
+        for file in self.disk_files() {
+            let reads = io_stats::current().reads;
+            records.extend(file.get(key)?);
+
+            if io_stats::current().reads > reads {
+                io_stats::count_file();
+            }
+        }
+
+        if let Some(mem_values) = self.mem_tree.get_with_meta(key) {
+            records.extend(mem_values.map(|(value, meta)| KeyValuePair {
+                key: key.clone(),
+                value: value.clone(),
+                kind: meta.kind,
+                seq: meta.seq,
+                written_at: meta.written_at,
+                expires_at: meta.expires_at,
+            }));
+        }
+
+        records.extend(
+            self.mem_tree
+                .superseded()
+                .iter()
+                .filter(|kv| kv.key == *key)
+                .cloned(),
+        );
+
+        newest_first(&mut records);
+
+        Ok(records)
+    }
+
+    /// Returns the audit log entries for `key`, oldest first
+    pub fn audit_for_key(&self, key: &K) -> Result<Vec<AuditEntry<K, V>>, Box<dyn Error>> {
+        self.audit()?.entries_for_key(&self.fold(key))
+    }
+
+    /// Returns the audit log entries made within `range` (milliseconds since the epoch)
+    pub fn audit_between(
+        &self,
+        range: Range<u64>,
+    ) -> Result<Vec<AuditEntry<K, V>>, Box<dyn Error>> {
+        self.audit()?.entries_between(range)
+    }
+
+    fn audit(&self) -> Result<&AuditLog<K, V>, Box<dyn Error>> {
+        self.audit_log.as_ref().ok_or_else(|| {
+            From::from(IOError::new(
+                ErrorKind::Unsupported,
+                "The audit log is not turned on in the options",
+            ))
+        })
+    }
+
+    /// Merges the records on disk with the records in memory
+    fn compact(&mut self, job: CompactionJob) -> Result<(), Box<dyn Error>> {
+        self.compact_within(&(..), job).map(|_| ())
+    }
+
+    /// Creates an empty file to write a new tree file to, beside the current one
+    fn create_new_tree_file(&self) -> Result<OnDiskBTree<K, V>, Box<dyn Error>> {
+        let new_tree_file_path = self.tree_file_path.to_owned() + ".new";
+
+        // a leftover from an interrupted compaction would otherwise be appended to
+        self.remove_new_tree_file()?;
+
+        OnDiskBTree::<K, V>::create(
+            &*self.storage,
+            &new_tree_file_path,
+            self.key_size,
+            self.value_size,
+            FileOptions {
+                hash_index: self.hash_index,
+                ..self.file_options()
+            },
+        )
+    }
+
+    /// Removes the file `create_new_tree_file` makes, and its sidecars
+    fn remove_new_tree_file(&self) -> Result<(), Box<dyn Error>> {
+        let new_tree_file_path = self.tree_file_path.to_owned() + ".new";
+
+        self.storage.remove_if_exists(&new_tree_file_path)?;
+
+        for sidecar in SIDECARS {
+            self.storage
+                .remove_if_exists(&sidecar(&new_tree_file_path))?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts a file written from `create_new_tree_file` in place of the tree file.
+    /// This leaves the generation odd, for the caller to move on once the rest of
+    /// the files are in place too.
+    fn replace_tree_file(
+        &mut self,
+        mut new_tree_file: OnDiskBTree<K, V>,
+        mut disk_expiries: Vec<u64>,
+    ) -> Result<(), Box<dyn Error>> {
+        let new_tree_file_path = self.tree_file_path.to_owned() + ".new";
+
+        disk_expiries.sort_unstable();
+
+        // the new file must be durable before it replaces the old one
+        new_tree_file.sync()?;
+
+        // readers wait until the new files are all in place
+        self.bump_generation()?;
+
+        // the old sidecars go first, so a crash can't leave them next to the new file
+        for sidecar in SIDECARS {
+            self.storage
+                .remove_if_exists(&sidecar(&self.tree_file_path))?;
+        }
+
+        self.storage
+            .rename(&new_tree_file_path, &self.tree_file_path)?;
+
+        for sidecar in SIDECARS {
+            if self.storage.exists(&sidecar(&new_tree_file_path))? {
+                self.storage.rename(
+                    &sidecar(&new_tree_file_path),
+                    &sidecar(&self.tree_file_path),
+                )?;
+            }
+        }
+        seal_with_sidecars(&*self.storage, &self.tree_file_path, 1)?;
+        new_tree_file.set_cache(self.block_cache.clone());
+        self.tree_file = new_tree_file;
+        self.disk_expiries = disk_expiries;
+
+        Ok(())
+    }
+
+    /// The settings for compacting the keys in `range` now
+    fn key_compaction(&self, range: (Bound<K>, Bound<K>)) -> KeyCompaction<'_, K, V> {
+        KeyCompaction {
+            range,
+            versioning: self.versioning.as_ref(),
+            purge_after: self.soft_delete_window.as_millis() as u64,
+            now: self.clock.now_millis(),
+            kept_since: self
+                .open_transactions
+                .as_ref()
+                .and_then(|open| open.oldest())
+                .unwrap_or(u64::MAX),
+            counting: self.counting,
+            value_cap: self.value_cap,
+        }
+    }
+
+    /// Compacts the records whose keys fall within `range`. Records on disk outside
+    /// the range are copied across as they are, and records in memory outside it
+    /// are written to a fresh WAL and kept in memory.
+    fn compact_within<R: RangeBounds<K>>(
+        &mut self,
+        range: &R,
+        job: CompactionJob,
+    ) -> Result<bool, Box<dyn Error>> {
+        let compacted = self.merge_within(range, job);
+
+        // nothing has been replaced yet, only the new file needs clearing away
+        if let Err(e) = &compacted {
+            if e.downcast_ref::<BTreeError>() == Some(&BTreeError::Cancelled) {
+                self.remove_new_tree_file()?;
+            }
+        }
+
+        compacted
+    }
+
+    /// Does the work of `compact_within`. A cancelled compaction returns before it
+    /// syncs the new tree file.
+    fn merge_within<R: RangeBounds<K>>(
+        &mut self,
+        range: &R,
+        job: CompactionJob,
+    ) -> Result<bool, Box<dyn Error>> {
+        // split what's in memory, including the old writes kept for versioning
+        let mut mem_records: Vec<KeyValuePair<K, V>> = (&mut self.mem_tree).into_iter().collect();
+        mem_records.extend(self.mem_tree.superseded().iter().cloned());
+
+        let (mut in_range, mut kept): (Vec<_>, Vec<_>) = mem_records
+            .into_iter()
+            .partition(|kv| range.contains(&kv.key));
+
+        let priority = match job {
+            CompactionJob::Flush => self.compaction.flush_priority,
+            CompactionJob::Expiry => self.compaction.expiry_priority,
+            CompactionJob::Manual => self.compaction.manual_priority,
+        };
+
+        // a low priority compaction's reads and writes are charged to the rate limiter
+        let limiter = match priority {
+            CompactionPriority::Low => self.compaction_limiter.as_ref(),
+            CompactionPriority::High => None,
+        };
+        let record_size = self.key_size + self.value_size + RECORD_OVERHEAD;
+        let charge = move || {
+            if let Some(limiter) = limiter {
+                limiter.request(record_size);
+            }
+        };
+
+        let on_disk = match self.tree_file.key_span()? {
+            Some((first, last)) => overlaps(range, &first, &last),
+            None => false,
+        };
+
+        // runs overlapping the range are merged down whole, the others are left be
+        let mut merged_runs = Vec::new();
+
+        for run in &self.runs {
+            if let Some((first, last)) = run.file.key_span()? {
+                if overlaps(range, &first, &last) {
+                    merged_runs.push(run.id);
+                    for kv in &run.file {
+                        charge();
+                        in_range.push(kv?);
+                    }
+                }
+            }
+        }
+
+        if in_range.is_empty() && !on_disk {
+            return Ok(false);
+        }
+
+        let (started, io) = (Instant::now(), io_stats::current());
+        let files = merged_runs.len() as u64 + u64::from(on_disk);
+        let total = (in_range.len() as u64 + self.tree_file.count()?) * record_size as u64;
+        let (progress, running) = (&*self.progress, self.progress.start(total));
+
+        let mut new_tree_file = self.create_new_tree_file()?;
+
+        in_range.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let key_compaction =
+            self.key_compaction((range.start_bound().cloned(), range.end_bound().cloned()));
+
+        // each sub-compaction takes the keys from one split key up to the next
+        let mut slices = Vec::new();
+        let (mut mem_start, mut disk_start) = (0, 0);
+
+        for key in self
+            .tree_file
+            .split_keys(self.compaction.max_subcompactions)?
+        {
+            let mem_end = in_range.partition_point(|kv| kv.key < key);
+            let disk_end = self.tree_file.lower_bound(&key)?;
+
+            slices.push((mem_start, mem_end, disk_start, disk_end));
+            mem_start = mem_end;
+            disk_start = disk_end;
+        }
+
+        slices.push((
+            mem_start,
+            in_range.len(),
+            disk_start,
+            self.tree_file.count()?,
+        ));
+
+        // get an iterator over a slice's items, in memory and on disk
+        let tree_file = &self.tree_file;
+        let in_range = &in_range;
+        let read = |(mem_start, mem_end, disk_start, disk_end): (usize, usize, u64, u64)| {
+            let disk_iter = tree_file
+                .iter_from(disk_start)
+                .take((disk_end - disk_start) as usize)
+                .inspect(move |_| charge());
+
+            in_range[mem_start..mem_end]
+                .iter()
+                .cloned()
+                .map(Ok)
+                .merge_by(disk_iter, read_before)
+                .take_while(move |_| !progress.cancelled())
+                .inspect(move |_| progress.advance(record_size as u64))
+        };
+
+        let mut disk_expiries = Vec::new();
+        let mut reported = 0;
+        let progress_hooks = &self.progress_hooks;
+        let mut write = |kv: KeyValuePair<K, V>| -> Result<(), Box<dyn Error>> {
+            if progress.cancelled() {
+                return Err(From::from(BTreeError::Cancelled));
+            }
+
+            charge();
+            new_tree_file.insert_record(&kv)?;
+            disk_expiries.extend(kv.expires_at);
+
+            if progress.done() >= reported + PROGRESS_STEP {
+                if let Some(current) = progress.current() {
+                    reported = current.bytes_done;
+                    progress_hooks.iter().for_each(|hook| hook(&current));
+                }
+            }
+
+            Ok(())
+        };
+
+        // a record that can't be read aborts the compaction, leaving the old files be
+        if slices.len() == 1 {
+            process_results(read(slices[0]), |records| -> Result<(), Box<dyn Error>> {
+                for kv in key_compaction.run(records) {
+                    write(kv)?;
+                }
+
+                Ok(())
+            })??;
+        } else {
+            let (key_compaction, read) = (&key_compaction, &read);
+
+            for batch in slices.chunks(self.compaction.max_jobs.max(1)) {
+                let mut outputs: Vec<_> = batch.iter().map(|_| None).collect();
+                let jobs = batch
+                    .iter()
+                    .zip(&mut outputs)
+                    .map(|(slice, output)| -> Job<'_> {
+                        Box::new(move || {
+                            let io = io_stats::current();
+                            let merged = process_results(read(*slice), |records| {
+                                key_compaction.run(records).collect::<Vec<_>>()
+                            });
+                            let merged = merged.map_err(|e| e.to_string());
+                            *output = Some((merged, io_stats::current().since(io)));
+                        })
+                    })
+                    .collect();
+
+                self.compaction_executor.run_all(jobs);
+
+                // the slices are in key order, so their outputs can be appended in turn
+                for output in outputs {
+                    let (output, read) = output.ok_or("a sub-compaction panicked")?;
+                    io_stats::add(read);
+
+                    for kv in output? {
+                        write(kv)?;
+                    }
+                }
+            }
+        }
+
+        // the last records read may have been cut short
+        if progress.cancelled() {
+            return Err(From::from(BTreeError::Cancelled));
+        }
+
+        // and once more with everything merged
+        if let Some(current) = progress.current() {
+            self.progress_hooks.iter().for_each(|hook| hook(&current));
+        }
+        drop(running);
+
+        let records = new_tree_file.count()?;
+        self.replace_tree_file(new_tree_file, disk_expiries)?;
+
+        // until the manifest is updated the merged runs are only duplicates
+        if !merged_runs.is_empty() {
+            self.runs.retain(|run| !merged_runs.contains(&run.id));
+            self.save_manifest()?;
+
+            for id in merged_runs {
+                Run::<K, V>::remove(&*self.storage, &self.tree_file_path, id)?;
+            }
+        }
+
+        // replaying them in the order they were written rebuilds any history
+        kept.sort_by_key(|kv| kv.seq);
+
+        if kept.is_empty() {
+            self.wal_file.truncate()?;
+        } else {
+            // a crash before the rename replays the old WAL, whose compacted
+            // records are then exact duplicates of the ones on disk
+            let wal_file_path = self.tree_file_path.to_owned() + ".wal";
+            let new_wal_file_path = wal_file_path.to_owned() + ".new";
+
+            if self.storage.exists(&new_wal_file_path)? {
+                self.storage.remove(&new_wal_file_path)?;
+            }
+
+            let mut new_wal_file = RecordFile::<K, V>::new_log(
+                &*self.storage,
+                &new_wal_file_path,
+                self.key_size,
+                self.value_size,
+            )?;
+            if self.wal_compression {
+                new_wal_file.set_compression(true);
+            }
+            new_wal_file.set_coalescing(coalesces(self.sync_policy))?;
+            new_wal_file.insert_records(&kept)?;
+
+            new_wal_file.sync()?;
+            self.storage.rename(&new_wal_file_path, &wal_file_path)?;
+            self.wal_file = new_wal_file;
+        }
+
+        self.bump_generation()?;
+        self.mem_tree = new_mem_tree(&self.versioning, self.counting);
+
+        for kv in kept {
+            self.mem_tree.insert_record(kv);
+        }
+
+        self.stats.compactions += 1;
+        self.stats.compaction_time += started.elapsed();
+        self.stats.compaction_latency.record(started.elapsed());
+
+        let mut io = io_stats::current().since(io);
+        io.files = files;
+        self.report_if_slow(SlowOp {
+            kind: SlowOpKind::Compaction,
+            took: started.elapsed(),
+            key_size: None,
+            records,
+            io,
+        });
+
+        // what wasn't compacted was rewritten to a synced WAL
+        self.durable.advance(self.last_seq);
+        self.report_pending();
+        self.measure_flushed_files()?;
+
+        Ok(true)
+    }
+}
+
+/// Wakes anyone still waiting on an `insert_async` write
+impl<K: KeyType, V: ValueType> Drop for BTree<K, V> {
+    fn drop(&mut self) {
+        self.durable.close();
+    }
+}
+
+impl<K: KeyType> BTree<K, Blob> {
+    /// Inserts `bytes` under `key`. Bytes too large to fit in a record are stored
+    /// once in a `.blobs` file however many keys they're inserted under, and the
+    /// record refers to them by their hash.
+    pub fn insert_blob(&mut self, key: K, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let blob = if bytes.len() + INLINE_OVERHEAD <= self.value_size {
+            Blob::Inline(bytes.to_vec())
+        } else {
+            self.blob_store_mut()?.put(bytes)?
+        };
+
+        self.insert(key, blob)
+    }
+
+    /// Returns a reader over each value associated with `key`, which read stored
+    /// blobs from disk as they go rather than cloning them into memory
+    pub fn get_streaming(&self, key: &K) -> Result<Option<Vec<BlobReader<'_>>>, Box<dyn Error>> {
+        match self.get(key)? {
+            Some(blobs) => Ok(Some(
+                blobs
+                    .into_iter()
+                    .map(|blob| self.blob_reader(blob))
+                    .collect::<Result<_, _>>()?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn blob_reader(&self, blob: Blob) -> Result<BlobReader<'_>, Box<dyn Error>> {
+        match (&self.blob_store, blob) {
+            (_, Blob::Inline(bytes)) => Ok(BlobReader::Inline(bytes, 0)),
+            (Some(store), blob) => store.reader(blob),
+            (None, _) => Err(From::from(IOError::new(
+                ErrorKind::NotFound,
+                "The blob store is missing",
+            ))),
+        }
+    }
+
+    /// Returns a writer that inserts the bytes written to it under `key` once it's
+    /// finished, so a large value never has to be held in memory whole
+    pub fn insert_streaming(&mut self, key: K) -> Result<BlobWriter<'_, K>, Box<dyn Error>> {
+        BlobWriter::new(self, key)
+    }
+
+    /// Removes `bytes` from the values associated with `key`. The stored blob
+    /// itself stays until `reclaim_blobs` finds nothing refers to it.
+    pub fn delete_blob(&mut self, key: K, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let blob = if bytes.len() + INLINE_OVERHEAD <= self.value_size {
+            Blob::Inline(bytes.to_vec())
+        } else {
+            match self
+                .blob_store
+                .as_ref()
+                .map(|store| store.find(bytes))
+                .transpose()?
+                .flatten()
+            {
+                Some(blob) => blob,
+                None => return Ok(()), // never stored, so never inserted
+            }
+        };
+
+        self.delete(key, blob)
+    }
+
+    /// Returns the bytes of every value associated with `key`
+    pub fn get_blobs(&self, key: &K) -> Result<Option<Vec<Vec<u8>>>, Box<dyn Error>> {
+        let blobs = match self.get(key)? {
+            Some(blobs) => blobs,
+            None => return Ok(None),
+        };
+
+        let mut values = Vec::with_capacity(blobs.len());
+
+        for blob in blobs {
+            let mut reader = self.blob_reader(blob)?;
+            let mut bytes = Vec::with_capacity(reader.remaining() as usize);
+
+            reader.read_to_end(&mut bytes)?;
+            values.push(bytes);
+        }
+
+        Ok(Some(values))
+    }
+
+    /// The blob store, opened on the first large value stored
+    fn blob_store_mut(&mut self) -> Result<&mut BlobStore, Box<dyn Error>> {
+        if self.value_size < STORED_SIZE {
+            return Err(From::from(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "A value size of {} can't refer to a stored blob",
+                    self.value_size
+                ),
+            )));
+        }
+
+        if self.blob_store.is_none() {
+            let path = blob_store_path(&self.tree_file_path);
+            self.blob_store = Some(BlobStore::open(&*self.storage, &path, true)?);
+        }
+
+        Ok(self.blob_store.as_mut().unwrap())
+    }
+
+    /// Rewrites the blob store without the blobs that no record in memory or on
+    /// disk refers to any more, returning how many bytes were freed. Deleted values
+    /// keep their blobs until compaction drops the records, so this is best run
+    /// after a `flush`.
+    pub fn reclaim_blobs(&mut self) -> Result<u64, Box<dyn Error>> {
+        let store = match self.blob_store.as_mut() {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+
+        let mut referenced = HashSet::new();
+        let records = self
+            .tree_file
+            .into_iter()
+            .chain(self.runs.iter().flat_map(|run| run.file.into_iter()))
+            .chain(self.mem_tree.iter().map(Ok))
+            .chain(self.mem_tree.superseded().iter().cloned().map(Ok));
+
+        // a blob is only dropped once every record has been read
+        for kv in records {
+            if let Blob::Stored { hash, slot, .. } = kv?.value {
+                referenced.insert((hash, slot));
+            }
+        }
+
+        let before = store.len()?;
+        store.rewrite(
+            &*self.storage,
+            &blob_store_path(&self.tree_file_path),
+            &referenced,
+        )?;
+
+        Ok(before - store.len()?)
+    }
+}
+
+impl<V: ValueType> BTree<String, V> {
+    /// Opens or creates a tree whose keys are case-insensitive: they're stored in
+    /// lowercase, so "Key" and "KEY" are the same key, and the keys given to reads,
+    /// ranges and scans are folded the same way. Whether a tree's keys are is
+    /// recorded when it's created, and it can only ever be opened the same way.
+    pub fn case_insensitive(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<BTree<String, V>, Box<dyn Error>> {
+        let fold_key: KeyFold<String> = |key| key.to_lowercase();
+        BTree::open_writer(
+            tree_file_path,
+            key_size,
+            value_size,
+            options,
+            Some(fold_key),
+            None,
+        )
+    }
+}
+
+impl<K: KeyType, V: ValueType + Counter> BTree<K, V> {
+    /// Opens or creates a tree of counters, which can be counted up by `increment`.
+    /// A key's count is its newest value; open the tree this way every time, as
+    /// otherwise its increments aren't added up.
+    pub fn counters(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<BTree<K, V>, Box<dyn Error>> {
+        BTree::open_writer(
+            tree_file_path,
+            key_size,
+            value_size,
+            options,
+            None,
+            Some(Counting::of()),
+        )
+    }
+
+    /// Adds `delta` to the count under `key`, starting from zero for a key with no
+    /// values. Only the increment is written, a single append to the WAL with no
+    /// read of the count; increments are added up when the key is read, and for good
+    /// when it's compacted.
+    pub fn increment(&mut self, key: K, delta: V) -> Result<(), Box<dyn Error>> {
+        if self.counting.is_none() {
+            return Err(From::from(IOError::new(
+                ErrorKind::Unsupported,
+                "Only a tree opened with `counters` can be incremented",
+            )));
+        }
+
+        let record = KeyValuePair {
+            kind: RecordKind::Merge,
+            ..KeyValuePair::new(key, delta)
+        };
+
+        self.insert_record(record, AuditOp::Increment, "")
+    }
+}
+
+impl<K: KeyType, V: ValueType + AsRef<[u8]>> BTree<K, V> {
+    /// Like `get`, returning each value as the bytes it's stored as, only decoded
+    /// when `LazyValue::value` is called, so a caller after one of many large values
+    /// doesn't pay to decode the rest. Values must be encoded as their length
+    /// followed by their bytes, as strings and byte vectors are.
+    pub fn get_lazy(&self, key: &K) -> Result<Option<Vec<LazyValue<V>>>, Box<dyn Error>> {
+        let key = &*self.fold(key);
+
+        // a value's bytes decode as a byte vector just as well
+        let mut records: Vec<KeyValuePair<K, Vec<u8>>> = self.tree_file.get_as(key)?;
+
+        for run in &self.runs {
+            records.extend(run.file.get_as(key)?);
+        }
+
+        if let Some(mem_values) = self.mem_tree.get_with_meta(key) {
+            records.extend(mem_values.map(|(value, meta)| KeyValuePair {
+                key: key.clone(),
+                value: value.as_ref().to_vec(),
+                kind: meta.kind,
+                seq: meta.seq,
+                written_at: meta.written_at,
+                expires_at: meta.expires_at,
+            }));
+        }
+
+        records.extend(
+            self.mem_tree
+                .superseded()
+                .iter()
+                .filter(|kv| kv.key == *key)
+                .map(|kv| KeyValuePair {
+                    key: key.clone(),
+                    value: kv.value.as_ref().to_vec(),
+                    kind: kv.kind,
+                    seq: kv.seq,
+                    written_at: kv.written_at,
+                    expires_at: kv.expires_at,
+                }),
+        );
+
+        newest_first(&mut records);
+
+        let now = self.clock.now_millis();
+        let values: Vec<LazyValue<V>> = newest_per_value(records, None, None)
+            .into_iter()
+            .filter(|kv| kv.is_live(now))
+            .map(|kv| LazyValue::new(kv.value))
+            .collect();
+
+        Ok(if values.is_empty() {
+            None
+        } else {
+            Some(values)
+        })
+    }
+
+    /// Returns the keys in `range` that have values, in key order, stepping over
+    /// the values on disk by their length rather than decoding them. Values must be
+    /// encoded as their length followed by their bytes, as strings and byte vectors
+    /// are. Only the keys with a delete or an expiry among their writes have their
+    /// values read, to tell whether any are left. The iterator ends after the
+    /// first error, reading or decoding, that it yields.
+    pub fn keys<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<K, Box<dyn Error>>> + '_, Box<dyn Error>> {
+        let span = Rc::new(self.span(&range));
+        let mut sources: Vec<Box<dyn Iterator<Item = ReadKeyRecord<K>> + '_>> = Vec::new();
+
+        for file in self.disk_files() {
+            let start = file.partition_point(|key| span.before(key))?;
+            let span = span.clone();
+
+            sources.push(Box::new(file.iter_from(start).keys().take_while(
+                move |record| !matches!(record, Ok(record) if span.after(&record.key)),
+            )));
+        }
+
+        let (start_span, end_span) = (span.clone(), span.clone());
+        sources.push(Box::new(
+            self.mem_tree
+                .key_records()
+                .skip_while(move |record| start_span.before(&record.key))
+                .take_while(move |record| !end_span.after(&record.key))
+                .map(Ok),
+        ));
+
+        let mut superseded: Vec<KeyRecord<K>> = self
+            .mem_tree
+            .superseded()
+            .iter()
+            .filter(|kv| !span.before(&kv.key) && !span.after(&kv.key))
+            .map(|kv| KeyRecord {
+                key: kv.key.clone(),
+                kind: kv.kind,
+                expires_at: kv.expires_at,
+            })
+            .collect();
+        superseded.sort_by(|a, b| a.key.cmp(&b.key));
+        sources.push(Box::new(superseded.into_iter().map(Ok)));
+
+        let merged = sources
+            .into_iter()
+            .kmerge_by(|a, b| match (a, b) {
+                (Ok(a), Ok(b)) => a.key < b.key,
+                (a, _) => a.is_err(),
+            })
+            .peekable()
+            .batching(|records| {
+                let first = match records.next()? {
+                    Ok(first) => first,
+                    Err(e) => return Some(Err(e)),
+                };
+                let mut group = vec![first];
+
+                while let Some(Ok(record)) = records
+                    .next_if(|record| matches!(record, Ok(record) if record.key == group[0].key))
+                {
+                    group.push(record);
+                }
+
+                Some(Ok(group))
+            });
+
+        Ok(until_error(merged).filter_map(move |group| {
+            let group = match group {
+                Ok(group) => group,
+                Err(e) => return Some(Err(e)),
+            };
+
+            // values that were only ever put, and never expire, can't have gone
+            let settled = group
+                .iter()
+                .all(|record| record.kind == RecordKind::Put && record.expires_at.is_none());
+            let key = group.into_iter().next()?.key;
+
+            if settled {
+                return Some(Ok(key));
+            }
+
+            // a check, not a get, so it's kept out of the get latencies and slow ops
+            match self.live_values(&key, None) {
+                Ok(values) if values.is_empty() => None,
+                Ok(_) => Some(Ok(key)),
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+}
+
+impl<K: KeyType + AsRef<[u8]>, V: ValueType> BTree<K, V> {
+    /// Returns the keys starting with `prefix` and their values, in key order. Keys
+    /// must sort the same as their bytes, as strings and byte vectors do.
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<impl Iterator<Item = (K, Vec<V>)> + '_, Box<dyn Error>> {
+        // only string keys are folded
+        let prefix = match (self.fold_key, std::str::from_utf8(prefix)) {
+            (Some(_), Ok(prefix)) => prefix.to_lowercase().into_bytes(),
+            _ => prefix.to_vec(),
+        };
+        let prefix = &prefix[..];
+        let mut files = Vec::new();
+
+        for file in self.disk_files() {
+            if file.may_contain_prefix(prefix, K::as_ref)? {
+                files.push(file);
+            }
+        }
+
+        let items = self.scan(
+            KeySpan::Prefix(prefix.to_vec(), K::as_ref),
+            files,
+            None,
+            None,
+        )?;
+
+        Ok(items.map_while(Result::ok))
+    }
+
+    /// Like `watch`, for every key starting with `prefix`
+    pub fn watch_prefix(&mut self, prefix: &[u8]) -> Receiver<Change<K, V>>
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let prefix = prefix.to_vec();
+
+        self.watch_matching(move |key| key.as_ref().starts_with(&prefix))
+    }
+
+    /// Builds prefix bloom filters over the first `prefix_len` bytes of each key
+    /// into the files written from now on, so `scan_prefix` can skip the files
+    /// without the prefix. Only prefixes at least this long benefit, and it needs
+    /// `bloom_bits_per_key` set in the options.
+    pub fn set_prefix_bloom(&mut self, prefix_len: Option<usize>) {
+        self.prefix_bloom = prefix_len.map(|len| (len, K::as_ref as KeyBytes<K>));
+    }
+}
+
+/// The keys a scan covers
+enum KeySpan<K> {
+    Range(Bound<K>, Bound<K>),
+    Prefix(Vec<u8>, KeyBytes<K>),
+}
+
+impl<K: KeyType> KeySpan<K> {
+    /// Whether `key` sorts before every key in the span
+    fn before(&self, key: &K) -> bool {
+        match self {
+            KeySpan::Range(Included(start), _) => key < start,
+            KeySpan::Range(Excluded(start), _) => key <= start,
+            KeySpan::Range(Unbounded, _) => false,
+            KeySpan::Prefix(prefix, key_bytes) => key_bytes(key) < &prefix[..],
+        }
+    }
+
+    /// Whether `key` sorts after every key in the span
+    fn after(&self, key: &K) -> bool {
+        match self {
+            KeySpan::Range(_, Included(end)) => key > end,
+            KeySpan::Range(_, Excluded(end)) => key >= end,
+            KeySpan::Range(_, Unbounded) => false,
+            KeySpan::Prefix(prefix, key_bytes) => {
+                let bytes = key_bytes(key);
+                bytes > &prefix[..] && !bytes.starts_with(prefix)
+            }
+        }
+    }
+}
+
+/// Sorts the writes of a key newest first. A WAL replayed after a crash can hold
+/// writes that are already on disk, so copies of a write are dropped.
+fn newest_first<K: KeyType, V: ValueType>(records: &mut Vec<KeyValuePair<K, V>>) {
+    records.sort_by_key(|kv| Reverse(kv.seq));
+    records.dedup_by_key(|kv| kv.seq);
+}
+
+/// The newest write of each value among a key's `records`, which are newest first,
+/// as of `point` (or now), with the increments up to then added up by `counting`
+fn newest_per_value<K: KeyType, V: ValueType>(
+    records: Vec<KeyValuePair<K, V>>,
+    point: Option<ReadPoint>,
+    counting: Option<Counting<V>>,
+) -> Vec<KeyValuePair<K, V>> {
+    let visible = |kv: &KeyValuePair<K, V>| match point {
+        None => true,
+        Some(ReadPoint::Seq(seq)) => kv.seq <= seq,
+        Some(ReadPoint::Timestamp(millis)) => kv.written_at <= millis,
+    };
+
+    let mut records: Vec<KeyValuePair<K, V>> = records.into_iter().filter(visible).collect();
+
+    if let Some(counting) = counting {
+        records = counting.fold(records);
+    }
+
+    let mut newest: BTreeMap<V, KeyValuePair<K, V>> = BTreeMap::new();
+
+    for kv in records {
+        newest.entry(kv.value.clone()).or_insert(kv);
+    }
+
+    newest.into_values().collect()
+}
+
+/// Why a compaction is running, which decides its priority
+#[derive(Clone, Copy)]
+enum CompactionJob {
+    Flush,
+    Expiry,
+    Manual,
+}
+
+/// The settings shared by the sub-compactions of one compaction
+struct KeyCompaction<'a, K, V> {
+    range: (Bound<K>, Bound<K>), // only keys in here are compacted, the rest are copied
+    versioning: Option<&'a VersionRetention>,
+    purge_after: u64,
+    now: u64,
+    kept_since: u64, // the newest write of each key after this seq is kept, tombstone or not
+    counting: Option<Counting<V>>,
+    value_cap: Option<ValueCap>,
+}
+
+impl<'a, K: KeyType, V: ValueType> KeyCompaction<'a, K, V> {
+    /// Compacts sorted records a key at a time
+    fn run<'b>(
+        &'b self,
+        records: impl Iterator<Item = KeyValuePair<K, V>> + 'b,
+    ) -> impl Iterator<Item = KeyValuePair<K, V>> + 'b
+    where
+        V: 'b,
+    {
+        // exact duplicates come from replaying a WAL that had already been compacted
+        records
+            .dedup()
+            .peekable()
+            .batching(|records| {
+                let first = records.next()?;
+                let mut group = vec![first];
+
+                while let Some(kv) = records.next_if(|kv| kv.key == group[0].key) {
+                    group.push(kv);
+                }
+
+                Some(group)
+            })
+            .flat_map(move |mut group| {
+                if self.range.contains(&group[0].key) {
+                    // the increments are added up newest first, then put back in order
+                    if let Some(counting) = self.counting {
+                        newest_first(&mut group);
+                        group = counting.fold(group);
+                        group.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    }
+
+                    let group = compact_key(
+                        group.into_iter(),
+                        self.versioning,
+                        self.purge_after,
+                        self.now,
+                        self.kept_since,
+                    );
+                    cap_key(group, self.value_cap)
+                } else {
+                    group
+                }
+            })
+    }
+}
+
+/// Whether `range` takes in any key between `first` and `last`
+fn overlaps<K: Ord, R: RangeBounds<K>>(range: &R, first: &K, last: &K) -> bool {
+    let starts_by_last = match range.start_bound() {
+        Included(start) => start <= last,
+        Excluded(start) => start < last,
+        Unbounded => true,
+    };
+
+    let ends_after_first = match range.end_bound() {
+        Included(end) => end >= first,
+        Excluded(end) => end > first,
+        Unbounded => true,
+    };
+
+    starts_by_last && ends_after_first
+}
+
+fn new_mem_tree<K: KeyType, V: ValueType>(
+    versioning: &Option<VersionRetention>,
+    counting: Option<Counting<V>>,
+) -> MultiMap<K, V> {
+    let mem_tree = match versioning {
+        Some(_) => MultiMap::with_history(),
+        None => MultiMap::new(),
+    };
+
+    mem_tree.counting(counting)
+}
+
+/// Whether `value` passes `predicate`, judged on its bincode encoding as it would
+/// be read from disk. Every value passes when there's no predicate.
+fn passes<V: Serialize>(predicate: Option<ValuePredicate>, value: &V) -> bool {
+    predicate.is_none_or(|predicate| {
+        Codec::unbounded()
+            .serialize(value)
+            .is_ok_and(|bytes| predicate(&bytes))
+    })
+}
+
+/// What a record written in a batch is, for the audit log
+fn audit_op(kind: RecordKind) -> AuditOp {
+    match kind {
+        RecordKind::Put => AuditOp::Insert,
+        RecordKind::Delete => AuditOp::Delete,
+        RecordKind::SoftDelete => AuditOp::SoftDelete,
+        RecordKind::Merge => AuditOp::Increment,
+    }
+}
+
+/// Whether the WAL holds writes back to append them together at the next sync. Only
+/// an interval sync policy does, as it already accepts losing the writes since the
+/// last sync.
+fn coalesces(sync_policy: SyncPolicy) -> bool {
+    matches!(sync_policy, SyncPolicy::Interval(_))
+}
+
+/// A record read from a file or memory by a scan or merge
+type ReadRecord<K, V> = Result<KeyValuePair<K, V>, Box<dyn Error>>;
+
+/// A key's record, as `keys` reads it without the value, or the error that ended the read
+type ReadKeyRecord<K> = Result<KeyRecord<K>, Box<dyn Error>>;
+
+/// A key and its values, as read back from the tree, or the error that ended the read
+type ReadEntry<K, V> = Result<(K, Vec<V>), Box<dyn Error>>;
+
+/// How a key differs between two trees, or the error that ended the read of one
+type ReadDiff<K, V> = Result<Diff<K, V>, Box<dyn Error>>;
+
+/// Orders the records a merge reads from its sources, a failed read first so it
+/// comes out as soon as it's read
+fn read_before<T: PartialOrd>(
+    a: &Result<T, Box<dyn Error>>,
+    b: &Result<T, Box<dyn Error>>,
+) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => a < b,
+        (Err(_), _) => true,
+        (Ok(_), Err(_)) => false,
+    }
+}
+
+/// Ends `items` after the first error among them, as the sources that didn't fail
+/// would otherwise carry on without the one that did
+fn until_error<T>(
+    items: impl Iterator<Item = Result<T, Box<dyn Error>>>,
+) -> impl Iterator<Item = Result<T, Box<dyn Error>>> {
+    let mut failed = false;
+
+    items.map_while(move |item| {
+        if failed {
+            return None;
+        }
+
+        failed = item.is_err();
+        Some(item)
+    })
+}
+
+/// Decides which records of a single key survive compaction. They arrive sorted by
+/// value and then newest first. The newest write of each value is kept unless it
+/// has expired, is a deletion, or is a soft deletion older than `purge_after`; in
+/// which case everything older goes with it, otherwise an older version would come
+/// back as the current one. Older writes are only kept when versioning is on.
+/// A dropped record written after `kept_since` stays on its own, for an optimistic
+/// transaction that started before it to find when it commits.
+fn compact_key<K: KeyType, V: ValueType>(
+    records: impl Iterator<Item = KeyValuePair<K, V>>,
+    versioning: Option<&VersionRetention>,
+    purge_after: u64,
+    now: u64,
+    kept_since: u64,
+) -> Vec<KeyValuePair<K, V>> {
+    let records: Vec<KeyValuePair<K, V>> = records.collect();
+
+    // used to rank each record by how recently it was written across the whole key
+    let mut seqs: Vec<u64> = records.iter().map(|kv| kv.seq).collect();
+    seqs.sort_unstable();
+
+    let mut cur_value: Option<V> = None;
+    let mut cur_dropped = false;
+
+    records
+        .into_iter()
+        .filter(|kv| {
+            let is_current = cur_value.as_ref() != Some(&kv.value);
+
+            if is_current {
+                cur_value = Some(kv.value.clone());
+                cur_dropped = kv.is_expired(now)
+                    || match kv.kind {
+                        RecordKind::Put => false,
+                        RecordKind::Delete => true,
+                        RecordKind::SoftDelete => now.saturating_sub(kv.written_at) >= purge_after,
+                        RecordKind::Merge => false,
+                    };
+            }
+
+            if cur_dropped {
+                return is_current && kv.seq > kept_since;
+            }
+
+            if is_current {
+                return true;
+            }
+
+            versioning.is_some_and(|retention| {
+                let newer = seqs.len() - seqs.partition_point(|seq| *seq <= kv.seq);
+                let young_enough = retention
+                    .max_age
+                    .is_none_or(|age| now.saturating_sub(kv.written_at) <= age.as_millis() as u64);
+
+                retention.max_versions.is_none_or(|max| newer < max) && young_enough
+            })
+        })
+        .collect()
+}
+
+/// Drops every record of the values of one key past `cap`, from the records
+/// `compact_key` kept: sorted by value and then newest first, each value held by
+/// the key when its newest record is a put
+fn cap_key<K: KeyType, V: ValueType>(
+    records: Vec<KeyValuePair<K, V>>,
+    cap: Option<ValueCap>,
+) -> Vec<KeyValuePair<K, V>> {
+    let cap = match cap {
+        Some(cap) => cap,
+        None => return records,
+    };
+
+    let mut held: Vec<(&V, u64)> = records
+        .iter()
+        .dedup_by(|a, b| a.value == b.value)
+        .filter(|kv| kv.kind == RecordKind::Put)
+        .map(|kv| (&kv.value, kv.seq))
+        .collect();
+
+    if held.len() <= cap.max_values {
+        return records;
+    }
+
+    // the values kept come first
+    match cap.when_full {
+        CapPolicy::EvictOldest => held.sort_by_key(|(_, seq)| Reverse(*seq)),
+        CapPolicy::Reject => held.sort_by_key(|(_, seq)| *seq),
+    }
+
+    let dropped: BTreeSet<V> = held[cap.max_values..]
+        .iter()
+        .map(|(value, _)| (*value).clone())
+        .collect();
+
+    records
+        .into_iter()
+        .filter(|kv| !dropped.contains(&kv.value))
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use block_filters::filter_path;
+    use legacy::set_aside;
+    use rand::distributions::Alphanumeric;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use read_only::write_generation;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use wal_file::{FRAME_OVERHEAD, RECORD_OVERHEAD};
+    use Clock;
+    use {
+        Agg, Aggregate, AuditOp, BTree, BTreeError, Blob, BlockCache, CapPolicy, Change,
+        CompactionExecutor, CompactionOptions, CompactionPriority, Consistency, Diff, DiskQuota,
+        Encoding, IoStats, Job, ManualClock, Options, QuotaPolicy, ReadOnlyBTree, ReadOptions,
+        ReadPoint, RecordKind, RecoveryReport, RepairReport, ReplaySummary, SetOp, SimDisk,
+        SlowOpKind, Storage, SyncPolicy, ThreadExecutor, TreeFileReader, ValueCap, Version,
+        VersionRetention, WriteBatch, WriteBufferManager, WriteThrottle, FORMAT_VERSION,
+        MAX_MEMORY_ITEMS,
+    };
+
+    pub fn gen_temp_name() -> String {
+        let file_name: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .map(char::from)
+            .collect();
+
+        String::from("/tmp/") + &file_name + &String::from(".btr")
+    }
+
+    fn remove_files(file_path: String) {
+        fs::remove_file(&file_path);
+        fs::remove_file(file_path + ".wal");
+    }
+
+    #[test]
+    fn new_blank_file() {
+        let file_path = gen_temp_name();
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // make sure our two files were created
+        let btf = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(&file_path)
+            .unwrap();
+        assert_eq!(btf.metadata().unwrap().len(), 0);
+
+        let wal = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(file_path.to_owned() + ".wal")
+            .unwrap();
+        assert_eq!(wal.metadata().unwrap().len(), 0);
+
+        // make sure they think they're new too
+        assert!(btree.wal_file.is_new().unwrap());
+        assert_eq!(btree.wal_file.count().unwrap(), 0);
+
+        assert!(btree.tree_file.is_new().unwrap());
+        assert_eq!(btree.tree_file.count().unwrap(), 0);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn new_existing_file() {
+        let file_path = gen_temp_name();
+
+        // scoped so it is cleaned up
+        {
+            BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+        }
+
+        let btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        // check our file lengths from the struct
+        assert_eq!(btree.tree_file.count().unwrap(), 0);
+        assert_eq!(btree.wal_file.count().unwrap(), 0);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn insert_new_u8() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<u8, u8>::new(&file_path, 1, 1).unwrap();
+
+        btree.insert(2, 3).unwrap(); // insert into a new file
+
+        assert_eq!(btree.wal_file.count().unwrap(), 1);
+        assert!(btree.mem_tree.contains_key(&2));
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn insert_new_str() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // insert into a new file
+        btree
+            .insert("Hello".to_owned(), "World".to_owned())
+            .unwrap();
+
+        assert!(!btree.wal_file.is_new().unwrap());
+        assert!(btree.mem_tree.contains_key(&String::from("Hello")));
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn get_returns_an_iter() {
+        let file_path = gen_temp_name();
+
+        // setup tree
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // expected return set
+        let mut expected: BTreeSet<String> = BTreeSet::new();
+        expected.insert("World".to_string());
+
+        btree.insert("Hello".to_owned(), "World".to_owned());
+
+        // get the set at the hello key
+        let set_at_hello: Vec<String> = btree.get(&"Hello".to_string()).unwrap().unwrap();
+
+        assert_eq!(set_at_hello, ["World".to_string()]);
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn insert_multiple() {
+        let file_path = gen_temp_name();
+
+        let mut btree = BTree::<String, String>::new(&file_path, 15, 15).unwrap();
+
+        // insert into a new file
+        btree
+            .insert("Hello".to_owned(), "World".to_owned())
+            .unwrap();
+        assert!(!btree.wal_file.is_new().unwrap());
+
+        btree
+            .insert("Hello".to_owned(), "Everyone".to_owned())
+            .unwrap();
+        assert!(!btree.wal_file.is_new().unwrap());
+
+        remove_files(file_path); // remove files assuming it all went well
+    }
+
+    #[test]
+    fn get_after_compaction() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..=MAX_MEMORY_ITEMS as u32 {
+            btree.insert(i, i * 2).unwrap();
+        }
+
+        // everything has been moved out of memory and onto disk
+        assert_eq!(btree.mem_tree.size(), 0);
+        assert_eq!(btree.wal_file.count().unwrap(), 0);
+        assert_eq!(
+            btree.tree_file.count().unwrap(),
+            MAX_MEMORY_ITEMS as u64 + 1
+        );
+
+        btree.insert(7, 1).unwrap();
+
+        assert_eq!(btree.get(&7).unwrap(), Some(vec![1, 14]));
+        assert_eq!(btree.get(&500).unwrap(), Some(vec![1000]));
+        assert_eq!(btree.get(&5000).unwrap(), None);
+    }
+
+    #[test]
+    fn compacted_records_survive_a_crash() {
+        let disk = SimDisk::new(3);
+
+        {
+            let options = Options {
+                storage: Arc::new(disk.clone()),
+                ..Options::default()
+            };
+            let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+            for i in 0..=MAX_MEMORY_ITEMS as u32 {
+                btree.insert(i, i).unwrap();
+            }
+        }
+
+        disk.crash();
+
+        let options = Options {
+            storage: Arc::new(disk),
+            ..Options::default()
+        };
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..=MAX_MEMORY_ITEMS as u32 {
+            assert_eq!(btree.get(&i).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn compactions_that_fail_to_read_the_tree_file_leave_it_in_place() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..1000 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        for i in 1000..1100 {
+            btree.insert(i, i).unwrap();
+        }
+
+        // the read fails partway through the merge, which must not install what it
+        // had read by then
+        let tree_file = disk.contents("db").unwrap();
+        disk.fail_read("db", 10);
+        assert!(btree.flush().is_err());
+        assert_eq!(disk.contents("db").unwrap(), tree_file);
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 1100);
+        for i in 0..1100 {
+            assert_eq!(btree.get(&i).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn wal_is_replayed_on_open() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk),
+            ..Options::default()
+        };
+
+        {
+            let mut btree = BTree::<u8, u8>::with_options("db", 1, 1, options.clone()).unwrap();
+            btree.insert(1, 2).unwrap();
+        }
+
+        let btree = BTree::<u8, u8>::with_options("db", 1, 1, options).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn ttl_values_expire() {
+        let clock = ManualClock::new(1_000);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        btree.insert(1, 1).unwrap();
+        btree
+            .insert_with_ttl(1, 2, Duration::from_secs(10))
+            .unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![1, 2]));
+
+        clock.advance(Duration::from_secs(10));
+
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![1]));
+
+        // re-inserting without a TTL makes the value permanent again
+        btree.insert(1, 2).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn compaction_drops_expired_values() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        btree
+            .insert_with_ttl(0, 0, Duration::from_millis(5))
+            .unwrap();
+        clock.advance(Duration::from_millis(5));
+
+        for i in 1..=MAX_MEMORY_ITEMS as u32 {
+            btree.insert(i, i).unwrap();
+        }
+
+        assert_eq!(btree.tree_file.count().unwrap(), MAX_MEMORY_ITEMS as u64);
+        assert_eq!(btree.get(&0).unwrap(), None);
+    }
+
+    #[test]
+    fn get_latest_follows_write_order() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        assert_eq!(btree.get_latest(&1).unwrap(), None);
+
+        btree.insert(1, 30).unwrap();
+        btree.insert(1, 10).unwrap();
+        btree.insert(1, 20).unwrap();
+
+        assert_eq!(btree.get_latest(&1).unwrap(), Some(20));
+        assert_eq!(
+            btree.get_by_recency(&1).unwrap().collect::<Vec<_>>(),
+            [20, 10, 30]
+        );
+
+        // re-writing a value makes it the newest again
+        btree.insert(1, 30).unwrap();
+        assert_eq!(btree.get_latest(&1).unwrap(), Some(30));
+
+        // push everything to disk and make sure the order survives a reopen
+        for i in 2..=MAX_MEMORY_ITEMS as u32 {
+            btree.insert(i, i).unwrap();
+        }
+        drop(btree);
+
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        btree.insert(1, 10).unwrap();
+
+        assert_eq!(
+            btree.get_by_recency(&1).unwrap().collect::<Vec<_>>(),
+            [10, 30, 20]
+        );
+    }
+
+    fn versioned_tree(clock: &ManualClock, retention: VersionRetention) -> BTree<u32, u32> {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            versioning: Some(retention),
+            ..Options::default()
+        };
+
+        BTree::with_options("db", 4, 4, options).unwrap()
+    }
+
+    // pushes everything in memory to disk using keys that won't collide with the test's
+    fn force_compaction(btree: &mut BTree<u32, u32>) {
+        for i in 0..=MAX_MEMORY_ITEMS as u32 {
+            btree.insert(1_000_000 + i, 0).unwrap();
+
+            if btree.mem_tree.size() == 0 {
+                return;
+            }
+        }
+        panic!("no compaction happened");
+    }
+
+    #[test]
+    fn versions_are_kept_through_compaction() {
+        let clock = ManualClock::new(0);
+        let mut btree = versioned_tree(&clock, VersionRetention::default());
+
+        btree.insert(1, 10).unwrap();
+        clock.advance(Duration::from_millis(1));
+        btree.insert(1, 10).unwrap();
+
+        force_compaction(&mut btree);
+        btree.insert(1, 10).unwrap();
+
+        let seqs: Vec<u64> = btree
+            .get_versions(&1)
+            .unwrap()
+            .iter()
+            .map(|v| v.seq)
+            .collect();
+        assert_eq!(seqs, [btree.last_seq, 2, 1]);
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![10]));
+
+        // without versioning only the latest write of each value is kept
+        let mut plain = BTree::<u32, u32>::with_options(
+            "db",
+            4,
+            4,
+            Options {
+                storage: Arc::new(SimDisk::new(0)),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        plain.insert(1, 10).unwrap();
+        plain.insert(1, 10).unwrap();
+
+        assert_eq!(plain.get_versions(&1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retention_limits_versions() {
+        let clock = ManualClock::new(0);
+        let retention = VersionRetention {
+            max_versions: Some(2),
+            max_age: None,
+        };
+        let mut btree = versioned_tree(&clock, retention);
+
+        for value in [10, 10, 10, 20] {
+            btree.insert(1, value).unwrap();
+        }
+
+        force_compaction(&mut btree);
+
+        // the newest two writes of the key, the current 20 and 10
+        let versions = btree.get_versions(&1).unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.value, v.seq))
+                .collect::<Vec<_>>(),
+            [(20, 4), (10, 3)]
+        );
+    }
+
+    #[test]
+    fn retention_limits_age() {
+        let clock = ManualClock::new(0);
+        let retention = VersionRetention {
+            max_versions: None,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        let mut btree = versioned_tree(&clock, retention);
+
+        btree.insert(1, 10).unwrap();
+        clock.advance(Duration::from_secs(30));
+        btree.insert(1, 10).unwrap();
+        clock.advance(Duration::from_secs(40));
+        btree.insert(1, 10).unwrap();
+
+        force_compaction(&mut btree);
+
+        let versions = btree.get_versions(&1).unwrap();
+        assert_eq!(
+            versions,
+            [
+                Version {
+                    value: 10,
+                    kind: RecordKind::Put,
+                    seq: 3,
+                    written_at: 70_000
+                },
+                Version {
+                    value: 10,
+                    kind: RecordKind::Put,
+                    seq: 2,
+                    written_at: 30_000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_at_a_past_point() {
+        let clock = ManualClock::new(0);
+        let mut btree = versioned_tree(&clock, VersionRetention::default());
+
+        btree.insert(1, 10).unwrap(); // seq 1
+        clock.advance(Duration::from_secs(1));
+        btree
+            .insert_with_ttl(1, 20, Duration::from_secs(5))
+            .unwrap(); // seq 2
+        clock.advance(Duration::from_secs(1));
+        btree.insert(1, 20).unwrap(); // seq 3, no longer expires
+
+        let snapshot = btree.snapshot_at(1);
+        assert_eq!(snapshot.get(&1).unwrap(), Some(vec![10]));
+        assert_eq!(btree.get_at(&1, ReadPoint::Seq(0)).unwrap(), None);
+
+        force_compaction(&mut btree);
+        clock.advance(Duration::from_secs(10));
+
+        // at seq 2 the TTL'd write was the newest, and it has expired since
+        assert_eq!(btree.get_at(&1, ReadPoint::Seq(2)).unwrap(), Some(vec![10]));
+        assert_eq!(
+            btree.get_at(&1, ReadPoint::Timestamp(1_500)).unwrap(),
+            Some(vec![10, 20])
+        );
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![10, 20]));
+    }
+
+    #[test]
+    fn audit_log_records_writes() {
+        let clock = ManualClock::new(5);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            audit: true,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        btree.insert_audited(1, 10, "alice").unwrap();
+        clock.advance(Duration::from_millis(10));
+        btree.insert(2, 20).unwrap();
+        drop(btree);
+
+        // the audit log outlives the tree being reopened
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        let entries = btree.audit_for_key(&1).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].actor, "alice");
+        assert_eq!(
+            (entries[0].seq, entries[0].at, entries[0].value),
+            (1, 5, 10)
+        );
+
+        let later: Vec<u32> = btree
+            .audit_between(10..20)
+            .unwrap()
+            .iter()
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(later, [2]);
+
+        let plain = BTree::<u32, u32>::with_options(
+            "other",
+            4,
+            4,
+            Options {
+                storage: Arc::new(SimDisk::new(0)),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert!(plain.audit_for_key(&1).is_err());
+    }
+
+    #[test]
+    fn delete_hides_values() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            audit: true,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        btree.insert(1, 10).unwrap();
+        btree.insert(1, 20).unwrap();
+        force_compaction(&mut btree);
+
+        btree.delete_audited(1, 10, "bob").unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![20]));
+
+        // the deletion is gone after compaction, along with the value
+        force_compaction(&mut btree);
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![20]));
+        assert_eq!(btree.get_versions(&1).unwrap().len(), 1);
+
+        let ops: Vec<AuditOp> = btree
+            .audit_for_key(&1)
+            .unwrap()
+            .iter()
+            .map(|e| e.op)
+            .collect();
+        assert_eq!(ops, [AuditOp::Insert, AuditOp::Insert, AuditOp::Delete]);
+    }
+
+    #[test]
+    fn soft_delete_and_undelete() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        btree.insert(1, 10).unwrap();
+        btree
+            .insert_with_ttl(1, 20, Duration::from_secs(600))
+            .unwrap();
+
+        assert!(btree.soft_delete(&1).unwrap());
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert!(!btree.soft_delete(&1).unwrap());
+
+        // still restorable after a compaction within the window, TTL included
+        force_compaction(&mut btree);
+        assert!(btree.undelete(&1).unwrap());
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![10, 20]));
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![10]));
+
+        // once the window has passed compaction purges it for good
+        btree.soft_delete(&1).unwrap();
+        clock.advance(Duration::from_secs(60));
+        force_compaction(&mut btree);
+
+        assert!(!btree.undelete(&1).unwrap());
+        assert_eq!(btree.get(&1).unwrap(), None);
+    }
+
+    #[test]
+    fn soft_deletes_survive_a_crash_whole_or_not_at_all() {
+        for seed in 0..16 {
+            let disk = SimDisk::new(seed);
+            let options = Options {
+                storage: Arc::new(disk.clone()),
+                sync_policy: SyncPolicy::Never,
+                ..Options::default()
+            };
+
+            {
+                let mut btree =
+                    BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+                btree.insert_all((0..8).map(|i| (1, i))).unwrap();
+                btree.flush().unwrap();
+
+                // the crash takes a random part of what hasn't been synced with it
+                btree.soft_delete(&1).unwrap();
+            }
+
+            disk.crash();
+
+            let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+            let values = btree.get(&1).unwrap();
+            assert!(values.is_none() || values == Some((0..8).collect()));
+        }
+    }
+
+    #[test]
+    fn expired_records_trigger_compaction() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            expired_compaction_trigger: Some(10),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..10 {
+            btree
+                .insert_with_ttl(i, i, Duration::from_secs(i as u64 + 1))
+                .unwrap();
+        }
+        force_compaction(&mut btree);
+        let on_disk = btree.tree_file.count().unwrap();
+
+        // only 9 have expired, not enough yet
+        clock.advance(Duration::from_secs(9));
+        assert!(!btree.maintain().unwrap());
+
+        // the 10th expiring comes from a reopened tree, so the schedule is rebuilt on open
+        drop(btree);
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        clock.advance(Duration::from_secs(1));
+        btree.insert(5000, 1).unwrap();
+
+        assert_eq!(btree.tree_file.count().unwrap(), on_disk - 10 + 1);
+        assert!(btree.disk_expiries.is_empty());
+    }
+
+    #[test]
+    fn writes_are_throttled() {
+        let clock = ManualClock::new(0);
+        let record_size = 4 + 4 + RECORD_OVERHEAD;
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            write_throttle: Some(WriteThrottle {
+                soft_limit: 2 * record_size,
+                hard_limit: 6 * record_size,
+                max_delay: Duration::from_millis(400),
+            }),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        // no delay up to the soft limit
+        for i in 0..3 {
+            btree.insert(i, i).unwrap();
+        }
+        assert_eq!(clock.now_millis(), 0);
+
+        let err = btree.try_insert(3, 3).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::Busy {
+                pending_bytes: 3 * record_size
+            })
+        );
+
+        // the delay grows the further past the soft limit we are
+        btree.insert(3, 3).unwrap();
+        assert_eq!(clock.now_millis(), 100);
+        btree.insert(4, 4).unwrap();
+        assert_eq!(clock.now_millis(), 300);
+        btree.insert(5, 5).unwrap();
+        assert_eq!(clock.now_millis(), 600);
+
+        let err = btree.insert(6, 6).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BTreeError>(),
+            Some(BTreeError::Stalled { .. })
+        ));
+        assert_eq!(btree.get(&6).unwrap(), None);
+
+        // flushing lets writes through again
+        btree.flush().unwrap();
+        btree.insert(6, 6).unwrap();
+        assert_eq!(btree.get(&6).unwrap(), Some(vec![6]));
+    }
+
+    #[test]
+    fn full_memtables_spill_to_overflow_runs() {
+        let record_size = 4 + 4 + RECORD_OVERHEAD;
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            write_throttle: Some(WriteThrottle {
+                soft_limit: 4 * record_size,
+                hard_limit: 4 * record_size,
+                max_delay: Duration::from_millis(0),
+            }),
+            spill_memtable: true,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        // rather than stalling, a write that finds four waiting spills them to a run first
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+        assert_eq!(btree.stats().spills, 2);
+        assert_eq!(btree.stats().stalled_writes, 0);
+        assert_eq!(btree.pending_bytes(), 2 * record_size);
+        assert_eq!(btree.range(..).unwrap().count(), 10);
+        drop(btree);
+
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![1]));
+        btree.flush().unwrap();
+        assert!(btree.runs.is_empty());
+        assert_eq!(btree.range(..).unwrap().count(), 10);
+    }
+
+    #[test]
+    fn trees_sharing_a_write_buffer_flush_the_largest() {
+        let record_size = 4 + 4 + RECORD_OVERHEAD;
+        let manager = Arc::new(WriteBufferManager::new(6 * record_size));
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            write_buffer_manager: Some(manager.clone()),
+            ..Options::default()
+        };
+        let mut first = BTree::<u32, u32>::with_options("first", 4, 4, options.clone()).unwrap();
+        let mut second = BTree::<u32, u32>::with_options("second", 4, 4, options).unwrap();
+
+        for i in 0..4 {
+            first.insert(i, i).unwrap();
+        }
+        for i in 0..3 {
+            second.insert(i, i).unwrap();
+        }
+
+        // the second tree took them over budget, but the first holds the most
+        assert_eq!(manager.usage(), 7 * record_size);
+        assert_eq!(second.pending_bytes(), 3 * record_size);
+        first.insert(4, 4).unwrap();
+        assert_eq!(first.pending_bytes(), 0);
+        assert_eq!(manager.usage(), 3 * record_size);
+
+        drop(second);
+        assert_eq!(manager.usage(), 0);
+        assert_eq!(first.get(&4).unwrap(), Some(vec![4]));
+    }
+
+    #[test]
+    fn compaction_is_rate_limited() {
+        let clock = ManualClock::new(0);
+        let record_size = (4 + 4 + RECORD_OVERHEAD) as u64;
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            compaction_rate_limit: Some(100 * record_size),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..300 {
+            btree.insert(i, i).unwrap();
+        }
+
+        // 300 records written: one second of burst, then 100 records per second
+        btree.flush().unwrap();
+        assert_eq!(clock.now_millis(), 2000);
+
+        // the next compaction also has to read those 300 back
+        clock.advance(Duration::from_secs(1));
+        btree.insert(300, 300).unwrap();
+        btree.flush().unwrap();
+        assert_eq!(clock.now_millis(), 3000 + 5010);
+    }
+
+    #[test]
+    fn compact_range_leaves_other_keys_in_memory() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+
+        for i in 0..5 {
+            btree.delete(i, i).unwrap();
+        }
+        btree.insert(20, 20).unwrap();
+
+        // nothing lives out there
+        assert!(!btree.compact_range(100..200).unwrap());
+
+        assert!(btree.compact_range(..5).unwrap());
+        assert_eq!(btree.tree_file.count().unwrap(), 5);
+        assert_eq!(btree.mem_tree.size(), 1);
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert_eq!(btree.get(&7).unwrap(), Some(vec![7]));
+
+        // the write left in memory is still in the WAL
+        drop(btree);
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&20).unwrap(), Some(vec![20]));
+        assert_eq!(btree.get(&3).unwrap(), None);
+    }
+
+    #[test]
+    fn subcompactions_match_a_single_compaction() {
+        let run = |compaction: CompactionOptions| {
+            let disk = SimDisk::new(0);
+            let options = Options {
+                storage: Arc::new(disk.clone()),
+                clock: Arc::new(ManualClock::new(0)),
+                compaction,
+                ..Options::default()
+            };
+            let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+            for i in 0..500 {
+                btree.insert(i % 50, i).unwrap();
+            }
+            btree.flush().unwrap();
+
+            for i in 0..500 {
+                if i % 3 == 0 {
+                    btree.delete(i % 50, i).unwrap();
+                } else {
+                    btree.insert(i % 70, i).unwrap();
+                }
+            }
+            btree.flush().unwrap();
+
+            disk.contents("db").unwrap()
+        };
+
+        let parallel = CompactionOptions {
+            max_jobs: 3,
+            max_subcompactions: 8,
+            ..CompactionOptions::default()
+        };
+
+        assert_eq!(run(parallel), run(CompactionOptions::default()));
+    }
+
+    #[test]
+    fn subcompactions_run_on_the_configured_executor() {
+        // runs the jobs one after another, on the compacting thread
+        struct Inline(Mutex<usize>);
+
+        impl CompactionExecutor for Inline {
+            fn run_all(&self, jobs: Vec<Job<'_>>) {
+                *self.0.lock().unwrap() += jobs.len();
+                jobs.into_iter().for_each(|job| job());
+            }
+        }
+
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let names = started.clone();
+        let threads = ThreadExecutor::new("merger").on_thread_start(move || {
+            names
+                .lock()
+                .unwrap()
+                .push(thread::current().name().unwrap().to_owned())
+        });
+        let inline = Arc::new(Inline(Mutex::new(0)));
+
+        for executor in [
+            Arc::new(threads) as Arc<dyn CompactionExecutor>,
+            inline.clone(),
+        ] {
+            let options = Options {
+                storage: Arc::new(SimDisk::new(0)),
+                compaction: CompactionOptions {
+                    max_jobs: 2,
+                    max_subcompactions: 4,
+                    ..CompactionOptions::default()
+                },
+                compaction_executor: executor,
+                ..Options::default()
+            };
+            let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+            for i in 0..5000 {
+                btree.insert(i, i).unwrap();
+            }
+            btree.flush().unwrap();
+            assert_eq!(btree.get(&4321).unwrap(), Some(vec![4321]));
+        }
+
+        // the first flush had no tree file to split, every later one made four slices
+        let mut started = started.lock().unwrap().clone();
+        started.sort();
+        started.dedup();
+        assert_eq!(started, ["merger-0", "merger-1"]);
+        assert!(*inline.0.lock().unwrap() > 0);
+    }
+
+    #[test]
+    fn high_priority_compactions_are_not_rate_limited() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            compaction_rate_limit: Some(1),
+            compaction: CompactionOptions {
+                flush_priority: CompactionPriority::High,
+                ..CompactionOptions::default()
+            },
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..100 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        assert_eq!(clock.now_millis(), 0);
+
+        // manual compactions are still low priority
+        btree.compact_range(..).unwrap();
+        assert!(clock.now_millis() > 0);
+    }
+
+    #[test]
+    fn l0_runs_are_merged_past_the_trigger() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            l0_compaction_trigger: Some(2),
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        // each full memtable becomes a run, leaving the tree file alone
+        for i in 0..=MAX_MEMORY_ITEMS as u32 * 2 + 1 {
+            btree.insert(i, i).unwrap();
+        }
+        assert_eq!(btree.runs.len(), 2);
+        assert_eq!(btree.tree_file.count().unwrap(), 0);
+
+        // a delete in a later run hides the value in an earlier one
+        btree.delete(5, 5).unwrap();
+        assert_eq!(btree.get(&5).unwrap(), None);
+        assert_eq!(btree.get(&6).unwrap(), Some(vec![6]));
+
+        // the runs survive reopening
+        drop(btree);
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.runs.len(), 2);
+        assert_eq!(btree.get(&2000).unwrap(), Some(vec![2000]));
+
+        // the third run is one too many
+        for i in 0..MAX_MEMORY_ITEMS as u32 {
+            btree.insert(10_000 + i, i).unwrap();
+        }
+        assert!(btree.runs.is_empty());
+        assert_eq!(btree.get(&5).unwrap(), None);
+        assert_eq!(btree.get(&6).unwrap(), Some(vec![6]));
+        assert_eq!(btree.get(&10_000).unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn large_wal_triggers_a_flush() {
+        let record_size = (4 + 4 + RECORD_OVERHEAD + FRAME_OVERHEAD) as u64;
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            wal_flush_trigger: Some(10 * record_size),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..9 {
+            btree.insert(i, i).unwrap();
+        }
+        assert_eq!(btree.wal_file.count().unwrap(), 9);
+
+        btree.insert(9, 9).unwrap();
+        assert_eq!(btree.wal_file.count().unwrap(), 0);
+        assert_eq!(btree.tree_file.count().unwrap(), 10);
+    }
+
+    #[test]
+    fn options_can_change_while_open() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..100 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+
+        // lookups fill the cache
+        for i in 0..100 {
+            btree.get(&i).unwrap();
+        }
+        assert!(btree.block_cache.used() > 0);
+
+        let mut options = btree.dynamic_options();
+        options.flush_threshold = 10;
+        options.compaction_rate_limit = Some(1 << 20);
+        options.cache_size = 0;
+
+        for i in 0..20 {
+            btree.insert(1000 + i, i).unwrap();
+        }
+        btree.set_options(options).unwrap();
+
+        // the memtable was over the new threshold
+        assert_eq!(btree.mem_tree.size(), 0);
+        assert_eq!(btree.block_cache.used(), 0);
+        assert_eq!(btree.dynamic_options().compaction_rate_limit, Some(1 << 20));
+        assert_eq!(btree.get(&1005).unwrap(), Some(vec![5]));
+    }
+
+    #[test]
+    fn synced_writes_survive_a_crash() {
+        for seed in 0..8 {
+            let disk = SimDisk::new(seed);
+            let options = Options {
+                storage: Arc::new(disk.clone()),
+                sync_policy: SyncPolicy::Always,
+                ..Options::default()
+            };
+
+            {
+                let mut btree =
+                    BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+                for i in 0..10 {
+                    btree.insert(i, i).unwrap();
+                }
+            }
+
+            disk.crash();
+
+            let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+            for i in 0..10 {
+                assert_eq!(btree.get(&i).unwrap(), Some(vec![i]));
+            }
+        }
+    }
+
+    #[test]
+    fn warming_a_range_caches_its_blocks() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..2000 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.block_cache.used(), 0);
+
+        assert!(btree.warm(500..1000).unwrap() > 0);
+        assert_eq!(btree.warm(500..1000).unwrap(), 0);
+
+        // lookups in the range, present or not, no longer miss
+        let (_, misses) = btree.block_cache.hits_and_misses();
+        for i in 500..1000 {
+            assert_eq!(btree.get(&i).unwrap(), Some(vec![i]));
+        }
+        assert_eq!(btree.block_cache.hits_and_misses().1, misses);
+
+        btree.get(&1500).unwrap();
+        assert!(btree.block_cache.hits_and_misses().1 > misses);
+    }
+
+    #[test]
+    fn block_cache_can_be_shared() {
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            block_cache: Some(cache.clone()),
+            ..Options::default()
+        };
+        let mut first = BTree::<u32, u32>::with_options("first", 4, 4, options.clone()).unwrap();
+        let mut second = BTree::<u32, String>::with_options("second", 4, 16, options).unwrap();
+
+        first.insert(1, 1).unwrap();
+        first.flush().unwrap();
+        second.insert(1, "one".to_owned()).unwrap();
+        second.flush().unwrap();
+
+        first.get(&1).unwrap();
+        let used = cache.used();
+        assert!(used > 0);
+
+        assert_eq!(second.get(&1).unwrap(), Some(vec!["one".to_owned()]));
+        assert!(cache.used() > used);
+        assert_eq!(first.get(&1).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn stale_filters_are_removed() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+
+        {
+            let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+            btree.insert(1, 1).unwrap();
+            btree.flush().unwrap();
+            assert!(disk.contents("db.filter").is_some());
+        }
+
+        // a tree file written without filters mustn't be paired with the old ones
+        let options = Options {
+            bloom_bits_per_key: None,
+            ..options
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        btree.insert(2, 2).unwrap();
+        btree.flush().unwrap();
+
+        assert!(disk.contents("db.filter").is_none());
+        assert_eq!(btree.get(&2).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn hash_index_is_kept_through_compactions() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            hash_index: true,
+            ..Options::default()
+        };
+
+        {
+            let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+            for i in 0..100 {
+                btree.insert(i, i).unwrap();
+            }
+            btree.flush().unwrap();
+
+            btree.delete(50, 50).unwrap();
+            btree.insert(50, 51).unwrap();
+            btree.flush().unwrap();
+            assert!(disk.contents("db.hash").is_some());
+        }
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        assert_eq!(btree.get(&49).unwrap(), Some(vec![49]));
+        assert_eq!(btree.get(&50).unwrap(), Some(vec![51]));
+        assert_eq!(btree.get(&100).unwrap(), None);
+    }
+
+    #[test]
+    fn range_scans_merge_memory_and_disk() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..10 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+
+        btree.insert(3, 30).unwrap();
+        btree.delete(4, 4).unwrap();
+        btree.insert(20, 20).unwrap();
+
+        let scanned: Vec<(u32, Vec<u32>)> = btree.range(2..=5).unwrap().collect();
+        assert_eq!(scanned, [(2, vec![2]), (3, vec![3, 30]), (5, vec![5])]);
+
+        let keys: Vec<u32> = btree.range(8..).unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, [8, 9, 20]);
+    }
+
+    #[test]
+    fn prefix_scans_skip_runs_without_the_prefix() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            l0_compaction_trigger: Some(4),
+            bloom_bits_per_key: Some(10),
+            flush_threshold: 10,
+            ..Options::default()
+        };
+        let mut btree = BTree::<String, u32>::with_options("db", 16, 4, options).unwrap();
+        btree.set_prefix_bloom(Some(4));
+
+        // each run holds the keys of one group
+        for group in ["aaa", "bbb", "ccc"] {
+            for i in 0..=10 {
+                btree.insert(format!("{}:{:02}", group, i), i).unwrap();
+            }
+        }
+        assert_eq!(btree.runs.len(), 3);
+
+        let runs_with_prefix = btree
+            .runs
+            .iter()
+            .filter(|run| {
+                run.file
+                    .may_contain_prefix(b"bbb:", String::as_bytes)
+                    .unwrap()
+            })
+            .count();
+        assert_eq!(runs_with_prefix, 1);
+
+        let scanned: Vec<(String, Vec<u32>)> = btree.scan_prefix(b"bbb:0").unwrap().collect();
+        let expected: Vec<(String, Vec<u32>)> = (0..10)
+            .map(|i| (format!("bbb:{:02}", i), vec![i]))
+            .collect();
+        assert_eq!(scanned, expected);
+
+        // too short to use the filters, but still found
+        assert_eq!(btree.scan_prefix(b"c").unwrap().count(), 11);
+        assert_eq!(btree.scan_prefix(b"ddd:").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn multi_get_reads_each_key() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in (0..2000).step_by(2) {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        btree.insert(7, 7).unwrap();
+
+        assert!(btree.storage.exists("db.zones").unwrap());
+        assert_eq!(
+            btree.multi_get(&[4, 3, 7, 5000]).unwrap(),
+            [Some(vec![4]), None, Some(vec![7]), None]
+        );
+        assert_eq!(btree.range(1..6).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn prefix_compressed_files_survive_compaction() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            l0_compaction_trigger: Some(2),
+            flush_threshold: 100,
+            prefix_compression: true,
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+
+        {
+            let mut btree =
+                BTree::<String, u32>::with_options("db", 32, 4, options.clone()).unwrap();
+
+            for i in 0..350 {
+                btree.insert(format!("user/{:06}/profile", i), i).unwrap();
+            }
+            btree.delete("user/000005/profile".to_owned(), 5).unwrap();
+        }
+
+        // three runs were merged into the tree file, the rest is in the WAL
+        let btree = BTree::<String, u32>::with_options("db", 32, 4, options).unwrap();
+        assert!(btree.runs.is_empty());
+        assert_eq!(btree.tree_file.count().unwrap(), 303);
+
+        assert_eq!(
+            btree.get(&"user/000004/profile".to_owned()).unwrap(),
+            Some(vec![4])
+        );
+        assert_eq!(btree.get(&"user/000005/profile".to_owned()).unwrap(), None);
+        assert_eq!(
+            btree.get(&"user/000349/profile".to_owned()).unwrap(),
+            Some(vec![349])
+        );
+        assert_eq!(btree.scan_prefix(b"user/0001").unwrap().count(), 100);
+
+        let plain = 303 * (32 + 4 + RECORD_OVERHEAD);
+        assert!(disk.contents("db").unwrap().len() * 2 < plain);
+    }
+
+    #[test]
+    fn large_blobs_are_stored_once() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            ..Options::default()
+        };
+        let large = vec![9u8; 4096];
+
+        {
+            let mut btree = BTree::<u32, Blob>::with_options("db", 4, 32, options.clone()).unwrap();
+
+            for i in 0..100 {
+                btree.insert_blob(i, &large).unwrap();
+            }
+            btree.insert_blob(0, b"small").unwrap();
+
+            // the small value is inline, the large one is stored just the once
+            assert_eq!(disk.contents("db.blobs").unwrap().len(), 4096 + 20);
+            assert_eq!(btree.get_blobs(&0).unwrap().unwrap().len(), 2);
+
+            for i in 0..99 {
+                btree.delete_blob(i, &large).unwrap();
+            }
+            btree.flush().unwrap();
+
+            // one key still refers to the blob
+            assert_eq!(btree.reclaim_blobs().unwrap(), 0);
+
+            btree.delete_blob(99, &large).unwrap();
+            btree.flush().unwrap();
+            assert_eq!(btree.reclaim_blobs().unwrap(), 4096 + 20);
+
+            btree.insert_blob(5, &large).unwrap();
+        }
+
+        let btree = BTree::<u32, Blob>::with_options("db", 4, 32, options).unwrap();
+        assert_eq!(btree.get_blobs(&5).unwrap(), Some(vec![large]));
+        assert_eq!(btree.get_blobs(&0).unwrap(), Some(vec![b"small".to_vec()]));
+        assert_eq!(btree.get_blobs(&99).unwrap(), None);
+    }
+
+    #[test]
+    fn streamed_blobs_are_stored_once() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, Blob>::with_options("db", 4, 32, options).unwrap();
+        let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        for key in 0..3 {
+            let mut writer = btree.insert_streaming(key).unwrap();
+
+            for piece in large.chunks(1000) {
+                writer.write_all(piece).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        // a writer dropped before it's finished inserts nothing
+        btree
+            .insert_streaming(3)
+            .unwrap()
+            .write_all(&large)
+            .unwrap();
+
+        let mut writer = btree.insert_streaming(4).unwrap();
+        writer.write_all(b"small").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(disk.contents("db.blobs").unwrap().len(), 200_000 + 20);
+        assert_eq!(btree.get_blobs(&2).unwrap(), Some(vec![large]));
+        assert_eq!(btree.get_blobs(&3).unwrap(), None);
+        assert_eq!(btree.get_blobs(&4).unwrap(), Some(vec![b"small".to_vec()]));
+    }
+
+    #[test]
+    fn blobs_can_be_read_in_pieces() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, Blob>::with_options("db", 4, 32, options).unwrap();
+        let large: Vec<u8> = (0..100_000u32).map(|i| (i % 13) as u8).collect();
+
+        btree.insert_blob(1, &large).unwrap();
+        btree.insert_blob(1, b"small").unwrap();
+        btree.flush().unwrap();
+
+        let mut readers = btree.get_streaming(&1).unwrap().unwrap();
+        assert_eq!(readers.len(), 2);
+
+        let mut copied = Vec::new();
+        let mut chunk = [0; 4096];
+
+        for reader in &mut readers {
+            while reader.remaining() > 0 {
+                let n = reader.read(&mut chunk).unwrap();
+                copied.extend_from_slice(&chunk[..n]);
+            }
+        }
+
+        // inline blobs sort first
+        assert_eq!(&copied[..5], b"small");
+        assert_eq!(&copied[5..], &large[..]);
+        assert!(btree.get_streaming(&2).unwrap().is_none());
+    }
+
+    #[test]
+    fn stats_count_the_work_done() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            flush_threshold: 100,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        for i in 0..250 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.get(&7).unwrap();
+
+        let stats = btree.stats();
+        assert_eq!(stats.writes, 250);
+        assert_eq!(
+            stats.wal_bytes,
+            250 * (8 + RECORD_OVERHEAD + FRAME_OVERHEAD) as u64
+        );
+        assert_eq!((stats.flushes, stats.compactions), (2, 2));
+        assert_eq!(stats.pending_bytes, 48 * (8 + RECORD_OVERHEAD));
+        assert!(stats.cache_hit_rate().is_some());
+
+        assert_eq!(stats.insert_latency.count(), 250);
+        assert_eq!(stats.get_latency.count(), 1);
+        assert_eq!(
+            (
+                stats.flush_latency.count(),
+                stats.compaction_latency.count()
+            ),
+            (2, 2)
+        );
+        assert!(stats.insert_latency.percentile(99.0).unwrap() <= stats.insert_latency.max());
+    }
+
+    #[test]
+    fn slow_ops_are_reported_with_what_they_read() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            flush_threshold: 100,
+            slow_op_threshold: Some(Duration::ZERO),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+
+        let log = reported.clone();
+        btree.add_slow_op_hook(move |op| log.lock().unwrap().push(*op));
+
+        for i in 0..250 {
+            btree.insert(i, i).unwrap();
+        }
+
+        let ops = reported.lock().unwrap().clone();
+        let of_kind = |kind| ops.iter().filter(move |op| op.kind == kind);
+
+        assert_eq!(of_kind(SlowOpKind::Write).count(), 250);
+        assert!(of_kind(SlowOpKind::Write).all(|op| op.key_size == Some(4) && op.records == 1));
+        assert_eq!(
+            of_kind(SlowOpKind::Flush)
+                .map(|op| op.records)
+                .collect::<Vec<_>>(),
+            [101, 101]
+        );
+
+        // the second compaction merged into the tree file the first wrote
+        let merged: Vec<_> = of_kind(SlowOpKind::Compaction)
+            .map(|op| (op.records, op.io.files))
+            .collect();
+        assert_eq!(merged, [(101, 0), (202, 1)]);
+        assert!(
+            of_kind(SlowOpKind::Compaction)
+                .next_back()
+                .unwrap()
+                .io
+                .bytes_read
+                > 0
+        );
+        drop(btree);
+
+        // a cold lookup has to read the tree file
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let log = reported.clone();
+        btree.add_slow_op_hook(move |op| log.lock().unwrap().push(*op));
+        reported.lock().unwrap().clear();
+
+        btree.get(&7).unwrap();
+        btree.get(&7).unwrap();
+        let gets = reported.lock().unwrap().clone();
+        assert_eq!(gets.len(), 2);
+        assert_eq!((gets[0].key_size, gets[0].io.files), (Some(4), 1));
+        assert!(gets[0].io.reads > 0);
+        assert_eq!(gets[1].io, IoStats::default());
+    }
+
+    #[test]
+    fn reads_can_count_their_io() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            l0_compaction_trigger: Some(4),
+            flush_threshold: 100,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        // one key in each of the tree file and a run, the rest of the run elsewhere
+        for i in 0..101 {
+            btree.insert(i * 10, i).unwrap();
+        }
+        btree.flush().unwrap();
+        btree.insert(5, 5).unwrap();
+        for i in 0..100 {
+            btree.insert(100_000 + i, i).unwrap();
+        }
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let collect = ReadOptions {
+            collect_io_stats: true,
+            ..ReadOptions::default()
+        };
+
+        let (values, io) = btree.get_with(&5, &collect).unwrap();
+        let io = io.unwrap();
+        assert_eq!(values, Some(vec![5]));
+        assert_eq!(io.files, 2);
+        assert!(io.reads >= 2 && io.bytes_read > 0);
+
+        // the cache answers the second time
+        assert_eq!(
+            btree.get_with(&5, &collect).unwrap().1,
+            Some(IoStats::default())
+        );
+        assert_eq!(
+            btree.get_with(&10, &ReadOptions::default()).unwrap(),
+            (Some(vec![1]), None)
+        );
+
+        let mut range = btree.range_with(500.., &collect).unwrap();
+        assert_eq!(range.by_ref().count(), 51 + 100);
+        assert_eq!(range.io_stats().unwrap().files, 2);
+        assert!(range.io_stats().unwrap().reads > io.reads);
+    }
+
+    #[test]
+    fn reads_see_only_the_writes_their_consistency_allows() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            sync_policy: SyncPolicy::Never,
+            versioning: Some(VersionRetention::default()),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        let read = |consistency| ReadOptions {
+            consistency,
+            ..ReadOptions::default()
+        };
+        let scan = |btree: &BTree<u32, u32>, consistency| {
+            btree
+                .range_with(.., &read(consistency))
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+
+        btree.insert(1, 10).unwrap();
+        btree.sync().unwrap();
+        btree.insert(1, 11).unwrap();
+        btree.insert(2, 20).unwrap();
+
+        assert_eq!(
+            btree.get_with(&2, &read(Consistency::Latest)).unwrap().0,
+            Some(vec![20])
+        );
+        assert_eq!(
+            btree
+                .get_with(&2, &read(Consistency::DurableOnly))
+                .unwrap()
+                .0,
+            None
+        );
+        assert_eq!(
+            btree
+                .get_with(&1, &read(Consistency::DurableOnly))
+                .unwrap()
+                .0,
+            Some(vec![10])
+        );
+        assert_eq!(scan(&btree, Consistency::DurableOnly), [(1, vec![10])]);
+        assert_eq!(
+            scan(&btree, Consistency::SnapshotSeq(2)),
+            [(1, vec![10, 11])]
+        );
+        assert_eq!(
+            scan(&btree, Consistency::Latest),
+            [(1, vec![10, 11]), (2, vec![20])]
+        );
+
+        // a flush makes everything durable
+        btree.flush().unwrap();
+        assert_eq!(
+            scan(&btree, Consistency::DurableOnly),
+            scan(&btree, Consistency::Latest)
+        );
+        assert_eq!(
+            btree
+                .get_with(&2, &read(Consistency::SnapshotSeq(2)))
+                .unwrap()
+                .0,
+            None
+        );
+
+        // a value re-written or deleted since the last sync is still seen as it was
+        btree.insert(1, 11).unwrap();
+        btree.sync().unwrap();
+        btree.insert(1, 11).unwrap();
+        btree.delete(2, 20).unwrap();
+        assert_eq!(
+            scan(&btree, Consistency::DurableOnly),
+            [(1, vec![10, 11]), (2, vec![20])]
+        );
+        assert_eq!(scan(&btree, Consistency::Latest), [(1, vec![10, 11])]);
+
+        // which takes versioning to keep what they superseded
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            versioning: None,
+            ..options
+        };
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert!(btree.get_with(&1, &read(Consistency::DurableOnly)).is_err());
+        assert!(btree
+            .range_with(.., &read(Consistency::DurableOnly))
+            .is_err());
+    }
+
+    #[test]
+    fn compactions_report_their_progress() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            flush_threshold: 100_000,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let monitor = btree.compaction_monitor();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+
+        let log = reported.clone();
+        btree.add_compaction_progress_hook(move |progress| {
+            // the monitor sees the same compaction from outside
+            assert!(monitor.progress().is_some());
+            log.lock().unwrap().push(*progress);
+        });
+
+        for i in 0..50_000 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+
+        let reported = reported.lock().unwrap().clone();
+        let total = 50_000 * btree.record_size() as u64;
+
+        assert_eq!(reported.len(), 2);
+        assert!(reported
+            .windows(2)
+            .all(|pair| pair[0].bytes_done < pair[1].bytes_done));
+        assert!(reported
+            .iter()
+            .all(|progress| progress.bytes_total == total));
+        assert!(reported[0].fraction() < 1.0 && reported[0].estimated_remaining().is_some());
+        assert_eq!(reported.last().unwrap().fraction(), 1.0);
+
+        assert_eq!(btree.compaction_monitor().progress(), None);
+        assert_eq!(btree.stats().compaction_progress, None);
+    }
+
+    #[test]
+    fn compactions_can_be_cancelled() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            flush_threshold: 100_000,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let monitor = btree.compaction_monitor();
+        let cancel = AtomicBool::new(true);
+
+        btree.add_compaction_progress_hook(move |_| {
+            if cancel.swap(false, Ordering::Relaxed) {
+                assert!(monitor.cancel());
+            }
+        });
+        assert!(!btree.compaction_monitor().cancel());
+
+        for i in 0..50_000 {
+            btree.insert(i, i).unwrap();
+        }
+
+        let err = btree.flush().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::Cancelled)
+        );
+
+        // the half-written file is gone and everything is still in memory
+        assert!(!disk.exists("db.new").unwrap());
+        assert_eq!(btree.tree_file.count().unwrap(), 0);
+        assert_eq!(btree.mem_tree.size(), 50_000);
+        assert_eq!(btree.get(&49_999).unwrap(), Some(vec![49_999]));
+
+        btree.flush().unwrap();
+        assert_eq!(btree.tree_file.count().unwrap(), 50_000);
+    }
+
+    #[test]
+    fn hooks_run_around_each_write() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let committed = Arc::new(Mutex::new(Vec::new()));
+
+        btree.add_pre_write_hook(|_, value| {
+            if value % 2 == 0 {
+                Ok(())
+            } else {
+                Err(From::from("odd"))
+            }
+        });
+
+        let log = committed.clone();
+        btree.add_post_commit_hook(move |key, value, seq| {
+            log.lock().unwrap().push((*key, *value, seq))
+        });
+
+        btree.insert(1, 2).unwrap();
+        assert_eq!(btree.insert(1, 3).unwrap_err().to_string(), "odd");
+        btree.delete(1, 2).unwrap();
+
+        // the refused write never got a sequence number
+        assert_eq!(*committed.lock().unwrap(), [(1, 2, 1), (1, 2, 2)]);
+        assert_eq!(btree.get(&1).unwrap(), None);
+    }
+
+    #[test]
+    fn watchers_are_sent_matching_writes() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<String, u32>::with_options("db", 16, 4, options).unwrap();
+
+        let key = btree.watch("user/1".to_owned());
+        let prefix = btree.watch_prefix(b"user/");
+
+        btree.insert("user/1".to_owned(), 1).unwrap();
+        btree.insert("user/2".to_owned(), 2).unwrap();
+        btree.insert("group/1".to_owned(), 3).unwrap();
+        btree.delete("user/1".to_owned(), 1).unwrap();
+
+        let seqs = |receiver: &Receiver<Change<String, u32>>| {
+            receiver
+                .try_iter()
+                .map(|change| change.seq)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(seqs(&key), [1, 4]);
+        assert_eq!(seqs(&prefix), [1, 2, 4]);
+
+        // a dropped watcher doesn't get in the way of writes
+        drop(key);
+        btree.insert("user/1".to_owned(), 5).unwrap();
+        assert_eq!(prefix.recv().unwrap().value, 5);
+    }
+
+    #[test]
+    fn async_inserts_resolve_at_the_covering_sync() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            sync_policy: SyncPolicy::Interval(Duration::from_secs(1)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+
+        let first = btree.insert_async(1, 1).unwrap();
+        let second = btree.insert_async(2, 2).unwrap();
+        assert!(!first.is_durable());
+        assert!(!second.wait_timeout(Duration::from_millis(10)).unwrap());
+
+        // a writer can be acknowledged from another thread once the sync lands
+        let waiter = thread::spawn(move || first.wait().map_err(|e| e.to_string()));
+
+        clock.advance(Duration::from_secs(1));
+        let third = btree.insert_async(3, 3).unwrap();
+        assert!(waiter.join().unwrap().is_ok());
+        assert!(second.is_durable() && third.is_durable());
+
+        let fourth = btree.insert_async(4, 4).unwrap();
+        btree.sync().unwrap();
+        assert!(fourth.is_durable());
+
+        let fifth = btree.insert_async(5, 5).unwrap();
+        drop(btree);
+        let error = fifth.wait().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::Closed)
+        );
+    }
+
+    #[test]
+    fn keys_hold_no_more_values_than_the_cap() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = |when_full| Options {
+            storage: storage.clone(),
+            value_cap: Some(ValueCap {
+                max_values: 3,
+                when_full,
+            }),
+            ..Options::default()
+        };
+        let mut btree =
+            BTree::<u32, u32>::with_options("db", 4, 4, options(CapPolicy::EvictOldest)).unwrap();
+
+        for value in [50, 40, 30] {
+            btree.insert(1, value).unwrap();
+        }
+        btree.flush().unwrap();
+
+        // the oldest go, wherever they are, and a value written again is the newest
+        btree.insert(1, 20).unwrap();
+        btree.insert(1, 40).unwrap();
+        btree.insert(1, 10).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![10, 20, 40]));
+        assert_eq!(btree.get(&2).unwrap(), None);
+
+        btree.insert_replace(1, 5).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![5]));
+        drop(btree);
+
+        let mut btree =
+            BTree::<u32, u32>::with_options("reject", 4, 4, options(CapPolicy::Reject)).unwrap();
+        for value in 0..3 {
+            btree.insert(1, value).unwrap();
+        }
+
+        let error = btree.insert(1, 3).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::ValueCapReached { max_values: 3 })
+        );
+        btree.insert(1, 2).unwrap();
+        btree.delete(1, 0).unwrap();
+        btree.insert(1, 3).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![1, 2, 3]));
+        drop(btree);
+
+        // keys written before the cap are brought down to it by compaction
+        let uncapped = Options {
+            value_cap: None,
+            ..options(CapPolicy::EvictOldest)
+        };
+        let mut btree = BTree::<u32, u32>::with_options("late", 4, 4, uncapped).unwrap();
+        for value in (0..6).rev() {
+            btree.insert(7, value).unwrap();
+        }
+        btree.flush().unwrap();
+        drop(btree);
+
+        let mut btree =
+            BTree::<u32, u32>::with_options("late", 4, 4, options(CapPolicy::EvictOldest)).unwrap();
+        assert_eq!(btree.get(&7).unwrap().unwrap().len(), 6);
+        btree.compact_range(..).unwrap();
+        assert_eq!(btree.get(&7).unwrap(), Some(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn trees_in_lru_mode_evict_the_least_recently_used_keys() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            lru_capacity: Some(3),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for key in 1..=3 {
+            btree.insert(key, key * 10).unwrap();
+        }
+        btree.flush().unwrap();
+
+        // a read keeps a key, so the one used least recently goes
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![10]));
+        btree.insert(4, 40).unwrap();
+        assert_eq!(btree.get(&2).unwrap(), None);
+        assert_eq!(btree.stats().evictions, 1);
+
+        // a key deleted outright leaves room without evicting another
+        btree.delete(3, 30).unwrap();
+        btree.insert(5, 50).unwrap();
+        assert_eq!(
+            btree
+                .range(..)
+                .unwrap()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            [1, 4, 5]
+        );
+        assert_eq!(btree.stats().evictions, 1);
+        drop(btree);
+
+        // reopened, the keys are used in the order they were last written
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        btree.insert(6, 60).unwrap();
+        assert_eq!(
+            btree
+                .range(..)
+                .unwrap()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            [4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn inserts_past_the_disk_quota_fail() {
+        let quota_tree = |when_full| {
+            let options = Options {
+                storage: Arc::new(SimDisk::new(0)),
+                flush_threshold: 10,
+                disk_quota: Some(DiskQuota {
+                    max_bytes: 4096,
+                    when_full,
+                }),
+                ..Options::default()
+            };
+            BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap()
+        };
+
+        let fill = |btree: &mut BTree<u32, u32>| {
+            let mut inserted = 0;
+            let error = loop {
+                match btree.insert(inserted, inserted) {
+                    Ok(()) => inserted += 1,
+                    Err(e) => break e,
+                }
+            };
+            assert!(matches!(
+                error.downcast_ref::<BTreeError>(),
+                Some(BTreeError::QuotaExceeded { .. })
+            ));
+            inserted
+        };
+
+        let mut btree = quota_tree(QuotaPolicy::Reject);
+        let inserted = fill(&mut btree);
+        assert!(inserted > 0);
+        assert!(btree.disk_size().unwrap() <= 4096);
+
+        // deletes still go through, though the space only comes back with a compaction
+        btree.delete(0, 0).unwrap();
+        assert!(btree.insert(inserted, inserted).is_err());
+        btree.flush().unwrap();
+        btree.insert(inserted, inserted).unwrap();
+
+        let mut btree = quota_tree(QuotaPolicy::Compact);
+        let inserted = fill(&mut btree);
+
+        for i in 0..inserted {
+            btree.delete(i, i).unwrap();
+        }
+        btree.insert(0, 0).unwrap();
+        assert_eq!(btree.get(&0).unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn opening_drops_what_follows_the_last_commit() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..3 {
+            btree.insert(i, i).unwrap();
+        }
+        drop(btree);
+
+        // a record torn by a crash part way through being appended
+        storage.open("db.wal").unwrap().append(&[1; 7]).unwrap();
+
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(
+            btree.replay_summary(),
+            ReplaySummary {
+                records: 3,
+                writes: 3,
+                aborted: 0,
+                discarded_bytes: 7,
+            }
+        );
+
+        // the torn bytes were truncated, so the next write isn't lost behind them
+        btree.insert(3, 3).unwrap();
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.replay_summary().records, 4);
+        assert_eq!(btree.get(&3).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn compressed_wal_writes_are_replayed() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            wal_compression: true,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, String>::with_options("db", 4, 256, options.clone()).unwrap();
+
+        for i in 0..20 {
+            btree.insert(i, format!("value {}", i)).unwrap();
+        }
+
+        let record_size = (4 + 256 + RECORD_OVERHEAD + FRAME_OVERHEAD) as u64;
+        assert!(btree.stats().wal_bytes < 20 * record_size / 4);
+        assert_eq!(btree.wal_file.count().unwrap(), 20);
+        drop(btree);
+
+        // a compressed write torn by a crash
+        storage.open("db.wal").unwrap().append(&[2; 7]).unwrap();
+
+        // with compression turned off what was compressed is still replayed
+        let uncompressed = Options {
+            wal_compression: false,
+            ..options.clone()
+        };
+        let mut btree = BTree::<u32, String>::with_options("db", 4, 256, uncompressed).unwrap();
+        assert_eq!(
+            btree.replay_summary(),
+            ReplaySummary {
+                records: 20,
+                writes: 20,
+                aborted: 0,
+                discarded_bytes: 7,
+            }
+        );
+
+        btree.insert(20, "value 20".to_owned()).unwrap();
+        assert_eq!(btree.wal_file.count().unwrap(), 21);
+        drop(btree);
+
+        let btree = BTree::<u32, String>::with_options("db", 4, 256, options).unwrap();
+        assert_eq!(btree.replay_summary().records, 21);
+        assert_eq!(btree.get(&7).unwrap(), Some(vec!["value 7".to_owned()]));
+        assert_eq!(btree.get(&20).unwrap(), Some(vec!["value 20".to_owned()]));
+    }
+
+    #[test]
+    fn opening_reports_what_recovery_did() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        btree.insert(1, 1).unwrap();
+        btree.insert_all(vec![(2, 2), (3, 3)]).unwrap();
+
+        // a commit cut off before its batch reached the WAL
+        let mut batch = WriteBatch::new();
+        batch.insert(4, 4);
+        let token = btree.prepare(batch).unwrap();
+        let first_seq = btree.last_seq + 1;
+        btree
+            .prepared_log
+            .as_mut()
+            .unwrap()
+            .commit(token, first_seq)
+            .unwrap();
+        drop(btree);
+
+        // and the crash tore a write, part way through a compaction
+        storage.open("db.wal").unwrap().append(&[1; 5]).unwrap();
+        storage.open("db.new").unwrap().append(&[1; 100]).unwrap();
+        write_generation(&*storage, "db", 1).unwrap();
+        storage.open("db.generation").unwrap().truncate(3).unwrap();
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(
+            btree.recovery_report(),
+            RecoveryReport {
+                records_replayed: 3,
+                writes_replayed: 2,
+                prepared_batches_applied: 1,
+                torn_tail_truncated: true,
+                stale_compaction_cleaned: true,
+            }
+        );
+        assert!(!storage.exists("db.new").unwrap());
+        assert_eq!(btree.get(&4).unwrap(), Some(vec![4]));
+        drop(btree);
+
+        // with nothing left to put right
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let report = btree.recovery_report();
+        assert_eq!(report.records_replayed, 4);
+        assert_eq!(report.prepared_batches_applied, 0);
+        assert!(!report.torn_tail_truncated && !report.stale_compaction_cleaned);
+    }
+
+    #[test]
+    fn damaged_files_are_salvaged_by_a_repairing_open() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..1000 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        drop(btree);
+
+        // three records overwritten, a torn write at the end and the filters cut short
+        let record_size = 4 + 4 + RECORD_OVERHEAD;
+        let file = storage.open("db").unwrap();
+        let mut bytes = vec![0; file.len().unwrap() as usize];
+        file.read_at(&mut bytes, 0).unwrap();
+        let header = 9;
+        bytes[header + 100 * record_size..header + 103 * record_size].fill(0xff);
+        bytes.extend_from_slice(&[1; 7]);
+
+        storage.remove("db").unwrap();
+        storage.open("db").unwrap().append(&bytes).unwrap();
+        storage
+            .open(&filter_path("db"))
+            .unwrap()
+            .truncate(3)
+            .unwrap();
+
+        assert!(BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).is_err());
+
+        let (btree, report) =
+            BTree::<u32, u32>::open_with_repair("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                files_rebuilt: 1,
+                damaged_files: vec!["db".to_owned()],
+                records_salvaged: 997,
+                records_lost: 3,
+                bytes_lost: 7,
+            }
+        );
+        assert_eq!(btree.get(&101).unwrap(), None);
+        assert_eq!(btree.get(&500).unwrap(), Some(vec![500]));
+        assert_eq!(btree.range(..).unwrap().count(), 997);
+        drop(btree);
+
+        // the rebuilt files open as usual
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&999).unwrap(), Some(vec![999]));
+    }
+
+    #[test]
+    fn files_from_before_the_header_are_migrated() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..100 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        assert_eq!(btree.tree_file.version(), FORMAT_VERSION);
+        drop(btree);
+
+        // as written before files had a header
+        let file = storage.open("db").unwrap();
+        let mut bytes = vec![0; file.len().unwrap() as usize];
+        file.read_at(&mut bytes, 0).unwrap();
+        storage.remove("db").unwrap();
+        assert_eq!(bytes[..9], *b"B+Tree\0\x02\x00");
+        storage.open("db").unwrap().append(&bytes[9..]).unwrap();
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(btree.tree_file.version(), 0);
+        assert_eq!(btree.get(&42).unwrap(), Some(vec![42]));
+        drop(btree);
+
+        assert_eq!(
+            BTree::<u32, u32>::migrate("db", 4, 4, options.clone()).unwrap(),
+            1
+        );
+        assert_eq!(
+            BTree::<u32, u32>::migrate("db", 4, 4, options.clone()).unwrap(),
+            0
+        );
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(btree.tree_file.version(), FORMAT_VERSION);
+        assert_eq!(btree.range(..).unwrap().count(), 100);
+        drop(btree);
+
+        // a format from a later version of the crate isn't guessed at
+        let mut bytes = vec![0; storage.open("db").unwrap().len().unwrap() as usize];
+        storage.open("db").unwrap().read_at(&mut bytes, 0).unwrap();
+        bytes[7] = FORMAT_VERSION + 1;
+        storage.remove("db").unwrap();
+        storage.open("db").unwrap().append(&bytes).unwrap();
+
+        let error = BTree::<u32, u32>::with_options("db", 4, 4, options.clone())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<BTreeError>(),
+            Some(BTreeError::UnsupportedVersion { .. })
+        ));
+
+        // and neither is an encoding it doesn't know
+        bytes[7] = FORMAT_VERSION;
+        bytes[8] = 7;
+        storage.remove("db").unwrap();
+        storage.open("db").unwrap().append(&bytes).unwrap();
+
+        let error = BTree::<u32, u32>::with_options("db", 4, 4, options)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<BTreeError>(),
+            Some(BTreeError::UnsupportedEncoding { encoding: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn trees_from_before_records_had_a_kind_are_migrated() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            ..Options::default()
+        };
+
+        // as the first versions of the crate laid out the tree file and WAL alike: a
+        // key and value in bincode, padded with zeroes to the key and value sizes
+        let record = |key: u32, value: &str| {
+            let mut bytes = key.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.resize(4 + 16, 0);
+            bytes
+        };
+        let tree_file = [record(1, "one"), record(2, "two")].concat();
+        let wal = [record(3, "three"), record(1, "uno")].concat();
+        storage.open("db").unwrap().append(&tree_file).unwrap();
+        storage.open("db.wal").unwrap().append(&wal).unwrap();
+
+        // it isn't opened, and its WAL isn't taken for a torn tail and cut off
+        let legacy = Some(BTreeError::LegacyFormat {
+            path: "db".to_owned(),
+        });
+        let error = BTree::<u32, String>::with_options("db", 4, 16, options.clone())
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref::<BTreeError>().cloned(), legacy);
+        assert!(ReadOnlyBTree::<u32, String>::with_options("db", 4, 16, options.clone()).is_err());
+        assert_eq!(storage.contents("db").unwrap(), tree_file);
+        assert_eq!(storage.contents("db.wal").unwrap(), wal);
+
+        // a copy in the current format can be written beside it, leaving it be
+        assert_eq!(
+            BTree::<u32, String>::migrate_to("db", "copy", 4, 16, options.clone()).unwrap(),
+            4
+        );
+        assert_eq!(storage.contents("db.wal").unwrap(), wal);
+
+        // a migration in place cut off once the files were set aside still isn't opened
+        set_aside::<u32, String>(&*storage, "db", 4, 12).unwrap();
+        let error = BTree::<u32, String>::with_options("db", 4, 16, options.clone())
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref::<BTreeError>().cloned(), legacy);
+
+        // until it's run again
+        assert_eq!(
+            BTree::<u32, String>::migrate("db", 4, 16, options.clone()).unwrap(),
+            2
+        );
+        assert!(!storage.exists("db.legacy").unwrap());
+        assert_eq!(
+            BTree::<u32, String>::migrate("db", 4, 16, options.clone()).unwrap(),
+            0
+        );
+
+        for path in ["db", "copy"] {
+            let btree = BTree::<u32, String>::with_options(path, 4, 16, options.clone()).unwrap();
+            assert_eq!(btree.tree_file.version(), FORMAT_VERSION);
+            assert_eq!(
+                btree.get(&1).unwrap(),
+                Some(vec!["one".to_owned(), "uno".to_owned()])
+            );
+            assert_eq!(btree.get(&3).unwrap(), Some(vec!["three".to_owned()]));
+            assert_eq!(btree.range(..).unwrap().count(), 3);
+        }
+
+        // a tree in the current format is copied while its writer has it open
+        let mut btree = BTree::<u32, String>::with_options("db", 4, 16, options.clone()).unwrap();
+        btree.insert(4, "four".to_owned()).unwrap();
+        assert_eq!(
+            BTree::<u32, String>::migrate_to("db", "later", 4, 16, options.clone()).unwrap(),
+            5
+        );
+        btree.insert(5, "five".to_owned()).unwrap();
+
+        let copy = BTree::<u32, String>::with_options("later", 4, 16, options).unwrap();
+        assert_eq!(copy.get(&4).unwrap(), Some(vec!["four".to_owned()]));
+        assert_eq!(copy.get(&5).unwrap(), None);
+        assert_eq!(copy.range(..).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn new_files_can_be_written_in_the_varint_encoding() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = |encoding| Options {
+            storage: storage.clone(),
+            encoding,
+            ..Options::default()
+        };
+        let mut btree =
+            BTree::<u64, String>::with_options("db", 8, 32, options(Encoding::Varint)).unwrap();
+
+        for i in 0..200 {
+            btree.insert(i, format!("{:<24}", i)).unwrap();
+        }
+        btree.flush().unwrap();
+        assert_eq!(btree.tree_file.encoding(), Encoding::Varint);
+        assert_eq!(storage.contents("db").unwrap()[..9], *b"B+Tree\0\x02\x01");
+        drop(btree);
+
+        let btree =
+            BTree::<u64, String>::with_options("db", 8, 32, options(Encoding::Varint)).unwrap();
+        assert_eq!(btree.get(&42).unwrap(), Some(vec![format!("{:<24}", 42)]));
+
+        // predicates still see the values in the legacy encoding
+        let tens = |bytes: &[u8]| {
+            bytes[8..]
+                .split(|byte| *byte == b' ')
+                .next()
+                .is_some_and(|n| n.ends_with(b"0"))
+        };
+        assert_eq!(btree.range_where(..50, tens).unwrap().count(), 5);
+        drop(btree);
+
+        // the files are read in the encoding they were written in whatever it's set to,
+        // and migrating them writes them in the one it's set to
+        let btree =
+            BTree::<u64, String>::with_options("db", 8, 32, options(Encoding::Legacy)).unwrap();
+        assert_eq!(btree.get(&42).unwrap(), Some(vec![format!("{:<24}", 42)]));
+        drop(btree);
+
+        assert_eq!(
+            BTree::<u64, String>::migrate("db", 8, 32, options(Encoding::Legacy)).unwrap(),
+            1
+        );
+        assert_eq!(storage.contents("db").unwrap()[..9], *b"B+Tree\0\x02\x00");
+        assert_eq!(
+            BTree::<u64, String>::migrate("db", 8, 32, options(Encoding::Legacy)).unwrap(),
+            0
+        );
+
+        // records take up less of an encoded block
+        let sizes: Vec<usize> = [("legacy", Encoding::Legacy), ("varint", Encoding::Varint)]
+            .iter()
+            .map(|&(path, encoding)| {
+                let options = Options {
+                    prefix_compression: true,
+                    ..options(encoding)
+                };
+                let mut btree = BTree::<u64, String>::with_options(path, 8, 32, options).unwrap();
+                for i in 0..200 {
+                    btree.insert(i * 7919, i.to_string()).unwrap();
+                }
+                btree.flush().unwrap();
+
+                storage.contents(path).unwrap().len()
+            })
+            .collect();
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn digests_compare_the_contents_alone() {
+        let options = || Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut flushed = BTree::<u32, String>::with_options("db", 4, 16, options()).unwrap();
+        let mut in_memory = BTree::<u32, String>::with_options("db", 4, 16, options()).unwrap();
+
+        for i in 0..200 {
+            flushed.insert(i, format!("a{}", i)).unwrap();
+            flushed.insert(i, format!("b{}", i)).unwrap();
+        }
+        flushed.flush().unwrap();
+        flushed.insert(500, "gone".to_owned()).unwrap();
+        flushed.delete(500, "gone".to_owned()).unwrap();
+
+        // the same contents, written the other way round
+        for i in (0..200).rev() {
+            in_memory.insert(i, format!("b{}", i)).unwrap();
+            in_memory.insert(i, format!("a{}", i)).unwrap();
+        }
+
+        assert_eq!(flushed.digest().unwrap(), in_memory.digest().unwrap());
+
+        in_memory.insert(7, "c7".to_owned()).unwrap();
+        assert_ne!(flushed.digest().unwrap(), in_memory.digest().unwrap());
+
+        let empty = BTree::<u32, String>::with_options("db", 4, 16, options()).unwrap();
+        assert_ne!(empty.digest().unwrap(), flushed.digest().unwrap());
+    }
+
+    #[test]
+    fn tree_files_can_be_read_and_verified_on_their_own() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..300 {
+            btree.insert(i, i * 2).unwrap();
+        }
+        btree.flush().unwrap();
+        btree.insert(1000, 1).unwrap();
+
+        // alongside the handle writing to the tree, which it never sees the WAL of
+        let reader = TreeFileReader::<u32, u32>::open(storage.clone(), "db", 4, 4).unwrap();
+        assert_eq!(reader.version(), FORMAT_VERSION);
+        assert_eq!(reader.count().unwrap(), 300);
+        assert_eq!(
+            reader.iter().map(|kv| kv.unwrap().value).sum::<u32>(),
+            (0..300).map(|i| i * 2).sum::<u32>()
+        );
+        assert_eq!(
+            reader.iter_from(&250).unwrap().next().unwrap().unwrap().key,
+            250
+        );
+
+        let check = reader.verify().unwrap();
+        assert!(check.is_ok(), "{:?}", check);
+        assert_eq!(check.records, 300);
+        drop(btree);
+
+        // filters taken from another tree of keys not in this one
+        let mut other = BTree::<u32, u32>::with_options("other", 4, 4, options.clone()).unwrap();
+        for i in 1000..1300 {
+            other.insert(i, i).unwrap();
+        }
+        other.flush().unwrap();
+        drop(other);
+
+        let filters = storage.open(&filter_path("other")).unwrap();
+        let mut filter_bytes = vec![0; filters.len().unwrap() as usize];
+        filters.read_at(&mut filter_bytes, 0).unwrap();
+        storage.remove(&filter_path("db")).unwrap();
+        storage
+            .open(&filter_path("db"))
+            .unwrap()
+            .append(&filter_bytes)
+            .unwrap();
+
+        // and a damaged record
+        let record_size = 4 + 4 + RECORD_OVERHEAD;
+        let file = storage.open("db").unwrap();
+        let mut bytes = vec![0; file.len().unwrap() as usize];
+        file.read_at(&mut bytes, 0).unwrap();
+        bytes[9 + 10 * record_size..9 + 11 * record_size].fill(0xff);
+        storage.remove("db").unwrap();
+        storage.open("db").unwrap().append(&bytes).unwrap();
+
+        let check = TreeFileReader::<u32, u32>::open(storage.clone(), "db", 4, 4)
+            .unwrap()
+            .verify()
+            .unwrap();
+        assert_eq!((check.records, check.damaged_records), (299, 1));
+        assert!(check.missed_by_sidecars > 250, "{:?}", check);
+        assert_eq!(check.open_error, None);
+        assert!(!check.is_ok());
+
+        assert!(TreeFileReader::<u32, u32>::open(storage, "missing", 4, 4).is_err());
+    }
+
+    #[test]
+    fn batches_are_written_together() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        btree.insert(1, 1).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.insert(1, 2);
+        batch.delete(1, 1);
+        batch.insert(2, 2);
+        btree.write(batch).unwrap();
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.replay_summary().writes, 2);
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![2]));
+        assert_eq!(btree.get(&2).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn insert_all_logs_one_write() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        btree.insert_all((0..50).map(|i| (i % 10, i))).unwrap();
+        btree.insert_all(Vec::new()).unwrap();
+        assert_eq!(btree.stats().writes, 50);
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.replay_summary().writes, 1);
+        assert_eq!(btree.get(&3).unwrap(), Some(vec![3, 13, 23, 33, 43]));
+    }
+
+    #[test]
+    fn insert_replace_returns_the_old_values() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(btree.insert_replace(1, 1).unwrap(), None);
+        btree.insert(1, 2).unwrap();
+
+        assert_eq!(btree.insert_replace(1, 2).unwrap(), Some(vec![1, 2]));
+        assert_eq!(btree.insert_replace(1, 3).unwrap(), Some(vec![2]));
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![3]));
+    }
+
+    #[test]
+    fn take_removes_every_value() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        btree.insert(1, 2).unwrap();
+        btree.insert(1, 1).unwrap();
+        btree.insert(2, 1).unwrap();
+
+        assert_eq!(btree.take(&1).unwrap(), Some(BTreeSet::from([1, 2])));
+        assert_eq!(btree.take(&1).unwrap(), None);
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert_eq!(btree.get(&2).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn ranges_are_aggregated() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            flush_threshold: 10,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        for i in 0..30 {
+            btree.insert(i % 15, 100 - i).unwrap();
+        }
+        btree.delete(5, 95).unwrap();
+
+        assert_eq!(
+            btree.aggregate(5..10, Agg::Count).unwrap(),
+            Aggregate::Count(9)
+        );
+        assert_eq!(
+            btree.aggregate(5..10, Agg::Min).unwrap(),
+            Aggregate::Min(Some(76))
+        );
+        assert_eq!(
+            btree.aggregate(5..10, Agg::Max).unwrap(),
+            Aggregate::Max(Some(94))
+        );
+        assert_eq!(
+            btree.aggregate(50.., Agg::Max).unwrap(),
+            Aggregate::Max(None)
+        );
+
+        // rather than a count of the values read before the failure
+        btree.flush().unwrap();
+        disk.fail_read("db", 0);
+        assert!(btree.aggregate(.., Agg::Count).is_err());
+    }
+
+    #[test]
+    fn range_where_skips_failing_values() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            flush_threshold: 10,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, String>::with_options("db", 4, 16, options).unwrap();
+        for i in 0..25 {
+            let value = if i % 3 == 0 { "keep" } else { "skip" };
+            btree.insert(i % 5, format!("{} {}", value, i)).unwrap();
+        }
+        btree.delete(3, "keep 3".to_owned()).unwrap();
+
+        // a String is encoded as its u64 length, then its bytes
+        let keep = |bytes: &[u8]| bytes[8..].starts_with(b"keep");
+        let kept: Vec<(u32, Vec<String>)> = btree.range_where(1..4, keep).unwrap().collect();
+        assert_eq!(
+            kept,
+            [
+                (1, vec!["keep 21".to_owned(), "keep 6".to_owned()]),
+                (2, vec!["keep 12".to_owned()]),
+                (3, vec!["keep 18".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_are_scanned_without_their_values() {
+        let clock = Arc::new(ManualClock::new(0));
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: clock.clone(),
+            flush_threshold: 10,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, Vec<u8>>::with_options("db", 4, 64, options).unwrap();
+        for i in 0..25 {
+            btree.insert(i, vec![i as u8; 32]).unwrap();
+        }
+        btree.delete(3, vec![3; 32]).unwrap();
+        btree.insert(4, b"other".to_vec()).unwrap();
+        btree.delete(4, vec![4; 32]).unwrap();
+        btree
+            .insert_with_ttl(30, b"short".to_vec(), Duration::from_millis(10))
+            .unwrap();
+        btree
+            .insert_with_ttl(31, b"long".to_vec(), Duration::from_secs(10))
+            .unwrap();
+        clock.advance(Duration::from_millis(100));
+
+        let keys: Vec<u32> = btree.keys(2..6).unwrap().map(Result::unwrap).collect();
+        assert_eq!(keys, [2, 4, 5]);
+        assert_eq!(
+            btree
+                .keys(20..)
+                .unwrap()
+                .collect::<Result<Vec<u32>, _>>()
+                .unwrap(),
+            [20, 21, 22, 23, 24, 31]
+        );
+
+        // checking the keys with deletes and expiries isn't counted as a get
+        assert_eq!(btree.stats().get_latency.count(), 0);
+    }
+
+    #[test]
+    fn lazy_values_decode_on_request() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            flush_threshold: 10,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, String>::with_options("db", 4, 16, options).unwrap();
+        for i in 0..12 {
+            btree.insert(i % 2, format!("value {}", i)).unwrap();
+        }
+        btree.delete(1, "value 3".to_owned()).unwrap();
+
+        let lazy = btree.get_lazy(&1).unwrap().unwrap();
+        assert_eq!(lazy[0].bytes(), b"value 1");
+
+        let values: Vec<String> = lazy.iter().map(|value| value.value().unwrap()).collect();
+        assert_eq!(Some(values), btree.get(&1).unwrap());
+        assert_eq!(btree.get_lazy(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn case_insensitive_keys_are_folded() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree =
+            BTree::<String, u32>::case_insensitive("db", 16, 4, options.clone()).unwrap();
+        btree.insert("Key".to_owned(), 1).unwrap();
+        btree.insert("KEY".to_owned(), 2).unwrap();
+        btree.insert("Other".to_owned(), 3).unwrap();
+
+        assert_eq!(btree.get(&"kEy".to_owned()).unwrap(), Some(vec![1, 2]));
+        assert_eq!(btree.scan_prefix(b"OT").unwrap().count(), 1);
+        let keys: Vec<String> = btree
+            .range("K".to_owned().."L".to_owned())
+            .unwrap()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["key"]);
+        drop(btree);
+
+        let error = BTree::<String, u32>::with_options("db", 16, 4, options.clone())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::FoldCaseMismatch {
+                created_folding: true
+            })
+        );
+        assert!(BTree::<String, u32>::case_insensitive("db", 16, 4, options).is_ok());
+    }
+
+    #[test]
+    fn trees_are_combined_as_sets() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut first = BTree::<u32, u32>::with_options("first", 4, 4, options.clone()).unwrap();
+        let mut second = BTree::<u32, u32>::with_options("second", 4, 4, options.clone()).unwrap();
+        first
+            .insert_all(vec![(1, 1), (1, 2), (2, 2), (3, 3)])
+            .unwrap();
+        first.flush().unwrap();
+        second
+            .insert_all(vec![(1, 2), (1, 3), (3, 3), (4, 4)])
+            .unwrap();
+
+        let combined = |op| {
+            first
+                .combine(&second, op)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        assert_eq!(
+            combined(SetOp::Union),
+            [(1, vec![1, 2, 3]), (2, vec![2]), (3, vec![3]), (4, vec![4])]
+        );
+        assert_eq!(combined(SetOp::Intersection), [(1, vec![2]), (3, vec![3])]);
+        assert_eq!(combined(SetOp::Difference), [(1, vec![1]), (2, vec![2])]);
+
+        let written = first
+            .combine_into(&second, SetOp::Intersection, "both", options)
+            .unwrap();
+        assert_eq!(
+            written.range(..).unwrap().collect::<Vec<_>>(),
+            [(1, vec![2]), (3, vec![3])]
+        );
+    }
+
+    #[test]
+    fn combining_trees_fails_on_a_record_that_cant_be_read() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            ..Options::default()
+        };
+        let mut first = BTree::<u32, u32>::with_options("first", 4, 4, options.clone()).unwrap();
+        let second = BTree::<u32, u32>::with_options("second", 4, 4, options.clone()).unwrap();
+        first.insert_all((0..1000).map(|i| (i, i))).unwrap();
+        first.flush().unwrap();
+
+        // the union would otherwise just end early, and the new tree hold only the
+        // keys read before the failure
+        disk.fail_read("first", 10);
+        assert!(first
+            .combine_into(&second, SetOp::Union, "both", options)
+            .is_err());
+    }
+
+    #[test]
+    fn trees_are_diffed_key_by_key() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            ..Options::default()
+        };
+        let mut first = BTree::<u32, u32>::with_options("first", 4, 4, options.clone()).unwrap();
+        let mut second = BTree::<u32, u32>::with_options("second", 4, 4, options).unwrap();
+        first.insert_all(vec![(1, 1), (2, 2), (3, 3)]).unwrap();
+        first.flush().unwrap();
+        second
+            .insert_all(vec![(1, 1), (2, 2), (2, 4), (4, 4)])
+            .unwrap();
+
+        assert_eq!(
+            first
+                .diff(&second)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            [
+                Diff::Changed(2, vec![2], vec![2, 4]),
+                Diff::Removed(3, vec![3]),
+                Diff::Added(4, vec![4])
+            ]
+        );
+
+        second.delete(2, 4).unwrap();
+        second.delete(4, 4).unwrap();
+        second.insert(3, 3).unwrap();
+        assert_eq!(first.diff(&second).unwrap().count(), 0);
+
+        // a failed read would otherwise show every key after it as removed
+        first.insert_all((10..1000).map(|i| (i, i))).unwrap();
+        first.flush().unwrap();
+        disk.fail_read("first", 10);
+        assert!(first
+            .diff(&first)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .is_err());
+    }
+
+    #[test]
+    fn trees_are_split_along_keys() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        btree.insert_all((0..10).map(|i| (i, i * 10))).unwrap();
+        btree.insert(4, 1).unwrap();
+
+        let shards = btree.split(&[3, 7], "out", options.clone()).unwrap();
+        let keys: Vec<Vec<u32>> = shards
+            .iter()
+            .map(|shard| shard.range(..).unwrap().map(|(key, _)| key).collect())
+            .collect();
+        assert_eq!(keys, [vec![0, 1, 2], vec![3, 4, 5, 6], vec![7, 8, 9]]);
+        assert_eq!(shards[1].get(&4).unwrap(), Some(vec![1, 40]));
+        drop(shards);
+
+        let reopened =
+            BTree::<u32, u32>::with_options("out/shard2", 4, 4, options.clone()).unwrap();
+        assert_eq!(reopened.get(&9).unwrap(), Some(vec![90]));
+        assert!(btree.split(&[7, 3], "out", options).is_err());
+    }
+
+    #[test]
+    fn bulk_loads_are_merged_into_the_tree_file() {
+        let storage = Arc::new(SimDisk::new(0));
+        let options = Options {
+            storage: storage.clone(),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        btree.insert(5, 0).unwrap();
+        btree.insert(1_000, 0).unwrap();
+
+        // a shuffled load, several runs' worth
+        let mut entries: Vec<(u32, u32)> = (0..1_000).map(|i| (i, i * 2)).collect();
+        entries.shuffle(&mut thread_rng());
+        assert_eq!(btree.bulk_load(entries, 64).unwrap(), 1_000);
+
+        assert_eq!(
+            btree.stats().wal_bytes,
+            2 * (FRAME_OVERHEAD + btree.record_size()) as u64
+        );
+        assert!(!storage.exists("db.load.L0.1").unwrap());
+        assert_eq!(btree.get(&5).unwrap(), Some(vec![0, 10]));
+
+        let keys: Vec<u32> = btree.range(..).unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, (0..=1_000).collect::<Vec<_>>());
+        drop(btree);
+
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&999).unwrap(), Some(vec![1_998]));
+        btree.insert(2_000, 0).unwrap();
+        assert_eq!(btree.get_versions(&2_000).unwrap()[0].seq, 1_003);
+    }
+
+    #[test]
+    fn counters_add_up_their_increments() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, i64>::counters("db", 4, 8, options.clone()).unwrap();
+        btree.insert(1, 10).unwrap();
+        btree.increment(1, 5).unwrap();
+        btree.increment(1, 5).unwrap();
+        btree.increment(2, 3).unwrap();
+        btree.increment(2, -1).unwrap();
+
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![20]));
+        assert_eq!(btree.get(&2).unwrap(), Some(vec![2]));
+
+        // with the counts on disk, an increment is only an append
+        btree.flush().unwrap();
+        let lookups =
+            |btree: &BTree<u32, i64>| btree.stats().cache_hits + btree.stats().cache_misses;
+        let before = lookups(&btree);
+        for _ in 0..3 {
+            btree.increment(1, 1).unwrap();
+        }
+        assert_eq!(lookups(&btree), before);
+
+        assert_eq!(
+            btree.range(..).unwrap().collect::<Vec<_>>(),
+            [(1, vec![23]), (2, vec![2])]
+        );
+        drop(btree);
+
+        let mut btree = BTree::<u32, i64>::counters("db", 4, 8, options.clone()).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![23]));
+        btree.flush().unwrap();
+        btree.increment(3, 1).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 3);
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![23]));
+        drop(btree);
+
+        let mut plain = BTree::<u32, i64>::with_options("db", 4, 8, options).unwrap();
+        assert!(plain.increment(1, 1).is_err());
+    }
+
+    #[test]
+    fn prepared_batches_wait_for_a_decision() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        let mut first = WriteBatch::new();
+        first.insert(1, 1);
+        let mut second = WriteBatch::new();
+        second.insert(2, 2);
+
+        let first = btree.prepare(first).unwrap();
+        let second = btree.prepare(second).unwrap();
+        assert_eq!(btree.get(&1).unwrap(), None);
+        drop(btree);
+
+        // the decision can come after the tree has been opened again
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(btree.prepared(), [first, second]);
+
+        btree.commit(first).unwrap();
+        btree.rollback(second).unwrap();
+        assert!(btree.commit(second).is_err());
+        assert_eq!(btree.get(&1).unwrap(), Some(vec![1]));
+        assert_eq!(btree.get(&2).unwrap(), None);
+
+        // a commit recorded but cut off before its batch reached the WAL is finished on opening
+        let mut third = WriteBatch::new();
+        third.insert(3, 3);
+        let third = btree.prepare(third).unwrap();
+        let first_seq = btree.last_seq + 1;
+        btree
+            .prepared_log
+            .as_mut()
+            .unwrap()
+            .commit(third, first_seq)
+            .unwrap();
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert!(btree.prepared().is_empty());
+        assert_eq!(btree.get(&3).unwrap(), Some(vec![3]));
     }
 }
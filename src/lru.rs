@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+/// The keys of a tree in LRU cache mode, see `Options::lru_capacity`, by how
+/// recently each was read or written
+pub struct LruKeys<K> {
+    capacity: usize,
+    tick: u64, // bumped on every use, so older ticks are less recently used
+    ticks: BTreeMap<K, u64>,
+    by_tick: BTreeMap<u64, K>,
+}
+
+impl<K: Ord + Clone> LruKeys<K> {
+    pub fn new(capacity: usize) -> LruKeys<K> {
+        LruKeys {
+            capacity,
+            tick: 0,
+            ticks: BTreeMap::new(),
+            by_tick: BTreeMap::new(),
+        }
+    }
+
+    /// Makes `key` the most recently used
+    pub fn touch(&mut self, key: &K) {
+        self.tick += 1;
+
+        if let Some(old) = self.ticks.insert(key.clone(), self.tick) {
+            self.by_tick.remove(&old);
+        }
+
+        self.by_tick.insert(self.tick, key.clone());
+    }
+
+    /// Stops tracking `key`, once it holds no values
+    pub fn forget(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.by_tick.remove(&tick);
+        }
+    }
+
+    /// Takes the least recently used key while there are more than the capacity
+    pub fn pop_excess(&mut self) -> Option<K> {
+        if self.ticks.len() <= self.capacity {
+            return None;
+        }
+
+        let (_, key) = self.by_tick.pop_first()?;
+        self.ticks.remove(&key);
+
+        Some(key)
+    }
+}
@@ -0,0 +1,258 @@
+//! The LZ4 block format: a sequence of literals, then a match copying bytes from
+//! up to 64KiB back, repeated. It's fast to decode rather than small, which suits
+//! compressing writes on their way to the WAL.
+
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+
+/// Matches are at least this long
+const MIN_MATCH: usize = 4;
+
+/// The last this many bytes are always literals
+const LAST_LITERALS: usize = 5;
+
+/// No match starts in the last this many bytes
+const MATCH_LIMIT: usize = 12;
+
+/// How far back a match can copy from
+const MAX_OFFSET: usize = 0xFFFF;
+
+const HASH_BITS: u32 = 12;
+
+fn corrupt() -> Box<dyn Error> {
+    From::from(IOError::new(ErrorKind::InvalidData, "corrupt lz4 block"))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Writes a length that didn't fit in its half of the token: as many 255s as it
+/// takes, then what's left
+fn put_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+
+    out.push(len as u8);
+}
+
+fn get_length(input: &[u8], pos: &mut usize, mut len: usize) -> Result<usize, Box<dyn Error>> {
+    if len == 15 {
+        loop {
+            let byte = *input.get(*pos).ok_or_else(corrupt)?;
+            *pos += 1;
+            len += byte as usize;
+
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+
+    Ok(len)
+}
+
+/// Writes `literals` followed by the match `found`, how far back it copies from
+/// and its length, or by nothing for the last sequence
+fn put_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+
+    if literals.len() >= 15 {
+        put_length(out, literals.len() - 15);
+    }
+
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+
+        if match_len >= 15 {
+            put_length(out, match_len - 15);
+        }
+    }
+}
+
+/// Compresses `input` as a single block
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut table = vec![0; 1 << HASH_BITS]; // one past where each hash was last seen
+    let (mut anchor, mut i) = (0, 0);
+
+    while input.len() >= MATCH_LIMIT && i <= input.len() - MATCH_LIMIT {
+        let sequence = read_u32(input, i);
+        let candidate = std::mem::replace(&mut table[hash(sequence)], i + 1);
+
+        if candidate > 0
+            && i - (candidate - 1) <= MAX_OFFSET
+            && read_u32(input, candidate - 1) == sequence
+        {
+            let from = candidate - 1;
+            let mut len = MIN_MATCH;
+
+            while i + len < input.len() - LAST_LITERALS && input[from + len] == input[i + len] {
+                len += 1;
+            }
+
+            put_sequence(&mut out, &input[anchor..i], Some((i - from, len)));
+            i += len;
+            anchor = i;
+        } else {
+            i += 1;
+        }
+    }
+
+    put_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// How much a byte of a block can decode to at most, a length byte of 255
+const MAX_EXPANSION: usize = 255;
+
+/// Decompresses a block that decodes to `len` bytes. Any input, however
+/// corrupt, gives back either the bytes or an error.
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    // a corrupt length isn't allocated up front
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(MAX_EXPANSION)));
+    let mut pos = 0;
+
+    loop {
+        let token = *input.get(pos).ok_or_else(corrupt)?;
+        pos += 1;
+
+        let literals = get_length(input, &mut pos, (token >> 4) as usize)?;
+
+        if out.len() + literals > len {
+            return Err(corrupt());
+        }
+
+        out.extend_from_slice(input.get(pos..pos + literals).ok_or_else(corrupt)?);
+        pos += literals;
+
+        // the last sequence has no match
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = input.get(pos..pos + 2).ok_or_else(corrupt)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]);
+        pos += 2;
+
+        let match_len = get_length(input, &mut pos, (token & 0xF) as usize)? + MIN_MATCH;
+
+        if offset == 0 || offset as usize > out.len() || out.len() + match_len > len {
+            return Err(corrupt());
+        }
+
+        // the match can overlap the bytes it produces, so it's copied a byte at a time
+        let from = out.len() - offset as usize;
+
+        for j in 0..match_len {
+            out.push(out[from + j]);
+        }
+    }
+
+    if out.len() != len {
+        return Err(corrupt());
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use lz4::{compress, decompress};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn blocks_decompress_to_what_was_compressed() {
+        let repetitive: Vec<u8> = (0..5000).map(|i| (i % 7) as u8).collect();
+        let varied: Vec<u8> = (0..5000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut padded = b"a record, then padding out to the record size".to_vec();
+        padded.resize(300, 0);
+
+        for input in [Vec::new(), b"short".to_vec(), repetitive, varied, padded] {
+            let block = compress(&input);
+
+            assert_eq!(decompress(&block, input.len()).unwrap(), input);
+        }
+
+        let mut zeros = vec![0; 4096];
+        assert!(compress(&zeros).len() < 64);
+
+        zeros.truncate(100);
+        assert!(decompress(&compress(&zeros), 99).is_err());
+    }
+
+    #[test]
+    fn random_blocks_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..2000 {
+            // from all-alike to all-random bytes, so matches of every length and offset turn up
+            let alphabet = rng.gen_range(1..=256);
+            let len = rng.gen_range(0..2000);
+            let mut input: Vec<u8> = (0..len).map(|_| rng.gen_range(0..alphabet) as u8).collect();
+
+            // and runs repeated from far back
+            if len > 100 && rng.gen_bool(0.5) {
+                let (from, to) = (rng.gen_range(0..len / 2), rng.gen_range(len / 2..len));
+                let run = rng.gen_range(1..=len - to);
+                input.copy_within(from..from + run, to);
+            }
+
+            let block = compress(&input);
+            assert_eq!(decompress(&block, input.len()).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn arbitrary_blocks_decode_or_fail_without_panicking() {
+        let mut rng = StdRng::seed_from_u64(2);
+
+        for _ in 0..20_000 {
+            let block: Vec<u8> = if rng.gen_bool(0.5) {
+                let len = rng.gen_range(0..64);
+                (0..len).map(|_| rng.gen()).collect()
+            } else {
+                // a valid block with a few bytes flipped, cut short or run on
+                let input: Vec<u8> = (0..rng.gen_range(0..500))
+                    .map(|_| rng.gen_range(0..4))
+                    .collect();
+                let mut block = compress(&input);
+                for _ in 0..rng.gen_range(1..4) {
+                    if !block.is_empty() {
+                        let at = rng.gen_range(0..block.len());
+                        block[at] = rng.gen();
+                    }
+                }
+                match rng.gen_range(0..3) {
+                    0 => block.truncate(rng.gen_range(0..=block.len())),
+                    1 => block.push(rng.gen()),
+                    _ => {}
+                }
+                block
+            };
+            let len = match rng.gen_range(0..3) {
+                0 => rng.gen_range(0..1000),
+                1 => usize::MAX,
+                _ => block.len() * 4,
+            };
+
+            if let Ok(out) = decompress(&block, len) {
+                assert_eq!(out.len(), len);
+            }
+        }
+    }
+}
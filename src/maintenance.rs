@@ -0,0 +1,124 @@
+use {BTree, KeyType, ValueType};
+
+use std::error::Error;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Calls `BTree::maintain` every so often, on a thread of its own, for a tree shared
+/// between threads. An instance that sees few writes still syncs the writes an
+/// interval sync policy held back, flushes its memtable once it's been idle for
+/// `Options::idle_flush`, and reclaims expired records, rather than waiting for a
+/// write to set them off. The timer stops when it's dropped, when the tree is
+/// dropped everywhere else, or when `maintain` fails.
+pub struct MaintenanceTimer {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl MaintenanceTimer {
+    /// Starts calling `tree.maintain()` every `every`, holding its lock only while
+    /// it does
+    pub fn start<K, V>(tree: &Arc<Mutex<BTree<K, V>>>, every: Duration) -> MaintenanceTimer
+    where
+        K: KeyType + 'static,
+        V: ValueType + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let tree = Arc::downgrade(tree);
+
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(every) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return Ok(()),
+            }
+
+            let tree = match tree.upgrade() {
+                Some(tree) => tree,
+                None => return Ok(()),
+            };
+
+            let mut tree = tree
+                .lock()
+                .map_err(|_| "A thread panicked while writing to the tree".to_owned())?;
+            tree.maintain().map_err(|e| e.to_string())?;
+        });
+
+        MaintenanceTimer {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the timer, waiting for a `maintain` under way to finish. Returns the
+    /// error that stopped it early, if there was one.
+    pub fn stop(mut self) -> Result<(), Box<dyn Error>> {
+        self.halt()
+    }
+
+    fn halt(&mut self) -> Result<(), Box<dyn Error>> {
+        drop(self.stop.take());
+
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result.map_err(From::from),
+            Some(Err(_)) => Err(From::from("The maintenance thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MaintenanceTimer {
+    fn drop(&mut self) {
+        let _ = self.halt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use maintenance::MaintenanceTimer;
+    use {BTree, ManualClock, Options, SimDisk, SyncPolicy};
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn idle_trees_are_synced_and_flushed_on_a_timer() {
+        let clock = ManualClock::new(0);
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(clock.clone()),
+            sync_policy: SyncPolicy::Interval(Duration::from_secs(1)),
+            idle_flush: Some(Duration::from_secs(10)),
+            ..Options::default()
+        };
+        let tree = Arc::new(Mutex::new(
+            BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap(),
+        ));
+        let write = tree.lock().unwrap().insert_async(1, 10).unwrap();
+        let flushes = || tree.lock().unwrap().stats().flushes;
+
+        let timer = MaintenanceTimer::start(&tree, Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!write.is_durable());
+
+        // no write follows, so it's the timer that syncs once the interval is up
+        clock.advance(Duration::from_secs(1));
+        assert!(write.wait_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(flushes(), 0);
+
+        // and that flushes the memtable once it's been idle long enough
+        clock.advance(Duration::from_secs(9));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while flushes() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(flushes(), 1);
+
+        thread::sleep(Duration::from_millis(20));
+        timer.stop().unwrap();
+        assert_eq!(flushes(), 1);
+        assert_eq!(tree.lock().unwrap().get(&1).unwrap(), Some(vec![10]));
+    }
+}
@@ -0,0 +1,170 @@
+use stats::Stats;
+
+use std::fmt::Write;
+
+/// Renders the stats of a tree in the Prometheus text exposition format. Counters
+/// only ever go up while the tree is open, so rates such as inserts per second are
+/// left to queries like `rate(btree_writes_total[1m])`.
+pub fn encode(stats: &Stats) -> String {
+    let metrics: [(&str, &str, &str, f64); 13] = [
+        (
+            "btree_writes_total",
+            "counter",
+            "Records written, puts and deletes alike",
+            stats.writes as f64,
+        ),
+        (
+            "btree_wal_bytes_total",
+            "counter",
+            "Bytes appended to the WAL",
+            stats.wal_bytes as f64,
+        ),
+        (
+            "btree_flushes_total",
+            "counter",
+            "Memtables written out",
+            stats.flushes as f64,
+        ),
+        (
+            "btree_compactions_total",
+            "counter",
+            "Merges into the tree file",
+            stats.compactions as f64,
+        ),
+        (
+            "btree_compaction_seconds_total",
+            "counter",
+            "Time spent merging into the tree file",
+            stats.compaction_time.as_secs_f64(),
+        ),
+        (
+            "btree_stall_seconds_total",
+            "counter",
+            "Time writes were delayed by the write throttle",
+            stats.stall_time.as_secs_f64(),
+        ),
+        (
+            "btree_stalled_writes_total",
+            "counter",
+            "Writes refused at the write throttle's hard limit",
+            stats.stalled_writes as f64,
+        ),
+        (
+            "btree_spills_total",
+            "counter",
+            "Memtables spilled to overflow runs at the write throttle's hard limit",
+            stats.spills as f64,
+        ),
+        (
+            "btree_evictions_total",
+            "counter",
+            "Keys evicted to keep the tree within its LRU capacity",
+            stats.evictions as f64,
+        ),
+        (
+            "btree_cache_hits_total",
+            "counter",
+            "Block lookups the cache answered",
+            stats.cache_hits as f64,
+        ),
+        (
+            "btree_cache_misses_total",
+            "counter",
+            "Block lookups the cache missed",
+            stats.cache_misses as f64,
+        ),
+        (
+            "btree_pending_bytes",
+            "gauge",
+            "Bytes in the memtable waiting to be flushed",
+            stats.pending_bytes as f64,
+        ),
+        (
+            "btree_cache_bytes",
+            "gauge",
+            "Bytes held by the block cache",
+            stats.cache_bytes as f64,
+        ),
+    ];
+
+    let mut text = String::new();
+
+    for (name, kind, help, value) in metrics {
+        // writing to a String can't fail
+        let _ = writeln!(
+            text,
+            "# HELP {} {}\n# TYPE {} {}\n{} {}",
+            name, help, name, kind, name, value
+        );
+    }
+
+    let latencies = [
+        (
+            "btree_get_seconds",
+            "Time taken by lookups of a key",
+            &stats.get_latency,
+        ),
+        (
+            "btree_insert_seconds",
+            "Time taken by writes, with any flush they set off",
+            &stats.insert_latency,
+        ),
+        (
+            "btree_flush_seconds",
+            "Time taken writing out memtables",
+            &stats.flush_latency,
+        ),
+        (
+            "btree_compaction_seconds",
+            "Time taken by merges into the tree file",
+            &stats.compaction_latency,
+        ),
+    ];
+
+    // the quantiles are those of every operation since the tree was opened
+    for (name, help, histogram) in latencies {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} summary", name, help, name);
+
+        for quantile in [0.5, 0.9, 0.99, 0.999] {
+            let value = histogram
+                .percentile(quantile * 100.0)
+                .map_or(f64::NAN, |at| at.as_secs_f64());
+            let _ = writeln!(text, "{}{{quantile=\"{}\"}} {}", name, quantile, value);
+        }
+
+        let (total, count) = (histogram.total().as_secs_f64(), histogram.count());
+        let _ = writeln!(text, "{}_sum {}\n{}_count {}", name, total, name, count);
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::encode;
+    use stats::Stats;
+
+    use std::time::Duration;
+
+    #[test]
+    fn stats_are_rendered_as_prometheus_text() {
+        let stats = Stats {
+            writes: 12,
+            compaction_time: Duration::from_millis(1500),
+            cache_bytes: 4096,
+            ..Stats::default()
+        };
+        let text = encode(&stats);
+
+        assert!(text.contains("# TYPE btree_writes_total counter\nbtree_writes_total 12\n"));
+        assert!(text.contains("\nbtree_compaction_seconds_total 1.5\n"));
+        assert!(text.contains("# TYPE btree_cache_bytes gauge\nbtree_cache_bytes 4096\n"));
+        assert_eq!(
+            text.lines().filter(|line| !line.starts_with('#')).count(),
+            13 + 4 * 6
+        );
+        assert!(
+            text.contains("\nbtree_get_seconds{quantile=\"0.999\"} NaN\nbtree_get_seconds_sum 0\n")
+        );
+    }
+}
@@ -1,47 +1,148 @@
+use counter::Counting;
 use {KeyType, ValueType};
 
-use wal_file::KeyValuePair;
+use wal_file::{KeyRecord, KeyValuePair, RecordKind};
 
 use std::collections::btree_map;
 use std::collections::btree_map::Entry::Occupied;
-use std::collections::btree_set;
-use std::collections::btree_set::Iter;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Keys;
+use std::collections::BTreeMap;
+
+/// What's stored alongside each value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueMeta {
+    pub kind: RecordKind,        // whether the value is present or deleted
+    pub seq: u64,                // when the value was last written
+    pub written_at: u64,         // the time it was last written
+    pub expires_at: Option<u64>, // when it expires, if ever
+}
+
+type ValueSet<V> = BTreeMap<V, ValueMeta>;
 
 pub struct MultiMap<K: KeyType, V: ValueType> {
-    multi_map: BTreeMap<K, BTreeSet<V>>,
-    count: usize, // total number of KV pairs
+    multi_map: BTreeMap<K, ValueSet<V>>,
+    count: usize,                                // total number of KV pairs
+    superseded: Option<Vec<KeyValuePair<K, V>>>, // older writes, when keeping history
+    counting: Option<Counting<V>>,               // how increments add up, in a tree of counters
 }
 
 pub struct MultiMapIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
     cur_key: Option<&'a K>,
-    key_it: btree_map::Iter<'a, K, BTreeSet<V>>,
-    value_it: Option<btree_set::Iter<'a, V>>,
+    key_it: btree_map::Iter<'a, K, ValueSet<V>>,
+    value_it: Option<btree_map::Iter<'a, V, ValueMeta>>,
 }
 
-impl<'a, K: KeyType, V: ValueType> MultiMap<K, V> {
+impl<K: KeyType, V: ValueType> MultiMap<K, V> {
     pub fn new() -> MultiMap<K, V> {
         MultiMap {
-            multi_map: BTreeMap::<K, BTreeSet<V>>::new(),
+            multi_map: BTreeMap::<K, ValueSet<V>>::new(),
             count: 0,
+            superseded: None,
+            counting: None,
+        }
+    }
+
+    /// A multimap that remembers the previous writes of a value when it's re-written
+    pub fn with_history() -> MultiMap<K, V> {
+        MultiMap {
+            superseded: Some(Vec::new()),
+            ..MultiMap::new()
         }
     }
 
+    /// This multimap, adding up the increments written to each key as they come
+    pub fn counting(self, counting: Option<Counting<V>>) -> MultiMap<K, V> {
+        MultiMap { counting, ..self }
+    }
+
     pub fn insert(&mut self, key: K, value: V) -> usize {
+        self.insert_record(KeyValuePair::new(key, value))
+    }
+
+    /// Inserts a record, replacing the metadata of the value if it's already present.
+    /// Increments are added up when the multimap is `counting`.
+    pub fn insert_record(&mut self, kv: KeyValuePair<K, V>) -> usize {
         self.count += 1;
 
-        if let Some(set) = self.multi_map.get_mut(&key) {
-            set.insert(value);
-            return self.count;
+        match self.counting {
+            Some(counting) if kv.kind == RecordKind::Merge => self.insert_increment(kv, counting),
+            _ => self.put(kv),
         }
 
-        let mut set = BTreeSet::<V>::new();
+        self.count
+    }
+
+    /// Adds an increment to the one pending under its key, or, once the put or
+    /// delete it counts up from is in memory too, folds them into the value counted
+    /// up to, so a key never holds more than one increment
+    fn insert_increment(&mut self, kv: KeyValuePair<K, V>, counting: Counting<V>) {
+        let set = self.multi_map.entry(kv.key.clone()).or_default();
+
+        let base = set
+            .iter()
+            .filter(|(_, meta)| meta.kind != RecordKind::Merge)
+            .max_by_key(|(_, meta)| meta.seq)
+            .map(|(value, meta)| record(&kv.key, value, meta));
+        let base_seq = base.as_ref().map_or(0, |base| base.seq);
+
+        // an increment older than the base was overtaken by it
+        let pending = set
+            .iter()
+            .find(|(_, meta)| meta.kind == RecordKind::Merge && meta.seq > base_seq)
+            .map(|(delta, _)| delta.clone());
+        set.retain(|_, meta| meta.kind != RecordKind::Merge);
+
+        let increment = KeyValuePair {
+            value: pending.map_or(kv.value.clone(), |pending| {
+                counting.add(&pending, &kv.value)
+            }),
+            ..kv
+        };
+
+        match base {
+            Some(base) => {
+                for folded in counting.fold(vec![increment, base.clone()]) {
+                    if folded != base {
+                        self.put(folded);
+                    }
+                }
+            }
+            None => self.put(increment),
+        }
+    }
 
-        set.insert(value);
+    /// Stores a record as it is, keeping the write it replaces when there's history
+    fn put(&mut self, kv: KeyValuePair<K, V>) {
+        let meta = ValueMeta {
+            kind: kv.kind,
+            seq: kv.seq,
+            written_at: kv.written_at,
+            expires_at: kv.expires_at,
+        };
+
+        if let Some(set) = self.multi_map.get_mut(&kv.key) {
+            if let Some(superseded) = self.superseded.as_mut() {
+                if let Some(old) = set.get(&kv.value) {
+                    superseded.push(KeyValuePair {
+                        key: kv.key.clone(),
+                        value: kv.value.clone(),
+                        kind: old.kind,
+                        seq: old.seq,
+                        written_at: old.written_at,
+                        expires_at: old.expires_at,
+                    });
+                }
+            }
 
-        self.multi_map.insert(key, set);
+            set.insert(kv.value, meta);
+            return;
+        }
 
-        self.count
+        let mut set = ValueSet::<V>::new();
+
+        set.insert(kv.value, meta);
+
+        self.multi_map.insert(kv.key, set);
     }
 
     /*
@@ -49,15 +150,34 @@ impl<'a, K: KeyType, V: ValueType> MultiMap<K, V> {
      * not one tied to our underlying implementation. Not really
      * sure how: https://goo.gl/9sisAb
      */
-    pub fn get(&self, key: &K) -> Option<Iter<V>> {
+    pub fn get(&self, key: &K) -> Option<Keys<'_, V, ValueMeta>> {
+        self.multi_map.get(key).map(|set| set.keys())
+    }
+
+    /// Like `get`, but also returns the metadata of each value
+    pub fn get_with_meta(&self, key: &K) -> Option<btree_map::Iter<'_, V, ValueMeta>> {
         self.multi_map.get(key).map(|set| set.iter())
     }
 
+    /// The older writes replaced since this multimap was created, in no particular
+    /// order. Always empty unless it was created `with_history`.
+    pub fn superseded(&self) -> &[KeyValuePair<K, V>] {
+        self.superseded.as_deref().unwrap_or(&[])
+    }
+
+    /// The key and metadata of every value, in key order, without cloning the values
+    pub fn key_records(&self) -> impl Iterator<Item = KeyRecord<K>> + '_ {
+        self.multi_map.iter().flat_map(|(key, values)| {
+            values.values().map(move |meta| KeyRecord {
+                key: key.clone(),
+                kind: meta.kind,
+                expires_at: meta.expires_at,
+            })
+        })
+    }
+
     pub fn contains_key(&self, key: &K) -> bool {
-        match self.get(key) {
-            Some(_) => true,
-            None => false,
-        }
+        self.get(key).is_some()
     }
 
     /*
@@ -67,7 +187,7 @@ impl<'a, K: KeyType, V: ValueType> MultiMap<K, V> {
      */
     pub fn delete(&mut self, key: K, value: V) -> usize {
         if let Occupied(mut entry) = self.multi_map.entry(key) {
-            if entry.get_mut().remove(&value) {
+            if entry.get_mut().remove(&value).is_some() {
                 self.count -= 1;
             }
 
@@ -84,11 +204,29 @@ impl<'a, K: KeyType, V: ValueType> MultiMap<K, V> {
     }
 }
 
+fn record<K: Clone, V: Clone>(key: &K, value: &V, meta: &ValueMeta) -> KeyValuePair<K, V> {
+    KeyValuePair {
+        key: key.clone(),
+        value: value.clone(),
+        kind: meta.kind,
+        seq: meta.seq,
+        written_at: meta.written_at,
+        expires_at: meta.expires_at,
+    }
+}
+
 impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a mut MultiMap<K, V> {
     type Item = KeyValuePair<K, V>;
     type IntoIter = MultiMapIterator<'a, K, V>;
 
     fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: KeyType, V: ValueType> MultiMap<K, V> {
+    /// Iterates over every record in key and then value order
+    pub fn iter(&self) -> MultiMapIterator<'_, K, V> {
         let mut key_it = self.multi_map.iter();
         let cur_entry = key_it.next();
 
@@ -96,7 +234,7 @@ impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a mut MultiMap<K, V> {
         if cur_entry.is_none() {
             return MultiMapIterator {
                 cur_key: None,
-                key_it: key_it,
+                key_it,
                 value_it: None,
             };
         }
@@ -106,7 +244,7 @@ impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a mut MultiMap<K, V> {
 
         MultiMapIterator {
             cur_key: Some(cur_key),
-            key_it: key_it,
+            key_it,
             value_it: Some(cur_set.iter()),
         }
     }
@@ -117,9 +255,7 @@ impl<'a, K: KeyType, V: ValueType> Iterator for MultiMapIterator<'a, K, V> {
 
     fn next(&mut self) -> Option<Self::Item> {
         // this is our invariant, when it's None we've gone through everything
-        if self.cur_key.is_none() {
-            return None;
-        }
+        self.cur_key?;
 
         // should be safe to call unwrap here, because we checked for None above
         let mut cur_val = self.value_it.as_mut().unwrap().next();
@@ -141,9 +277,15 @@ impl<'a, K: KeyType, V: ValueType> Iterator for MultiMapIterator<'a, K, V> {
             cur_val = self.value_it.as_mut().unwrap().next(); // set our current value
         }
 
+        let (value, meta) = cur_val.unwrap();
+
         Some(KeyValuePair {
             key: self.cur_key.unwrap().clone(),
-            value: cur_val.unwrap().clone(),
+            value: value.clone(),
+            kind: meta.kind,
+            seq: meta.seq,
+            written_at: meta.written_at,
+            expires_at: meta.expires_at,
         })
     }
 }
@@ -151,6 +293,7 @@ impl<'a, K: KeyType, V: ValueType> Iterator for MultiMapIterator<'a, K, V> {
 #[cfg(test)]
 mod tests {
     use multi_map::MultiMap;
+    use wal_file::KeyValuePair;
 
     #[test]
     fn test_insert() {
@@ -214,6 +357,46 @@ mod tests {
 
         let mut it = mmap.into_iter();
 
-        assert!(it.next() == None);
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut plain = MultiMap::<i32, i32>::new();
+
+        plain.insert(1, 1);
+        plain.insert(1, 1);
+        assert!(plain.superseded().is_empty());
+
+        let mut mmap = MultiMap::<i32, i32>::with_history();
+
+        mmap.insert_record(KeyValuePair {
+            seq: 1,
+            ..KeyValuePair::new(1, 1)
+        });
+        mmap.insert_record(KeyValuePair {
+            seq: 2,
+            ..KeyValuePair::new(1, 2)
+        });
+        mmap.insert_record(KeyValuePair {
+            seq: 3,
+            ..KeyValuePair::new(1, 1)
+        });
+
+        // only the old write of 1 was replaced
+        assert_eq!(
+            mmap.superseded(),
+            [KeyValuePair {
+                seq: 1,
+                ..KeyValuePair::new(1, 1)
+            }]
+        );
+        assert_eq!(
+            mmap.get_with_meta(&1)
+                .unwrap()
+                .map(|(_, m)| m.seq)
+                .collect::<Vec<_>>(),
+            [3, 2]
+        );
     }
 }
@@ -0,0 +1,640 @@
+use storage::{Storage, StorageFile};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::Error as IOError;
+use std::io::{ErrorKind, Result as IOResult};
+use std::sync::{Arc, Mutex, Weak};
+
+/// A bucket of immutable objects, like S3: each is written whole and read back
+/// by ranges
+pub trait ObjectStore: Send + Sync {
+    /// Reads `len` bytes of the object at `key`, from `offset`
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> IOResult<Vec<u8>>;
+
+    /// The size of the object at `key`, or None if there isn't one
+    fn size(&self, key: &str) -> IOResult<Option<u64>>;
+
+    /// Writes the object at `key`, replacing any that's there
+    fn put(&self, key: &str, bytes: &[u8]) -> IOResult<()>;
+
+    /// Starts writing the object at `key` a part at a time, so an object needn't
+    /// fit in memory, as a multipart upload does. By default the parts are
+    /// gathered up and put in one go.
+    fn upload<'a>(&'a self, key: &str) -> IOResult<Box<dyn ObjectUpload + 'a>> {
+        Ok(Box::new(BufferedUpload {
+            store: self,
+            key: key.to_owned(),
+            bytes: Vec::new(),
+        }))
+    }
+
+    fn delete(&self, key: &str) -> IOResult<()>;
+
+    fn copy(&self, from: &str, to: &str) -> IOResult<()> {
+        let len = self
+            .size(from)?
+            .ok_or_else(|| IOError::new(ErrorKind::NotFound, from.to_owned()))?;
+
+        self.put(to, &self.get_range(from, 0, len)?)
+    }
+}
+
+/// An object being written a part at a time, see `ObjectStore::upload`. Nothing
+/// is at its key until it's finished; dropping it before then abandons it.
+pub trait ObjectUpload {
+    /// Adds the next part of the object
+    fn write_part(&mut self, part: &[u8]) -> IOResult<()>;
+
+    /// Puts the object in place
+    fn finish(self: Box<Self>) -> IOResult<()>;
+}
+
+/// The upload stores without multipart uploads get, see `ObjectStore::upload`
+struct BufferedUpload<'a, S: ?Sized> {
+    store: &'a S,
+    key: String,
+    bytes: Vec<u8>,
+}
+
+impl<S: ObjectStore + ?Sized> ObjectUpload for BufferedUpload<'_, S> {
+    fn write_part(&mut self, part: &[u8]) -> IOResult<()> {
+        self.bytes.extend_from_slice(part);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> IOResult<()> {
+        self.store.put(&self.key, &self.bytes)
+    }
+}
+
+/// An object store in memory, for tests
+#[derive(Default)]
+pub struct MemoryObjectStore {
+    objects: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+}
+
+impl MemoryObjectStore {
+    pub fn new() -> MemoryObjectStore {
+        MemoryObjectStore::default()
+    }
+
+    /// The keys of every object, sorted
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.objects.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+}
+
+impl ObjectStore for MemoryObjectStore {
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> IOResult<Vec<u8>> {
+        let object = self.objects.lock().unwrap().get(key).cloned();
+        let object = object.ok_or_else(|| IOError::new(ErrorKind::NotFound, key.to_owned()))?;
+
+        match object.get(offset as usize..(offset + len) as usize) {
+            Some(bytes) => Ok(bytes.to_vec()),
+            None => Err(IOError::new(
+                ErrorKind::UnexpectedEof,
+                "read past the end of the object",
+            )),
+        }
+    }
+
+    fn size(&self, key: &str) -> IOResult<Option<u64>> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|object| object.len() as u64))
+    }
+
+    fn put(&self, key: &str, bytes: &[u8]) -> IOResult<()> {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), Arc::new(bytes.to_vec()));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> IOResult<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// Storage that keeps files on `local` storage while they're written, and moves
+/// them to an object store once they're sealed: the tree file once compaction
+/// has installed it, and L0 runs once they're flushed. The WAL and the other
+/// files that change in place never leave local storage, so writes stay as fast
+/// as they are without it, while the bulk of the data sits somewhere cheap and
+/// durable. Sealed files are read back by ranges, a block at a time, through
+/// the block cache.
+///
+/// A sealed file leaves a pointer behind on local storage, next to where it was,
+/// holding its size and the key of its object. Opening a file goes by the
+/// pointer, so finding where a file lives never costs a request to the object
+/// store, and another process opening the tree finds the files where this one
+/// put them. A local file is used over a pointer beside it, which is what makes
+/// sealing and renames safe to crash in: a new file or pointer is put in place
+/// before the one it replaces is taken away, and objects are deleted last.
+pub struct ObjectStorage {
+    local: Arc<dyn Storage>,
+    store: Arc<dyn ObjectStore>,
+    cold_level: u32, // files sealed at this level or an older one move to the store
+    open_files: Mutex<HashMap<String, Vec<Weak<Mutex<Backing>>>>>, // local files with open handles, by path
+}
+
+enum Backing {
+    Local(Box<dyn StorageFile>),
+    Sealed {
+        store: Arc<dyn ObjectStore>,
+        key: String,
+        len: u64,
+    },
+}
+
+struct ObjectFile {
+    backing: Arc<Mutex<Backing>>, // switched to the object once the file's sealed
+}
+
+/// What a sealed file's pointer is called
+fn pointer_path(path: &str) -> String {
+    path.to_owned() + ".cold"
+}
+
+/// Objects are keyed by the file's path, without a leading slash
+fn object_key(path: &str) -> String {
+    path.trim_start_matches('/').to_owned()
+}
+
+/// How much of a file is uploaded at a time as it's sealed
+const PART_SIZE: u64 = 8 << 20;
+
+impl ObjectStorage {
+    pub fn new(local: Arc<dyn Storage>, store: Arc<dyn ObjectStore>) -> ObjectStorage {
+        ObjectStorage {
+            local,
+            store,
+            cold_level: 0,
+            open_files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps the files of levels newer than `level` on local storage, moving only
+    /// the rest to the object store. Level 0 is the L0 runs and level 1 the tree
+    /// file, so `cold_from_level(1)` keeps the recent writes in runs local and
+    /// moves them out when compaction merges them into the tree file.
+    pub fn cold_from_level(mut self, level: u32) -> ObjectStorage {
+        self.cold_level = level;
+        self
+    }
+
+    /// Whether the file at `path` has been moved to the object store
+    pub fn is_cold(&self, path: &str) -> IOResult<bool> {
+        Ok(!self.local.exists(path)? && self.local.exists(&pointer_path(path))?)
+    }
+
+    /// The size and object key of the sealed file at `path`, from its pointer
+    fn read_pointer(&self, path: &str) -> IOResult<(u64, String)> {
+        let pointer = self.local.open(&pointer_path(path))?;
+        let mut bytes = vec![0; pointer.len()? as usize];
+        pointer.read_at(&mut bytes, 0)?;
+
+        let invalid = || IOError::new(ErrorKind::InvalidData, "a file pointer is corrupt");
+        let len = bytes.get(..8).ok_or_else(invalid)?;
+        let len = u64::from_le_bytes(len.try_into().map_err(|_| invalid())?);
+        let key = String::from_utf8(bytes[8..].to_vec()).map_err(|_| invalid())?;
+
+        Ok((len, key))
+    }
+
+    /// Points `path` at the object at `key`, replacing the pointer there in one step
+    fn write_pointer(&self, path: &str, len: u64, key: &str) -> IOResult<()> {
+        let new_pointer = pointer_path(path) + ".new";
+        let mut pointer = self.local.open(&new_pointer)?;
+
+        pointer.truncate(0)?;
+        pointer.append(&[&len.to_le_bytes()[..], key.as_bytes()].concat())?;
+        pointer.sync()?;
+        self.local.rename(&new_pointer, &pointer_path(path))
+    }
+
+    /// The object key of the pointer at `path`, if there is one
+    fn pointed_at(&self, path: &str) -> IOResult<Option<String>> {
+        if !self.local.exists(&pointer_path(path))? {
+            return Ok(None);
+        }
+
+        Ok(Some(self.read_pointer(path)?.1))
+    }
+}
+
+impl Storage for ObjectStorage {
+    fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>> {
+        if self.is_cold(path)? {
+            let (len, key) = self.read_pointer(path)?;
+
+            return Ok(Box::new(ObjectFile {
+                backing: Arc::new(Mutex::new(Backing::Sealed {
+                    store: self.store.clone(),
+                    key,
+                    len,
+                })),
+            }));
+        }
+
+        let backing = Arc::new(Mutex::new(Backing::Local(self.local.open(path)?)));
+        let mut open_files = self.open_files.lock().unwrap();
+        let handles = open_files.entry(path.to_owned()).or_default();
+
+        handles.retain(|handle| handle.strong_count() > 0);
+        handles.push(Arc::downgrade(&backing));
+
+        Ok(Box::new(ObjectFile { backing }))
+    }
+
+    fn exists(&self, path: &str) -> IOResult<bool> {
+        Ok(self.local.exists(path)? || self.local.exists(&pointer_path(path))?)
+    }
+
+    fn remove(&self, path: &str) -> IOResult<()> {
+        let sealed = self.pointed_at(path)?;
+
+        // a crash part way through sealing leaves both
+        if sealed.is_some() {
+            self.local.remove(&pointer_path(path))?;
+        }
+
+        if sealed.is_none() || self.local.exists(path)? {
+            self.local.remove(path)?;
+        }
+
+        match sealed {
+            Some(key) => self.store.delete(&key),
+            None => Ok(()),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> IOResult<()> {
+        // the file being replaced may have been sealed, and its object goes last
+        let replaced = self.pointed_at(to)?;
+
+        if self.is_cold(from)? {
+            let (len, from_key) = self.read_pointer(from)?;
+            let key = format!("{}.{:016x}", object_key(to), rand::random::<u64>());
+
+            self.store.copy(&from_key, &key)?;
+            self.write_pointer(to, len, &key)?;
+            self.local.remove_if_exists(to)?;
+            self.local.remove(&pointer_path(from))?;
+            self.store.delete(&from_key)?;
+        } else {
+            self.local.rename(from, to)?;
+
+            let mut open_files = self.open_files.lock().unwrap();
+
+            if let Some(handles) = open_files.remove(from) {
+                open_files.insert(to.to_owned(), handles);
+            }
+            drop(open_files);
+
+            if replaced.is_some() {
+                self.local.remove(&pointer_path(to))?;
+            }
+        }
+
+        match replaced {
+            Some(key) => self.store.delete(&key),
+            None => Ok(()),
+        }
+    }
+
+    /// Uploads the file a part at a time, if its level is cold, writes its
+    /// pointer and points every open handle at the object, then removes the
+    /// local copy
+    fn seal(&self, path: &str, level: u32) -> IOResult<()> {
+        if level < self.cold_level || !self.local.exists(path)? {
+            return Ok(());
+        }
+
+        let key = object_key(path);
+        let file = self.local.open(path)?;
+        let len = file.len()?;
+        let mut upload = self.store.upload(&key)?;
+        let mut offset = 0;
+
+        while offset < len {
+            let mut part = vec![0; PART_SIZE.min(len - offset) as usize];
+            file.read_at(&mut part, offset)?;
+            upload.write_part(&part)?;
+            offset += part.len() as u64;
+        }
+        upload.finish()?;
+
+        // a crash before the local copy is gone leaves it in use
+        self.write_pointer(path, len, &key)?;
+
+        let handles = self
+            .open_files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .unwrap_or_default();
+
+        for backing in handles.iter().filter_map(Weak::upgrade) {
+            *backing.lock().unwrap() = Backing::Sealed {
+                store: self.store.clone(),
+                key: key.clone(),
+                len,
+            };
+        }
+
+        self.local.remove(path)
+    }
+}
+
+fn sealed_error() -> IOError {
+    IOError::new(
+        ErrorKind::PermissionDenied,
+        "a sealed file can't be changed",
+    )
+}
+
+impl StorageFile for ObjectFile {
+    fn len(&self) -> IOResult<u64> {
+        match &*self.backing.lock().unwrap() {
+            Backing::Local(file) => file.len(),
+            Backing::Sealed { len, .. } => Ok(*len),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()> {
+        match &*self.backing.lock().unwrap() {
+            Backing::Local(file) => file.read_at(buf, offset),
+            Backing::Sealed { store, key, .. } => {
+                let bytes = store.get_range(key, offset, buf.len() as u64)?;
+
+                if bytes.len() != buf.len() {
+                    return Err(IOError::new(
+                        ErrorKind::UnexpectedEof,
+                        "read past the end of the object",
+                    ));
+                }
+
+                buf.copy_from_slice(&bytes);
+                Ok(())
+            }
+        }
+    }
+
+    fn append(&mut self, buf: &[u8]) -> IOResult<()> {
+        match &mut *self.backing.lock().unwrap() {
+            Backing::Local(file) => file.append(buf),
+            Backing::Sealed { .. } => Err(sealed_error()),
+        }
+    }
+
+    fn truncate(&mut self, len: u64) -> IOResult<()> {
+        match &mut *self.backing.lock().unwrap() {
+            Backing::Local(file) => file.truncate(len),
+            Backing::Sealed { .. } => Err(sealed_error()),
+        }
+    }
+
+    fn sync(&mut self) -> IOResult<()> {
+        match &mut *self.backing.lock().unwrap() {
+            Backing::Local(file) => file.sync(),
+            Backing::Sealed { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::{MemoryObjectStore, ObjectStorage, ObjectStore};
+    use storage::StorageFile;
+    use {BTree, Options, SimDisk, Storage, SyncPolicy};
+
+    use std::io::{Error as IOError, Result as IOResult};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Storage, a file or an object store that stops making changes once it's made
+    /// `steps` of them, as if the process had died there
+    struct Crashing<T> {
+        inner: T,
+        steps: Arc<AtomicUsize>,
+    }
+
+    impl<T> Crashing<T> {
+        fn step(&self) -> IOResult<()> {
+            self.steps
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |steps| {
+                    steps.checked_sub(1)
+                })
+                .map(|_| ())
+                .map_err(|_| IOError::other("crashed"))
+        }
+    }
+
+    impl Storage for Crashing<SimDisk> {
+        fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>> {
+            Ok(Box::new(Crashing {
+                inner: self.inner.open(path)?,
+                steps: self.steps.clone(),
+            }))
+        }
+
+        fn exists(&self, path: &str) -> IOResult<bool> {
+            self.inner.exists(path)
+        }
+
+        fn remove(&self, path: &str) -> IOResult<()> {
+            self.step()?;
+            self.inner.remove(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> IOResult<()> {
+            self.step()?;
+            self.inner.rename(from, to)
+        }
+    }
+
+    impl StorageFile for Crashing<Box<dyn StorageFile>> {
+        fn len(&self) -> IOResult<u64> {
+            self.inner.len()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()> {
+            self.inner.read_at(buf, offset)
+        }
+
+        fn append(&mut self, buf: &[u8]) -> IOResult<()> {
+            self.step()?;
+            self.inner.append(buf)
+        }
+
+        fn truncate(&mut self, len: u64) -> IOResult<()> {
+            self.step()?;
+            self.inner.truncate(len)
+        }
+
+        fn sync(&mut self) -> IOResult<()> {
+            self.step()?;
+            self.inner.sync()
+        }
+    }
+
+    impl ObjectStore for Crashing<Arc<MemoryObjectStore>> {
+        fn get_range(&self, key: &str, offset: u64, len: u64) -> IOResult<Vec<u8>> {
+            self.inner.get_range(key, offset, len)
+        }
+
+        fn size(&self, key: &str) -> IOResult<Option<u64>> {
+            self.inner.size(key)
+        }
+
+        fn put(&self, key: &str, bytes: &[u8]) -> IOResult<()> {
+            self.step()?;
+            self.inner.put(key, bytes)
+        }
+
+        fn delete(&self, key: &str) -> IOResult<()> {
+            self.step()?;
+            self.inner.delete(key)
+        }
+    }
+
+    #[test]
+    fn sealed_files_move_to_the_object_store() {
+        let disk = Arc::new(SimDisk::new(0));
+        let store = Arc::new(MemoryObjectStore::new());
+        let options = Options {
+            storage: Arc::new(ObjectStorage::new(disk.clone(), store.clone())),
+            l0_compaction_trigger: Some(1),
+            flush_threshold: 100,
+            bloom_bits_per_key: Some(10),
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..101 {
+            btree.insert(i, i).unwrap();
+        }
+        assert_eq!(store.keys(), ["db.L0.1", "db.L0.1.filter", "db.L0.1.zones"]);
+
+        // a second run is one too many, so the two are merged into the tree file
+        for i in 101..202 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.insert(202, 202).unwrap();
+
+        assert_eq!(store.keys(), ["db", "db.filter", "db.zones"]);
+        assert!(!disk.exists("db").unwrap());
+        assert!(disk.exists("db.wal").unwrap());
+        assert_eq!(btree.get(&42).unwrap(), Some(vec![42]));
+        drop(btree);
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&150).unwrap(), Some(vec![150]));
+        assert_eq!(btree.get(&202).unwrap(), Some(vec![202]));
+        assert_eq!(btree.range(..).unwrap().count(), 203);
+    }
+
+    #[test]
+    fn recent_levels_stay_local_until_compacted() {
+        let disk = Arc::new(SimDisk::new(0));
+        let store = Arc::new(MemoryObjectStore::new());
+        let storage = Arc::new(ObjectStorage::new(disk.clone(), store.clone()).cold_from_level(1));
+        let options = Options {
+            storage: storage.clone(),
+            l0_compaction_trigger: Some(1),
+            flush_threshold: 100,
+            ..Options::default()
+        };
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        for i in 0..101 {
+            btree.insert(i, i).unwrap();
+        }
+        assert!(store.keys().is_empty());
+        assert!(disk.exists("db.L0.1").unwrap());
+
+        for i in 101..202 {
+            btree.insert(i, i).unwrap();
+        }
+        assert_eq!(store.keys(), ["db", "db.zones"]);
+        assert!(storage.is_cold("db").unwrap());
+        drop(btree);
+
+        // another process finds the tree file by its pointer
+        let options = Options {
+            storage: Arc::new(ObjectStorage::new(disk.clone(), store.clone()).cold_from_level(1)),
+            ..options
+        };
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        assert_eq!(btree.get(&150).unwrap(), Some(vec![150]));
+        assert_eq!(btree.range(..).unwrap().count(), 202);
+    }
+
+    #[test]
+    fn replacing_a_sealed_tree_file_survives_a_crash_at_every_step() {
+        let options = |storage: Arc<dyn Storage>| Options {
+            storage,
+            sync_policy: SyncPolicy::Always,
+            ..Options::default()
+        };
+
+        for steps in 0.. {
+            let (disk, store) = (
+                SimDisk::new(steps as u64),
+                Arc::new(MemoryObjectStore::new()),
+            );
+            let budget = Arc::new(AtomicUsize::new(usize::MAX));
+            let crashing = ObjectStorage::new(
+                Arc::new(Crashing {
+                    inner: disk.clone(),
+                    steps: budget.clone(),
+                }),
+                Arc::new(Crashing {
+                    inner: store.clone(),
+                    steps: budget.clone(),
+                }),
+            );
+            let mut btree =
+                BTree::<u32, u32>::with_options("db", 4, 4, options(Arc::new(crashing))).unwrap();
+
+            for i in 0..100 {
+                btree.insert(i, i).unwrap();
+            }
+            btree.flush().unwrap();
+            for i in 100..200 {
+                btree.insert(i, i).unwrap();
+            }
+
+            // the sealed tree file is replaced by the one the flush writes, then sealed
+            budget.store(steps, Ordering::SeqCst);
+            let finished = btree.flush().is_ok();
+            drop(btree);
+            disk.crash();
+
+            let storage = ObjectStorage::new(Arc::new(disk), store);
+            let btree = BTree::<u32, u32>::with_options("db", 4, 4, options(Arc::new(storage)))
+                .unwrap_or_else(|e| panic!("crashed after {} steps: {}", steps, e));
+            assert_eq!(
+                btree.range(..).unwrap().count(),
+                200,
+                "after {} steps",
+                steps
+            );
+            assert_eq!(btree.get(&42).unwrap(), Some(vec![42]));
+
+            if finished {
+                assert!(steps > 10);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use block_cache::BlockCache;
+use clock::{Clock, SystemClock};
+use encoding::Encoding;
+use executor::{CompactionExecutor, ThreadExecutor};
+use schema::Schema;
+use storage::{FileStorage, Storage};
+use write_buffer::WriteBufferManager;
+use MAX_MEMORY_ITEMS;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Settings used when opening a BTree. Start from `Options::default()` and
+/// override the fields you care about.
+#[derive(Clone)]
+pub struct Options {
+    pub storage: Arc<dyn Storage>, // where the WAL and tree files live
+    pub clock: Arc<dyn Clock>,     // the time used for TTLs
+    pub versioning: Option<VersionRetention>, // keep old versions of values, off by default
+    pub audit: bool,               // record every mutation in a .audit sidecar file
+    pub soft_delete_window: Duration, // how long soft-deleted values can be restored
+    pub expired_compaction_trigger: Option<usize>, // compact once this many on-disk records expire
+    pub l0_compaction_trigger: Option<usize>, // flush to L0 runs, merging once there are more than this
+    pub wal_flush_trigger: Option<u64>,       // flush the memtable once the WAL is this many bytes
+    pub idle_flush: Option<Duration>, // flush the memtable once it's gone this long without a write
+    pub wal_compression: bool,        // compress each write to the WAL with lz4
+    pub write_throttle: Option<WriteThrottle>, // slow writers down when flushes fall behind
+    pub spill_memtable: bool,         // at the hard limit, spill the memtable to a run instead
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>, // a memtable budget shared with other trees
+    pub compaction_rate_limit: Option<u64>, // bytes per second compaction may read and write
+    pub compaction: CompactionOptions,      // how compaction work is spread over threads
+    pub compaction_executor: Arc<dyn CompactionExecutor>, // and the threads it runs on
+    pub flush_threshold: usize,             // flush once the memtable holds this many writes
+    pub sync_policy: SyncPolicy,            // when writes to the WAL are made durable
+    pub cache_size: usize,                  // bytes of tree file blocks to cache, 0 turns it off
+    pub block_cache: Option<Arc<BlockCache>>, // a cache shared with other trees, instead of cache_size
+    pub bloom_bits_per_key: Option<usize>,    // build a bloom filter for each block on disk
+    pub hash_index: bool,                     // index the tree file's keys by hash for faster gets
+    pub prefix_compression: bool, // store each record as what it shares with the one before
+    pub compression_dictionary: Option<usize>, // and with a dictionary of this many bytes sampled per file
+    pub encoding: Encoding, // how the records of new files are encoded, legacy by default
+    pub disk_quota: Option<DiskQuota>, // cap the bytes the tree's files take up
+    pub value_cap: Option<ValueCap>, // cap the values any one key holds
+    pub lru_capacity: Option<usize>, // act as a cache of this many keys, evicting the least recently used
+    pub schema: Option<Schema>,      // what the keys and values are, by default their type names
+    pub slow_op_threshold: Option<Duration>, // report operations slower than this to the slow op hooks
+}
+
+/// The options that can be changed while a BTree is open. Get the current ones
+/// with `dynamic_options()`, change what you need, and pass them to `set_options()`.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicOptions {
+    pub flush_threshold: usize,
+    pub sync_policy: SyncPolicy,
+    pub write_throttle: Option<WriteThrottle>,
+    pub compaction_rate_limit: Option<u64>,
+    pub cache_size: usize,
+}
+
+/// How a single read is done, see `BTree::get_with` and `BTree::range_with`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadOptions {
+    pub collect_io_stats: bool, // return what the read took from the files, to measure read amplification
+    pub consistency: Consistency, // which writes the read may see
+}
+
+/// Which writes a read with `ReadOptions` sees. A snapshot works like
+/// `BTree::get_at`: without versioning turned on, a value re-written or deleted
+/// since that point isn't found. `DurableOnly` needs `Options::versioning` turned
+/// on, so a value re-written or deleted since the last sync is still seen as it was
+/// synced; without it the read fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Consistency {
+    #[default]
+    Latest, // every committed write, in memory or on disk
+    DurableOnly,      // only the writes already synced, which a crash can't lose
+    SnapshotSeq(u64), // only the writes up to and including the one with this sequence number
+}
+
+/// When writes to the WAL are synced to durable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    Never,  // leave it to the OS, a crash can lose the latest writes
+    Always, // after every write
+    Interval(Duration), // after a write, if this long has passed since the last sync; the writes
+            // in between are appended to the WAL together, just before the sync
+}
+
+/// How much of the machine compaction may use. A compaction splits the key space
+/// into up to `max_subcompactions` ranges, merged on up to `max_jobs` threads at
+/// once, which `Options::compaction_executor` provides. Each kind of compaction has a priority: low priority ones are held to
+/// `Options::compaction_rate_limit`, high priority ones aren't.
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionOptions {
+    pub max_jobs: usize,
+    pub max_subcompactions: usize,
+    pub flush_priority: CompactionPriority, // flushing a full memtable, or `flush()`
+    pub expiry_priority: CompactionPriority, // reclaiming expired records
+    pub manual_priority: CompactionPriority, // `compact_range()`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionPriority {
+    High,
+    Low,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> CompactionOptions {
+        CompactionOptions {
+            max_jobs: 1,
+            max_subcompactions: 1,
+            flush_priority: CompactionPriority::Low,
+            expiry_priority: CompactionPriority::Low,
+            manual_priority: CompactionPriority::Low,
+        }
+    }
+}
+
+/// Limits on the bytes waiting in the memtable. Between the soft and hard limits
+/// each write is delayed, growing linearly up to `max_delay`; past the hard limit
+/// writes fail with `BTreeError::Stalled` until the memtable has been flushed.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteThrottle {
+    pub soft_limit: usize,
+    pub hard_limit: usize,
+    pub max_delay: Duration,
+}
+
+/// A cap on the bytes a tree's files take up on disk, WAL and sidecars included.
+/// Inserts that would go over it fail with `BTreeError::QuotaExceeded`; deletes
+/// are always let through, as compaction turns them into free space.
+#[derive(Debug, Clone, Copy)]
+pub struct DiskQuota {
+    pub max_bytes: u64,
+    pub when_full: QuotaPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    Reject,  // fail the insert straight away
+    Compact, // first compact, dropping deleted and expired values, and only fail if that didn't free enough
+}
+
+/// A cap on the values stored under any one key, so a hot key in a log-style
+/// workload can't grow without bound. It's enforced as values are inserted, which
+/// costs a lookup of the key, and again by compaction, which brings keys written
+/// before the cap was set, or lowered, down to it.
+#[derive(Debug, Clone, Copy)]
+pub struct ValueCap {
+    pub max_values: usize,
+    pub when_full: CapPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapPolicy {
+    EvictOldest, // delete the values written longest ago, in the same write as the insert
+    Reject,      // fail the insert with `BTreeError::ValueCapReached`, keeping the oldest values
+}
+
+/// Which old versions to keep through compaction when versioning is on. The
+/// current write of every value is always kept; limits only apply to history.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VersionRetention {
+    pub max_versions: Option<usize>, // keep at most this many of the newest versions per key
+    pub max_age: Option<Duration>,   // drop versions written longer ago than this
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            storage: Arc::new(FileStorage),
+            clock: Arc::new(SystemClock),
+            versioning: None,
+            audit: false,
+            soft_delete_window: Duration::from_secs(24 * 60 * 60),
+            expired_compaction_trigger: Some(1000),
+            l0_compaction_trigger: None,
+            wal_flush_trigger: None,
+            idle_flush: None,
+            wal_compression: false,
+            write_throttle: None,
+            spill_memtable: false,
+            write_buffer_manager: None,
+            compaction_rate_limit: None,
+            compaction: CompactionOptions::default(),
+            compaction_executor: Arc::new(ThreadExecutor::default()),
+            flush_threshold: MAX_MEMORY_ITEMS,
+            sync_policy: SyncPolicy::Never,
+            cache_size: 8 * 1024 * 1024,
+            block_cache: None,
+            bloom_bits_per_key: None,
+            hash_index: false,
+            prefix_compression: false,
+            compression_dictionary: None,
+            encoding: Encoding::Legacy,
+            disk_quota: None,
+            value_cap: None,
+            lru_capacity: None,
+            schema: None,
+            slow_op_threshold: None,
+        }
+    }
+}
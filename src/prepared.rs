@@ -0,0 +1,199 @@
+use storage::{Storage, StorageFile};
+use wal_file::KeyValuePair;
+use {KeyType, ValueType};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+pub fn prepared_log_path(tree_file_path: &str) -> String {
+    tree_file_path.to_owned() + ".prepared"
+}
+
+/// A batch prepared for a two-phase commit, see `BTree::prepare`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PrepareToken {
+    id: u64,
+}
+
+impl PrepareToken {
+    /// The number identifying the batch, for the coordinator to keep track of it
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum PreparedEntry<K, V> {
+    Prepare {
+        id: u64,
+        records: Vec<KeyValuePair<K, V>>,
+    },
+    Commit {
+        id: u64,
+        first_seq: u64,
+    }, // the batch's records were given the seqs from `first_seq` on
+    Rollback {
+        id: u64,
+    },
+}
+
+/// A batch whose commit was decided, as found when the log was opened
+pub struct Committed<K, V> {
+    pub first_seq: u64,
+    pub records: Vec<KeyValuePair<K, V>>,
+}
+
+/// A sidecar log of the batches prepared for two-phase commits and of what was
+/// decided about them. Each entry is synced before the call making it returns, so
+/// a prepared batch and a decision to commit one both survive a crash. Entries are
+/// a little-endian u32 length followed by the bincode-encoded entry, as in the
+/// audit log, and the file is emptied whenever no batch is left waiting.
+pub struct PreparedLog<K: KeyType, V: ValueType> {
+    fd: Box<dyn StorageFile>,
+    waiting: BTreeMap<u64, Vec<KeyValuePair<K, V>>>, // prepared, and not yet decided
+    committed: Vec<Committed<K, V>>, // found when opened, which may not have reached the WAL
+    next_id: u64,
+}
+
+impl<K: KeyType, V: ValueType> PreparedLog<K, V> {
+    pub fn open(
+        storage: &dyn Storage,
+        file_path: &str,
+    ) -> Result<PreparedLog<K, V>, Box<dyn Error>> {
+        let mut log = PreparedLog {
+            fd: storage.open(file_path)?,
+            waiting: BTreeMap::new(),
+            committed: Vec::new(),
+            next_id: 1,
+        };
+
+        for entry in log.entries()? {
+            match entry {
+                PreparedEntry::Prepare { id, records } => {
+                    log.next_id = log.next_id.max(id + 1);
+                    log.waiting.insert(id, records);
+                }
+                PreparedEntry::Commit { id, first_seq } => {
+                    if let Some(records) = log.waiting.remove(&id) {
+                        log.committed.push(Committed { first_seq, records });
+                    }
+                }
+                PreparedEntry::Rollback { id } => {
+                    log.waiting.remove(&id);
+                }
+            }
+        }
+
+        Ok(log)
+    }
+
+    /// The batches the log said were committed when it was opened, oldest first
+    pub fn take_committed(&mut self) -> Vec<Committed<K, V>> {
+        std::mem::take(&mut self.committed)
+    }
+
+    fn entries(&self) -> Result<Vec<PreparedEntry<K, V>>, Box<dyn Error>> {
+        let len = self.fd.len()?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset + 4 <= len {
+            let mut size = [0; 4];
+            self.fd.read_at(&mut size, offset)?;
+            let size = u32::from_le_bytes(size) as u64;
+
+            // a torn entry was never synced, so the call making it never returned
+            if offset + 4 + size > len {
+                break;
+            }
+
+            let mut body = vec![0; size as usize];
+            self.fd.read_at(&mut body, offset + 4)?;
+            entries.push(bincode::deserialize(&body)?);
+
+            offset += 4 + size;
+        }
+
+        Ok(entries)
+    }
+
+    fn append(&mut self, entry: &PreparedEntry<K, V>) -> Result<(), Box<dyn Error>> {
+        let body = bincode::serialize(entry)?;
+        let mut buff = Vec::with_capacity(4 + body.len());
+
+        buff.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buff.extend_from_slice(&body);
+
+        self.fd.append(&buff)?;
+        Ok(self.fd.sync()?)
+    }
+
+    pub fn prepare(
+        &mut self,
+        records: Vec<KeyValuePair<K, V>>,
+    ) -> Result<PrepareToken, Box<dyn Error>> {
+        let id = self.next_id;
+
+        self.append(&PreparedEntry::Prepare {
+            id,
+            records: records.clone(),
+        })?;
+
+        self.waiting.insert(id, records);
+        self.next_id += 1;
+        Ok(PrepareToken { id })
+    }
+
+    /// The batches prepared and not yet committed or rolled back
+    pub fn waiting(&self) -> Vec<PrepareToken> {
+        self.waiting.keys().map(|&id| PrepareToken { id }).collect()
+    }
+
+    /// Records the decision to commit the batch, returning its records
+    pub fn commit(
+        &mut self,
+        token: PrepareToken,
+        first_seq: u64,
+    ) -> Result<Vec<KeyValuePair<K, V>>, Box<dyn Error>> {
+        if !self.waiting.contains_key(&token.id) {
+            return Err(not_prepared(token));
+        }
+
+        self.append(&PreparedEntry::Commit {
+            id: token.id,
+            first_seq,
+        })?;
+
+        Ok(self.waiting.remove(&token.id).unwrap_or_default())
+    }
+
+    pub fn rollback(&mut self, token: PrepareToken) -> Result<(), Box<dyn Error>> {
+        if !self.waiting.contains_key(&token.id) {
+            return Err(not_prepared(token));
+        }
+
+        self.append(&PreparedEntry::Rollback { id: token.id })?;
+        self.waiting.remove(&token.id);
+
+        Ok(())
+    }
+
+    /// Empties the file if no batch is waiting. The commits it records must be
+    /// durable in the WAL by then.
+    pub fn clear_if_done(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.waiting.is_empty() && self.fd.len()? > 0 {
+            self.fd.truncate(0)?;
+            self.fd.sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn not_prepared(token: PrepareToken) -> Box<dyn Error> {
+    From::from(format!(
+        "Batch {} isn't prepared, or was already committed or rolled back",
+        token.id
+    ))
+}
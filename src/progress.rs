@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How far the running compaction has got. Bytes are counted as the records
+/// merged are read, from memory, the runs being merged and the tree file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionProgress {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub elapsed: Duration, // since the merge started
+}
+
+impl CompactionProgress {
+    /// The share of the bytes merged so far, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        match self.bytes_total {
+            0 => 1.0,
+            total => self.bytes_done.min(total) as f64 / total as f64,
+        }
+    }
+
+    /// How much longer the compaction should take, going by how fast it's been so
+    /// far, or None before it's merged anything
+    pub fn estimated_remaining(&self) -> Option<Duration> {
+        match self.bytes_done {
+            0 => None,
+            done => Some(
+                self.elapsed
+                    .mul_f64(self.bytes_total.saturating_sub(done) as f64 / done as f64),
+            ),
+        }
+    }
+}
+
+/// The progress of a tree's compactions, shared with its `CompactionMonitor`s
+#[derive(Default)]
+pub struct ProgressState {
+    done: AtomicU64,
+    total: AtomicU64,
+    started: Mutex<Option<Instant>>, // when the running compaction started, if one is
+    cancelled: AtomicBool,           // the running compaction is to stop
+}
+
+impl ProgressState {
+    /// Starts tracking a compaction of `total` bytes, until the guard is dropped
+    pub fn start(self: &Arc<ProgressState>, total: u64) -> Running {
+        let mut started = self.started.lock().unwrap();
+
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+        self.cancelled.store(false, Ordering::Relaxed);
+        *started = Some(Instant::now());
+
+        Running {
+            state: self.clone(),
+        }
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        self.done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// The bytes merged so far, without the lock `current` takes
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Whether the running compaction has been told to stop
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn current(&self) -> Option<CompactionProgress> {
+        let started = (*self.started.lock().unwrap())?;
+
+        Some(CompactionProgress {
+            bytes_done: self.done.load(Ordering::Relaxed),
+            bytes_total: self.total.load(Ordering::Relaxed),
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Marks a compaction as running for as long as it's held, so one that fails part
+/// way through isn't left looking stuck
+pub struct Running {
+    state: Arc<ProgressState>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *self.state.started.lock().unwrap() = None;
+    }
+}
+
+/// Watches a tree's compactions from another thread, while the tree itself is
+/// busy running one. See `BTree::compaction_monitor`.
+#[derive(Clone)]
+pub struct CompactionMonitor {
+    state: Arc<ProgressState>,
+}
+
+impl CompactionMonitor {
+    pub fn new(state: Arc<ProgressState>) -> CompactionMonitor {
+        CompactionMonitor { state }
+    }
+
+    /// The progress of the running compaction, or None when there isn't one
+    pub fn progress(&self) -> Option<CompactionProgress> {
+        self.state.current()
+    }
+
+    /// Stops the running compaction, if there is one, before it replaces any file.
+    /// What it had written is removed and the files it was merging stay live;
+    /// the compaction fails with `BTreeError::Cancelled`, unless a write set it
+    /// off, in which case the write still succeeds and a later one flushes. Returns
+    /// whether there was a compaction to cancel.
+    pub fn cancel(&self) -> bool {
+        // under the lock, so it can't land between one compaction and the next
+        let started = self.state.started.lock().unwrap();
+        self.state
+            .cancelled
+            .store(started.is_some(), Ordering::Relaxed);
+
+        started.is_some()
+    }
+}
@@ -0,0 +1,114 @@
+use clock::Clock;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A token bucket limiting how many bytes per second pass through it. Callers
+/// ask for bytes before doing I/O and are put to sleep (through the clock) when
+/// they're going faster than the limit. Up to one second's worth of bytes can
+/// be used in a burst.
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    state: Mutex<RateState>,
+}
+
+struct RateState {
+    bytes_per_sec: u64,
+    available: f64,   // bytes that can be used without waiting
+    last_refill: u64, // milliseconds since the epoch
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64, clock: Arc<dyn Clock>) -> RateLimiter {
+        let now = clock.now_millis();
+
+        RateLimiter {
+            clock,
+            state: Mutex::new(RateState {
+                bytes_per_sec,
+                available: bytes_per_sec as f64,
+                last_refill: now,
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.state.lock().unwrap().bytes_per_sec
+    }
+
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.bytes_per_sec = bytes_per_sec;
+        state.available = state.available.min(bytes_per_sec as f64);
+    }
+
+    /// Waits until `bytes` can be used, then uses them
+    pub fn request(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = self.clock.now_millis();
+            let rate = state.bytes_per_sec.max(1) as f64;
+
+            // top the bucket up for the time that's passed
+            let elapsed = now.saturating_sub(state.last_refill) as f64 / 1000.0;
+            state.available = (state.available + elapsed * rate).min(rate);
+            state.last_refill = now;
+
+            state.available -= bytes as f64;
+
+            if state.available >= 0.0 {
+                return;
+            }
+
+            // sleeping pays off the debt, which is refilled from the time we wake
+            let wait = Duration::from_secs_f64(-state.available / rate);
+            state.available = 0.0;
+            state.last_refill = now + wait.as_millis() as u64;
+            wait
+        };
+
+        self.clock.sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clock::{Clock, ManualClock};
+    use rate_limiter::RateLimiter;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_throughput() {
+        let clock = ManualClock::new(0);
+        let limiter = RateLimiter::new(1000, Arc::new(clock.clone()));
+
+        // the first second's worth is a burst
+        limiter.request(1000);
+        assert_eq!(clock.now_millis(), 0);
+
+        // after that it's 1 byte per millisecond
+        limiter.request(500);
+        assert_eq!(clock.now_millis(), 500);
+        limiter.request(250);
+        assert_eq!(clock.now_millis(), 750);
+
+        // idle time refills the bucket
+        clock.advance(Duration::from_secs(5));
+        limiter.request(1000);
+        assert_eq!(clock.now_millis(), 5750);
+    }
+
+    #[test]
+    fn rate_can_change() {
+        let clock = ManualClock::new(0);
+        let limiter = RateLimiter::new(1000, Arc::new(clock.clone()));
+
+        limiter.set_bytes_per_sec(100);
+        assert_eq!(limiter.bytes_per_sec(), 100);
+
+        limiter.request(200);
+        assert_eq!(clock.now_millis(), 1000);
+    }
+}
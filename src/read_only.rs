@@ -0,0 +1,239 @@
+use blob_store::{blob_store_path, BlobStore};
+use storage::Storage;
+use {refuse_legacy, BTree, KeyType, Options, ValueType};
+
+use std::error::Error;
+use std::ops::Deref;
+use std::time::Duration;
+
+// how often, and how long apart, an open is retried while the writer installs files
+const OPEN_ATTEMPTS: usize = 100;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// The path of the file holding a tree's generation: a counter the writer bumps to
+/// an odd number before it starts replacing files in a flush or compaction, and to
+/// an even one once it's done. Readers open the files only while it's even and
+/// unchanged, the way a seqlock works, so they never see half an install.
+pub fn generation_path(tree_file_path: &str) -> String {
+    tree_file_path.to_owned() + ".generation"
+}
+
+pub fn read_generation(storage: &dyn Storage, tree_file_path: &str) -> Result<u64, Box<dyn Error>> {
+    let path = generation_path(tree_file_path);
+
+    if !storage.exists(&path)? {
+        return Ok(0);
+    }
+
+    let file = storage.open(&path)?;
+
+    // the generation isn't synced, so a crash can leave it cut short, and it's
+    // read as an install under way for the writer to put right
+    if file.len()? < 8 {
+        return Ok(1);
+    }
+
+    let mut bytes = [0; 8];
+    file.read_at(&mut bytes, 0)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Replaces the generation with a rename, so readers see either the old one or the
+/// new one. It isn't synced: a crash mid-install is put right when the writer opens
+/// the tree again.
+pub fn write_generation(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    generation: u64,
+) -> Result<(), Box<dyn Error>> {
+    let path = generation_path(tree_file_path);
+    let new_path = path.to_owned() + ".new";
+
+    storage.remove_if_exists(&new_path)?;
+    storage.open(&new_path)?.append(&generation.to_le_bytes())?;
+
+    Ok(storage.rename(&new_path, &path)?)
+}
+
+/// A handle that reads a tree another handle, in this process or another, writes
+/// to. It sees the tree as it was when opened, or last refreshed, and derefs to the
+/// `BTree` for every read. There can be any number of readers, but only one writer.
+pub struct ReadOnlyBTree<K: KeyType, V: ValueType> {
+    tree: BTree<K, V>,
+    key_size: usize,
+    value_size: usize,
+    options: Options,
+    wal_index: u64, // the next WAL record to replay
+}
+
+impl<K: KeyType, V: ValueType> ReadOnlyBTree<K, V> {
+    pub fn with_options(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<ReadOnlyBTree<K, V>, Box<dyn Error>> {
+        if !options.storage.exists(tree_file_path)? {
+            return Err(From::from(format!(
+                "There's no tree at {} to read",
+                tree_file_path
+            )));
+        }
+
+        refuse_legacy::<K, V>(&*options.storage, tree_file_path, key_size, value_size)?;
+
+        for _ in 0..OPEN_ATTEMPTS {
+            let before = read_generation(&*options.storage, tree_file_path)?;
+
+            if before % 2 == 1 {
+                options.clock.sleep(RETRY_DELAY);
+                continue;
+            }
+
+            let opened = BTree::open(
+                tree_file_path,
+                key_size,
+                value_size,
+                options.clone(),
+                true,
+                None,
+                None,
+            );
+
+            // a failure while files were being replaced is just a sign to try again
+            if read_generation(&*options.storage, tree_file_path)? != before {
+                continue;
+            }
+
+            let mut reader = ReadOnlyBTree {
+                tree: opened?,
+                key_size,
+                value_size,
+                options,
+                wal_index: 0,
+            };
+
+            // the WAL could have grown since the open replayed it
+            reader.catch_up()?;
+
+            return Ok(reader);
+        }
+
+        Err(From::from(
+            "The writer kept replacing files while the tree was being opened",
+        ))
+    }
+
+    /// Brings the handle up to date with the writer, returning whether anything
+    /// changed. New WAL records are replayed; after a flush or compaction the tree
+    /// is opened again.
+    pub fn refresh(&mut self) -> Result<bool, Box<dyn Error>> {
+        let path = self.tree.tree_file_path.clone();
+
+        if read_generation(&*self.options.storage, &path)? == self.tree.generation {
+            let changed = self.catch_up()?;
+
+            // the records replayed came from the WAL this handle already knew
+            if read_generation(&*self.options.storage, &path)? == self.tree.generation {
+                return Ok(changed);
+            }
+        }
+
+        *self = ReadOnlyBTree::with_options(
+            &path,
+            self.key_size,
+            self.value_size,
+            self.options.clone(),
+        )?;
+
+        Ok(true)
+    }
+
+    /// Replays the writes committed to the WAL after the ones already in memory
+    fn catch_up(&mut self) -> Result<bool, Box<dyn Error>> {
+        let tree = &mut self.tree;
+        let mut changed = false;
+
+        let replay = tree.wal_file.replay_from(self.wal_index);
+        self.wal_index = replay.next;
+
+        for kv in replay.records {
+            if kv.seq > tree.last_seq {
+                tree.last_seq = kv.seq;
+                tree.mem_tree.insert_record(kv);
+                changed = true;
+            }
+        }
+
+        // new records can point at new blobs
+        let blob_store_file = blob_store_path(&tree.tree_file_path);
+
+        if changed && tree.storage.exists(&blob_store_file)? {
+            tree.blob_store = Some(BlobStore::open(&*tree.storage, &blob_store_file, false)?);
+        }
+
+        Ok(changed)
+    }
+}
+
+impl<K: KeyType, V: ValueType> Deref for ReadOnlyBTree<K, V> {
+    type Target = BTree<K, V>;
+
+    fn deref(&self) -> &BTree<K, V> {
+        &self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use read_only::write_generation;
+    use {BTree, ManualClock, Options, ReadOnlyBTree, SimDisk};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn readers_follow_the_writer() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: Arc::new(ManualClock::new(0)),
+            flush_threshold: 5,
+            l0_compaction_trigger: Some(2),
+            ..Options::default()
+        };
+        let mut writer = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+
+        writer.insert(1, 1).unwrap();
+        let mut first =
+            ReadOnlyBTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        let mut second =
+            ReadOnlyBTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        assert_eq!(first.get(&1).unwrap(), Some(vec![1]));
+
+        // appends to the WAL are replayed
+        writer.insert(2, 2).unwrap();
+        assert_eq!(first.get(&2).unwrap(), None);
+        assert!(first.refresh().unwrap());
+        assert_eq!(first.get(&2).unwrap(), Some(vec![2]));
+        assert!(!first.refresh().unwrap());
+
+        // and flushes and compactions reopen the tree
+        for i in 3..20 {
+            writer.insert(i, i).unwrap();
+        }
+        writer.delete(1, 1).unwrap();
+        assert!(second.refresh().unwrap());
+        assert_eq!(second.range(..).unwrap().count(), 18);
+
+        // until it refreshes, a reader keeps the files it opened
+        assert_eq!(first.range(..).unwrap().count(), 2);
+        assert!(first.refresh().unwrap());
+        assert_eq!(first.get(&1).unwrap(), None);
+        assert_eq!(first.range(..).unwrap().count(), 18);
+
+        // a reader won't open the files while they're being replaced
+        write_generation(&*options.storage, "db", 7).unwrap();
+        assert!(ReadOnlyBTree::<u32, u32>::with_options("db", 4, 4, options.clone()).is_err());
+        assert!(ReadOnlyBTree::<u32, u32>::with_options("missing", 4, 4, options).is_err());
+    }
+}
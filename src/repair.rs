@@ -0,0 +1,195 @@
+use block_filters::FilterSettings;
+use disk_btree::{FileOptions, OnDiskBTree};
+use read_only::{read_generation, write_generation};
+use runs::{read_manifest, write_manifest, Run};
+use schema::{schema_path, Schema};
+use storage::Storage;
+use {seal_with_sidecars, KeyType, Options, ValueType, SIDECARS};
+
+use std::cmp::Ordering;
+use std::error::Error;
+
+/// What `BTree::open_with_repair` found damaged, and what it couldn't save
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub files_rebuilt: u64, // tree files and runs rewritten, with fresh sidecars, from their records
+    pub damaged_files: Vec<String>, // the files found damaged, in the order they were checked
+    pub records_salvaged: u64, // read back from the tree file and runs
+    pub records_lost: u64,  // that couldn't be decoded, as far as they can be counted
+    pub bytes_lost: u64,    // that couldn't be read as records at all
+}
+
+/// Puts the files of the tree at `tree_file_path` back into a state it can be
+/// opened in, saving whatever records can still be read. Every run listed in the
+/// manifest, and the tree file, is read record by record and written again, so
+/// its sparse index, filters and zone map are rebuilt from what survived. A
+/// manifest that can't be read is made again from the runs found on disk, from
+/// the first up to the first one missing. The WAL is left to replay as usual, and
+/// blobs aren't checked.
+pub fn repair<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    key_size: usize,
+    value_size: usize,
+    options: &Options,
+) -> Result<RepairReport, Box<dyn Error>> {
+    let mut report = RepairReport::default();
+
+    let file_options = FileOptions {
+        filters: options
+            .bloom_bits_per_key
+            .map(|bits_per_key| FilterSettings {
+                bits_per_key,
+                prefix: None,
+            }),
+        hash_index: false,
+        prefix_compression: options.prefix_compression,
+        dictionary_size: options.compression_dictionary,
+        encoding: options.encoding,
+    };
+
+    // an odd generation has the next writer clean up after an install cut off part way
+    if read_generation(storage, tree_file_path).is_err() {
+        report
+            .damaged_files
+            .push(tree_file_path.to_owned() + ".generation");
+        write_generation(storage, tree_file_path, 1)?;
+    }
+
+    // opening the tree records the schema it's opened with again
+    let schema_file = schema_path(tree_file_path);
+
+    if storage.exists(&schema_file)? && !schema_readable(storage, &schema_file) {
+        report.damaged_files.push(schema_file.clone());
+        storage.remove(&schema_file)?;
+    }
+
+    let ids = match read_manifest(storage, tree_file_path) {
+        Ok(ids) => ids,
+        Err(_) => {
+            let ids: Vec<u64> = (1..)
+                .take_while(|id| {
+                    storage
+                        .exists(&Run::<K, V>::path(tree_file_path, *id))
+                        .unwrap_or(false)
+                })
+                .collect();
+
+            report
+                .damaged_files
+                .push(tree_file_path.to_owned() + ".runs");
+            write_manifest(storage, tree_file_path, &ids)?;
+            ids
+        }
+    };
+
+    for id in ids {
+        let path = Run::<K, V>::path(tree_file_path, id);
+
+        if storage.exists(&path)? {
+            rebuild::<K, V>(
+                storage,
+                &path,
+                key_size,
+                value_size,
+                file_options,
+                0,
+                &mut report,
+            )?;
+        }
+    }
+
+    if storage.exists(tree_file_path)? {
+        let tree_file_options = FileOptions {
+            hash_index: options.hash_index,
+            ..file_options
+        };
+
+        rebuild::<K, V>(
+            storage,
+            tree_file_path,
+            key_size,
+            value_size,
+            tree_file_options,
+            1,
+            &mut report,
+        )?;
+    }
+
+    Ok(report)
+}
+
+fn schema_readable(storage: &dyn Storage, path: &str) -> bool {
+    let read = || -> Result<Schema, Box<dyn Error>> {
+        let file = storage.open(path)?;
+        let mut bytes = vec![0; file.len()? as usize];
+        file.read_at(&mut bytes, 0)?;
+
+        Ok(bincode::deserialize(&bytes)?)
+    };
+
+    read().is_ok()
+}
+
+/// Writes what can be read of the file at `path` to a new file, with `options`,
+/// and puts it in the old one's place along with its sidecars, sealed at `level`
+fn rebuild<K: KeyType, V: ValueType>(
+    storage: &dyn Storage,
+    path: &str,
+    key_size: usize,
+    value_size: usize,
+    options: FileOptions<K>,
+    level: u32,
+    report: &mut RepairReport,
+) -> Result<(), Box<dyn Error>> {
+    let opens = OnDiskBTree::<K, V>::new(storage, path, key_size, value_size).is_ok();
+    let mut salvaged = OnDiskBTree::<K, V>::salvage(storage, path, key_size, value_size)?;
+
+    // a damaged record can decode as a key out of place; the rest stay in order
+    let sorted = salvaged.records.windows(2).all(|pair| pair[0] <= pair[1]);
+    salvaged
+        .records
+        .sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    if !opens || !sorted || salvaged.lost > 0 || salvaged.bytes_lost > 0 {
+        report.damaged_files.push(path.to_owned());
+    }
+
+    let new_path = path.to_owned() + ".repair";
+
+    storage.remove_if_exists(&new_path)?;
+
+    for sidecar in SIDECARS {
+        storage.remove_if_exists(&sidecar(&new_path))?;
+    }
+
+    let mut file = OnDiskBTree::<K, V>::create(storage, &new_path, key_size, value_size, options)?;
+
+    for kv in &salvaged.records {
+        file.insert_record(kv)?;
+    }
+
+    file.sync()?;
+    drop(file);
+
+    for sidecar in SIDECARS {
+        storage.remove_if_exists(&sidecar(path))?;
+    }
+
+    storage.rename(&new_path, path)?;
+
+    for sidecar in SIDECARS {
+        if storage.exists(&sidecar(&new_path))? {
+            storage.rename(&sidecar(&new_path), &sidecar(path))?;
+        }
+    }
+
+    seal_with_sidecars(storage, path, level)?;
+
+    report.files_rebuilt += 1;
+    report.records_salvaged += salvaged.records.len() as u64;
+    report.records_lost += salvaged.lost;
+    report.bytes_lost += salvaged.bytes_lost;
+
+    Ok(())
+}
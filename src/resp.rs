@@ -0,0 +1,504 @@
+use BTree;
+
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::RangeFull;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A tree as a Redis client sees it: every key is a set of values
+pub type RespTree = BTree<Vec<u8>, Vec<u8>>;
+
+// requests larger than this, all their arguments together, are refused rather
+// than allocated
+const MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+
+// the most arguments a command may have
+const MAX_ARGS: usize = 1024;
+
+// the longest line read, an inline command or the header of an array or bulk string
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+// how many arguments are made room for up front, before they've been read
+const ARGS_RESERVED: usize = 16;
+
+// how many keys a SCAN returns when the client doesn't say
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP reply
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>), // None is the null reply
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        match self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status)?,
+            Reply::Error(message) => {
+                write!(writer, "-ERR {}\r\n", message.replace(['\r', '\n'], " "))?
+            }
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(None) => write!(writer, "$-1\r\n")?,
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+
+                for reply in replies {
+                    reply.write(writer)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Answers Redis clients on every connection made to `listener`, a thread each,
+/// until accepting fails. A subset of the commands are understood, mapped onto
+/// the multimap: the values of a key are a set.
+///
+/// - `SADD key member...`, `SREM key member...` and `SMEMBERS key` work on the set
+/// - `SET key value` replaces the set with the one value, `GET key` returns the
+///   most recently written one, and `DEL key...` empties the sets
+/// - `SCAN cursor [MATCH pattern] [COUNT n]` walks the keys in order
+/// - `PING` and `COMMAND` are enough for `redis-cli` to connect
+pub fn serve(tree: Arc<Mutex<RespTree>>, listener: TcpListener) -> Result<(), Box<dyn Error>> {
+    for stream in listener.incoming() {
+        let (stream, tree) = (stream?, tree.clone());
+
+        thread::spawn(move || {
+            let _ = handle_connection(&tree, stream);
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection(tree: &Mutex<RespTree>, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(command) = read_command(&mut reader)? {
+        let reply = match tree.lock() {
+            Ok(mut tree) => {
+                execute(&mut tree, &command).unwrap_or_else(|e| Reply::Error(e.to_string()))
+            }
+            Err(_) => Reply::Error("a command panicked while holding the tree".to_owned()),
+        };
+
+        reply.write(&mut writer)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn protocol_error(message: &str) -> Box<dyn Error> {
+    From::from(IOError::new(ErrorKind::InvalidData, message.to_owned()))
+}
+
+/// Reads a line without its CRLF, or None at the end of the stream. A line longer
+/// than `MAX_LINE_SIZE` is refused before it's all been read.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut line = Vec::new();
+
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+
+    if read > MAX_LINE_SIZE {
+        return Err(protocol_error("line too long"));
+    }
+
+    while line
+        .last()
+        .is_some_and(|byte| *byte == b'\n' || *byte == b'\r')
+    {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+/// Parses the length of an array or bulk string, refusing one over `max`
+fn parse_len(bytes: &[u8], max: usize, too_large: &str) -> Result<usize, Box<dyn Error>> {
+    let len: usize = String::from_utf8_lossy(bytes)
+        .parse()
+        .map_err(|_| protocol_error("invalid length"))?;
+
+    if len > max {
+        return Err(protocol_error(too_large));
+    }
+
+    Ok(len)
+}
+
+/// Reads the next command: an array of bulk strings, or a line of words as typed
+/// into telnet. A command of more than `MAX_ARGS` arguments, or whose arguments
+/// come to more than `MAX_REQUEST_SIZE` bytes, is refused, and the room made for
+/// its arguments grows only as they're read, whatever count it claims.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, Box<dyn Error>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        let words = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+
+        return Ok(Some(words));
+    }
+
+    let count = parse_len(&line[1..], MAX_ARGS, "too many arguments")?;
+    let mut args = Vec::with_capacity(count.min(ARGS_RESERVED));
+    let mut left = MAX_REQUEST_SIZE;
+
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| protocol_error("truncated command"))?;
+
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected a bulk string"));
+        }
+
+        let len = parse_len(&header[1..], left, "request too large")?;
+        left -= len;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// Runs one command against the tree
+pub fn execute(tree: &mut RespTree, command: &[Vec<u8>]) -> Result<Reply, Box<dyn Error>> {
+    let (name, args) = match command.split_first() {
+        Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_uppercase(), args),
+        None => return Ok(Reply::Error("empty command".to_owned())),
+    };
+
+    let arity = |ok: bool| {
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("wrong number of arguments for '{}'", name))
+        }
+    };
+
+    if let Err(message) = match name.as_str() {
+        "GET" | "SMEMBERS" => arity(args.len() == 1),
+        "SET" => arity(args.len() == 2),
+        "SADD" | "SREM" => arity(args.len() >= 2),
+        "DEL" | "SCAN" => arity(!args.is_empty()),
+        _ => Ok(()),
+    } {
+        return Ok(Reply::Error(message));
+    }
+
+    Ok(match name.as_str() {
+        "PING" => match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Status("PONG"),
+        },
+        "COMMAND" => Reply::Array(Vec::new()),
+        "GET" => Reply::Bulk(tree.get_latest(&args[0])?),
+        "SET" => {
+            for value in tree.get(&args[0])?.unwrap_or_default() {
+                if value != args[1] {
+                    tree.delete(args[0].clone(), value)?;
+                }
+            }
+
+            tree.insert(args[0].clone(), args[1].clone())?;
+            Reply::Status("OK")
+        }
+        "SMEMBERS" => Reply::Array(
+            tree.get(&args[0])?
+                .unwrap_or_default()
+                .into_iter()
+                .map(|value| Reply::Bulk(Some(value)))
+                .collect(),
+        ),
+        "SADD" | "SREM" => {
+            let (key, members) = (&args[0], &args[1..]);
+            let mut changed = 0;
+
+            for member in members {
+                let present = tree.get(key)?.is_some_and(|values| values.contains(member));
+
+                if name == "SADD" && !present {
+                    tree.insert(key.clone(), member.clone())?;
+                    changed += 1;
+                } else if name == "SREM" && present {
+                    tree.delete(key.clone(), member.clone())?;
+                    changed += 1;
+                }
+            }
+
+            Reply::Integer(changed)
+        }
+        "DEL" => {
+            let mut removed = 0;
+
+            for key in args {
+                if let Some(values) = tree.get(key)? {
+                    for value in values {
+                        tree.delete(key.clone(), value)?;
+                    }
+
+                    removed += 1;
+                }
+            }
+
+            Reply::Integer(removed)
+        }
+        "SCAN" => scan(tree, args)?,
+        _ => Reply::Error(format!("unknown command '{}'", name)),
+    })
+}
+
+/// The cursor is how many keys have been returned so far, so a scan sees keys
+/// added or removed behind it shift what comes next, as Redis allows
+fn scan(tree: &RespTree, args: &[Vec<u8>]) -> Result<Reply, Box<dyn Error>> {
+    let cursor: usize = match String::from_utf8_lossy(&args[0]).parse() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(Reply::Error("invalid cursor".to_owned())),
+    };
+
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+
+    for option in args[1..].chunks(2) {
+        match (
+            String::from_utf8_lossy(&option[0])
+                .to_ascii_uppercase()
+                .as_str(),
+            option.get(1),
+        ) {
+            ("MATCH", Some(value)) => pattern = value,
+            ("COUNT", Some(value)) => match String::from_utf8_lossy(value).parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Reply::Error("invalid count".to_owned())),
+            },
+            _ => return Ok(Reply::Error("syntax error".to_owned())),
+        }
+    }
+
+    let mut keys = Vec::new();
+    let mut seen = 0;
+
+    // COUNT bounds the keys looked at, some of which the pattern may then filter out
+    for (key, _) in tree.range::<RangeFull>(..)?.skip(cursor).take(count) {
+        seen += 1;
+
+        if glob_match(pattern, &key) {
+            keys.push(Reply::Bulk(Some(key)));
+        }
+    }
+
+    // a cursor of 0 tells the client the scan is over
+    let next = if seen == count { cursor + count } else { 0 };
+
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+/// Matches `text` against a pattern where `*` is any run of bytes and `?` is any
+/// one byte. On a mismatch only the last `*` is retried, with one more byte of
+/// the text under it, which keeps a match linear in the pattern times the text.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None; // the last `*` seen, and where in the text it took over
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&byte) if byte == b'?' || byte == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use resp::{execute, glob_match, read_command, serve, Reply, RespTree};
+    use {Options, SimDisk};
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn run(tree: &mut RespTree, command: &str) -> Reply {
+        let command: Vec<Vec<u8>> = command
+            .split(' ')
+            .map(|word| word.as_bytes().to_vec())
+            .collect();
+        execute(tree, &command).unwrap()
+    }
+
+    fn bulk(text: &str) -> Reply {
+        Reply::Bulk(Some(text.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn commands_map_onto_the_multimap() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut tree = RespTree::with_options("db", 24, 24, options).unwrap();
+
+        assert_eq!(
+            run(&mut tree, "SADD colours red green red"),
+            Reply::Integer(2)
+        );
+        assert_eq!(run(&mut tree, "SREM colours green blue"), Reply::Integer(1));
+        assert_eq!(
+            run(&mut tree, "SMEMBERS colours"),
+            Reply::Array(vec![bulk("red")])
+        );
+
+        assert_eq!(run(&mut tree, "set name first"), Reply::Status("OK"));
+        assert_eq!(run(&mut tree, "SET name second"), Reply::Status("OK"));
+        assert_eq!(run(&mut tree, "GET name"), bulk("second"));
+        assert_eq!(
+            run(&mut tree, "SMEMBERS name"),
+            Reply::Array(vec![bulk("second")])
+        );
+        assert_eq!(run(&mut tree, "DEL name missing"), Reply::Integer(1));
+        assert_eq!(run(&mut tree, "GET name"), Reply::Bulk(None));
+
+        for i in 0..5 {
+            run(&mut tree, &format!("SET key{} {}", i, i));
+        }
+
+        assert_eq!(
+            run(&mut tree, "SCAN 0 MATCH key* COUNT 4"),
+            Reply::Array(vec![
+                bulk("4"),
+                Reply::Array(vec![bulk("key0"), bulk("key1"), bulk("key2")])
+            ])
+        );
+        assert_eq!(
+            run(&mut tree, "SCAN 4 MATCH key* COUNT 4"),
+            Reply::Array(vec![
+                bulk("0"),
+                Reply::Array(vec![bulk("key3"), bulk("key4")])
+            ])
+        );
+
+        assert!(matches!(run(&mut tree, "GET"), Reply::Error(_)));
+        assert!(matches!(run(&mut tree, "FLUSHALL"), Reply::Error(_)));
+        assert!(glob_match(b"k?y*", b"key42") && !glob_match(b"k?y", b"key42"));
+    }
+
+    #[test]
+    fn globs_match_without_backtracking_blowing_up() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(glob_match(b"*b*b", b"abab"));
+        assert!(!glob_match(b"a*b", b"axxbc"));
+        assert!(!glob_match(b"?", b""));
+
+        // thirty stars against a text that nearly matches is a lot of backtracking
+        // for a recursive match
+        let pattern = [b"a*".repeat(30), b"b".to_vec()].concat();
+        assert!(!glob_match(&pattern, &[b'a'; 100]));
+    }
+
+    #[test]
+    fn clients_speak_resp_over_tcp() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = Arc::new(Mutex::new(
+            RespTree::with_options("db", 24, 24, options).unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || serve(tree, listener).map_err(|e| e.to_string()));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        stream
+            .write_all(b"*3\r\n$4\r\nSADD\r\n$1\r\nk\r\n$5\r\nv\r\n1!\r\n")
+            .unwrap();
+        stream.write_all(b"SMEMBERS k\r\n").unwrap();
+
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        assert_eq!(reply, ":1\r\n");
+
+        // the reply to SMEMBERS is an array of one bulk string
+        let reply = read_command(&mut reader).unwrap().unwrap();
+        assert_eq!(reply, [b"v\r\n1!".to_vec()]);
+    }
+
+    #[test]
+    fn oversized_commands_are_refused_before_they_are_read() {
+        let read = |bytes: &[u8]| read_command(&mut &bytes[..]);
+
+        // claiming billions of arguments allocates nothing
+        assert!(read(b"*4000000000\r\n").is_err());
+        assert!(read(b"*1025\r\n").is_err());
+
+        // nor do arguments that come to more than a request may, together
+        let request = [
+            b"*2\r\n$40000000\r\n".to_vec(),
+            vec![b'a'; 40_000_000],
+            b"\r\n$40000000\r\n".to_vec(),
+        ]
+        .concat();
+        assert!(read(&request).is_err());
+
+        // nor a line that never ends
+        assert!(read(&vec![b'a'; 100_000]).is_err());
+
+        assert_eq!(
+            read(b"*2\r\n$4\r\nPING\r\n$2\r\nhi\r\n").unwrap(),
+            Some(vec![b"PING".to_vec(), b"hi".to_vec()])
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use disk_btree::{FileOptions, OnDiskBTree};
+use storage::Storage;
+use wal_file::KeyValuePair;
+use {KeyType, ValueType, SIDECARS};
+
+use std::error::Error;
+
+/// An L0 run: the sorted contents of one memtable flush, written as is so a flush
+/// doesn't have to rewrite the whole tree file. Runs can overlap each other and the
+/// tree file, so every read has to consult them all until they're merged down.
+pub struct Run<K: KeyType, V: ValueType> {
+    pub id: u64,
+    pub file: OnDiskBTree<K, V>,
+    pub expiries: Vec<u64>, // when each TTL'd record in the run expires, sorted
+}
+
+impl<K: KeyType, V: ValueType> Run<K, V> {
+    pub fn path(tree_file_path: &str, id: u64) -> String {
+        format!("{}.L0.{}", tree_file_path, id)
+    }
+
+    pub fn open(
+        storage: &dyn Storage,
+        tree_file_path: &str,
+        id: u64,
+        key_size: usize,
+        value_size: usize,
+    ) -> Result<Run<K, V>, Box<dyn Error>> {
+        let file = OnDiskBTree::new(
+            storage,
+            &Run::<K, V>::path(tree_file_path, id),
+            key_size,
+            value_size,
+        )?;
+
+        let mut expiries = Vec::new();
+
+        for kv in &file {
+            expiries.extend(kv?.expires_at);
+        }
+
+        expiries.sort_unstable();
+
+        Ok(Run { id, file, expiries })
+    }
+
+    /// Writes `records`, which must be sorted, to a new run and syncs it. The run
+    /// isn't part of the tree until it's been added to the manifest.
+    pub fn create(
+        storage: &dyn Storage,
+        tree_file_path: &str,
+        id: u64,
+        key_size: usize,
+        value_size: usize,
+        options: FileOptions<K>,
+        records: impl Iterator<Item = KeyValuePair<K, V>>,
+    ) -> Result<Run<K, V>, Box<dyn Error>> {
+        // left behind by a flush that crashed before updating the manifest
+        Run::<K, V>::remove(storage, tree_file_path, id)?;
+
+        let path = Run::<K, V>::path(tree_file_path, id);
+        let mut file = OnDiskBTree::create(storage, &path, key_size, value_size, options)?;
+        let mut expiries = Vec::new();
+
+        for kv in records {
+            file.insert_record(&kv)?;
+            expiries.extend(kv.expires_at);
+        }
+
+        file.sync()?;
+        expiries.sort_unstable();
+
+        Ok(Run { id, file, expiries })
+    }
+
+    /// Removes a run's file, and its sidecars
+    pub fn remove(
+        storage: &dyn Storage,
+        tree_file_path: &str,
+        id: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = Run::<K, V>::path(tree_file_path, id);
+
+        storage.remove_if_exists(&path)?;
+
+        for sidecar in SIDECARS {
+            storage.remove_if_exists(&sidecar(&path))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn manifest_path(tree_file_path: &str) -> String {
+    tree_file_path.to_owned() + ".runs"
+}
+
+/// Returns the ids of the tree's L0 runs, oldest first
+pub fn read_manifest(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+) -> Result<Vec<u64>, Box<dyn Error>> {
+    let path = manifest_path(tree_file_path);
+
+    if !storage.exists(&path)? {
+        return Ok(Vec::new());
+    }
+
+    let file = storage.open(&path)?;
+    let mut buff = vec![0; file.len()? as usize];
+    file.read_at(&mut buff, 0)?;
+
+    Ok(bincode::deserialize(&buff)?)
+}
+
+/// Replaces the manifest with one listing `ids`. The new manifest is written
+/// beside the old one and renamed over it, so a crash leaves one or the other.
+pub fn write_manifest(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    ids: &[u64],
+) -> Result<(), Box<dyn Error>> {
+    let path = manifest_path(tree_file_path);
+    let new_path = path.to_owned() + ".new";
+
+    if storage.exists(&new_path)? {
+        storage.remove(&new_path)?;
+    }
+
+    let mut file = storage.open(&new_path)?;
+    file.append(&bincode::serialize(ids)?)?;
+    file.sync()?;
+
+    Ok(storage.rename(&new_path, &path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use runs::{read_manifest, write_manifest};
+    use sim_disk::SimDisk;
+
+    #[test]
+    fn manifest_round_trips() {
+        let disk = SimDisk::new(0);
+
+        assert!(read_manifest(&disk, "db").unwrap().is_empty());
+
+        write_manifest(&disk, "db", &[1, 2]).unwrap();
+        write_manifest(&disk, "db", &[2, 3]).unwrap();
+
+        disk.crash();
+        assert_eq!(read_manifest(&disk, "db").unwrap(), [2, 3]);
+    }
+}
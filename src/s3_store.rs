@@ -0,0 +1,460 @@
+use object_store::{ObjectStore, ObjectUpload};
+
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::serde_types::Part;
+use s3::{Bucket, Region};
+use std::io::Error as IOError;
+use std::io::{ErrorKind, Result as IOResult};
+use std::time::Duration;
+
+// how long a request to the bucket may take before it fails
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+// the size of each part of a multipart upload but the last, see `with_part_size`
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// A bucket of S3, or of an S3-compatible store such as MinIO, reached through
+/// the `rust-s3` client: it signs the requests, speaks TLS to an `https`
+/// endpoint, retries the ones that fail and gives each a timeout. Uploads larger
+/// than one part go up as multipart uploads, so a sealed file is never held in
+/// memory whole.
+pub struct S3ObjectStore {
+    bucket: Box<Bucket>,
+    part_size: usize,
+}
+
+impl S3ObjectStore {
+    /// The bucket `bucket` at `endpoint`, such as `https://s3.us-east-1.amazonaws.com`
+    /// or a MinIO server's address, addressed by path, with requests signed for
+    /// `region` with the access key and secret
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+    ) -> IOResult<S3ObjectStore> {
+        let region = Region::Custom {
+            region: region.to_owned(),
+            endpoint: endpoint.to_owned(),
+        };
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+            .map_err(IOError::other)?;
+        let bucket = Bucket::new(bucket, region, credentials)
+            .map_err(s3_error)?
+            .with_path_style()
+            .with_request_timeout(REQUEST_TIMEOUT)
+            .map_err(s3_error)?;
+
+        Ok(S3ObjectStore::from_bucket(bucket))
+    }
+
+    /// A bucket the caller has set up, for credentials from the environment or an
+    /// instance profile, virtual-hosted addressing and the like
+    pub fn from_bucket(bucket: Box<Bucket>) -> S3ObjectStore {
+        S3ObjectStore {
+            bucket,
+            part_size: PART_SIZE,
+        }
+    }
+
+    /// Sets how large the parts of a multipart upload are, 8MiB by default. S3
+    /// takes no part but the last smaller than 5MiB.
+    pub fn with_part_size(mut self, bytes: usize) -> S3ObjectStore {
+        self.part_size = bytes.max(1);
+        self
+    }
+}
+
+/// A failed request as an IO error, a missing object as `NotFound`
+fn s3_error(e: S3Error) -> IOError {
+    match e {
+        S3Error::HttpFailWithBody(404, body) => IOError::new(ErrorKind::NotFound, body),
+        S3Error::Io(e) => e,
+        e => IOError::other(e),
+    }
+}
+
+/// Fails unless the request succeeded
+fn check_status(status: u16) -> IOResult<()> {
+    match status {
+        200..=299 => Ok(()),
+        404 => Err(IOError::new(ErrorKind::NotFound, "no such object")),
+        _ => Err(IOError::other(format!(
+            "the bucket answered with HTTP {}",
+            status
+        ))),
+    }
+}
+
+impl ObjectStore for S3ObjectStore {
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> IOResult<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        // the end of the range is inclusive
+        let response = self
+            .bucket
+            .get_object_range(key, offset, Some(offset + len - 1))
+            .map_err(s3_error)?;
+        check_status(response.status_code())?;
+
+        match response.as_slice() {
+            bytes if bytes.len() as u64 == len => Ok(bytes.to_vec()),
+            _ => Err(IOError::new(
+                ErrorKind::UnexpectedEof,
+                "read past the end of the object",
+            )),
+        }
+    }
+
+    fn size(&self, key: &str) -> IOResult<Option<u64>> {
+        let (head, status) = match self.bucket.head_object(key) {
+            Ok(response) => response,
+            Err(S3Error::HttpFailWithBody(404, _)) => return Ok(None),
+            Err(e) => return Err(s3_error(e)),
+        };
+
+        if status == 404 {
+            return Ok(None);
+        }
+
+        check_status(status)?;
+
+        match head.content_length {
+            Some(len) if len >= 0 => Ok(Some(len as u64)),
+            _ => Err(IOError::new(
+                ErrorKind::InvalidData,
+                "the bucket didn't say how large the object is",
+            )),
+        }
+    }
+
+    fn put(&self, key: &str, bytes: &[u8]) -> IOResult<()> {
+        let response = self.bucket.put_object(key, bytes).map_err(s3_error)?;
+        check_status(response.status_code())
+    }
+
+    fn upload<'a>(&'a self, key: &str) -> IOResult<Box<dyn ObjectUpload + 'a>> {
+        Ok(Box::new(S3Upload {
+            store: self,
+            key: key.to_owned(),
+            upload_id: None,
+            parts: Vec::new(),
+            pending: Vec::new(),
+        }))
+    }
+
+    fn delete(&self, key: &str) -> IOResult<()> {
+        match self.bucket.delete_object(key) {
+            Ok(response) => check_status(response.status_code()),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(()),
+            Err(e) => Err(s3_error(e)),
+        }
+    }
+
+    fn copy(&self, from: &str, to: &str) -> IOResult<()> {
+        check_status(
+            self.bucket
+                .copy_object_internal(from, to)
+                .map_err(s3_error)?,
+        )
+    }
+}
+
+/// An object going up to S3 a part at a time. An object that fits in one part is
+/// put in one request when it's finished; a larger one is started as a multipart
+/// upload once its first part is full, and that upload is aborted if it's dropped
+/// unfinished.
+struct S3Upload<'a> {
+    store: &'a S3ObjectStore,
+    key: String,
+    upload_id: Option<String>, // once the multipart upload has started
+    parts: Vec<Part>,          // uploaded so far
+    pending: Vec<u8>,          // written but not yet uploaded, less than a part
+}
+
+impl S3Upload<'_> {
+    /// Uploads `part` as the next part, starting the multipart upload if need be
+    fn upload_part(&mut self, part: &[u8]) -> IOResult<()> {
+        let bucket = &self.store.bucket;
+
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let upload = bucket
+                    .initiate_multipart_upload(&self.key, CONTENT_TYPE)
+                    .map_err(s3_error)?;
+                self.upload_id = Some(upload.upload_id.clone());
+                upload.upload_id
+            }
+        };
+
+        let part_number = self.parts.len() as u32 + 1;
+        let part = bucket
+            .put_multipart_chunk(part, &self.key, part_number, &upload_id, CONTENT_TYPE)
+            .map_err(s3_error)?;
+
+        self.parts.push(part);
+        Ok(())
+    }
+}
+
+impl ObjectUpload for S3Upload<'_> {
+    fn write_part(&mut self, part: &[u8]) -> IOResult<()> {
+        self.pending.extend_from_slice(part);
+
+        while self.pending.len() >= self.store.part_size {
+            let rest = self.pending.split_off(self.store.part_size);
+            let full = std::mem::replace(&mut self.pending, rest);
+            self.upload_part(&full)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> IOResult<()> {
+        let pending = std::mem::take(&mut self.pending);
+
+        if self.upload_id.is_none() {
+            return self.store.put(&self.key, &pending);
+        }
+
+        if !pending.is_empty() {
+            self.upload_part(&pending)?;
+        }
+
+        let upload_id = self.upload_id.take().unwrap();
+        let parts = std::mem::take(&mut self.parts);
+
+        match self
+            .store
+            .bucket
+            .complete_multipart_upload(&self.key, &upload_id, parts)
+        {
+            Ok(response) => check_status(response.status_code()),
+            Err(e) => {
+                let _ = self.store.bucket.abort_upload(&self.key, &upload_id);
+                Err(s3_error(e))
+            }
+        }
+    }
+}
+
+impl Drop for S3Upload<'_> {
+    fn drop(&mut self) {
+        if let Some(upload_id) = &self.upload_id {
+            let _ = self.store.bucket.abort_upload(&self.key, upload_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::ObjectStore;
+    use s3_store::S3ObjectStore;
+    use {BTree, ObjectStorage, Options, SimDisk};
+
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// The objects of a bucket served by `serve_bucket`, and the parts of its
+    /// multipart uploads under way
+    #[derive(Default)]
+    struct Bucket {
+        objects: HashMap<String, Vec<u8>>,
+        uploads: HashMap<String, Vec<Vec<u8>>>,
+    }
+
+    /// Serves just enough of the S3 API over plain HTTP for `S3ObjectStore`,
+    /// taking any signature, a connection per request
+    fn serve_bucket() -> (String, Arc<Mutex<Bucket>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let served = bucket.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = answer(&mut served.lock().unwrap(), stream.unwrap());
+            }
+        });
+
+        (endpoint, bucket)
+    }
+
+    fn answer(bucket: &mut Bucket, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        let mut words = line.split_whitespace();
+        let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut headers = HashMap::new();
+
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+
+            match header.trim_end().split_once(':') {
+                Some((name, value)) => {
+                    headers.insert(name.to_ascii_lowercase(), value.trim().to_owned())
+                }
+                None => break,
+            };
+        }
+
+        let len = headers
+            .get("content-length")
+            .map_or(0, |len| len.parse().unwrap());
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+
+        let param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_owned)
+        };
+        let key = path.to_owned();
+        let (status, extra, reply): (&str, String, Vec<u8>) = match method {
+            "PUT" => match param("uploadId") {
+                Some(upload) => {
+                    bucket.uploads.get_mut(&upload).unwrap().push(body);
+                    ("200 OK", "ETag: \"part\"\r\n".to_owned(), Vec::new())
+                }
+                None => {
+                    bucket.objects.insert(key, body);
+                    ("200 OK", "ETag: \"object\"\r\n".to_owned(), Vec::new())
+                }
+            },
+            "POST" if query.starts_with("uploads") => {
+                let upload = format!("upload{}", bucket.uploads.len());
+                bucket.uploads.insert(upload.clone(), Vec::new());
+                let reply = format!(
+                    "<InitiateMultipartUploadResult><Bucket>b</Bucket><Key>{}</Key>\
+                     <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    key, upload
+                );
+                ("200 OK", String::new(), reply.into_bytes())
+            }
+            "POST" => {
+                let parts = bucket.uploads.remove(&param("uploadId").unwrap()).unwrap();
+                bucket.objects.insert(key.clone(), parts.concat());
+                let reply = format!(
+                    "<CompleteMultipartUploadResult><Location>{}</Location><Bucket>b</Bucket>\
+                     <Key>{}</Key><ETag>\"object\"</ETag></CompleteMultipartUploadResult>",
+                    key, key
+                );
+                ("200 OK", String::new(), reply.into_bytes())
+            }
+            "DELETE" => {
+                match param("uploadId") {
+                    Some(upload) => drop(bucket.uploads.remove(&upload)),
+                    None => drop(bucket.objects.remove(&key)),
+                }
+                ("204 No Content", String::new(), Vec::new())
+            }
+            _ => match bucket.objects.get(&key) {
+                None => ("404 Not Found", String::new(), b"<Error/>".to_vec()),
+                Some(object) if method == "HEAD" => (
+                    "200 OK",
+                    format!("Content-Length: {}\r\n", object.len()),
+                    Vec::new(),
+                ),
+                Some(object) => {
+                    let range = headers["range"].trim_start_matches("bytes=");
+                    let (start, end) = range.split_once('-').unwrap();
+                    let (start, end): (usize, usize) =
+                        (start.parse().unwrap(), end.parse().unwrap());
+                    let reply = object[start..(end + 1).min(object.len())].to_vec();
+                    ("206 Partial Content", String::new(), reply)
+                }
+            },
+        };
+
+        let length = if method == "HEAD" {
+            String::new()
+        } else {
+            format!("Content-Length: {}\r\n", reply.len())
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n{}{}Connection: close\r\n\r\n",
+            status, extra, length
+        )?;
+        stream.write_all(&reply)?;
+        stream.flush()
+    }
+
+    #[test]
+    fn objects_go_to_and_from_an_s3_bucket() {
+        let (endpoint, bucket) = serve_bucket();
+        let store = S3ObjectStore::new(&endpoint, "bucket", "us-east-1", "key", "secret")
+            .unwrap()
+            .with_part_size(1024);
+
+        store.put("small", b"hello, world").unwrap();
+        assert_eq!(store.size("small").unwrap(), Some(12));
+        assert_eq!(store.get_range("small", 7, 5).unwrap(), b"world");
+        assert!(store.get_range("small", 7, 50).is_err());
+
+        // more than a part goes up as a multipart upload
+        let bytes: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let mut upload = store.upload("large").unwrap();
+        for chunk in bytes.chunks(700) {
+            upload.write_part(chunk).unwrap();
+        }
+        upload.finish().unwrap();
+        assert_eq!(store.get_range("large", 0, 2500).unwrap(), bytes);
+        assert!(bucket.lock().unwrap().uploads.is_empty());
+
+        // and one dropped before it's finished is aborted
+        let mut upload = store.upload("abandoned").unwrap();
+        upload.write_part(&bytes).unwrap();
+        drop(upload);
+        assert!(bucket.lock().unwrap().uploads.is_empty());
+        assert_eq!(store.size("abandoned").unwrap(), None);
+
+        store.delete("small").unwrap();
+        assert_eq!(store.size("small").unwrap(), None);
+        assert_eq!(
+            store.get_range("small", 0, 1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn sealed_files_are_read_back_from_an_s3_bucket() {
+        let (endpoint, bucket) = serve_bucket();
+        let store = S3ObjectStore::new(&endpoint, "bucket", "us-east-1", "key", "secret").unwrap();
+        let options = Options {
+            storage: Arc::new(ObjectStorage::new(
+                Arc::new(SimDisk::new(0)),
+                Arc::new(store),
+            )),
+            ..Options::default()
+        };
+
+        let mut btree = BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).unwrap();
+        for i in 0..2000 {
+            btree.insert(i, i).unwrap();
+        }
+        btree.flush().unwrap();
+        drop(btree);
+        assert!(!bucket.lock().unwrap().objects.is_empty());
+
+        let btree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        for i in (0..2000).step_by(97) {
+            assert_eq!(btree.get(&i).unwrap(), Some(vec![i]));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use bloom::fnv1a;
+use error::BTreeError;
+use storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use std::any::type_name;
+use std::error::Error;
+
+/// The path of the file recording the schema a tree was created with
+pub fn schema_path(tree_file_path: &str) -> String {
+    tree_file_path.to_owned() + ".schema"
+}
+
+/// What a tree's keys and values are. It's recorded when the tree is created, and
+/// opening the tree with a different one fails with `BTreeError::SchemaMismatch`
+/// rather than decoding the records as the wrong types.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schema {
+    pub name: String,
+    pub hash: u64, // of the types' layout, however it's worked out
+}
+
+impl Schema {
+    pub fn new(name: &str, hash: u64) -> Schema {
+        Schema {
+            name: name.to_owned(),
+            hash,
+        }
+    }
+
+    /// The schema used when the options don't give one, named after the key and
+    /// value types. Type names can change between compiler versions, so a tree
+    /// meant to outlive the build should be given a schema of its own.
+    pub fn of<K, V>() -> Schema {
+        let name = format!("{}, {}", type_name::<K>(), type_name::<V>());
+        let hash = fnv1a(name.as_bytes());
+
+        Schema { name, hash }
+    }
+}
+
+/// Checks the tree at `tree_file_path` was created with `schema`, and folds the
+/// case of its keys if `fold_case` says so, recording both first when the tree has
+/// no schema yet, unless it's being opened `read_only`
+pub fn check_schema(
+    storage: &dyn Storage,
+    tree_file_path: &str,
+    schema: &Schema,
+    fold_case: bool,
+    read_only: bool,
+) -> Result<(), Box<dyn Error>> {
+    let path = schema_path(tree_file_path);
+
+    if !storage.exists(&path)? {
+        if !read_only {
+            let mut file = storage.open(&path)?;
+            file.append(&bincode::serialize(&(schema, fold_case))?)?;
+            file.sync()?;
+        }
+
+        return Ok(());
+    }
+
+    let file = storage.open(&path)?;
+    let mut bytes = vec![0; file.len()? as usize];
+    file.read_at(&mut bytes, 0)?;
+
+    // the case folding flag follows the schema
+    let mut rest = &bytes[..];
+    let created_with: Schema = bincode::deserialize_from(&mut rest)?;
+    let created_folding = !rest.is_empty() && bincode::deserialize::<bool>(rest)?;
+
+    if created_with != *schema {
+        return Err(Box::new(BTreeError::SchemaMismatch {
+            created_as: created_with.name,
+            opened_as: schema.name.clone(),
+        }));
+    }
+
+    if created_folding != fold_case {
+        return Err(Box::new(BTreeError::FoldCaseMismatch { created_folding }));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use error::BTreeError;
+    use {BTree, Options, Schema, SimDisk};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn trees_open_only_with_their_schema() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        BTree::<u32, u32>::with_options("db", 4, 4, options.clone())
+            .unwrap()
+            .insert(1, 1)
+            .unwrap();
+
+        let error = BTree::<u32, String>::with_options("db", 4, 4, options.clone())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::SchemaMismatch {
+                created_as: "u32, u32".to_owned(),
+                opened_as: "u32, alloc::string::String".to_owned(),
+            })
+        );
+        assert!(BTree::<u32, u32>::with_options("db", 4, 4, options.clone()).is_ok());
+
+        let pinned = Options {
+            schema: Some(Schema::new("users", 2)),
+            ..options
+        };
+        BTree::<u32, u32>::with_options("pinned", 4, 4, pinned.clone()).unwrap();
+
+        let changed = Options {
+            schema: Some(Schema::new("users", 3)),
+            ..pinned.clone()
+        };
+        assert!(BTree::<u32, u32>::with_options("pinned", 4, 4, changed).is_err());
+        assert!(BTree::<u32, u32>::with_options("pinned", 4, 4, pinned).is_ok());
+    }
+}
@@ -0,0 +1,344 @@
+use {BTree, KeyType, ValueType};
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// frames larger than this are refused rather than allocated, or sent
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+// the most keys one scan request returns, however many it asks for
+const MAX_SCAN_LIMIT: usize = 10_000;
+
+// how many keys `Client::scan` asks for at a time
+const SCAN_PAGE: usize = 1000;
+
+/// A request to the server. Every frame on the wire, either way, is a little-endian
+/// u32 length followed by that many bytes of bincode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request<K, V> {
+    Get(K),
+    Insert(K, V),
+    Delete(K, V),
+    Scan(Option<K>, Option<K>, usize), // from the first key, inclusive, to the second, exclusive, at most this many
+}
+
+/// Keys with their values, in key order, as a scan returns them
+pub type Entries<K, V> = Vec<(K, Vec<V>)>;
+
+/// A page of a scan, and the key the next one starts at if there are more
+pub type Page<K, V> = (Entries<K, V>, Option<K>);
+
+/// The server's answer to a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response<K, V> {
+    Values(Option<Vec<V>>),
+    Done,
+    Entries(Entries<K, V>, Option<K>), // and the key the next page starts at, if the scan stopped short
+    Error(String),
+}
+
+pub fn write_frame<W: Write, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> Result<(), Box<dyn Error>> {
+    let body = bincode::serialize(message)?;
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_SIZE)
+        .ok_or_else(|| too_large(body.len()))?;
+
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&body)?;
+
+    Ok(writer.flush()?)
+}
+
+fn too_large(len: usize) -> Box<dyn Error> {
+    From::from(IOError::new(
+        ErrorKind::InvalidData,
+        format!("A frame of {} bytes is too large", len),
+    ))
+}
+
+/// Reads the next frame, or None when the other end has closed the connection
+pub fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(
+    reader: &mut R,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let mut len = [0; 4];
+
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(From::from(e)),
+    }
+
+    let len = u32::from_le_bytes(len);
+
+    if len > MAX_FRAME_SIZE {
+        return Err(too_large(len as usize));
+    }
+
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(bincode::deserialize(&body)?))
+}
+
+/// Answers requests from every connection made to `listener`, a thread each, until
+/// accepting fails. The tree is locked for one request at a time, and a scan returns
+/// at most `MAX_SCAN_LIMIT` keys, so none holds it for long.
+pub fn serve<K, V>(
+    tree: Arc<Mutex<BTree<K, V>>>,
+    listener: TcpListener,
+) -> Result<(), Box<dyn Error>>
+where
+    K: KeyType + 'static,
+    V: ValueType + 'static,
+{
+    for stream in listener.incoming() {
+        let (stream, tree) = (stream?, tree.clone());
+
+        // a client that goes away mid-request only ends its own connection
+        thread::spawn(move || {
+            let _ = handle_connection(&tree, stream);
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection<K: KeyType, V: ValueType>(
+    tree: &Mutex<BTree<K, V>>,
+    stream: TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(request) = read_frame::<_, Request<K, V>>(&mut reader)? {
+        let mut response = match tree.lock() {
+            Ok(mut tree) => {
+                answer(&mut tree, request).unwrap_or_else(|e| Response::Error(e.to_string()))
+            }
+            Err(_) => Response::Error("A request panicked while holding the tree".to_owned()),
+        };
+
+        // answered with an error instead, rather than ending the connection
+        if bincode::serialized_size(&response)? > MAX_FRAME_SIZE as u64 {
+            response = Response::Error(
+                "The response is too large for a frame, scan fewer keys at a time".to_owned(),
+            );
+        }
+
+        write_frame(&mut writer, &response)?;
+    }
+
+    Ok(())
+}
+
+fn answer<K: KeyType, V: ValueType>(
+    tree: &mut BTree<K, V>,
+    request: Request<K, V>,
+) -> Result<Response<K, V>, Box<dyn Error>> {
+    Ok(match request {
+        Request::Get(key) => Response::Values(tree.get(&key)?),
+        Request::Insert(key, value) => {
+            tree.insert(key, value)?;
+            Response::Done
+        }
+        Request::Delete(key, value) => {
+            tree.delete(key, value)?;
+            Response::Done
+        }
+        Request::Scan(start, end, limit) => {
+            let start = start.map_or(Unbounded, Included);
+            let end = end.map_or(Unbounded, Excluded);
+            let limit = limit.clamp(1, MAX_SCAN_LIMIT);
+
+            // one key past the limit, to say where the next page starts
+            let mut entries = tree
+                .try_range((start, end))?
+                .take(limit + 1)
+                .collect::<Result<Entries<K, V>, _>>()?;
+            let next = if entries.len() > limit {
+                entries.pop().map(|(key, _)| key)
+            } else {
+                None
+            };
+
+            Response::Entries(entries, next)
+        }
+    })
+}
+
+/// A connection to a server started with `serve`
+pub struct Client<K, V> {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K: KeyType, V: ValueType> Client<K, V> {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Client<K, V>, Box<dyn Error>> {
+        let stream = TcpStream::connect(address)?;
+
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn get(&mut self, key: K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        match self.call(Request::Get(key))? {
+            Response::Values(values) => Ok(values),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        match self.call(Request::Insert(key, value))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    pub fn delete(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        match self.call(Request::Delete(key, value))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    /// Returns the keys from `start` up to, but not including, `end`, with their
+    /// values, asked for a page at a time
+    pub fn scan(
+        &mut self,
+        start: Option<K>,
+        end: Option<K>,
+    ) -> Result<Entries<K, V>, Box<dyn Error>> {
+        let (mut entries, mut start) = (Vec::new(), start);
+
+        loop {
+            let (page, next) = self.scan_page(start, end.clone(), SCAN_PAGE)?;
+            entries.extend(page);
+
+            match next {
+                Some(next) => start = Some(next),
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    /// Returns up to `limit` of the keys from `start` up to, but not including,
+    /// `end`, with their values, and the key the next page starts at if there are
+    /// more. The server returns no more than 10,000 keys at a time.
+    pub fn scan_page(
+        &mut self,
+        start: Option<K>,
+        end: Option<K>,
+        limit: usize,
+    ) -> Result<Page<K, V>, Box<dyn Error>> {
+        match self.call(Request::Scan(start, end, limit))? {
+            Response::Entries(entries, next) => Ok((entries, next)),
+            response => Err(unexpected(&response)),
+        }
+    }
+
+    fn call(&mut self, request: Request<K, V>) -> Result<Response<K, V>, Box<dyn Error>> {
+        write_frame(&mut self.writer, &request)?;
+
+        match read_frame(&mut self.reader)? {
+            Some(Response::Error(message)) => Err(From::from(message)),
+            Some(response) => Ok(response),
+            None => Err(From::from(IOError::new(
+                ErrorKind::UnexpectedEof,
+                "The server closed the connection",
+            ))),
+        }
+    }
+}
+
+fn unexpected<K, V>(response: &Response<K, V>) -> Box<dyn Error> {
+    let kind = match response {
+        Response::Values(_) => "values",
+        Response::Done => "done",
+        Response::Entries(_, _) => "entries",
+        Response::Error(_) => "an error",
+    };
+
+    From::from(IOError::new(
+        ErrorKind::InvalidData,
+        format!(
+            "The server answered with {} when something else was expected",
+            kind
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use server::{serve, write_frame, Client, MAX_FRAME_SIZE};
+    use {BTree, Options, SimDisk};
+
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn clients_share_a_tree_over_tcp() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = Arc::new(Mutex::new(
+            BTree::<u32, String>::with_options("db", 4, 16, options).unwrap(),
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || serve(tree, listener).map_err(|e| e.to_string()));
+
+        let mut first = Client::<u32, String>::connect(address).unwrap();
+        let mut second = Client::<u32, String>::connect(address).unwrap();
+
+        for i in 0..10 {
+            first.insert(i, format!("value {}", i)).unwrap();
+        }
+        second.delete(3, "value 3".to_owned()).unwrap();
+
+        assert_eq!(second.get(1).unwrap(), Some(vec!["value 1".to_owned()]));
+        assert_eq!(first.get(3).unwrap(), None);
+
+        let keys: Vec<u32> = first
+            .scan(Some(2), Some(6))
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, [2, 4, 5]);
+        assert_eq!(second.scan(None, None).unwrap().len(), 9);
+
+        let (page, next) = first.scan_page(None, None, 5).unwrap();
+        assert_eq!(page.len(), 5);
+        assert_eq!(next, Some(6));
+        let (page, next) = first.scan_page(next, None, 5).unwrap();
+        assert_eq!((page.len(), next), (4, None));
+    }
+
+    #[test]
+    fn frames_too_large_to_send_are_refused() {
+        let mut sent = Vec::new();
+        let body = vec![0u8; MAX_FRAME_SIZE as usize + 1];
+
+        assert!(write_frame(&mut sent, &body).is_err());
+        assert!(sent.is_empty());
+    }
+}
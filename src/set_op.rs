@@ -0,0 +1,75 @@
+use itertools::{EitherOrBoth, Itertools};
+
+use std::cmp::Ordering;
+
+/// Which of the key and value pairs of two trees `BTree::combine` keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Union,        // the pairs in either tree
+    Intersection, // the pairs in both
+    Difference,   // the pairs in the first tree but not the second
+}
+
+impl SetOp {
+    /// Merge-joins two streams of keys with their values, both in key and then
+    /// value order, into the keys with any values left, in the same order. An error
+    /// reading either stream comes out as soon as it's met.
+    pub fn apply<K, V, E, A, B>(
+        self,
+        first: A,
+        second: B,
+    ) -> impl Iterator<Item = Result<(K, Vec<V>), E>>
+    where
+        K: Ord,
+        V: Ord,
+        A: Iterator<Item = Result<(K, Vec<V>), E>>,
+        B: Iterator<Item = Result<(K, Vec<V>), E>>,
+    {
+        first
+            .merge_join_by(second, errors_first)
+            .filter_map(move |entry| {
+                // a key missing from one side has no values there
+                let (key, a, b) = match entry {
+                    EitherOrBoth::Both(Ok((key, a)), Ok((_, b))) => (key, a, b),
+                    EitherOrBoth::Left(Ok((key, a))) => (key, a, Vec::new()),
+                    EitherOrBoth::Right(Ok((key, b))) => (key, Vec::new(), b),
+                    EitherOrBoth::Left(Err(error)) | EitherOrBoth::Right(Err(error)) => {
+                        return Some(Err(error))
+                    }
+                    // an error is never equal to anything, so never comes paired
+                    EitherOrBoth::Both(_, _) => unreachable!(),
+                };
+
+                let values: Vec<V> = a
+                    .into_iter()
+                    .merge_join_by(b, |a, b| a.cmp(b))
+                    .filter_map(|value| self.keep(value))
+                    .collect();
+
+                if values.is_empty() {
+                    None
+                } else {
+                    Some(Ok((key, values)))
+                }
+            })
+    }
+
+    fn keep<V>(self, value: EitherOrBoth<V, V>) -> Option<V> {
+        match (self, value) {
+            (SetOp::Union, value) => Some(value.reduce(|a, _| a)),
+            (SetOp::Intersection, EitherOrBoth::Both(a, _)) => Some(a),
+            (SetOp::Difference, EitherOrBoth::Left(a)) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// Orders the entries of two streams being merge-joined by key, an error before
+/// anything so it's passed on straight away
+pub fn errors_first<K: Ord, V, E>(a: &Result<(K, V), E>, b: &Result<(K, V), E>) -> Ordering {
+    match (a, b) {
+        (Ok((a, _)), Ok((b, _))) => a.cmp(b),
+        (Err(_), _) => Ordering::Less,
+        (_, Err(_)) => Ordering::Greater,
+    }
+}
@@ -0,0 +1,592 @@
+use block_cache::BlockCache;
+use bloom::fnv1a;
+use encoding::Codec;
+use options::Options;
+use set_op::errors_first;
+use {BTree, KeyType, ReadEntry, ValueType};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+/// How a `ShardedBTree` splits its keys between shards
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Partitioning<K> {
+    Range(Vec<K>), // shard i holds the keys from split i - 1 up to, but not including, split i
+    Hash(usize),   // this many shards, each holding the keys that hash to it
+}
+
+impl<K: KeyType> Partitioning<K> {
+    pub fn shard_count(&self) -> usize {
+        match self {
+            Partitioning::Range(splits) => splits.len() + 1,
+            Partitioning::Hash(shards) => *shards,
+        }
+    }
+
+    /// The shard `key` belongs in
+    pub fn shard_for(&self, key: &K) -> Result<usize, Box<dyn Error>> {
+        match self {
+            Partitioning::Range(splits) => Ok(splits.partition_point(|split| split <= key)),
+            Partitioning::Hash(shards) => {
+                Ok((fnv1a(&Codec::unbounded().serialize(key)?) % *shards as u64) as usize)
+            }
+        }
+    }
+}
+
+/// A tree split across several BTrees, each with its own WAL, memtable and files,
+/// so flushes, syncs and compactions are spread over them. Shard `i` of the tree
+/// at `path` lives at `path.shard<i>`, and the partitioning is kept in
+/// `path.shards`, as opening a tree with a different one would put keys in the
+/// wrong shards.
+pub struct ShardedBTree<K: KeyType, V: ValueType> {
+    partitioning: Partitioning<K>,
+    shards: Vec<BTree<K, V>>,
+}
+
+impl<K: KeyType, V: ValueType> ShardedBTree<K, V> {
+    /// Opens or creates the shards. Unless the options bring a block cache, one is
+    /// made for all the shards to share.
+    pub fn with_options(
+        path: &str,
+        key_size: usize,
+        value_size: usize,
+        partitioning: Partitioning<K>,
+        options: Options,
+    ) -> Result<ShardedBTree<K, V>, Box<dyn Error>> {
+        let shards = open_shards(path, key_size, value_size, &partitioning, options)?;
+
+        Ok(ShardedBTree {
+            partitioning,
+            shards,
+        })
+    }
+
+    pub fn partitioning(&self) -> &Partitioning<K> {
+        &self.partitioning
+    }
+
+    pub fn shards(&self) -> &[BTree<K, V>] {
+        &self.shards
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        let shard = self.partitioning.shard_for(&key)?;
+        self.shards[shard].insert(key, value)
+    }
+
+    /// Inserts every pair, each shard taking its share on its own thread
+    pub fn insert_batch(&mut self, pairs: Vec<(K, V)>) -> Result<(), Box<dyn Error>> {
+        let mut batches: Vec<Vec<(K, V)>> = self.shards.iter().map(|_| Vec::new()).collect();
+
+        for (key, value) in pairs {
+            batches[self.partitioning.shard_for(&key)?].push((key, value));
+        }
+
+        self.on_each_shard(batches, |shard, batch| {
+            for (key, value) in batch {
+                shard.insert(key, value)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn delete(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        let shard = self.partitioning.shard_for(&key)?;
+        self.shards[shard].delete(key, value)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.shards[self.partitioning.shard_for(key)?].get(key)
+    }
+
+    /// Returns the keys within `range` and their values, in key order, merging the
+    /// scans of the shards. Like `BTree::range`, it ends at a record that can't be
+    /// read, in whichever shard.
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = (K, Vec<V>)> + '_, Box<dyn Error>> {
+        Ok(self.try_range(range)?.map_while(Result::ok))
+    }
+
+    /// Like `range`, a record that can't be read coming out as an error, as soon as
+    /// its shard's scan meets it, rather than ending the scan
+    pub fn try_range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = ReadEntry<K, V>> + '_, Box<dyn Error>> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut scans = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            scans.push(shard.try_range((start.clone(), end.clone()))?);
+        }
+
+        Ok(scans
+            .into_iter()
+            .kmerge_by(|a, b| errors_first(a, b) == Ordering::Less))
+    }
+
+    /// Flushes every shard, each on its own thread
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        let units = self.shards.iter().map(|_| ()).collect();
+
+        self.on_each_shard(units, |shard, _| shard.flush())
+    }
+
+    /// Runs `job` on each shard with its input, the shards in parallel
+    fn on_each_shard<T: Send, F>(&mut self, inputs: Vec<T>, job: F) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(&mut BTree<K, V>, T) -> Result<(), Box<dyn Error>> + Sync,
+    {
+        let job = &job;
+
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .shards
+                .iter_mut()
+                .zip(inputs)
+                .map(|(shard, input)| {
+                    scope.spawn(move || job(shard, input).map_err(|e| e.to_string()))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .collect::<Vec<_>>()
+        });
+
+        for result in results {
+            result.map_err(|_| "a shard's thread panicked")??;
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens or creates the shards of the tree at `path`, checking they were created
+/// with `partitioning`. Unless the options bring a block cache, one is made for
+/// all the shards to share.
+fn open_shards<K: KeyType, V: ValueType>(
+    path: &str,
+    key_size: usize,
+    value_size: usize,
+    partitioning: &Partitioning<K>,
+    mut options: Options,
+) -> Result<Vec<BTree<K, V>>, Box<dyn Error>> {
+    let invalid = |message| Err(From::from(IOError::new(ErrorKind::InvalidInput, message)));
+
+    if partitioning.shard_count() == 0 {
+        return invalid("A sharded tree needs at least one shard");
+    }
+
+    if let Partitioning::Range(splits) = partitioning {
+        if splits.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return invalid("The split keys must be in ascending order");
+        }
+    }
+
+    let partitioning_path = path.to_owned() + ".shards";
+
+    if options.storage.exists(&partitioning_path)? {
+        let file = options.storage.open(&partitioning_path)?;
+        let mut bytes = vec![0; file.len()? as usize];
+        file.read_at(&mut bytes, 0)?;
+
+        if bincode::deserialize::<Partitioning<K>>(&bytes)? != *partitioning {
+            return invalid("The tree was created with a different partitioning");
+        }
+    } else {
+        let mut file = options.storage.open(&partitioning_path)?;
+        file.append(&bincode::serialize(partitioning)?)?;
+        file.sync()?;
+    }
+
+    if options.block_cache.is_none() {
+        options.block_cache = Some(Arc::new(BlockCache::new(options.cache_size)));
+    }
+
+    (0..partitioning.shard_count())
+        .map(|i| {
+            BTree::with_options(
+                &format!("{}.shard{}", path, i),
+                key_size,
+                value_size,
+                options.clone(),
+            )
+        })
+        .collect()
+}
+
+/// A tree split by hash across shards that each sit behind their own lock, so
+/// threads sharing it write to different shards fully in parallel, each shard with
+/// its own WAL and memtable. Keys aren't kept in order across shards, so there are
+/// no scans; use a `ShardedBTree` for those.
+pub struct ConcurrentBTree<K: KeyType, V: ValueType> {
+    partitioning: Partitioning<K>,
+    shards: Vec<Mutex<BTree<K, V>>>,
+    loads: Mutex<Loads<K, V>>,
+}
+
+/// The keys `ConcurrentBTree::get_or_load` is loading, for the misses waiting on them
+struct Loads<K, V> {
+    loading: BTreeMap<K, Arc<Load<V>>>,
+    landed: u64, // loads finished, so a miss can tell whether one landed while it looked
+}
+
+/// A load under way in `ConcurrentBTree::get_or_load`, and once it's done, what
+/// it came to. Errors are kept as text, to hand each thread waiting a copy.
+struct Load<V> {
+    result: Mutex<Option<Result<Vec<V>, String>>>,
+    done: Condvar,
+}
+
+impl<V: ValueType> Load<V> {
+    /// Waits for the load to finish, returning what it came to
+    fn wait(&self) -> Result<Vec<V>, Box<dyn Error>> {
+        let mut result = self.result.lock().map_err(|_| POISONED)?;
+
+        while result.is_none() {
+            result = self.done.wait(result).map_err(|_| POISONED)?;
+        }
+
+        result.clone().unwrap().map_err(From::from)
+    }
+}
+
+/// Finishes a load when dropped, so the threads waiting on it are woken even if
+/// the loader panics
+struct Landing<'a, K: KeyType + 'a, V: ValueType + 'a> {
+    tree: &'a ConcurrentBTree<K, V>,
+    key: K,
+    load: Arc<Load<V>>,
+    result: Result<Vec<V>, String>,
+}
+
+impl<'a, K: KeyType, V: ValueType> Drop for Landing<'a, K, V> {
+    fn drop(&mut self) {
+        if let Ok(mut loads) = self.tree.loads.lock() {
+            loads.loading.remove(&self.key);
+            loads.landed += 1;
+        }
+
+        let result = std::mem::replace(&mut self.result, Err("The loader panicked".to_owned()));
+
+        if let Ok(mut slot) = self.load.result.lock() {
+            *slot = Some(result);
+        }
+
+        self.load.done.notify_all();
+    }
+}
+
+impl<K: KeyType, V: ValueType> ConcurrentBTree<K, V> {
+    /// Opens or creates a tree of `shards` shards, laid out as a `ShardedBTree`
+    /// with `Partitioning::Hash(shards)` would be
+    pub fn with_options(
+        path: &str,
+        key_size: usize,
+        value_size: usize,
+        shards: usize,
+        options: Options,
+    ) -> Result<ConcurrentBTree<K, V>, Box<dyn Error>> {
+        let partitioning = Partitioning::Hash(shards);
+        let shards = open_shards(path, key_size, value_size, &partitioning, options)?;
+
+        Ok(ConcurrentBTree {
+            partitioning,
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            loads: Mutex::new(Loads {
+                loading: BTreeMap::new(),
+                landed: 0,
+            }),
+        })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.shard(&key)?.insert(key, value)
+    }
+
+    pub fn delete(&self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.shard(&key)?.delete(key, value)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.shard(key)?.get(key)
+    }
+
+    /// Like `BTree::get_or_load`, but `loader` runs without the shard locked, so
+    /// other keys are read and written meanwhile, and threads that miss on a key
+    /// already being loaded wait for that load rather than starting their own. They
+    /// all get what it returns, or its error.
+    pub fn get_or_load<F>(&self, key: &K, loader: F) -> Result<Vec<V>, Box<dyn Error>>
+    where
+        F: FnOnce(&K) -> Result<V, Box<dyn Error>>,
+    {
+        let load = loop {
+            let landed = {
+                let loads = self.loads.lock().map_err(|_| POISONED)?;
+
+                match loads.loading.get(key).cloned() {
+                    Some(load) => {
+                        drop(loads);
+                        return load.wait();
+                    }
+                    None => loads.landed,
+                }
+            };
+
+            // looked up without the loads locked, so misses on other keys go on meanwhile
+            if let Some(values) = self.get(key)? {
+                return Ok(values);
+            }
+
+            let mut loads = self.loads.lock().map_err(|_| POISONED)?;
+
+            // a load that started or landed since could have been of this key
+            if loads.landed != landed || loads.loading.contains_key(key) {
+                continue;
+            }
+
+            let load = Arc::new(Load {
+                result: Mutex::new(None),
+                done: Condvar::new(),
+            });
+
+            loads.loading.insert(key.clone(), load.clone());
+            break load;
+        };
+
+        let mut landing = Landing {
+            tree: self,
+            key: key.clone(),
+            load,
+            result: Err("The loader panicked".to_owned()),
+        };
+
+        let loaded = loader(key).and_then(|value| {
+            self.insert(key.clone(), value.clone())?;
+            Ok(vec![value])
+        });
+
+        landing.result = loaded.as_ref().map(Vec::clone).map_err(|e| e.to_string());
+
+        loaded
+    }
+
+    /// Flushes every shard, each on its own thread
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .shards
+                .iter()
+                .map(|shard| {
+                    scope.spawn(move || match shard.lock() {
+                        Ok(mut shard) => shard.flush().map_err(|e| e.to_string()),
+                        Err(_) => Err(POISONED.to_owned()),
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .collect::<Vec<_>>()
+        });
+
+        for result in results {
+            result.map_err(|_| "a shard's thread panicked")??;
+        }
+
+        Ok(())
+    }
+
+    /// Locks the shard `key` belongs in
+    fn shard(&self, key: &K) -> Result<MutexGuard<'_, BTree<K, V>>, Box<dyn Error>> {
+        self.shards[self.partitioning.shard_for(key)?]
+            .lock()
+            .map_err(|_| From::from(POISONED))
+    }
+}
+
+const POISONED: &str = "A thread panicked while writing to the shard";
+
+#[cfg(test)]
+mod tests {
+    use sharded::{ConcurrentBTree, Partitioning, ShardedBTree};
+    use {Options, SimDisk};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn keys_are_spread_over_the_shards_and_scanned_in_order() {
+        let disk = SimDisk::new(0);
+        let options = Options {
+            storage: Arc::new(disk.clone()),
+            flush_threshold: 50,
+            ..Options::default()
+        };
+
+        let partitionings = [
+            ("by_range", Partitioning::Range(vec![100, 200])),
+            ("by_hash", Partitioning::Hash(3)),
+        ];
+
+        for (path, partitioning) in partitionings {
+            {
+                let mut btree = ShardedBTree::<u32, u32>::with_options(
+                    path,
+                    4,
+                    4,
+                    partitioning.clone(),
+                    options.clone(),
+                )
+                .unwrap();
+
+                btree
+                    .insert_batch((0..300).map(|i| (i, i)).collect())
+                    .unwrap();
+                btree.delete(150, 150).unwrap();
+                btree.flush().unwrap();
+
+                assert_eq!(btree.shards().len(), 3);
+                assert!(btree
+                    .shards()
+                    .iter()
+                    .all(|shard| shard.range(..).unwrap().count() > 50));
+            }
+
+            let btree =
+                ShardedBTree::<u32, u32>::with_options(path, 4, 4, partitioning, options.clone())
+                    .unwrap();
+
+            assert_eq!(btree.get(&250).unwrap(), Some(vec![250]));
+            assert_eq!(btree.get(&150).unwrap(), None);
+
+            let keys: Vec<u32> = btree.range(95..205).unwrap().map(|(key, _)| key).collect();
+            assert_eq!(
+                keys,
+                (95..205).filter(|key| *key != 150).collect::<Vec<_>>()
+            );
+
+            // a shard that can't be read fails the scan rather than leaving its keys out
+            disk.fail_read(&format!("{}.shard1", path), 0);
+            assert!(btree
+                .try_range(..)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .is_err());
+
+            // a tree can't be opened with a different partitioning
+            assert!(ShardedBTree::<u32, u32>::with_options(
+                path,
+                4,
+                4,
+                Partitioning::Hash(2),
+                options.clone()
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn threads_write_to_the_shards_in_parallel() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            flush_threshold: 100,
+            ..Options::default()
+        };
+        let btree =
+            ConcurrentBTree::<u32, u32>::with_options("db", 4, 4, 4, options.clone()).unwrap();
+
+        thread::scope(|scope| {
+            for writer in 0..4 {
+                let btree = &btree;
+                scope.spawn(move || {
+                    for i in 0..250 {
+                        btree.insert(writer * 1000 + i, i).unwrap();
+                    }
+                });
+            }
+        });
+
+        btree.delete(3007, 7).unwrap();
+        btree.flush().unwrap();
+        drop(btree);
+
+        let btree = ConcurrentBTree::<u32, u32>::with_options("db", 4, 4, 4, options).unwrap();
+
+        assert_eq!(btree.shard_count(), 4);
+        assert_eq!(btree.get(&2249).unwrap(), Some(vec![249]));
+        assert_eq!(btree.get(&3007).unwrap(), None);
+        assert_eq!(
+            (0..4000)
+                .filter(|key| btree.get(key).unwrap().is_some())
+                .count(),
+            999
+        );
+    }
+
+    #[test]
+    fn concurrent_misses_on_a_key_load_it_once() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let btree =
+            ConcurrentBTree::<u32, u32>::with_options("db", 4, 4, 2, options.clone()).unwrap();
+        let loads = AtomicUsize::new(0);
+        let barrier = Barrier::new(8);
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+
+                    let values = btree
+                        .get_or_load(&7, |key| {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(50));
+                            Ok(key * 100)
+                        })
+                        .unwrap();
+                    assert_eq!(values, [700]);
+                });
+            }
+        });
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // a failed load inserts nothing, and a stored key isn't loaded again
+        assert!(btree
+            .get_or_load(&8, |_| Err(From::from("no such row")))
+            .is_err());
+        assert_eq!(btree.get(&8).unwrap(), None);
+        assert_eq!(
+            btree.get_or_load(&7, |_| panic!("loaded twice")).unwrap(),
+            [700]
+        );
+        drop(btree);
+
+        // what was loaded went through the WAL
+        let btree = ConcurrentBTree::<u32, u32>::with_options("db", 4, 4, 2, options).unwrap();
+        assert_eq!(btree.get(&7).unwrap(), Some(vec![700]));
+    }
+}
@@ -0,0 +1,282 @@
+use storage::{Storage, StorageFile};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::io::Error as IOError;
+use std::io::{ErrorKind, Result as IOResult};
+use std::sync::{Arc, Mutex};
+
+/// An in-memory disk for deterministic crash-consistency testing.
+///
+/// Writes are only durable once the file they were made to is synced. Calling
+/// `crash()` simulates a power loss: every write made since the last sync is
+/// either kept or lost, and the survivors are applied in a shuffled order, all
+/// decided by the seed the disk was created with. Metadata operations (create,
+/// rename, remove) are treated as durable immediately. `fail_read()` injects a
+/// read fault into a file.
+///
+/// Cloning a `SimDisk` gives another handle to the same disk, so a test can
+/// keep one to call `crash()` on while the BTree owns the other.
+#[derive(Clone)]
+pub struct SimDisk {
+    state: Arc<Mutex<SimState>>,
+}
+
+struct SimState {
+    files: BTreeMap<String, Arc<Mutex<SimFile>>>, // ordered so crashes are deterministic
+    rng: StdRng,
+}
+
+#[derive(Default)]
+struct SimFile {
+    durable: Vec<u8>,            // what survives a crash
+    current: Vec<u8>,            // what readers see
+    pending: Vec<PendingOp>,     // writes since the last sync
+    failing_read: Option<usize>, // the number of reads left before one fails
+}
+
+#[derive(Clone)]
+enum PendingOp {
+    Write { offset: usize, data: Vec<u8> },
+    SetLen(usize),
+}
+
+struct SimFileHandle {
+    file: Arc<Mutex<SimFile>>,
+}
+
+impl SimDisk {
+    pub fn new(seed: u64) -> SimDisk {
+        SimDisk {
+            state: Arc::new(Mutex::new(SimState {
+                files: BTreeMap::new(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Simulates a power loss. Any BTree using this disk should be dropped first.
+    pub fn crash(&self) {
+        let mut state = self.state.lock().unwrap();
+        let SimState { files, rng } = &mut *state;
+
+        for file in files.values() {
+            let mut file = file.lock().unwrap();
+
+            let mut survivors: Vec<PendingOp> = file
+                .pending
+                .drain(..)
+                .filter(|_| rng.gen_bool(0.5))
+                .collect();
+            survivors.shuffle(rng);
+
+            let mut contents = file.durable.clone();
+            for op in survivors {
+                op.apply(&mut contents);
+            }
+
+            file.durable = contents.clone();
+            file.current = contents;
+        }
+    }
+
+    /// Makes a read of the file at `path` fail, once `reads` more reads of it have
+    /// succeeded. Reads after the failed one succeed again.
+    pub fn fail_read(&self, path: &str, reads: usize) {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.entry(path.to_owned()).or_default();
+
+        file.lock().unwrap().failing_read = Some(reads);
+    }
+
+    /// Returns the bytes a reader would currently see at `path`
+    pub fn contents(&self, path: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .files
+            .get(path)
+            .map(|file| file.lock().unwrap().current.clone())
+    }
+}
+
+impl PendingOp {
+    fn apply(&self, contents: &mut Vec<u8>) {
+        match self {
+            PendingOp::Write { offset, data } => {
+                let end = offset + data.len();
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[*offset..end].copy_from_slice(data);
+            }
+            PendingOp::SetLen(len) => contents.resize(*len, 0),
+        }
+    }
+}
+
+impl Storage for SimDisk {
+    fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>> {
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.entry(path.to_owned()).or_default().clone();
+
+        Ok(Box::new(SimFileHandle { file }))
+    }
+
+    fn exists(&self, path: &str) -> IOResult<bool> {
+        Ok(self.state.lock().unwrap().files.contains_key(path))
+    }
+
+    fn remove(&self, path: &str) -> IOResult<()> {
+        match self.state.lock().unwrap().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(IOError::new(ErrorKind::NotFound, path.to_owned())),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> IOResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.files.remove(from) {
+            Some(file) => {
+                state.files.insert(to.to_owned(), file);
+                Ok(())
+            }
+            None => Err(IOError::new(ErrorKind::NotFound, from.to_owned())),
+        }
+    }
+}
+
+impl SimFile {
+    fn push(&mut self, op: PendingOp) {
+        op.apply(&mut self.current);
+        self.pending.push(op);
+    }
+}
+
+impl StorageFile for SimFileHandle {
+    fn len(&self) -> IOResult<u64> {
+        Ok(self.file.lock().unwrap().current.len() as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()> {
+        let mut file = self.file.lock().unwrap();
+        let start = offset as usize;
+
+        match file.failing_read {
+            Some(0) => {
+                file.failing_read = None;
+                return Err(IOError::other("injected read fault"));
+            }
+            Some(reads) => file.failing_read = Some(reads - 1),
+            None => {}
+        }
+        let end = start + buf.len();
+
+        if end > file.current.len() {
+            return Err(IOError::new(
+                ErrorKind::UnexpectedEof,
+                "read past the end of the file",
+            ));
+        }
+
+        buf.copy_from_slice(&file.current[start..end]);
+        Ok(())
+    }
+
+    fn append(&mut self, buf: &[u8]) -> IOResult<()> {
+        let mut file = self.file.lock().unwrap();
+        let offset = file.current.len();
+
+        file.push(PendingOp::Write {
+            offset,
+            data: buf.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> IOResult<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .push(PendingOp::SetLen(len as usize));
+        Ok(())
+    }
+
+    fn sync(&mut self) -> IOResult<()> {
+        let mut file = self.file.lock().unwrap();
+
+        file.durable = file.current.clone();
+        file.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sim_disk::SimDisk;
+    use storage::Storage;
+
+    #[test]
+    fn synced_writes_survive_a_crash() {
+        let disk = SimDisk::new(7);
+
+        {
+            let mut file = disk.open("a").unwrap();
+            file.append(b"hello").unwrap();
+            file.sync().unwrap();
+        }
+
+        disk.crash();
+
+        assert_eq!(disk.contents("a").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn unsynced_writes_may_be_lost() {
+        let disk = SimDisk::new(7);
+
+        {
+            let mut file = disk.open("a").unwrap();
+            for _ in 0..64 {
+                file.append(b"x").unwrap();
+            }
+            assert_eq!(file.len().unwrap(), 64);
+        }
+
+        disk.crash();
+
+        // with 64 independent coin flips at least one write goes missing
+        let contents = disk.contents("a").unwrap();
+        assert!(contents.iter().filter(|b| **b == b'x').count() < 64);
+    }
+
+    #[test]
+    fn crashes_are_deterministic() {
+        let run = |seed| {
+            let disk = SimDisk::new(seed);
+            let mut file = disk.open("a").unwrap();
+            for i in 0..32u8 {
+                file.append(&[i]).unwrap();
+            }
+            disk.crash();
+            disk.contents("a").unwrap()
+        };
+
+        assert_eq!(run(42), run(42));
+    }
+
+    #[test]
+    fn rename_replaces_target() {
+        let disk = SimDisk::new(0);
+
+        disk.open("old").unwrap().append(b"old").unwrap();
+        disk.open("new").unwrap().append(b"new").unwrap();
+
+        disk.rename("new", "old").unwrap();
+
+        assert!(!disk.exists("new").unwrap());
+        assert_eq!(disk.contents("old").unwrap(), b"new");
+    }
+}
@@ -0,0 +1,237 @@
+use io_stats::IoStats;
+use progress::CompactionProgress;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+/// Durations under this many nanoseconds get a bucket each; above it every power
+/// of two is split into this many buckets
+const SUB_BUCKETS: u64 = 8;
+
+/// Enough buckets for any number of nanoseconds a u64 holds
+const BUCKETS: usize = (SUB_BUCKETS + (64 - 3) * SUB_BUCKETS) as usize;
+
+/// Counters of what a BTree has done since it was opened, see `BTree::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub writes: u64,                          // records written, puts and deletes alike
+    pub wal_bytes: u64,                       // bytes appended to the WAL
+    pub flushes: u64,                         // memtables written out, to a run or the tree file
+    pub compactions: u64,                     // merges into the tree file
+    pub compaction_time: Duration,            // spent in those merges
+    pub stall_time: Duration,                 // writes were delayed by the write throttle
+    pub stalled_writes: u64,                  // writes refused at the throttle's hard limit
+    pub spills: u64, // memtables spilled to overflow runs at the hard limit instead
+    pub evictions: u64, // keys deleted to stay within `Options::lru_capacity`
+    pub cache_hits: u64, // block lookups the cache answered, across every tree sharing it
+    pub cache_misses: u64, // and the ones it didn't
+    pub pending_bytes: usize, // in the memtable waiting to be flushed, right now
+    pub cache_bytes: usize, // held by the block cache, right now
+    pub get_latency: LatencyHistogram, // of lookups of a key
+    pub insert_latency: LatencyHistogram, // of writes, puts and deletes alike, with any flush they set off
+    pub flush_latency: LatencyHistogram,  // of memtables being written out
+    pub compaction_latency: LatencyHistogram, // of merges into the tree file
+    pub compaction_progress: Option<CompactionProgress>, // of the one running, see `BTree::compaction_monitor`
+}
+
+impl Stats {
+    /// The share of block lookups the cache answered, or None before the first
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        match self.cache_hits + self.cache_misses {
+            0 => None,
+            lookups => Some(self.cache_hits as f64 / lookups as f64),
+        }
+    }
+}
+
+/// What kind of operation a `SlowOp` was
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowOpKind {
+    Get,
+    Write, // a put or delete, or a batch of them, with any flush it set off
+    Flush,
+    Compaction,
+}
+
+/// An operation that took longer than `Options::slow_op_threshold`, as reported
+/// to the slow operation hooks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlowOp {
+    pub kind: SlowOpKind,
+    pub took: Duration,
+    pub key_size: Option<usize>, // bytes in the key looked up or written, the largest of a batch
+    pub records: u64,            // written, flushed or compacted
+    pub io: IoStats,             // read on the thread running it, sub-compactions included
+}
+
+/// How long operations took, in HDR-style buckets: a bucket per nanosecond up to
+/// 8ns, then 8 buckets for each power of two, so every bucket is within an eighth
+/// of the durations it holds. That keeps the tail as precise as the median in a
+/// fixed few KiB, where an average would hide the odd lookup stuck behind a
+/// compaction.
+#[derive(Clone, Copy, PartialEq)]
+pub struct LatencyHistogram {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram {
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            buckets: [0; BUCKETS],
+        }
+    }
+}
+
+/// The bucket durations of `nanos` go in
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+
+    let power = 63 - nanos.leading_zeros() as u64;
+    let sub = (nanos >> (power - 3)) & (SUB_BUCKETS - 1);
+
+    (SUB_BUCKETS + (power - 3) * SUB_BUCKETS + sub) as usize
+}
+
+/// The longest duration in `bucket`, in nanoseconds
+fn bucket_max(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+
+    let (power, sub) = (
+        (bucket - SUB_BUCKETS) / SUB_BUCKETS + 3,
+        (bucket - SUB_BUCKETS) % SUB_BUCKETS,
+    );
+    let width = 1 << (power - 3);
+
+    (SUB_BUCKETS + sub) * width + (width - 1)
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+        self.buckets[bucket(nanos)] += 1;
+    }
+
+    /// Adds the operations recorded in `other`, say by another thread
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        self.count += other.count;
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+    }
+
+    /// The number of operations recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The time taken by all of them together
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(Duration::from_nanos(
+                (self.total.as_nanos() / u128::from(count)) as u64,
+            )),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The duration `percent` percent of operations took no longer than, rounded
+    /// up to the top of its bucket, or None before the first
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((percent / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return Some(Duration::from_nanos(bucket_max(bucket)).min(self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+}
+
+// thousands of buckets would swamp the stats they're printed with
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count)
+            .field("p50", &self.percentile(50.0))
+            .field("p99", &self.percentile(99.0))
+            .field("max", &self.max)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stats::{bucket, bucket_max, LatencyHistogram, BUCKETS};
+
+    use std::time::Duration;
+
+    #[test]
+    fn percentiles_come_from_the_buckets() {
+        // every duration is in the bucket it maps to
+        for nanos in (0..10_000).chain([u64::MAX / 3, u64::MAX]) {
+            let at = bucket(nanos);
+
+            assert!(at < BUCKETS && nanos <= bucket_max(at));
+            assert!(at == 0 || nanos > bucket_max(at - 1));
+        }
+
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(1));
+
+        let p50 = histogram.percentile(50.0).unwrap();
+        assert!(p50 >= Duration::from_micros(500) && p50 <= Duration::from_micros(500) * 9 / 8);
+        assert!(histogram.percentile(99.0).unwrap() < Duration::from_millis(2));
+        assert_eq!(histogram.percentile(100.0), Some(Duration::from_secs(1)));
+        assert_eq!(histogram.count(), 1001);
+
+        // merging adds the other's operations to the same buckets
+        let mut merged = LatencyHistogram::default();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.count(), 2002);
+        assert_eq!(merged.percentile(50.0), Some(p50));
+        assert_eq!(merged.max(), Duration::from_secs(1));
+    }
+}
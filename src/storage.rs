@@ -0,0 +1,106 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result as IOResult, Write};
+use std::os::unix::fs::FileExt;
+
+/// Where the WAL and tree files live. Everything the BTree reads or writes goes
+/// through this trait, so it can be backed by something other than real files
+/// (see `SimDisk`).
+pub trait Storage: Send + Sync {
+    /// Opens the file at `path`, creating an empty one if it doesn't exist
+    fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>>;
+
+    fn exists(&self, path: &str) -> IOResult<bool>;
+
+    fn remove(&self, path: &str) -> IOResult<()>;
+
+    fn remove_if_exists(&self, path: &str) -> IOResult<()> {
+        if self.exists(path)? {
+            self.remove(path)?;
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces whatever is at `to` with the file at `from`
+    fn rename(&self, from: &str, to: &str) -> IOResult<()>;
+
+    /// Says the file at `path` is finished and will only be read from now on, so
+    /// it can be moved somewhere cheaper (see `ObjectStorage`). `level` is how old
+    /// its data is: 0 for an L0 run, 1 for the tree file compaction installs.
+    fn seal(&self, _path: &str, _level: u32) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+/// A handle to a single file. Files are only ever appended to or truncated,
+/// and reads are positional so a handle can be shared for reading.
+pub trait StorageFile: Send + Sync {
+    fn len(&self) -> IOResult<u64>;
+
+    fn is_empty(&self) -> IOResult<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Fills `buf` with the bytes starting at `offset`
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()>;
+
+    fn append(&mut self, buf: &[u8]) -> IOResult<()>;
+
+    fn truncate(&mut self, len: u64) -> IOResult<()>;
+
+    /// Makes everything written so far durable
+    fn sync(&mut self) -> IOResult<()>;
+}
+
+/// The default storage: plain files on the local file system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileStorage;
+
+struct LocalFile {
+    fd: File,
+}
+
+impl Storage for FileStorage {
+    fn open(&self, path: &str) -> IOResult<Box<dyn StorageFile>> {
+        let fd = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        Ok(Box::new(LocalFile { fd }))
+    }
+
+    fn exists(&self, path: &str) -> IOResult<bool> {
+        fs::exists(path)
+    }
+
+    fn remove(&self, path: &str) -> IOResult<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> IOResult<()> {
+        fs::rename(from, to)
+    }
+}
+
+impl StorageFile for LocalFile {
+    fn len(&self) -> IOResult<u64> {
+        Ok(self.fd.metadata()?.len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> IOResult<()> {
+        self.fd.read_exact_at(buf, offset)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> IOResult<()> {
+        self.fd.write_all(buf)
+    }
+
+    fn truncate(&mut self, len: u64) -> IOResult<()> {
+        self.fd.set_len(len)
+    }
+
+    fn sync(&mut self) -> IOResult<()> {
+        self.fd.sync_all()
+    }
+}
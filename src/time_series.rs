@@ -0,0 +1,129 @@
+use audit_log::AuditOp;
+use wal_file::KeyValuePair;
+use {BTree, CompositeKey, Options, ValueType};
+
+use std::error::Error;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+// a series id and a timestamp, and the length they're stored with
+const KEY_SIZE: usize = 8 + 8 + 8;
+
+/// A tree of time series: values keyed by a series id and a timestamp, in
+/// milliseconds since the epoch, stored as a `CompositeKey` so each series' points
+/// sit together on disk in time order. With a retention period, each point expires
+/// that long after its timestamp and is dropped by the next compaction.
+pub struct TimeSeriesBTree<V: ValueType> {
+    tree: BTree<CompositeKey, V>,
+    retention: Option<Duration>,
+}
+
+impl<V: ValueType> TimeSeriesBTree<V> {
+    pub fn with_options(
+        tree_file_path: &str,
+        value_size: usize,
+        retention: Option<Duration>,
+        options: Options,
+    ) -> Result<TimeSeriesBTree<V>, Box<dyn Error>> {
+        Ok(TimeSeriesBTree {
+            tree: BTree::with_options(tree_file_path, KEY_SIZE, value_size, options)?,
+            retention,
+        })
+    }
+
+    /// Adds a point to `series`. A point older than the retention period is
+    /// written already expired.
+    pub fn append(&mut self, series: u64, timestamp: u64, value: V) -> Result<(), Box<dyn Error>> {
+        let record = KeyValuePair {
+            expires_at: self
+                .retention
+                .map(|retention| timestamp.saturating_add(retention.as_millis() as u64)),
+            ..KeyValuePair::new(point_key(series, timestamp), value)
+        };
+
+        self.tree.insert_record(record, AuditOp::Insert, "")
+    }
+
+    /// Returns the points of `series` in `time_range`, oldest first, with the
+    /// values at each timestamp
+    pub fn query<R: RangeBounds<u64>>(
+        &self,
+        series: u64,
+        time_range: R,
+    ) -> Result<impl Iterator<Item = (u64, Vec<V>)> + '_, Box<dyn Error>> {
+        let start = match time_range.start_bound() {
+            Included(&at) => Included(point_key(series, at)),
+            Excluded(&at) => Excluded(point_key(series, at)),
+            Unbounded => Included(point_key(series, 0)),
+        };
+        let end = match time_range.end_bound() {
+            Included(&at) => Included(point_key(series, at)),
+            Excluded(&at) => Excluded(point_key(series, at)),
+            Unbounded => Included(point_key(series, u64::MAX)),
+        };
+
+        Ok(self
+            .tree
+            .range::<(Bound<CompositeKey>, Bound<CompositeKey>)>((start, end))?
+            .filter_map(|(key, values)| {
+                let mut components = key.components();
+                components.next_u64().ok()?;
+
+                Some((components.next_u64().ok()?, values))
+            }))
+    }
+
+    pub fn tree(&self) -> &BTree<CompositeKey, V> {
+        &self.tree
+    }
+
+    /// The tree underneath, for flushing, compacting and the like. Points written
+    /// to it directly don't get the retention period.
+    pub fn tree_mut(&mut self) -> &mut BTree<CompositeKey, V> {
+        &mut self.tree
+    }
+}
+
+fn point_key(series: u64, timestamp: u64) -> CompositeKey {
+    CompositeKey::new().push_u64(series).push_u64(timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use {ManualClock, Options, SimDisk, TimeSeriesBTree};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn points_are_queried_by_series_and_time() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            clock: clock.clone(),
+            ..Options::default()
+        };
+        let retention = Some(Duration::from_millis(500));
+        let mut series = TimeSeriesBTree::<u32>::with_options("db", 4, retention, options).unwrap();
+
+        for at in [900, 1_000, 1_100, 1_200] {
+            series.append(1, at, at as u32).unwrap();
+            series.append(2, at, 0).unwrap();
+        }
+        series.append(1, 1_100, 1).unwrap();
+
+        let points: Vec<(u64, Vec<u32>)> = series.query(1, 1_000..1_200).unwrap().collect();
+        assert_eq!(points, [(1_000, vec![1_000]), (1_100, vec![1, 1_100])]);
+        assert_eq!(series.query(2, ..).unwrap().count(), 4);
+
+        // each point expires the retention period after its timestamp
+        clock.advance(Duration::from_millis(550));
+        let times: Vec<u64> = series.query(1, ..).unwrap().map(|(at, _)| at).collect();
+        assert_eq!(times, [1_100, 1_200]);
+
+        series.tree_mut().compact_range(..).unwrap();
+        assert_eq!(series.tree().stats().writes, 9);
+        assert_eq!(series.query(2, ..=1_100).unwrap().count(), 1);
+    }
+}
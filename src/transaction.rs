@@ -0,0 +1,392 @@
+use error::BTreeError;
+use {BTree, KeyType, ValueType, WriteBatch};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A tree shared between threads that make their writes in transactions. Each
+/// transaction takes a lock on every key it reads for update or writes, held until
+/// it commits or is dropped, so transactions touching the same keys run one after
+/// the other rather than retrying. Optimistic transactions take no locks, and
+/// instead fail to commit if another write got to their keys first.
+pub struct TransactionalBTree<K: KeyType + Hash, V: ValueType> {
+    tree: Mutex<BTree<K, V>>,
+    locks: Mutex<HashMap<K, u64>>, // the transaction holding each locked key
+    unlocked: Condvar,
+    lock_timeout: Duration, // how long to wait for a lock before taking it for a deadlock
+    next_id: AtomicU64,
+    open: Arc<OpenTransactions>, // shared with the tree, for its compactions to see
+}
+
+/// When the optimistic transactions still open started, each as the last seq then
+/// and how many started at it. Compaction keeps the newest write of every key made
+/// since the oldest of them, deletions included, so its commit can't miss one a
+/// compaction would otherwise have dropped.
+#[derive(Default)]
+pub struct OpenTransactions {
+    started_at: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl OpenTransactions {
+    /// The seq the oldest open transaction started at
+    pub fn oldest(&self) -> Option<u64> {
+        let started_at = self.started_at.lock().ok()?;
+        started_at.keys().next().copied()
+    }
+
+    fn open(&self, seq: u64) -> Result<(), Box<dyn Error>> {
+        *self
+            .started_at
+            .lock()
+            .map_err(|_| POISONED)?
+            .entry(seq)
+            .or_default() += 1;
+
+        Ok(())
+    }
+
+    fn close(&self, seq: u64) {
+        if let Ok(mut started_at) = self.started_at.lock() {
+            if let Some(count) = started_at.get_mut(&seq) {
+                *count -= 1;
+
+                if *count == 0 {
+                    started_at.remove(&seq);
+                }
+            }
+        }
+    }
+}
+
+impl<K: KeyType + Hash, V: ValueType> TransactionalBTree<K, V> {
+    pub fn new(mut tree: BTree<K, V>, lock_timeout: Duration) -> TransactionalBTree<K, V> {
+        let open = Arc::new(OpenTransactions::default());
+        tree.open_transactions = Some(open.clone());
+
+        TransactionalBTree {
+            tree: Mutex::new(tree),
+            locks: Mutex::new(HashMap::new()),
+            unlocked: Condvar::new(),
+            lock_timeout,
+            next_id: AtomicU64::new(1),
+            open,
+        }
+    }
+
+    pub fn begin(&self) -> Transaction<'_, K, V> {
+        Transaction {
+            db: self,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            batch: WriteBatch::new(),
+            locked: Vec::new(),
+            started_at: None,
+        }
+    }
+
+    /// Starts a transaction that takes no locks. When it commits, the keys it
+    /// writes are checked for writes made since it started, by any handle, and if
+    /// there are any it fails with a `Conflict` rather than interleaving with them.
+    pub fn begin_optimistic(&self) -> Result<Transaction<'_, K, V>, Box<dyn Error>> {
+        // registered with the tree locked, so no compaction runs in between
+        let tree = self.tree()?;
+        let started_at = tree.last_seq;
+        self.open.open(started_at)?;
+        drop(tree);
+
+        Ok(Transaction {
+            db: self,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            batch: WriteBatch::new(),
+            locked: Vec::new(),
+            started_at: Some(started_at),
+        })
+    }
+
+    /// The tree itself, for reads and writes outside of transactions. Writes made
+    /// through it don't take any locks.
+    pub fn tree(&self) -> Result<MutexGuard<'_, BTree<K, V>>, Box<dyn Error>> {
+        self.tree.lock().map_err(|_| From::from(POISONED))
+    }
+
+    /// Waits for `key` to be free and locks it for transaction `id`. Returns whether
+    /// it was newly locked, rather than already held by the transaction.
+    fn lock(&self, key: &K, id: u64) -> Result<bool, Box<dyn Error>> {
+        let deadline = Instant::now() + self.lock_timeout;
+        let mut locks = self.locks.lock().map_err(|_| POISONED)?;
+
+        loop {
+            match locks.get(key) {
+                Some(&holder) if holder == id => return Ok(false),
+                Some(_) => (),
+                None => {
+                    locks.insert(key.clone(), id);
+                    return Ok(true);
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Err(Box::new(BTreeError::LockTimeout));
+            }
+
+            locks = self
+                .unlocked
+                .wait_timeout(locks, deadline - now)
+                .map_err(|_| POISONED)?
+                .0;
+        }
+    }
+
+    fn unlock(&self, keys: &[K]) {
+        if let Ok(mut locks) = self.locks.lock() {
+            for key in keys {
+                locks.remove(key);
+            }
+        }
+
+        self.unlocked.notify_all();
+    }
+}
+
+/// Writes collected to be committed together, see `TransactionalBTree::begin`.
+/// Dropping a transaction without committing it rolls it back.
+pub struct Transaction<'a, K: KeyType + Hash, V: ValueType> {
+    db: &'a TransactionalBTree<K, V>,
+    id: u64,
+    batch: WriteBatch<K, V>,
+    locked: Vec<K>,          // the keys this transaction holds the locks of
+    started_at: Option<u64>, // the last seq when an optimistic transaction started
+}
+
+impl<'a, K: KeyType + Hash, V: ValueType> Transaction<'a, K, V> {
+    /// The values of `key` as committed, without taking its lock. The transaction's
+    /// own writes aren't included.
+    pub fn get(&self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.db.tree()?.get(key)
+    }
+
+    /// Locks `key`, waiting for any other transaction holding it to finish, then
+    /// reads its values. No other transaction can write the key until this one is
+    /// done. Fails with `BTreeError::LockTimeout` if the lock can't be had within
+    /// the timeout, as it would in a deadlock. An optimistic transaction just reads.
+    pub fn get_for_update(&mut self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        self.lock(key)?;
+        self.get(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.lock(&key)?;
+        self.batch.insert(key, value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        self.lock(&key)?;
+        self.batch.delete(key, value);
+        Ok(())
+    }
+
+    /// Writes everything in the transaction as one batch, then releases its locks.
+    /// An optimistic transaction fails with a `Conflict`, writing nothing, if any of
+    /// its keys has been written since it started.
+    pub fn commit(mut self) -> Result<(), Box<dyn Error>>
+    where
+        K: 'static,
+    {
+        let batch = std::mem::take(&mut self.batch);
+        let mut tree = self.db.tree()?;
+
+        if let Some(started_at) = self.started_at {
+            let mut keys = BTreeSet::new();
+
+            for kv in batch.records() {
+                if tree
+                    .last_write_to(&kv.key)?
+                    .is_some_and(|seq| seq > started_at)
+                {
+                    keys.insert(kv.key.clone());
+                }
+            }
+
+            if !keys.is_empty() {
+                return Err(Box::new(Conflict {
+                    keys: keys.into_iter().collect(),
+                }));
+            }
+        }
+
+        tree.write(batch)
+    }
+
+    /// Throws the transaction's writes away and releases its locks
+    pub fn rollback(self) {}
+
+    fn lock(&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        if self.started_at.is_none() && self.db.lock(key, self.id)? {
+            self.locked.push(key.clone());
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, K: KeyType + Hash, V: ValueType> Drop for Transaction<'a, K, V> {
+    fn drop(&mut self) {
+        self.db.unlock(&self.locked);
+
+        if let Some(started_at) = self.started_at {
+            self.db.open.close(started_at);
+        }
+    }
+}
+
+/// An optimistic transaction's keys that were written by someone else while it ran
+pub struct Conflict<K> {
+    pub keys: Vec<K>, // in key order
+}
+
+impl<K> fmt::Display for Conflict<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Conflict: {} keys were written since the transaction started",
+            self.keys.len()
+        )
+    }
+}
+
+// keys needn't be Debug, so only how many there are is shown
+impl<K> fmt::Debug for Conflict<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Conflict {{ {} keys }}", self.keys.len())
+    }
+}
+
+impl<K> Error for Conflict<K> {}
+
+const POISONED: &str = "A thread panicked while using the tree";
+
+#[cfg(test)]
+mod tests {
+    use error::BTreeError;
+    use {BTree, Conflict, Options, SimDisk, TransactionalBTree};
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn locked_increments_are_never_lost() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = BTree::<u32, u64>::with_options("db", 4, 8, options).unwrap();
+        let db = TransactionalBTree::new(tree, Duration::from_secs(10));
+        db.tree().unwrap().insert(0, 0).unwrap();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        let mut txn = db.begin();
+                        let count = txn.get_for_update(&0).unwrap().unwrap()[0];
+                        txn.delete(0, count).unwrap();
+                        txn.insert(0, count + 1).unwrap();
+                        txn.commit().unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(db.tree().unwrap().get(&0).unwrap(), Some(vec![100]));
+    }
+
+    #[test]
+    fn waiting_too_long_for_a_lock_fails() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let db = TransactionalBTree::new(tree, Duration::from_millis(20));
+
+        let mut first = db.begin();
+        first.insert(1, 1).unwrap();
+
+        let mut second = db.begin();
+        let error = second.get_for_update(&1).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BTreeError>(),
+            Some(&BTreeError::LockTimeout)
+        );
+        assert_eq!(second.get(&1).unwrap(), None);
+
+        // rolling back frees the lock
+        first.rollback();
+        second.insert(1, 2).unwrap();
+        second.commit().unwrap();
+        assert_eq!(db.tree().unwrap().get(&1).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn optimistic_commits_fail_on_conflicting_writes() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let db = TransactionalBTree::new(tree, Duration::from_secs(1));
+
+        let mut first = db.begin_optimistic().unwrap();
+        let mut second = db.begin_optimistic().unwrap();
+        for key in [1, 2, 3] {
+            first.insert(key, 1).unwrap();
+        }
+        second.insert(2, 2).unwrap();
+        second.insert(4, 2).unwrap();
+        second.commit().unwrap();
+
+        // writes outside of transactions count too
+        db.tree().unwrap().insert(3, 3).unwrap();
+
+        let error = first.commit().unwrap_err();
+        assert_eq!(error.downcast_ref::<Conflict<u32>>().unwrap().keys, [2, 3]);
+        assert_eq!(db.tree().unwrap().get(&1).unwrap(), None);
+    }
+
+    #[test]
+    fn optimistic_commits_see_deletions_compacted_away_since_they_started() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let tree = BTree::<u32, u32>::with_options("db", 4, 4, options).unwrap();
+        let db = TransactionalBTree::new(tree, Duration::from_secs(1));
+        db.tree().unwrap().insert(1, 1).unwrap();
+        db.tree().unwrap().flush().unwrap();
+
+        let mut txn = db.begin_optimistic().unwrap();
+        assert_eq!(txn.get(&1).unwrap(), Some(vec![1]));
+        txn.delete(1, 1).unwrap();
+        txn.insert(1, 2).unwrap();
+
+        // the deletion's tombstone would be the only trace of it after the compaction
+        db.tree().unwrap().delete(1, 1).unwrap();
+        db.tree().unwrap().flush().unwrap();
+        assert_eq!(db.tree().unwrap().get(&1).unwrap(), None);
+
+        let error = txn.commit().unwrap_err();
+        assert_eq!(error.downcast_ref::<Conflict<u32>>().unwrap().keys, [1]);
+
+        // once no transaction could be looking, the tombstone goes
+        db.tree().unwrap().flush().unwrap();
+        assert_eq!(db.tree().unwrap().records_for(&1).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+use disk_btree::OnDiskBTree;
+use storage::Storage;
+use wal_file::KeyValuePair;
+use {KeyType, ReadRecord, ValueType};
+
+use itertools::Itertools;
+use std::error::Error;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+/// Reads a single tree file, or L0 run, on its own: no WAL is replayed, no
+/// manifest or generation read, and nothing written, so it can be pointed at the
+/// file of a tree another handle has open, or at a copy taken off a broken
+/// machine. Its records come back as they're stored, deletes and all.
+pub struct TreeFileReader<K: KeyType, V: ValueType> {
+    storage: Arc<dyn Storage>,
+    path: String,
+    key_size: usize,
+    value_size: usize,
+    file: OnDiskBTree<K, V>, // opened without its sidecars, in case they're what's damaged
+}
+
+/// What `TreeFileReader::verify` found in a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileCheck {
+    pub version: u8,                // the format the file is in, see `FORMAT_VERSION`
+    pub records: u64,               // that could be read
+    pub damaged_records: u64,       // that couldn't be decoded
+    pub trailing_bytes: u64, // too few for a record at the end, or a file whose blocks can't be found
+    pub out_of_order: u64,   // records with a smaller key than the one before
+    pub open_error: Option<String>, // why the tree couldn't open the file with its sidecars, if it couldn't
+    pub missed_by_sidecars: u64,    // keys a lookup through the filters and indexes doesn't find
+}
+
+impl FileCheck {
+    /// Whether nothing is wrong with the file
+    pub fn is_ok(&self) -> bool {
+        self.damaged_records == 0
+            && self.trailing_bytes == 0
+            && self.out_of_order == 0
+            && self.open_error.is_none()
+            && self.missed_by_sidecars == 0
+    }
+}
+
+impl<K: KeyType, V: ValueType> TreeFileReader<K, V> {
+    pub fn open(
+        storage: Arc<dyn Storage>,
+        path: &str,
+        key_size: usize,
+        value_size: usize,
+    ) -> Result<TreeFileReader<K, V>, Box<dyn Error>> {
+        // opening a file that isn't there would create it
+        if !storage.exists(path)? {
+            return Err(From::from(IOError::new(
+                ErrorKind::NotFound,
+                format!("No file at {}", path),
+            )));
+        }
+
+        let file = OnDiskBTree::without_sidecars(&*storage, path, key_size, value_size)?;
+
+        Ok(TreeFileReader {
+            storage,
+            path: path.to_owned(),
+            key_size,
+            value_size,
+            file,
+        })
+    }
+
+    /// The format the file is in
+    pub fn version(&self) -> u8 {
+        self.file.version()
+    }
+
+    /// The number of records in the file
+    pub fn count(&self) -> Result<u64, Box<dyn Error>> {
+        self.file.count()
+    }
+
+    /// Iterates over the records in the order they're stored, ending with an error
+    /// at the first one that can't be read; `verify` reads past it
+    pub fn iter(&self) -> impl Iterator<Item = ReadRecord<K, V>> + '_ {
+        self.file.iter_from(0)
+    }
+
+    /// Iterates over the records from the first with a key >= `key`
+    pub fn iter_from(
+        &self,
+        key: &K,
+    ) -> Result<impl Iterator<Item = ReadRecord<K, V>> + '_, Box<dyn Error>> {
+        Ok(self.file.iter_from(self.file.lower_bound(key)?))
+    }
+
+    /// Reads every record of the file, checking each decodes, that they're in key
+    /// order, and that the tree, through the file's filters and indexes, would
+    /// find each of their keys
+    pub fn verify(&self) -> Result<FileCheck, Box<dyn Error>> {
+        let salvaged = OnDiskBTree::<K, V>::salvage(
+            &*self.storage,
+            &self.path,
+            self.key_size,
+            self.value_size,
+        )?;
+        let records: &[KeyValuePair<K, V>] = &salvaged.records;
+
+        let mut check = FileCheck {
+            version: self.file.version(),
+            records: records.len() as u64,
+            damaged_records: salvaged.lost,
+            trailing_bytes: salvaged.bytes_lost,
+            out_of_order: records
+                .windows(2)
+                .filter(|pair| pair[0].key > pair[1].key)
+                .count() as u64,
+            ..FileCheck::default()
+        };
+
+        match OnDiskBTree::<K, V>::new(&*self.storage, &self.path, self.key_size, self.value_size) {
+            Ok(indexed) => {
+                for key in records.iter().map(|kv| &kv.key).dedup() {
+                    if indexed.get(key).map_or(true, |found| found.is_empty()) {
+                        check.missed_by_sidecars += 1;
+                    }
+                }
+            }
+            Err(e) => check.open_error = Some(e.to_string()),
+        }
+
+        Ok(check)
+    }
+}
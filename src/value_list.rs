@@ -0,0 +1,131 @@
+use audit_log::AuditOp;
+use wal_file::KeyValuePair;
+use {BTree, KeyType, Options, ValueType};
+
+use std::error::Error;
+use std::ops::RangeBounds;
+
+/// A value in a `ListBTree`, after the sequence number of the write that appended
+/// it. Values sort by it first, so a key's values come back in the order they
+/// were appended, and appending the same value twice stores it twice.
+pub type Sequenced<V> = (u64, V);
+
+/// A tree whose keys hold lists of values rather than sets: in the order they were
+/// appended, duplicates and all. Each value is stored with the sequence number of
+/// its write, so the order is on disk with it and survives compaction and
+/// reopening the tree.
+pub struct ListBTree<K: KeyType, V: ValueType> {
+    tree: BTree<K, Sequenced<V>>,
+}
+
+impl<K: KeyType, V: ValueType> ListBTree<K, V> {
+    /// Opens or creates a list tree, with values of up to `value_size` bytes
+    pub fn with_options(
+        tree_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+        options: Options,
+    ) -> Result<ListBTree<K, V>, Box<dyn Error>> {
+        // the sequence number goes in front of each value
+        Ok(ListBTree {
+            tree: BTree::with_options(tree_file_path, key_size, value_size + 8, options)?,
+        })
+    }
+
+    /// Adds `value` to the end of the list under `key`
+    pub fn push(&mut self, key: K, value: V) -> Result<(), Box<dyn Error>> {
+        let seq = self.tree.last_seq + 1;
+
+        self.tree
+            .insert_record(KeyValuePair::new(key, (seq, value)), AuditOp::Insert, "")
+    }
+
+    /// The list under `key`, oldest first, or None if it's empty
+    pub fn get(&self, key: &K) -> Result<Option<Vec<V>>, Box<dyn Error>> {
+        Ok(self.tree.get(key)?.map(strip))
+    }
+
+    /// Removes the first `value` in the list under `key`, leaving any later ones.
+    /// Returns whether there was one.
+    pub fn remove(&mut self, key: K, value: &V) -> Result<bool, Box<dyn Error>> {
+        let first = self
+            .tree
+            .get(&key)?
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(_, stored)| stored == value);
+
+        match first {
+            Some(sequenced) => self.tree.delete(key, sequenced).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The lists under the keys in `range`, each oldest first
+    pub fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = (K, Vec<V>)> + '_, Box<dyn Error>> {
+        Ok(self
+            .tree
+            .range(range)?
+            .map(|(key, values)| (key, strip(values))))
+    }
+
+    pub fn tree(&self) -> &BTree<K, Sequenced<V>> {
+        &self.tree
+    }
+
+    /// The tree underneath, for flushing, compacting and the like
+    pub fn tree_mut(&mut self) -> &mut BTree<K, Sequenced<V>> {
+        &mut self.tree
+    }
+}
+
+fn strip<V>(values: Vec<Sequenced<V>>) -> Vec<V> {
+    values.into_iter().map(|(_, value)| value).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {ListBTree, Options, SimDisk};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn lists_keep_the_order_values_were_pushed_in() {
+        let options = Options {
+            storage: Arc::new(SimDisk::new(0)),
+            ..Options::default()
+        };
+        let mut lists =
+            ListBTree::<u32, String>::with_options("db", 4, 16, options.clone()).unwrap();
+
+        for value in ["c", "a", "b", "a"] {
+            lists.push(1, value.to_owned()).unwrap();
+        }
+        lists.push(2, "z".to_owned()).unwrap();
+        lists.tree_mut().flush().unwrap();
+        lists.push(1, "c".to_owned()).unwrap();
+
+        let list = |lists: &ListBTree<u32, String>| lists.get(&1).unwrap().unwrap().join("");
+        assert_eq!(list(&lists), "cabac");
+
+        // the first one goes, whether it's on disk or in memory
+        assert!(lists.remove(1, &"a".to_owned()).unwrap());
+        assert!(!lists.remove(1, &"x".to_owned()).unwrap());
+        assert_eq!(list(&lists), "cbac");
+
+        // and the order survives reopening and compaction
+        drop(lists);
+        let mut lists = ListBTree::<u32, String>::with_options("db", 4, 16, options).unwrap();
+        lists.push(1, "a".to_owned()).unwrap();
+        lists.tree_mut().compact_range(..).unwrap();
+
+        assert_eq!(list(&lists), "cbaca");
+        assert_eq!(
+            lists.range(2..).unwrap().collect::<Vec<_>>(),
+            [(2, vec!["z".to_owned()])]
+        );
+    }
+}
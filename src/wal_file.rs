@@ -1,81 +1,331 @@
 use {KeyType, ValueType};
 
+use bloom::{fnv1a, fnv1a_extend};
+use encoding::{Codec, Encoding};
+use lz4;
+use storage::{Storage, StorageFile};
+
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::convert::TryInto;
 use std::error::Error;
-use std::fs::{File, OpenOptions};
 use std::io::Error as IOError;
-use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::ErrorKind;
 use std::marker::PhantomData;
-use serde::{Deserialize, Serialize};
+
+/// The number of bytes each record needs on top of the key and value
+pub const RECORD_OVERHEAD: usize = 29;
+
+/// The bytes a log adds to each of its records: a frame byte, saying whether the
+/// write the record belongs to commits with it, and a checksum of the record
+pub const FRAME_OVERHEAD: usize = 5;
+
+const FRAME_CONTINUES: u8 = 0; // more records of the same write follow
+const FRAME_COMMITS: u8 = 1; // the last record of its write
+const FRAME_COMPRESSED: u8 = 2; // a whole write, its records compressed together
+
+/// The bytes a compressed write starts with: its frame byte, the length of the
+/// compressed records, how many there are, and a checksum of them all
+const COMPRESSED_HEADER: usize = 13;
+
+/// What a record does to its key and value
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RecordKind {
+    Put,        // the value is present
+    Delete,     // the value has been removed
+    SoftDelete, // the value is hidden, but can be restored until it's purged
+    Merge,      // the value is added to the key's count, see `BTree::increment`
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct KeyValuePair<K, V> {
     pub key: K,
     pub value: V,
+    pub kind: RecordKind,
+    pub seq: u64,                // the order the record was written in
+    pub written_at: u64,         // milliseconds since the epoch
+    pub expires_at: Option<u64>, // milliseconds since the epoch, None never expires
+}
+
+impl<K, V> KeyValuePair<K, V> {
+    /// A record that hasn't been assigned a sequence number or timestamp yet
+    pub fn new(key: K, value: V) -> KeyValuePair<K, V> {
+        KeyValuePair {
+            key,
+            value,
+            kind: RecordKind::Put,
+            seq: 0,
+            written_at: 0,
+            expires_at: None,
+        }
+    }
+
+    pub fn is_expired(&self, now_millis: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_millis)
+    }
+
+    /// Whether the value should be returned by reads
+    pub fn is_live(&self, now_millis: u64) -> bool {
+        self.kind == RecordKind::Put && !self.is_expired(now_millis)
+    }
 }
 
+/// Records are sorted by key then value, and when the same pair was written more
+/// than once the newest write comes first
 impl<K: KeyType, V: ValueType> PartialOrd for KeyValuePair<K, V> {
     fn partial_cmp(&self, other: &KeyValuePair<K, V>) -> Option<Ordering> {
-        if self.key == other.key {
-            Some(self.value.cmp(&other.value))
-        } else {
-            Some(self.key.cmp(&other.key))
+        Some(
+            self.key
+                .cmp(&other.key)
+                .then_with(|| self.value.cmp(&other.value))
+                .then_with(|| other.seq.cmp(&self.seq)),
+        )
+    }
+}
+
+/// A test of a value's encoded bytes, see `BTree::range_where`. On disk the bytes
+/// are followed by the rest of the record, so it should read only as far as the
+/// encoding says the value goes.
+pub type ValuePredicate = fn(&[u8]) -> bool;
+
+/// A record read with a `ValuePredicate`: the whole of it when its value passed,
+/// otherwise only its key
+pub enum Filtered<K, V> {
+    Kept(KeyValuePair<K, V>),
+    Skipped(K),
+}
+
+impl<K, V> Filtered<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Filtered::Kept(kv) => &kv.key,
+            Filtered::Skipped(key) => key,
+        }
+    }
+
+    pub fn kept(self) -> Option<KeyValuePair<K, V>> {
+        match self {
+            Filtered::Kept(kv) => Some(kv),
+            Filtered::Skipped(_) => None,
         }
     }
 }
 
+/// Decodes the record `bytes` hold, in `codec`. With a predicate only the key is
+/// decoded first, and the rest only if the value's bytes pass.
+pub fn decode_where<K: KeyType, V: ValueType>(
+    codec: Codec,
+    bytes: &[u8],
+    predicate: Option<ValuePredicate>,
+) -> Result<Filtered<K, V>, Box<dyn Error>> {
+    // predicates test the legacy encoding, which a record in another has to be
+    // decoded whole to get at
+    if let Some(predicate) = predicate.filter(|_| codec.encoding() != Encoding::Legacy) {
+        let kv: KeyValuePair<K, V> = codec.deserialize(bytes)?;
+
+        if !predicate(&Codec::unbounded().serialize(&kv.value)?) {
+            return Ok(Filtered::Skipped(kv.key));
+        }
+
+        return Ok(Filtered::Kept(kv));
+    }
+
+    if let Some(predicate) = predicate {
+        let mut rest = bytes;
+        let key: K = codec.deserialize_from(&mut rest)?;
+
+        // the value is encoded straight after the key
+        if !predicate(rest) {
+            return Ok(Filtered::Skipped(key));
+        }
+    }
+
+    Ok(Filtered::Kept(codec.deserialize(bytes)?))
+}
+
+/// A record's key and what the record does to it, read without decoding its value
+pub struct KeyRecord<K> {
+    pub key: K,
+    pub kind: RecordKind,
+    pub expires_at: Option<u64>,
+}
+
+/// Decodes the key of the record `bytes` hold, in `codec`, stepping over the value
+/// by its length rather than decoding it. That only works for values encoded as
+/// their length followed by their bytes, as strings and byte vectors are.
+pub fn decode_key<K: KeyType>(codec: Codec, bytes: &[u8]) -> Result<KeyRecord<K>, Box<dyn Error>> {
+    let mut rest = bytes;
+    let key: K = codec.deserialize_from(&mut rest)?;
+    let len: u64 = codec.deserialize_from(&mut rest)?;
+
+    let rest = rest
+        .get(len as usize..)
+        .ok_or("The value runs past the end of the record")?;
+    let (kind, _seq, _written_at, expires_at): (RecordKind, u64, u64, Option<u64>) =
+        codec.deserialize(rest)?;
+
+    Ok(KeyRecord {
+        key,
+        kind,
+        expires_at,
+    })
+}
+
 pub struct RecordFile<K: KeyType, V: ValueType> {
-    fd: File, // the file
+    fd: Box<dyn StorageFile>, // the file
     key_size: usize,
     value_size: usize,
+    framed: bool,         // whether it's a log, its records framed into writes
+    coalescing: bool,     // whether records are held back to be appended together
+    pending: Vec<u8>,     // records inserted but not yet appended, when coalescing
+    compression: bool,    // whether each write is appended compressed, in a log
+    counted: Option<u64>, // the records in a log holding compressed writes, which its size doesn't say
+    header: u64,          // the bytes at the start of the file before the first record
+    encoding: Encoding,   // what its records are encoded in
     // Represent TypeState to ensure K and V are not ignored by the compiler
     // event though no value of type K and V are stored
     _k_marker: PhantomData<K>,
     _v_marker: PhantomData<V>,
 }
 
-
-// The a' lifetime is explicitly used because RecordFileIterator holds a mutable reference
+// The a' lifetime is explicitly used because RecordFileIterator holds a reference
 // to RecordFile. This means the iterator's lifetime is directly tied to the lifetime of the borrowed RecordFile.\
 // K: KeyType + 'a and V: ValueType + 'a ensure that the key and value types live at least as long as the iterator.
 pub struct RecordFileIterator<'a, K: KeyType + 'a, V: ValueType + 'a> {
-    wal_file: &'a mut RecordFile<K, V>, // the file
+    wal_file: &'a RecordFile<K, V>, // the file
+    index: u64,                     // the next record to read
+    failed: bool,                   // a read has failed, so there's nothing more to read
+}
+
+/// The writes read back from a log, see `RecordFile::replay_from`
+pub struct Replay<K, V> {
+    pub records: Vec<KeyValuePair<K, V>>, // of every committed write, in the order written
+    pub writes: u64,                      // how many committed writes they make up
+    pub next: u64,                        // the index of the record after the last commit
+    pub next_offset: u64,                 // where in the file that record starts
+    pub aborted: u64,                     // whole records after it, of a write that never committed
+    pub compressed: bool,                 // whether any of the writes read were compressed
+}
+
+/// A framed record or a compressed write, as read from a log
+struct Logged<K, V> {
+    records: Vec<KeyValuePair<K, V>>,
+    commits: bool, // whether their write commits with them
+    len: u64,      // the bytes they take up
+    compressed: bool,
+}
+
+/// What opening a BTree found in its WAL
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub records: u64,         // replayed into the memtable
+    pub writes: u64,          // the committed writes, or batches of them, the records made up
+    pub aborted: u64,         // records of a write cut off by a crash before it committed
+    pub discarded_bytes: u64, // after the last commit, aborted records and torn ones alike, dropped
+}
+
+/// What opening a BTree to write to did to bring it back to where it was before
+/// it was last closed, or crashed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub records_replayed: u64,          // from the WAL into the memtable
+    pub writes_replayed: u64,           // the committed writes, or batches of them, those made up
+    pub prepared_batches_applied: u64, // committed by `BTree::commit` but cut off before reaching the WAL
+    pub torn_tail_truncated: bool,     // whether what followed the WAL's last commit was cut off
+    pub stale_compaction_cleaned: bool, // whether a flush or compaction cut off part way was cleaned up
 }
 
 impl<K: KeyType, V: ValueType> RecordFile<K, V> {
     pub fn new(
-        wal_file_path: &String,
+        storage: &dyn Storage,
+        wal_file_path: &str,
         key_size: usize,
         value_size: usize,
     ) -> Result<RecordFile<K, V>, Box<dyn Error>> {
-        let wal_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(wal_file_path)?;
+        let wal_file = storage.open(wal_file_path)?;
 
         Ok(RecordFile {
             fd: wal_file,
             key_size,
             value_size,
+            framed: false,
+            coalescing: false,
+            pending: Vec::new(),
+            compression: false,
+            counted: None,
+            header: 0,
+            encoding: Encoding::Legacy,
             _k_marker: PhantomData,
             _v_marker: PhantomData,
         })
     }
 
+    /// Opens a log: a file whose records are framed into writes, so replaying it
+    /// skips a write that was cut off part way, and checksummed, so a torn record
+    /// isn't mistaken for a whole one
+    pub fn new_log(
+        storage: &dyn Storage,
+        wal_file_path: &str,
+        key_size: usize,
+        value_size: usize,
+    ) -> Result<RecordFile<K, V>, Box<dyn Error>> {
+        let mut wal_file = RecordFile::new(storage, wal_file_path, key_size, value_size)?;
+        wal_file.framed = true;
+
+        Ok(wal_file)
+    }
+
     pub fn is_new(&self) -> Result<bool, Box<dyn Error>> {
-        Ok(self.fd.metadata()?.len() == 0)
+        Ok(self.fd.len()? <= self.header)
+    }
+
+    /// Takes the first `len` bytes of the file as a header, which every offset
+    /// and index into the file counts from the end of
+    pub fn set_header_len(&mut self, len: u64) {
+        self.header = len;
+    }
+
+    /// Reads and writes records in `encoding`, as the file's header says they are
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The size of a single record on disk
+    pub fn record_size(&self) -> usize {
+        if self.framed {
+            self.payload_size() + FRAME_OVERHEAD
+        } else {
+            self.payload_size()
+        }
+    }
+
+    /// The size of a record without its frame
+    fn payload_size(&self) -> usize {
+        self.key_size + self.value_size + RECORD_OVERHEAD
+    }
+
+    /// Decodes records no bigger than one can be
+    pub fn codec(&self) -> Codec {
+        Codec::new(self.payload_size()).with_encoding(self.encoding)
     }
 
     /// Returns the number of records in the WAL file
     pub fn count(&self) -> Result<u64, Box<dyn Error>> {
-        let file_size = self.fd.metadata()?.len();
-        let rec_size: u64 = (self.key_size + self.value_size) as u64;
+        if let Some(count) = self.counted {
+            return Ok(count);
+        }
 
-        if file_size % rec_size != 0 {
+        let file_size = self.fd.len()? + self.pending.len() as u64 - self.header;
+        let rec_size = self.record_size() as u64;
+
+        if !file_size.is_multiple_of(rec_size) {
             Err(From::from(IOError::new(
                 ErrorKind::InvalidData,
-                "File size is NOT a multiple of key size + value size",
+                "File size is NOT a multiple of the record size",
             )))
         } else {
             Ok(file_size / rec_size)
@@ -83,67 +333,416 @@ impl<K: KeyType, V: ValueType> RecordFile<K, V> {
     }
 
     pub fn insert_record(&mut self, kv: &KeyValuePair<K, V>) -> Result<(), Box<dyn Error>> {
-        // encode the record
-        let record_size = self.key_size + self.value_size;
+        self.insert_records(std::slice::from_ref(kv))
+    }
+
+    /// Appends `records` in a single write. In a log they're framed as one write,
+    /// which is replayed whole or not at all.
+    pub fn insert_records(&mut self, records: &[KeyValuePair<K, V>]) -> Result<(), Box<dyn Error>> {
+        let mut buff = Vec::with_capacity(records.len() * self.record_size());
+
+        for (i, kv) in records.iter().enumerate() {
+            let mut payload = self.encode_record(kv)?;
+
+            // pad it out to the max size
+            payload.resize(self.payload_size(), 0);
+
+            if self.framed && !self.compression {
+                let frame = if i + 1 == records.len() {
+                    FRAME_COMMITS
+                } else {
+                    FRAME_CONTINUES
+                };
+
+                buff.push(frame);
+                buff.extend_from_slice(&checksum(frame, &payload).to_le_bytes());
+            }
+
+            buff.extend_from_slice(&payload);
+        }
+
+        if self.compression && !records.is_empty() {
+            buff = compress_write(&buff, records.len());
+        }
+
+        if let Some(count) = self.counted.as_mut() {
+            *count += records.len() as u64;
+        }
+
+        if self.coalescing {
+            self.pending.extend_from_slice(&buff);
+            return Ok(());
+        }
+
+        Ok(self.fd.append(&buff)?)
+    }
+
+    /// Holds inserted records back until the next sync and appends them all in one
+    /// write, rather than making a write per record. Turning it off appends any
+    /// records being held.
+    pub fn set_coalescing(&mut self, coalescing: bool) -> Result<(), Box<dyn Error>> {
+        if !coalescing {
+            self.append_pending()?;
+        }
+
+        self.coalescing = coalescing;
+        Ok(())
+    }
+
+    /// Compresses each write appended to a log from now on, with lz4, as a single
+    /// block. It's set before anything is held back by coalescing, as the records
+    /// already in the file, compressed or not, are counted by replaying them.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+        self.counted = Some(self.replay_from(0).next);
+    }
+
+    fn append_pending(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.pending.is_empty() {
+            self.fd.append(&self.pending)?;
+            self.pending.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Encodes a record without padding, checking that it fits in a record
+    pub fn encode_record(&self, kv: &KeyValuePair<K, V>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let record_size = self.payload_size();
         let mut buff = Vec::with_capacity(record_size);
-        bincode::serialize_into(&mut buff, &kv)?;
+        Codec::unbounded()
+            .with_encoding(self.encoding)
+            .serialize_into(&mut buff, &kv)?;
 
-        // pad it out to the max size
-        if buff.len() > self.key_size + self.value_size {
+        if buff.len() > record_size {
             return Err(From::from(IOError::new(
                 ErrorKind::InvalidData,
                 "Key and value size are too large",
             )));
-        } else {
-            let diff = (self.key_size + self.value_size) - buff.len();
-            buff.extend(vec![0; diff]);
         }
 
-        match self.fd.write_all(&buff) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(From::from(e)),
+        Ok(buff)
+    }
+
+    /// Iterates over the records from `index` onwards
+    pub fn iter_from(&self, index: u64) -> RecordFileIterator<'_, K, V> {
+        RecordFileIterator {
+            wal_file: self,
+            index,
+            failed: false,
         }
     }
+
+    /// Reads the record at `index`, counting from the start of the file
+    pub fn read_record(&self, index: u64) -> Result<KeyValuePair<K, V>, Box<dyn Error>> {
+        Ok(self.read_framed(index)?.0)
+    }
+
+    /// Reads the record at `index`, and whether its write commits with it
+    fn read_framed(&self, index: u64) -> Result<(KeyValuePair<K, V>, bool), Box<dyn Error>> {
+        let (payload, commits) = self.read_payload(index)?;
+
+        Ok((self.codec().deserialize(&payload)?, commits))
+    }
+
+    /// Reads the encoded record at `index`, with any frame checked and taken off,
+    /// and whether its write commits with it
+    pub fn read_payload(&self, index: u64) -> Result<(Vec<u8>, bool), Box<dyn Error>> {
+        self.read_payload_at(index * self.record_size() as u64)
+            .map_err(|e| From::from(format!("Record {}: {}", index, e)))
+    }
+
+    fn read_payload_at(&self, offset: u64) -> Result<(Vec<u8>, bool), Box<dyn Error>> {
+        let mut buff = vec![0; self.record_size()];
+
+        self.fd.read_at(&mut buff, self.header + offset)?;
+
+        if !self.framed {
+            return Ok((buff, true));
+        }
+
+        let frame = buff[0];
+
+        if u32::from_le_bytes(buff[1..FRAME_OVERHEAD].try_into()?)
+            != checksum(frame, &buff[FRAME_OVERHEAD..])
+        {
+            return Err(From::from(IOError::new(
+                ErrorKind::InvalidData,
+                "failed its checksum",
+            )));
+        }
+
+        buff.drain(..FRAME_OVERHEAD);
+
+        Ok((buff, frame == FRAME_COMMITS))
+    }
+
+    /// Reads what's logged at `offset`: one framed record, or a compressed write of
+    /// any number of them
+    fn read_logged(&self, offset: u64) -> Result<Logged<K, V>, Box<dyn Error>> {
+        let mut header = [0; COMPRESSED_HEADER];
+        self.fd.read_at(&mut header[..1], offset)?;
+
+        if header[0] != FRAME_COMPRESSED {
+            let (payload, commits) = self.read_payload_at(offset)?;
+
+            return Ok(Logged {
+                records: vec![self.codec().deserialize(&payload)?],
+                commits,
+                len: self.record_size() as u64,
+                compressed: false,
+            });
+        }
+
+        self.fd.read_at(&mut header, offset)?;
+
+        let len = u32::from_le_bytes(header[1..5].try_into()?) as u64;
+        let count = u32::from_le_bytes(header[5..9].try_into()?) as usize;
+
+        // a torn header can claim any length
+        if offset + COMPRESSED_HEADER as u64 + len > self.fd.len()? {
+            return Err(From::from(IOError::new(
+                ErrorKind::UnexpectedEof,
+                "compressed write was cut off",
+            )));
+        }
+
+        let mut block = vec![0; len as usize];
+        self.fd
+            .read_at(&mut block, offset + COMPRESSED_HEADER as u64)?;
+
+        let sum = fnv1a_extend(fnv1a_extend(fnv1a(&header[..1]), &header[1..9]), &block) as u32;
+
+        if u32::from_le_bytes(header[9..].try_into()?) != sum {
+            return Err(From::from(IOError::new(
+                ErrorKind::InvalidData,
+                "compressed write failed its checksum",
+            )));
+        }
+
+        let payloads = lz4::decompress(&block, count * self.payload_size())?;
+        let records = payloads
+            .chunks(self.payload_size())
+            .map(|payload| self.codec().deserialize(payload).map_err(From::from))
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        Ok(Logged {
+            records,
+            commits: true,
+            len: COMPRESSED_HEADER as u64 + len,
+            compressed: true,
+        })
+    }
+
+    /// Reads back the writes logged from record `index` on, up to the first record
+    /// that's torn or fails its checksum. Only the records of writes that reached
+    /// their commit are returned; those of a write cut short are counted as aborted.
+    ///
+    /// Once compressed writes may be in the file, records no longer sit at a fixed
+    /// offset, so it's read from the start and the records before `index` skipped.
+    pub fn replay_from(&self, index: u64) -> Replay<K, V> {
+        let mut replay = Replay {
+            records: Vec::new(),
+            writes: 0,
+            next: index,
+            next_offset: index * self.record_size() as u64,
+            aborted: 0,
+            compressed: false,
+        };
+        let mut write = Vec::new();
+
+        if index > 0 && self.counted.is_none() {
+            while let Ok((kv, commits)) = self.read_framed(replay.next + write.len() as u64) {
+                write.push(kv);
+
+                if commits {
+                    replay.next += write.len() as u64;
+                    replay.records.append(&mut write);
+                    replay.writes += 1;
+                }
+            }
+
+            replay.next_offset = replay.next * self.record_size() as u64;
+            replay.aborted = write.len() as u64;
+            return replay;
+        }
+
+        let (mut next, mut offset) = (0, 0);
+
+        while let Ok(mut logged) = self.read_logged(offset) {
+            replay.compressed |= logged.compressed;
+            offset += logged.len;
+            write.append(&mut logged.records);
+
+            if logged.commits {
+                let skipped = index.saturating_sub(next).min(write.len() as u64);
+                next += write.len() as u64;
+                write.drain(..skipped as usize);
+
+                if !write.is_empty() {
+                    replay.records.append(&mut write);
+                    replay.writes += 1;
+                }
+
+                replay.next = next.max(index);
+                replay.next_offset = offset;
+            }
+        }
+
+        replay.aborted = write.len() as u64;
+        replay
+    }
+
+    /// Reads `count` records starting at `index` without decoding them
+    pub fn read_raw(&self, index: u64, count: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let record_size = self.record_size() as u64;
+        let mut buff = vec![0; (count * record_size) as usize];
+
+        self.fd
+            .read_at(&mut buff, self.header + index * record_size)?;
+
+        Ok(buff)
+    }
+
+    /// The size of the file in bytes, after its header
+    pub fn byte_len(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.fd.len()?.saturating_sub(self.header))
+    }
+
+    /// Reads `len` bytes at `offset`, for files not laid out as padded records
+    pub fn read_bytes(&self, offset: u64, len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buff = vec![0; len as usize];
+
+        self.fd.read_at(&mut buff, self.header + offset)?;
+
+        Ok(buff)
+    }
+
+    /// Appends `bytes` as they are, for files not laid out as padded records
+    pub fn append_bytes(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(self.fd.append(bytes)?)
+    }
+
+    /// Flushes all inserted records to durable storage
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.append_pending()?;
+        Ok(self.fd.sync()?)
+    }
+
+    /// Removes every record from the file
+    pub fn truncate(&mut self) -> Result<(), Box<dyn Error>> {
+        self.truncate_at(0)
+    }
+
+    /// Removes everything from `offset` on, which is where a record or a write
+    /// starts, as `Replay::next_offset` is
+    pub fn truncate_at(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        self.pending.clear();
+        self.fd.truncate(offset)?;
+
+        if offset == 0 && !self.compression {
+            self.counted = None;
+        } else if self.counted.is_some() {
+            self.set_compression(self.compression);
+        }
+
+        self.sync()
+    }
+
+    /// The bytes appended to the file, counting any held back by coalescing
+    pub fn appended_len(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(self.fd.len()? + self.pending.len() as u64)
+    }
+}
+
+fn checksum(frame: u8, payload: &[u8]) -> u32 {
+    fnv1a_extend(fnv1a(&[frame]), payload) as u32
+}
+
+/// Frames the padded records of a write, `count` of them, as one compressed block
+fn compress_write(payloads: &[u8], count: usize) -> Vec<u8> {
+    let block = lz4::compress(payloads);
+    let mut header = [0; COMPRESSED_HEADER];
+
+    header[0] = FRAME_COMPRESSED;
+    header[1..5].copy_from_slice(&(block.len() as u32).to_le_bytes());
+    header[5..9].copy_from_slice(&(count as u32).to_le_bytes());
+
+    let sum = fnv1a_extend(fnv1a_extend(fnv1a(&header[..1]), &header[1..9]), &block) as u32;
+    header[9..].copy_from_slice(&sum.to_le_bytes());
+
+    let mut buff = header.to_vec();
+    buff.extend_from_slice(&block);
+    buff
 }
 
-impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a mut RecordFile<K, V> {
-    type Item = KeyValuePair<K, V>;
+/// Records held back by coalescing still reach the file when it's closed, though
+/// they're only durable if it was synced
+impl<K: KeyType, V: ValueType> Drop for RecordFile<K, V> {
+    fn drop(&mut self) {
+        let _ = self.append_pending();
+    }
+}
+
+impl<'a, K: KeyType, V: ValueType> IntoIterator for &'a RecordFile<K, V> {
+    type Item = Result<KeyValuePair<K, V>, Box<dyn Error>>;
     type IntoIter = RecordFileIterator<'a, K, V>;
 
     fn into_iter(self) -> Self::IntoIter {
-        // seek back to the start
-        self.fd.seek(SeekFrom::Start(0)).expect("TODO: panic message");
-
-        // create our iterator
-        RecordFileIterator { wal_file: self }
+        // start at the first record
+        self.iter_from(0)
     }
 }
 
-impl<'a, K: KeyType, V: ValueType> Iterator for RecordFileIterator<'a, K, V> {
-    type Item = KeyValuePair<K, V>;
+impl<'a, K: KeyType, V: ValueType> RecordFileIterator<'a, K, V> {
+    /// Reads the next record with `decode`, which is given the file's codec and
+    /// the record's encoded bytes. A record that can't be read or decoded is
+    /// returned as an error, and ends the iteration.
+    pub fn next_decoded<R, F>(&mut self, decode: F) -> Option<Result<R, Box<dyn Error>>>
+    where
+        F: FnOnce(Codec, &[u8]) -> Result<R, Box<dyn Error>>,
+    {
+        if self.failed {
+            return None;
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let total_size = self.wal_file.key_size + self.wal_file.value_size;
-        let mut buff = vec![0; total_size];
+        let read = || -> Result<Option<R>, Box<dyn Error>> {
+            if self.index >= self.wal_file.count()? {
+                return Ok(None);
+            }
 
-        println!("Creating buffer: {}", total_size);
+            let (payload, _) = self.wal_file.read_payload(self.index)?;
 
-        // attempt to read a buffer's worth and decode
-        match self.wal_file.fd.read_exact(&mut buff) {
-            Ok(_) => match bincode::deserialize(&buff) {
-                Ok(record) => Some(record),
-                Err(_) => None,
-            },
-            Err(_) => None
+            decode(self.wal_file.codec(), &payload).map(Some)
+        };
+
+        match read() {
+            Ok(record) => {
+                self.index += 1;
+                record.map(Ok)
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
         }
     }
 }
 
+impl<'a, K: KeyType, V: ValueType> Iterator for RecordFileIterator<'a, K, V> {
+    type Item = Result<KeyValuePair<K, V>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_decoded(|codec, bytes| Ok(codec.deserialize(bytes)?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use storage::FileStorage;
     use tests::gen_temp_name;
-    use wal_file::{KeyValuePair, RecordFile};
+    use wal_file::{KeyValuePair, RecordFile, RecordKind};
 
     #[test]
     fn test_iterator() {
@@ -151,17 +750,25 @@ mod tests {
         let file_path = temp_path.to_owned() + ".wal";
 
         // create a new blank file
-        let mut wal_file = RecordFile::new(&file_path, 20, 20).unwrap();
+        let mut wal_file = RecordFile::new(&FileStorage, &file_path, 20, 20).unwrap();
 
         assert!(wal_file.is_new().unwrap());
 
         let kv1 = KeyValuePair {
             key: "hello".to_owned(),
             value: "world".to_owned(),
+            kind: RecordKind::Put,
+            seq: 1,
+            written_at: 100,
+            expires_at: None,
         };
         let kv2 = KeyValuePair {
             key: "foo".to_owned(),
             value: "bar".to_owned(),
+            kind: RecordKind::SoftDelete,
+            seq: 2,
+            written_at: 200,
+            expires_at: Some(1234),
         };
 
         wal_file.insert_record(&kv1).unwrap();
@@ -171,16 +778,88 @@ mod tests {
 
         let mut wal_it = wal_file.into_iter();
 
-        let it_kv1 = wal_it.next().unwrap();
+        let it_kv1 = wal_it.next().unwrap().unwrap();
 
         assert_eq!(kv1.key, it_kv1.key);
         assert_eq!(kv1.value, it_kv1.value);
 
-        let it_kv2 = wal_it.next().unwrap();
+        let it_kv2 = wal_it.next().unwrap().unwrap();
 
         assert_eq!(kv2.key, it_kv2.key);
         assert_eq!(kv2.value, it_kv2.value);
+        assert_eq!(kv2.kind, it_kv2.kind);
+        assert_eq!(kv2.seq, it_kv2.seq);
+        assert_eq!(kv2.written_at, it_kv2.written_at);
+        assert_eq!(kv2.expires_at, it_kv2.expires_at);
 
         fs::remove_file(&file_path).expect("TODO: panic message");
     }
+
+    #[test]
+    fn coalesced_records_are_appended_at_the_sync() {
+        let file_path = gen_temp_name() + ".wal";
+        let mut wal_file = RecordFile::new(&FileStorage, &file_path, 8, 8).unwrap();
+        wal_file.set_coalescing(true).unwrap();
+
+        for i in 0..10u64 {
+            wal_file.insert_record(&KeyValuePair::new(i, i)).unwrap();
+        }
+
+        // held back, but counted
+        assert!(wal_file.is_new().unwrap());
+        assert_eq!(wal_file.count().unwrap(), 10);
+
+        wal_file.sync().unwrap();
+        assert_eq!(
+            wal_file.byte_len().unwrap(),
+            10 * wal_file.record_size() as u64
+        );
+        assert_eq!(
+            wal_file
+                .into_iter()
+                .map(|kv| kv.unwrap().key)
+                .collect::<Vec<u64>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+
+        // and whatever is still held back is appended when the file is closed
+        wal_file.insert_record(&KeyValuePair::new(10, 10)).unwrap();
+        drop(wal_file);
+
+        let wal_file = RecordFile::<u64, u64>::new(&FileStorage, &file_path, 8, 8).unwrap();
+        assert_eq!(wal_file.count().unwrap(), 11);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn replay_skips_writes_that_never_committed() {
+        let file_path = gen_temp_name() + ".wal";
+        let mut wal_file = RecordFile::new_log(&FileStorage, &file_path, 8, 8).unwrap();
+        let records: Vec<KeyValuePair<u64, u64>> =
+            (0..6).map(|i| KeyValuePair::new(i, i)).collect();
+
+        wal_file.insert_record(&records[0]).unwrap();
+        wal_file.insert_records(&records[1..4]).unwrap();
+        wal_file.insert_records(&records[4..]).unwrap();
+        assert_eq!(wal_file.replay_from(0).records, records);
+
+        // cut the last write off part way through its second record
+        let record_size = wal_file.record_size() as u64;
+        wal_file.fd.truncate(5 * record_size + 3).unwrap();
+
+        let replay = wal_file.replay_from(0);
+        assert_eq!(replay.records, records[..4]);
+        assert_eq!((replay.writes, replay.next, replay.aborted), (2, 4, 1));
+
+        // a corrupt record ends the replay too
+        wal_file.truncate_at(4 * record_size).unwrap();
+        wal_file.fd.append(&vec![1; record_size as usize]).unwrap();
+
+        let replay = wal_file.replay_from(1);
+        assert_eq!(replay.records, records[1..4]);
+        assert_eq!(replay.aborted, 0);
+
+        fs::remove_file(&file_path).unwrap();
+    }
 }
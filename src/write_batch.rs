@@ -0,0 +1,104 @@
+use wal_file::{KeyValuePair, RecordKind};
+
+use std::error::Error;
+
+/// Writes made together, see `BTree::write`: they're logged as one write, so after
+/// a crash either all of them are there or none are
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteBatch<K, V> {
+    records: Vec<KeyValuePair<K, V>>,
+    savepoints: Vec<usize>, // how many records there were at each savepoint, oldest first
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> WriteBatch<K, V> {
+        WriteBatch {
+            records: Vec::new(),
+            savepoints: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.records.push(KeyValuePair::new(key, value));
+    }
+
+    pub fn delete(&mut self, key: K, value: V) {
+        self.records.push(KeyValuePair {
+            kind: RecordKind::Delete,
+            ..KeyValuePair::new(key, value)
+        });
+    }
+
+    /// Marks the writes so far, for `rollback_to_savepoint` to go back to.
+    /// Savepoints nest: each rollback goes back to the newest one still set.
+    pub fn set_savepoint(&mut self) {
+        self.savepoints.push(self.records.len());
+    }
+
+    /// Drops the writes added since the newest savepoint, and the savepoint with them
+    pub fn rollback_to_savepoint(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.savepoints.pop() {
+            Some(len) => {
+                self.records.truncate(len);
+                Ok(())
+            }
+            None => Err(From::from("There's no savepoint to roll back to")),
+        }
+    }
+
+    /// Forgets the newest savepoint, keeping the writes made since
+    pub fn pop_savepoint(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.savepoints.pop() {
+            Some(_) => Ok(()),
+            None => Err(From::from("There's no savepoint to pop")),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn records(&self) -> &[KeyValuePair<K, V>] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<KeyValuePair<K, V>> {
+        self.records
+    }
+}
+
+impl<K, V> Default for WriteBatch<K, V> {
+    fn default() -> WriteBatch<K, V> {
+        WriteBatch::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use write_batch::WriteBatch;
+
+    #[test]
+    fn savepoints_undo_part_of_a_batch() {
+        let mut batch = WriteBatch::<u32, u32>::new();
+        batch.insert(1, 1);
+
+        batch.set_savepoint();
+        batch.insert(2, 2);
+        batch.set_savepoint();
+        batch.delete(1, 1);
+        batch.pop_savepoint().unwrap();
+        batch.insert(3, 3);
+
+        assert_eq!(batch.len(), 4);
+        batch.rollback_to_savepoint().unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(batch.rollback_to_savepoint().is_err());
+
+        let keys: Vec<u32> = batch.into_records().into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, [1]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// A budget for the bytes waiting in the memtables of every tree given it through
+/// `Options::write_buffer_manager`. When their total goes over it, the tree holding
+/// the most is made to flush, the next time it's written to.
+pub struct WriteBufferManager {
+    budget: usize,
+    state: Mutex<BufferState>,
+}
+
+struct BufferState {
+    next_id: u64,
+    pending: HashMap<u64, usize>, // the bytes in each tree's memtable, as last reported
+    flush_due: HashSet<u64>,      // the trees told to flush that haven't written since
+}
+
+impl WriteBufferManager {
+    pub fn new(budget: usize) -> WriteBufferManager {
+        WriteBufferManager {
+            budget,
+            state: Mutex::new(BufferState {
+                next_id: 0,
+                pending: HashMap::new(),
+                flush_due: HashSet::new(),
+            }),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// The bytes waiting in the memtables of all the trees sharing the budget
+    pub fn usage(&self) -> usize {
+        self.state.lock().unwrap().pending.values().sum()
+    }
+
+    /// Adds a tree to those sharing the budget, until the share is dropped
+    pub fn join(manager: &Arc<WriteBufferManager>) -> WriteBufferShare {
+        let mut state = manager.state.lock().unwrap();
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.insert(id, 0);
+
+        WriteBufferShare {
+            id,
+            manager: manager.clone(),
+        }
+    }
+}
+
+/// One tree's part of a `WriteBufferManager`'s budget
+pub struct WriteBufferShare {
+    id: u64,
+    manager: Arc<WriteBufferManager>,
+}
+
+impl WriteBufferShare {
+    /// Records that the tree's memtable now holds `pending_bytes`, and returns
+    /// whether it should flush: because it was told to, or because the trees are
+    /// over budget and it holds the most
+    pub fn report(&self, pending_bytes: usize) -> bool {
+        let mut state = self.manager.state.lock().unwrap();
+
+        state.pending.insert(self.id, pending_bytes);
+
+        if state.pending.values().sum::<usize>() > self.manager.budget {
+            let largest = state
+                .pending
+                .iter()
+                .max_by_key(|(_, bytes)| **bytes)
+                .map(|(id, _)| *id);
+            state.flush_due.extend(largest);
+        }
+
+        state.flush_due.remove(&self.id)
+    }
+}
+
+impl Drop for WriteBufferShare {
+    fn drop(&mut self) {
+        let mut state = self.manager.state.lock().unwrap();
+
+        state.pending.remove(&self.id);
+        state.flush_due.remove(&self.id);
+    }
+}
@@ -0,0 +1,121 @@
+use storage::{Storage, StorageFile};
+use KeyType;
+
+use std::error::Error;
+
+/// The path of the sidecar holding the zone map for the file at `path`, a
+/// little-endian u64 length followed by the bincode-encoded zones
+pub fn zone_map_path(path: &str) -> String {
+    path.to_owned() + ".zones"
+}
+
+/// The smallest and largest key of each block of a file, kept in memory so a
+/// search can go straight to the one block that could hold a key
+pub struct ZoneMap<K: KeyType> {
+    zones: Vec<(K, K)>,
+}
+
+/// Builds the zone map of a file as its records are written
+pub struct ZoneMapBuilder<K: KeyType> {
+    file: Box<dyn StorageFile>,
+    per_block: u64,
+    in_block: u64, // records added to the current block
+    zones: Vec<(K, K)>,
+}
+
+impl<K: KeyType> ZoneMapBuilder<K> {
+    pub fn new(
+        storage: &dyn Storage,
+        path: &str,
+        per_block: u64,
+    ) -> Result<ZoneMapBuilder<K>, Box<dyn Error>> {
+        let path = zone_map_path(path);
+
+        storage.remove_if_exists(&path)?;
+
+        Ok(ZoneMapBuilder {
+            file: storage.open(&path)?,
+            per_block,
+            in_block: 0,
+            zones: Vec::new(),
+        })
+    }
+
+    /// Adds the key of the next record written to the file
+    pub fn add(&mut self, key: &K) {
+        match self.zones.last_mut() {
+            Some((_, last)) if self.in_block > 0 => *last = key.clone(),
+            _ => self.zones.push((key.clone(), key.clone())),
+        }
+
+        self.in_block = (self.in_block + 1) % self.per_block;
+    }
+
+    /// Writes out and syncs the sidecar, returning the zone map ready for searches
+    pub fn finish(mut self) -> Result<ZoneMap<K>, Box<dyn Error>> {
+        let encoded = bincode::serialize(&self.zones)?;
+
+        self.file.append(&(encoded.len() as u64).to_le_bytes())?;
+        self.file.append(&encoded)?;
+        self.file.sync()?;
+
+        Ok(ZoneMap { zones: self.zones })
+    }
+}
+
+impl<K: KeyType> ZoneMap<K> {
+    /// Loads the zone map for the file at `path`, ignoring an incomplete one or one
+    /// that doesn't have a zone for each of the file's `blocks`
+    pub fn open(
+        storage: &dyn Storage,
+        path: &str,
+        blocks: u64,
+    ) -> Result<Option<ZoneMap<K>>, Box<dyn Error>> {
+        let path = zone_map_path(path);
+
+        if !storage.exists(&path)? {
+            return Ok(None);
+        }
+
+        let file = storage.open(&path)?;
+        let len = file.len()?;
+
+        if len < 8 {
+            return Ok(None);
+        }
+
+        let mut encoded_len = [0; 8];
+        file.read_at(&mut encoded_len, 0)?;
+        let encoded_len = u64::from_le_bytes(encoded_len);
+
+        if len != 8 + encoded_len {
+            return Ok(None);
+        }
+
+        let mut encoded = vec![0; encoded_len as usize];
+        file.read_at(&mut encoded, 8)?;
+
+        match bincode::deserialize::<Vec<(K, K)>>(&encoded) {
+            Ok(zones) if zones.len() as u64 == blocks => Ok(Some(ZoneMap { zones })),
+            _ => Ok(None),
+        }
+    }
+
+    /// The first block whose largest key fails `pred`, which holds for every key
+    /// before some point and for none after it. Every record in the earlier blocks
+    /// passes, so the first record to fail is in this block, if there is one.
+    pub fn partition_block(&self, pred: impl Fn(&K) -> bool) -> u64 {
+        self.zones.partition_point(|(_, last)| pred(last)) as u64
+    }
+
+    /// False when `key` falls outside the file's keys, or between two blocks
+    pub fn may_contain(&self, key: &K) -> bool {
+        match self
+            .zones
+            .get(self.partition_block(|last| last < key) as usize)
+        {
+            Some((first, _)) => first <= key,
+            None => false,
+        }
+    }
+}